@@ -8,6 +8,12 @@ use std::cell::RefCell;
 use std::num::NonZeroUsize;
 use std::rc::Rc;
 
+/// Length of the decrypted pages kept in the read cache.
+const PAGE_LEN: usize = 0x200;
+
+/// Number of decrypted pages cached by default.
+pub const DEFAULT_CACHE_PAGES: usize = 16;
+
 /// Implements `RandomAccessFile` layer that does AES-128-CTR encryption
 pub struct AesCtrFile {
     data: Rc<dyn RandomAccessFile>,
@@ -15,6 +21,8 @@ pub struct AesCtrFile {
     ctr: [u8; 16],
     len: usize,
     cache: RefCell<LruCache<usize, [u8; 16]>>, // cache for recent XOR pads
+    // Cache for recently read pages of decrypted data, keyed by page index. `None` if disabled.
+    page_cache: RefCell<Option<LruCache<usize, Vec<u8>>>>,
     repeat_ctr: bool,
 }
 
@@ -33,24 +41,50 @@ impl AesCtrFile {
     /// - `key`: the 128-bit AES key.
     /// - `ctr`: the 128-bit IV / CTR.
     /// - `repeat_ctr`: whether to emulate a 3DS bug where CTR is reused every 512 bytes.
+    ///   When set, the counter for the 16-byte block at index `i` is `ctr + i % 0x20`
+    ///   instead of `ctr + i`.
     pub fn new(
         data: Rc<dyn RandomAccessFile>,
         key: [u8; 16],
         ctr: [u8; 16],
         repeat_ctr: bool,
+    ) -> AesCtrFile {
+        AesCtrFile::with_cipher(data, Aes128::new(key[..].into()), ctr, repeat_ctr)
+    }
+
+    /// Creates a new `AesCtrFile` with an already expanded key, so that many files
+    /// encrypted with the same key don't each redo the key expansion.
+    pub fn with_cipher(
+        data: Rc<dyn RandomAccessFile>,
+        aes128: Aes128,
+        ctr: [u8; 16],
+        repeat_ctr: bool,
     ) -> AesCtrFile {
         let len = data.len();
-        let aes128 = Aes128::new(key[..].into());
         AesCtrFile {
             data,
             aes128,
             ctr,
             len,
             cache: RefCell::new(LruCache::new(NonZeroUsize::new(16).unwrap())),
+            page_cache: RefCell::new(NonZeroUsize::new(DEFAULT_CACHE_PAGES).map(LruCache::new)),
             repeat_ctr,
         }
     }
 
+    /// Sets how many 512-byte pages of decrypted data are kept to serve repeated reads
+    /// without reading and decrypting the underlying file again. `0` disables the cache.
+    /// [`DEFAULT_CACHE_PAGES`] is used if this is never called.
+    ///
+    /// Reads spanning more pages than the whole cache bypass it.
+    pub fn set_cache_pages(&self, pages: usize) {
+        let mut page_cache = self.page_cache.borrow_mut();
+        match (page_cache.as_mut(), NonZeroUsize::new(pages)) {
+            (Some(cache), Some(pages)) => cache.resize(pages),
+            (_, pages) => *page_cache = pages.map(LruCache::new),
+        }
+    }
+
     /// Get the XOR pad for the specified block.
     fn get_pad(&self, mut block_index: usize) -> [u8; 16] {
         if self.repeat_ctr {
@@ -60,6 +94,8 @@ impl AesCtrFile {
         if let Some(cached) = cache.get(&block_index) {
             *cached
         } else {
+            #[cfg(feature = "metrics")]
+            crate::metrics::record_aes_block();
             let mut ctr = self.ctr;
             seek_ctr(&mut ctr, block_index);
             self.aes128.encrypt_block((&mut ctr[..]).into());
@@ -67,21 +103,16 @@ impl AesCtrFile {
             ctr
         }
     }
-}
-impl RandomAccessFile for AesCtrFile {
-    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+
+    /// Reads and decrypts data from the underlying file, without the page cache.
+    fn read_uncached(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
         let end = pos + buf.len();
-        if end > self.len() {
-            return make_error(Error::OutOfBound);
-        }
         self.data.read(pos, buf)?;
 
         // block index range the operation covers
         let begin_block = pos / 16;
         let end_block = divide_up(end, 16);
 
-        let mut ctr = self.ctr;
-        seek_ctr(&mut ctr, begin_block);
         for i in begin_block..end_block {
             let pad = self.get_pad(i);
 
@@ -91,8 +122,55 @@ impl RandomAccessFile for AesCtrFile {
             for p in data_begin..data_end {
                 buf[p - pos] ^= pad[p - i * 16];
             }
+        }
+
+        Ok(())
+    }
+}
+impl RandomAccessFile for AesCtrFile {
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let end = pos + buf.len();
+        if end > self.len() {
+            return make_error(Error::OutOfBound);
+        }
+
+        // page index range the operation covers
+        let begin_page = pos / PAGE_LEN;
+        let end_page = divide_up(end, PAGE_LEN);
 
-            seek_ctr(&mut ctr, 1);
+        let mut page_cache = self.page_cache.borrow_mut();
+        let cache = match page_cache.as_mut() {
+            Some(cache) if end_page - begin_page <= cache.cap().get() => cache,
+            _ => return self.read_uncached(pos, buf),
+        };
+
+        let mut page = begin_page;
+        while page < end_page {
+            if !cache.contains(&page) {
+                // Reads consecutive missing pages at once
+                let mut miss_end = page + 1;
+                while miss_end < end_page && !cache.contains(&miss_end) {
+                    miss_end += 1;
+                }
+                let miss_pos = page * PAGE_LEN;
+                let mut data = vec![0; std::cmp::min(miss_end * PAGE_LEN, self.len) - miss_pos];
+                self.read_uncached(miss_pos, &mut data)?;
+                for (i, chunk) in data.chunks(PAGE_LEN).enumerate() {
+                    cache.put(page + i, chunk.to_vec());
+                }
+            }
+            while page < end_page {
+                let data = match cache.get(&page) {
+                    Some(data) => data,
+                    None => break,
+                };
+                let data_begin = std::cmp::max(page * PAGE_LEN, pos);
+                let data_end = std::cmp::min((page + 1) * PAGE_LEN, end);
+                buf[data_begin - pos..data_end - pos].copy_from_slice(
+                    &data[data_begin - page * PAGE_LEN..data_end - page * PAGE_LEN],
+                );
+                page += 1;
+            }
         }
 
         Ok(())
@@ -103,12 +181,16 @@ impl RandomAccessFile for AesCtrFile {
             return make_error(Error::OutOfBound);
         }
 
+        if let Some(cache) = self.page_cache.borrow_mut().as_mut() {
+            for page in pos / PAGE_LEN..divide_up(end, PAGE_LEN) {
+                cache.pop(&page);
+            }
+        }
+
         // block index range the operation covers
         let begin_block = pos / 16;
         let end_block = divide_up(end, 16);
 
-        let mut ctr = self.ctr;
-        seek_ctr(&mut ctr, begin_block);
         for i in begin_block..end_block {
             let mut pad = self.get_pad(i);
 
@@ -121,8 +203,6 @@ impl RandomAccessFile for AesCtrFile {
 
             self.data
                 .write(data_begin, &pad[data_begin - i * 16..data_end - i * 16])?;
-
-            seek_ctr(&mut ctr, 1);
         }
 
         Ok(())
@@ -155,7 +235,13 @@ mod test {
             let key: [u8; 16] = rng.gen();
             let ctr: [u8; 16] = rng.gen();
             let repeat_ctr = rng.gen();
-            let aes_ctr_file = AesCtrFile::new(data.clone(), key, ctr, repeat_ctr);
+            let cache_pages = rng.gen_range(0..4);
+            let open = || {
+                let file = AesCtrFile::new(data.clone(), key, ctr, repeat_ctr);
+                file.set_cache_pages(cache_pages);
+                file
+            };
+            let aes_ctr_file = open();
             let mut init: Vec<u8> = vec![0; len];
             aes_ctr_file.read(0, &mut init).unwrap();
             let plain = MemoryFile::new(init);
@@ -164,9 +250,89 @@ mod test {
                 aes_ctr_file,
                 |aes_ctr_file| aes_ctr_file,
                 |aes_ctr_file| aes_ctr_file.commit().unwrap(),
-                || AesCtrFile::new(data.clone(), key, ctr, repeat_ctr),
+                open,
                 plain,
             );
         }
     }
+
+    fn from_hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_vector() {
+        // NIST SP 800-38A F.5.1 CTR-AES128.Encrypt
+        let mut key = [0; 16];
+        key.copy_from_slice(&from_hex("2b7e151628aed2a6abf7158809cf4f3c"));
+        let mut ctr = [0; 16];
+        ctr.copy_from_slice(&from_hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff"));
+        let plain0 = from_hex("6bc1bee22e409f96e93d7e117393172a");
+        let plain1 = from_hex("ae2d8a571e03ac9c9eb76fac45af8e51");
+        let cipher0 = from_hex("874d6191b620e3261bef6864990db6ce");
+        let cipher1 = from_hex("9806f66b7970fdff8617187bb9fffdff");
+
+        for &repeat_ctr in &[false, true] {
+            let data = Rc::new(MemoryFile::new(vec![0; 0x220]));
+            let aes_ctr_file = AesCtrFile::new(data.clone(), key, ctr, repeat_ctr);
+            aes_ctr_file.write(0, &plain0).unwrap();
+            aes_ctr_file.write(0x10, &plain1).unwrap();
+            aes_ctr_file.write(0x200, &plain0).unwrap();
+            aes_ctr_file.write(0x210, &plain1).unwrap();
+
+            let mut buf = vec![0; 0x10];
+            data.read(0, &mut buf).unwrap();
+            assert_eq!(buf, cipher0);
+            data.read(0x10, &mut buf).unwrap();
+            assert_eq!(buf, cipher1);
+
+            // With repeat_ctr, the counter wraps back every 512 bytes
+            data.read(0x200, &mut buf).unwrap();
+            assert_eq!(buf == cipher0, repeat_ctr);
+            data.read(0x210, &mut buf).unwrap();
+            assert_eq!(buf == cipher1, repeat_ctr);
+
+            let mut buf = vec![0; 0x20];
+            aes_ctr_file.read(0x200, &mut buf).unwrap();
+            assert_eq!(buf[0..0x10], plain0[..]);
+            assert_eq!(buf[0x10..0x20], plain1[..]);
+        }
+    }
+
+    #[test]
+    fn page_cache() {
+        use crate::faulty_file::FaultyFile;
+
+        let data = Rc::new(FaultyFile::new(vec![0; 0x1000]));
+        let aes_ctr_file = AesCtrFile::new(data.clone(), [1; 16], [2; 16], false);
+        aes_ctr_file.write(0x100, &[3; 0x300]).unwrap();
+
+        let mut buf = vec![0; 0x300];
+        aes_ctr_file.read(0x100, &mut buf).unwrap();
+        assert_eq!(buf, vec![3; 0x300]);
+
+        // Cached pages are served without touching the underlying file
+        data.fail_reads(0..0x1000);
+        let mut buf = vec![0; 0x10];
+        aes_ctr_file.read(0x180, &mut buf).unwrap();
+        assert_eq!(buf, vec![3; 0x10]);
+        assert!(aes_ctr_file.read(0x400, &mut buf).is_err());
+
+        // Writes invalidate the pages they touch
+        aes_ctr_file.write(0x180, &[4; 0x10]).unwrap();
+        assert!(aes_ctr_file.read(0x180, &mut buf).is_err());
+        aes_ctr_file.read(0x200, &mut buf).unwrap();
+        assert_eq!(buf, vec![3; 0x10]);
+        data.heal();
+        aes_ctr_file.read(0x180, &mut buf).unwrap();
+        assert_eq!(buf, vec![4; 0x10]);
+
+        // Disabling the cache always reads through
+        aes_ctr_file.set_cache_pages(0);
+        data.fail_reads(0..0x1000);
+        assert!(aes_ctr_file.read(0x180, &mut buf).is_err());
+    }
 }
@@ -1,6 +1,7 @@
 use crate::aes_ctr_file::*;
 use crate::error::*;
 use crate::file_system::*;
+use crate::header_dump::HeaderDump;
 use crate::random_access_file::*;
 use crate::save_data::*;
 use crate::wear_leveling::*;
@@ -17,7 +18,9 @@ pub(crate) struct CartFormat {
 /// specialized for cartridge save data. Implements [`FileSystem`](../file_system/trait.FileSystem.html).
 pub struct CartSaveData {
     wear_leveling: Option<Rc<WearLeveling>>,
+    save: Rc<AesCtrFile>,
     save_data: SaveData,
+    write: bool,
 }
 
 impl CartSaveData {
@@ -56,6 +59,7 @@ impl CartSaveData {
             key_cmac,
             repeat_ctr,
         }: &CartFormat,
+        write: bool,
     ) -> Result<CartSaveData, Error> {
         let (wear_leveling, file): (_, Rc<dyn RandomAccessFile>) = if wear_leveling {
             let wear_leveling = Rc::new(WearLeveling::new(file)?);
@@ -68,11 +72,47 @@ impl CartSaveData {
 
         Ok(CartSaveData {
             wear_leveling,
-            save_data: SaveData::new(save, SaveDataType::Cart(key_cmac))?,
+            save: save.clone(),
+            save_data: SaveData::new(save, SaveDataType::Cart(key_cmac), write)?,
+            write,
         })
     }
 }
 
+impl CartSaveData {
+    /// Same as [`SaveData::dump_headers`](../save_data/struct.SaveData.html#method.dump_headers).
+    /// The wear-leveling layer has no header of its own to dump.
+    pub fn dump_headers(&self) -> Result<Vec<HeaderDump>, Error> {
+        self.save_data.dump_headers()
+    }
+
+    /// Same as [`SaveData::duplicate_data`](../save_data/struct.SaveData.html#method.duplicate_data).
+    pub fn duplicate_data(&self) -> bool {
+        self.save_data.duplicate_data()
+    }
+
+    /// Same as [`SaveData::table_storage`](../save_data/struct.SaveData.html#method.table_storage).
+    pub fn table_storage(&self) -> TableStorage {
+        self.save_data.table_storage()
+    }
+
+    /// Same as [`SaveData::metadata_hash`](../save_data/struct.SaveData.html#method.metadata_hash).
+    pub fn metadata_hash(&self) -> Result<[u8; 32], Error> {
+        self.save_data.metadata_hash()
+    }
+
+    /// Same as [`SaveData::set_fill_byte`](../save_data/struct.SaveData.html#method.set_fill_byte).
+    pub fn set_fill_byte(&self, fill: u8) {
+        self.save_data.set_fill_byte(fill)
+    }
+
+    /// Sets how many 512-byte pages of decrypted data are cached to serve repeated reads.
+    /// `0` disables the cache, which saves memory at the cost of decrypting again on every read.
+    pub fn set_cache_pages(&self, pages: usize) {
+        self.save.set_cache_pages(pages)
+    }
+}
+
 impl FileSystem for CartSaveData {
     type FileType = <SaveData as FileSystem>::FileType;
     type DirType = <SaveData as FileSystem>::DirType;
@@ -86,10 +126,16 @@ impl FileSystem for CartSaveData {
         self.save_data.open_dir(ino)
     }
 
+    /// This is a no-op if the save data is opened read-only.
     fn commit(&self) -> Result<(), Error> {
+        if !self.write {
+            return Ok(());
+        }
         self.save_data.commit()?;
         if let Some(wear_leveling) = &self.wear_leveling {
-            wear_leveling.commit()?;
+            wear_leveling
+                .commit()
+                .map_err(|e| e.in_commit_stage(CommitStage::WearLeveling))?;
         }
         Ok(())
     }
@@ -151,16 +197,184 @@ mod test {
             let len = [0x20_000, 0x80_000, 0x100_000][rng.gen_range(0..3)];
             let raw = Rc::new(MemoryFile::new(vec![0; len]));
             CartSaveData::format(raw.clone(), &cart_format, &param).unwrap();
-            let file_system = CartSaveData::new(raw.clone(), &cart_format).unwrap();
+            let file_system = CartSaveData::new(raw.clone(), &cart_format, true).unwrap();
 
             crate::file_system::test::fuzzer(
                 file_system,
                 param.max_dir,
                 param.max_file,
-                || CartSaveData::new(raw.clone(), &cart_format).unwrap(),
+                || CartSaveData::new(raw.clone(), &cart_format, true).unwrap(),
                 gen_name,
                 gen_len,
             );
         }
     }
+
+    #[test]
+    fn flat_image() {
+        use crate::memory_file::*;
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: true,
+        };
+        let cart_format = CartFormat {
+            wear_leveling: true,
+            key: [1; 16],
+            key_cmac: [2; 16],
+            repeat_ctr: true,
+        };
+        let flat_format = CartFormat {
+            wear_leveling: false,
+            ..cart_format
+        };
+
+        let raw = Rc::new(MemoryFile::new(vec![0; 0x20_000]));
+        CartSaveData::format(raw.clone(), &cart_format, &param).unwrap();
+        let save = CartSaveData::new(raw.clone(), &cart_format, true).unwrap();
+        let name = *b"a\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+        let file = save.open_root().unwrap().new_sub_file(name, 4).unwrap();
+        file.write(0, &[1, 2, 3, 4]).unwrap();
+        save.commit().unwrap();
+        drop(file);
+        drop(save);
+
+        assert!(CartSaveData::new(raw.clone(), &flat_format, true).is_err());
+
+        // Remove the wear leveling layer
+        let wear_leveling = WearLeveling::new(raw).unwrap();
+        let mut flat = vec![0; wear_leveling.len()];
+        wear_leveling.read(0, &mut flat).unwrap();
+
+        let save = CartSaveData::new(Rc::new(MemoryFile::new(flat)), &flat_format, true).unwrap();
+        let mut buf = [0; 4];
+        save.open_root()
+            .unwrap()
+            .open_sub_file(name)
+            .unwrap()
+            .read(0, &mut buf)
+            .unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn read_only() {
+        use crate::faulty_file::FaultyFile;
+        use crate::memory_file::*;
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: true,
+        };
+        let cart_format = CartFormat {
+            wear_leveling: true,
+            key: [1; 16],
+            key_cmac: [2; 16],
+            repeat_ctr: true,
+        };
+        let raw = Rc::new(MemoryFile::new(vec![0; 0x20_000]));
+        CartSaveData::format(raw.clone(), &cart_format, &param).unwrap();
+        let save = CartSaveData::new(raw.clone(), &cart_format, true).unwrap();
+        let name = *b"a\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+        let file = save.open_root().unwrap().new_sub_file(name, 4).unwrap();
+        file.write(0, &[1, 2, 3, 4]).unwrap();
+        save.commit().unwrap();
+        drop(file);
+        drop(save);
+
+        let mut image = vec![0; raw.len()];
+        raw.read(0, &mut image).unwrap();
+        let raw = Rc::new(FaultyFile::new(image.clone()));
+        raw.fail_writes_after(0);
+        let save = CartSaveData::new(raw.clone(), &cart_format, false).unwrap();
+        let mut buf = [0; 4];
+        save.open_root()
+            .unwrap()
+            .open_sub_file(name)
+            .unwrap()
+            .read(0, &mut buf)
+            .unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+        save.commit().unwrap();
+        drop(save);
+        assert_eq!(raw.writes(), 0);
+        assert_eq!(raw.snapshot(), image);
+    }
+
+    /// Times reading every file of a cartridge save on the disk, as `--extract` does, with and
+    /// without the page cache. Run with
+    /// `cargo test --release -p libsave3ds bench_cache_pages -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_cache_pages() {
+        use crate::disk_file::DiskFile;
+        use crate::memory_file::*;
+        use crate::test_util::TempPath;
+        use rand::prelude::*;
+        use std::time::Instant;
+        let mut rng = rand::thread_rng();
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 100,
+            file_buckets: 100,
+            duplicate_data: true,
+        };
+        let cart_format = CartFormat {
+            wear_leveling: true,
+            key: [1; 16],
+            key_cmac: [2; 16],
+            repeat_ctr: false,
+        };
+
+        let raw = Rc::new(MemoryFile::new(vec![0; 0x80_000]));
+        CartSaveData::format(raw.clone(), &cart_format, &param).unwrap();
+        let save = CartSaveData::new(raw.clone(), &cart_format, true).unwrap();
+        let root = save.open_root().unwrap();
+        for i in 0..80 {
+            let mut name = [0; 16];
+            name[0] = b'a' + i % 26;
+            name[1] = b'0' + i / 26;
+            let data: Vec<u8> = (0..rng.gen_range(0..0x1000)).map(|_| rng.gen()).collect();
+            root.new_sub_file(name, data.len())
+                .unwrap()
+                .write(0, &data)
+                .unwrap();
+        }
+        save.commit().unwrap();
+        drop(root);
+        drop(save);
+        let path = TempPath::new("libsave3ds_bench_cache_pages");
+        let mut image = vec![0; raw.len()];
+        raw.read(0, &mut image).unwrap();
+        std::fs::write(&path, image).unwrap();
+
+        let mut contents = vec![];
+        for pages in [0, DEFAULT_CACHE_PAGES] {
+            let disk = DiskFile::new(std::fs::File::open(&path).unwrap(), false).unwrap();
+            let save = CartSaveData::new(disk.into_shared(), &cart_format, false).unwrap();
+            save.set_cache_pages(pages);
+            let start = Instant::now();
+            let mut content = vec![];
+            for _ in 0..20 {
+                content.clear();
+                for (name, ino) in save.open_root().unwrap().list_sub_file().unwrap() {
+                    let file = save.open_file(ino).unwrap();
+                    let mut buf = vec![0; file.len()];
+                    file.read(0, &mut buf).unwrap();
+                    content.push((name, buf));
+                }
+            }
+            println!("{} cache pages: {:?}", pages, start.elapsed());
+            contents.push(content);
+        }
+        assert_eq!(contents[0], contents[1]);
+    }
 }
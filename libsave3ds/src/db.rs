@@ -121,6 +121,7 @@ struct DbHeader {
 }
 
 #[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DbType {
     Ticket,
     NandTitle,
@@ -131,6 +132,49 @@ pub enum DbType {
     SdImport,
 }
 
+/// The raw title info entry stored as file data in title and import databases.
+#[derive(ByteStruct)]
+#[byte_struct_le]
+struct TitleInfo {
+    size: u64,
+    title_type: u32,
+    version: u32,
+    flags_0: u32,
+    tmd_content_id: u32,
+    cmd_content_id: u32,
+    flags_1: u32,
+    extdata_id_low: u32,
+    padding1: u32,
+    flags_2: u64,
+    product_code: [u8; 16],
+    padding2: [u8; 16],
+    unknown: u32,
+    padding3: [u8; 0x2C],
+}
+
+/// A title entry in a title or import database. See [`Db::list_titles`](struct.Db.html#method.list_titles).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TitleEntry {
+    /// The title ID, which is also the file name of the entry.
+    pub title_id: u64,
+
+    /// Total size in bytes of the installed title content.
+    pub size: u64,
+
+    /// Title type, usually 0x40.
+    pub title_type: u32,
+
+    /// Title version.
+    pub version: u32,
+
+    /// Content ID of the TMD.
+    pub tmd_content_id: u32,
+
+    /// Product code, e.g. `CTR-P-ABCE`.
+    pub product_code: String,
+}
+
 struct FakeSizeFile {
     parent: Rc<dyn RandomAccessFile>,
     len: usize,
@@ -138,6 +182,9 @@ struct FakeSizeFile {
 
 impl RandomAccessFile for FakeSizeFile {
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        if pos + buf.len() > self.len {
+            return make_error(Error::OutOfBound);
+        }
         if pos >= self.parent.len() {
             return Ok(());
         }
@@ -145,12 +192,17 @@ impl RandomAccessFile for FakeSizeFile {
         self.parent.read(pos, &mut buf[0..end - pos])
     }
     fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        if pos + buf.len() > self.len {
+            return make_error(Error::OutOfBound);
+        }
         if pos >= self.parent.len() {
             return Ok(());
         }
         let end = std::cmp::min(pos + buf.len(), self.parent.len());
         self.parent.write(pos, &buf[0..end - pos])
     }
+    /// Returns the claimed length, which can exceed the parent length.
+    /// Reads beyond the parent leave `buf` untouched and writes there are dropped.
     fn len(&self) -> usize {
         self.len
     }
@@ -159,6 +211,8 @@ impl RandomAccessFile for FakeSizeFile {
     }
 }
 
+/// Signs the DIFF header of a database as `"CTR-9DB0"`, followed by the ID of the database
+/// kind in little endian, followed by the header.
 struct DbSigner {
     pub id: u32,
 }
@@ -178,6 +232,8 @@ struct DbInner {
     fs: Rc<FsMeta>,
     block_len: usize,
     block_count: usize,
+    db_type: DbType,
+    write: bool,
 }
 
 /// Implements [`FileSystem`](../file_system/trait.FileSystem.html) for title database.
@@ -186,24 +242,27 @@ pub struct Db {
 }
 
 impl Db {
+    /// Returns the signer of the database kind. The ID follows the file name, so SD and NAND
+    /// databases of the same name share it, and only differ in the key.
+    fn get_signer(db_type: DbType, key: [u8; 16]) -> (Box<dyn Signer>, [u8; 16]) {
+        let id = match db_type {
+            DbType::Ticket => 0,
+            DbType::SdTitle | DbType::NandTitle => 2,
+            DbType::SdImport | DbType::NandImport => 3,
+            DbType::TmpTitle => 4,
+            DbType::TmpImport => 5,
+        };
+        (Box::new(DbSigner { id }), key)
+    }
+
     pub(crate) fn new(
         file: Rc<dyn RandomAccessFile>,
         db_type: DbType,
         key: [u8; 16],
+        write: bool,
     ) -> Result<Db, Error> {
-        let signer: (Box<dyn Signer>, [u8; 16]) = (
-            Box::new(DbSigner {
-                id: match db_type {
-                    DbType::Ticket => 0,
-                    DbType::SdTitle | DbType::NandTitle => 2,
-                    DbType::SdImport | DbType::NandImport => 3,
-                    DbType::TmpTitle => 4,
-                    DbType::TmpImport => 5,
-                },
-            }),
-            key,
-        );
-        let diff = Rc::new(Diff::new(file, Some(signer))?);
+        // The signature is verified here, and rewritten on every commit of the DIFF header
+        let diff = Rc::new(Diff::new(file, Some(Db::get_signer(db_type, key)))?);
         let pre_len = if db_type == DbType::Ticket {
             0x10
         } else {
@@ -315,9 +374,53 @@ impl Db {
                 fs,
                 block_len: fs_info.block_len as usize,
                 block_count: fs_info.data_block_count as usize,
+                db_type,
+                write,
             }),
         })
     }
+
+    /// Sets the byte that fills data failing hash verification on read, such as
+    /// uninitialized file data. `0xDD` is used if this is never called.
+    pub fn set_fill_byte(&self, fill: u8) {
+        self.center.diff.set_fill(fill);
+    }
+
+    /// Parses all title entries in the database, sorted by title ID.
+    ///
+    /// Ticket database doesn't contain title entries, for which `Error::Unsupported` is returned.
+    pub fn list_titles(&self) -> Result<Vec<TitleEntry>, Error> {
+        if self.center.db_type == DbType::Ticket {
+            return make_error(Error::Unsupported);
+        }
+        let mut titles = vec![];
+        for (title_id, ino) in self.open_root()?.list_sub_file()? {
+            let file = self.open_file(ino)?;
+            if file.len() < TitleInfo::BYTE_LEN {
+                error!("Title entry {:016x} is too short", title_id);
+                return make_error(Error::SizeMismatch);
+            }
+            let mut buf = [0; TitleInfo::BYTE_LEN];
+            file.read(0, &mut buf)?;
+            let info = TitleInfo::read_bytes(&buf);
+            let product_code_end = info
+                .product_code
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(info.product_code.len());
+            titles.push(TitleEntry {
+                title_id,
+                size: info.size,
+                title_type: info.title_type,
+                version: info.version,
+                tmd_content_id: info.tmd_content_id,
+                product_code: String::from_utf8_lossy(&info.product_code[0..product_code_end])
+                    .into_owned(),
+            });
+        }
+        titles.sort_by_key(|t| t.title_id);
+        Ok(titles)
+    }
 }
 
 /// Implements [`FileSystemFile`](../file_system/trait.FileSystemFile.html) for title database file.
@@ -422,6 +525,9 @@ impl FileSystemFile for File {
         if pos + buf.len() > self.len {
             return make_error(Error::OutOfBound);
         }
+        if buf.is_empty() {
+            return Ok(());
+        }
         self.data.as_ref().unwrap().read(pos, buf)
     }
 
@@ -429,6 +535,9 @@ impl FileSystemFile for File {
         if pos + buf.len() > self.len {
             return make_error(Error::OutOfBound);
         }
+        if buf.is_empty() {
+            return Ok(());
+        }
         self.data.as_ref().unwrap().write(pos, buf)
     }
 
@@ -469,12 +578,12 @@ impl FileSystemDir for Dir {
     }
 
     /// Always returns an empty list.
-    fn list_sub_dir(&self) -> Result<Vec<(u64, u32)>, Error> {
-        Ok(vec![])
+    fn iter_sub_dir(&self) -> Result<EntryIter<u64>, Error> {
+        Ok(Box::new(std::iter::empty()))
     }
 
-    fn list_sub_file(&self) -> Result<Vec<(u64, u32)>, Error> {
-        self.meta.list_sub_file()
+    fn iter_sub_file(&self) -> Result<EntryIter<u64>, Error> {
+        Ok(Box::new(self.meta.iter_sub_file()?))
     }
 
     fn new_sub_file(&self, name: u64, len: usize) -> Result<Self::FileType, Error> {
@@ -555,7 +664,12 @@ impl FileSystem for Db {
     ///
     /// If the save data is dropped with uncommitted change,
     /// all data rolls back to the state the last time `commit` is called.
+    ///
+    /// This is a no-op if the database is opened read-only.
     fn commit(&self) -> Result<(), Error> {
+        if !self.center.write {
+            return Ok(());
+        }
         self.center.diff.commit()
     }
 
@@ -572,3 +686,118 @@ impl FileSystem for Db {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::db::*;
+    use crate::difi_partition::DifiPartitionParam;
+    use crate::memory_file::MemoryFile;
+
+    #[test]
+    fn struct_size() {
+        assert_eq!(TitleInfo::BYTE_LEN, 0x80);
+    }
+
+    fn sign(db_type: DbType, key: [u8; 16], data: &[u8]) -> [u8; 16] {
+        let (signer, key) = Db::get_signer(db_type, key);
+        let signature = Rc::new(MemoryFile::new(vec![0; 16]));
+        let data = Rc::new(MemoryFile::new(data.to_vec()));
+        SignedFile::new_unverified(signature.clone(), data, signer, key)
+            .unwrap()
+            .commit()
+            .unwrap();
+        let mut result = [0; 16];
+        signature.read(0, &mut result).unwrap();
+        result
+    }
+
+    #[test]
+    fn signer_known_values() {
+        // Reference values computed independently from the documented layout
+        let key: [u8; 16] = std::array::from_fn(|i| i as u8);
+        let data: Vec<u8> = (0..0x100).map(|i| i as u8).collect();
+        let ticket = [
+            0x73, 0x40, 0x04, 0x94, 0x8A, 0xA3, 0x7D, 0x62, 0x21, 0x0C, 0x41, 0xF8, 0x84, 0xE0,
+            0x9F, 0xD8,
+        ];
+        let title = [
+            0xBE, 0xFE, 0x8F, 0x5F, 0x4F, 0xED, 0xAF, 0xA6, 0x27, 0xE6, 0x66, 0xBE, 0x8C, 0xE8,
+            0xAF, 0x93,
+        ];
+        let import = [
+            0xB7, 0x15, 0x71, 0xEB, 0x6A, 0x19, 0x7A, 0xDF, 0xA2, 0x25, 0x7B, 0xE0, 0x95, 0x90,
+            0x28, 0x1A,
+        ];
+        let tmp_title = [
+            0x00, 0xD4, 0xE4, 0x5A, 0x8F, 0x8D, 0xF1, 0x90, 0x4F, 0x18, 0xDA, 0x42, 0x3B, 0x5A,
+            0x6E, 0xDB,
+        ];
+        let tmp_import = [
+            0x34, 0x43, 0x80, 0x95, 0x93, 0x34, 0xEA, 0x60, 0x34, 0xE6, 0xCD, 0x23, 0x09, 0xC0,
+            0xD8, 0xC3,
+        ];
+        for (db_type, expected) in [
+            (DbType::Ticket, ticket),
+            (DbType::NandTitle, title),
+            (DbType::SdTitle, title),
+            (DbType::NandImport, import),
+            (DbType::SdImport, import),
+            (DbType::TmpTitle, tmp_title),
+            (DbType::TmpImport, tmp_import),
+        ] {
+            assert_eq!(sign(db_type, key, &data), expected, "{:?}", db_type);
+        }
+    }
+
+    #[test]
+    fn signer_round_trip() {
+        let param = DifiPartitionParam {
+            dpfs_level2_block_len: 128,
+            dpfs_level3_block_len: 4096,
+            ivfc_level1_block_len: 512,
+            ivfc_level2_block_len: 512,
+            ivfc_level3_block_len: 4096,
+            ivfc_level4_block_len: 4096,
+            data_len: 0x1000,
+            external_ivfc_level4: true,
+        };
+        let key = [0x5A; 16];
+        let types = [
+            DbType::Ticket,
+            DbType::NandTitle,
+            DbType::NandImport,
+            DbType::TmpTitle,
+            DbType::TmpImport,
+        ];
+        for &db_type in &types {
+            let raw = Rc::new(MemoryFile::new(vec![0; Diff::calculate_size(&param)]));
+            Diff::format(raw.clone(), Some(Db::get_signer(db_type, key)), &param, 0).unwrap();
+
+            // A modified database is signed again on commit
+            let diff = Diff::new(raw.clone(), Some(Db::get_signer(db_type, key))).unwrap();
+            diff.partition().write(0, &[1; 0x100]).unwrap();
+            diff.commit().unwrap();
+            drop(diff);
+
+            let mut header = vec![0; 0x100];
+            raw.read(0x100, &mut header).unwrap();
+            let mut signature = [0; 16];
+            raw.read(0, &mut signature).unwrap();
+            assert_eq!(sign(db_type, key, &header), signature);
+
+            let diff = Diff::new(raw.clone(), Some(Db::get_signer(db_type, key))).unwrap();
+            let mut buf = [0; 0x100];
+            diff.partition().read(0, &mut buf).unwrap();
+            assert_eq!(buf, [1; 0x100]);
+            drop(diff);
+
+            // No other kind accepts the signature
+            for &other in &types {
+                if other != db_type {
+                    assert!(Diff::new(raw.clone(), Some(Db::get_signer(other, key))).is_err());
+                }
+            }
+            assert!(Diff::new(raw.clone(), Some(Db::get_signer(db_type, [0; 16]))).is_err());
+        }
+    }
+}
@@ -1,13 +1,16 @@
 use crate::difi_partition::*;
 use crate::dual_file::DualFile;
 use crate::error::*;
+use crate::header_dump::*;
 use crate::ivfc_level::IvfcLevel;
+use crate::memory_file::MemoryFile;
 use crate::misc::*;
 use crate::random_access_file::*;
 use crate::signed_file::*;
 use crate::sub_file::SubFile;
 use byte_struct::*;
 use log::*;
+use std::cell::Cell;
 use std::rc::Rc;
 
 #[derive(ByteStruct)]
@@ -28,12 +31,15 @@ struct DiffHeader {
 
 /// DIFF container format that contains one DIFI partition.
 pub struct Diff {
+    file: Rc<dyn RandomAccessFile>,
+    header_raw: Rc<dyn RandomAccessFile>,
+    header_buffer: Rc<MemoryFile>,
     parent_len: usize,
     header_file: Rc<dyn RandomAccessFile>,
     table_upper: Rc<DualFile>,
     table_lower: Rc<IvfcLevel>,
     partition: Rc<DifiPartition>,
-    unique_id: u64,
+    unique_id: Cell<u64>,
 }
 
 struct DiffInfo {
@@ -125,14 +131,21 @@ impl Diff {
         signer: Option<(Box<dyn Signer>, [u8; 16])>,
     ) -> Result<Diff, Error> {
         let parent_len = file.len();
-        let header_file_bare = Rc::new(SubFile::new(file.clone(), 0x100, 0x100)?);
+        // The header is buffered and written back in one piece on commit, so that the hash and
+        // the selector of the new partition table reach the host file together. Otherwise an
+        // interrupted commit could leave the hash of the new table next to the old selector.
+        let header_raw: Rc<dyn RandomAccessFile> =
+            Rc::new(SubFile::new(file.clone(), 0x100, 0x100)?);
+        let header_buffer = Rc::new(MemoryFile::from_file(header_raw.as_ref())?);
+        let header_file_bare = header_buffer.clone();
         let header_file: Rc<dyn RandomAccessFile> = match signer {
             None => header_file_bare,
-            Some((signer, key)) => Rc::new(SignedFile::new(
+            Some((signer, key)) => Rc::new(SignedFile::new_with_magic(
                 Rc::new(SubFile::new(file.clone(), 0, 0x10)?),
                 header_file_bare,
                 signer,
                 key,
+                b"DIFF",
             )?),
         };
 
@@ -178,12 +191,15 @@ impl Diff {
         let partition = Rc::new(DifiPartition::new(table_lower.clone(), partition)?);
 
         Ok(Diff {
+            file,
+            header_raw,
+            header_buffer,
             parent_len,
             header_file,
             table_upper,
             table_lower,
             partition,
-            unique_id: header.unique_id,
+            unique_id: Cell::new(header.unique_id),
         })
     }
 
@@ -193,17 +209,72 @@ impl Diff {
 
     pub fn commit(&self) -> Result<(), Error> {
         self.partition.commit()?;
-        self.table_lower.commit()?;
-        self.table_upper.commit()?;
-        self.header_file.commit()
+        self.table_lower
+            .commit()
+            .map_err(|e| e.in_commit_stage(CommitStage::Ivfc))?;
+        let switch = || -> Result<(), Error> {
+            self.table_upper.commit()?;
+            let mut header = vec![0; self.header_raw.len()];
+            self.header_buffer.read(0, &mut header)?;
+            self.header_raw.write(0, &header)
+        };
+        switch().map_err(|e| e.in_commit_stage(CommitStage::Dpfs))?;
+        self.header_file
+            .commit()
+            .map_err(|e| e.in_commit_stage(CommitStage::Signature))?;
+        self.file
+            .commit()
+            .map_err(|e| e.in_commit_stage(CommitStage::Io))
     }
 
     pub fn partition(&self) -> &Rc<DifiPartition> {
         &self.partition
     }
 
+    /// Sets the byte that fills data failing verification on read, on the partition table
+    /// and the partition.
+    pub fn set_fill(&self, fill: u8) {
+        self.table_lower.set_fill(fill);
+        self.partition.set_fill(fill);
+    }
+
     pub fn unique_id(&self) -> u64 {
-        self.unique_id
+        self.unique_id.get()
+    }
+
+    /// Rewrites the unique ID in the header. The change is written on `commit`.
+    pub fn set_unique_id(&self, unique_id: u64) -> Result<(), Error> {
+        let mut header: DiffHeader = read_struct(self.header_file.as_ref(), 0)?;
+        header.unique_id = unique_id;
+        write_struct(self.header_file.as_ref(), 0, header)?;
+        self.unique_id.set(unique_id);
+        Ok(())
+    }
+
+    /// Dumps the DIFF header and the partition descriptor, without the CMAC.
+    /// `label` prefixes the names.
+    pub fn dump_headers(&self, label: &str) -> Result<Vec<HeaderDump>, Error> {
+        let header: DiffHeader = read_struct(self.header_file.as_ref(), 0)?;
+        let mut dumps = vec![dump_fields!(
+            &format!("{} DiffHeader", label),
+            header,
+            magic,
+            version,
+            secondary_table_offset,
+            primary_table_offset,
+            table_size,
+            partition_offset,
+            partition_size,
+            active_table,
+            padding,
+            sha,
+            unique_id,
+        )];
+        dumps.extend(DifiPartition::dump_descriptor(
+            self.table_lower.as_ref(),
+            &format!("{} partition", label),
+        )?);
+        Ok(dumps)
     }
 }
 #[cfg(test)]
@@ -1,6 +1,7 @@
-use crate::dpfs_level::DpfsLevel;
+use crate::dpfs_level::{DpfsLevel, DpfsLevelState};
 use crate::dual_file::DualFile;
 use crate::error::*;
+use crate::header_dump::*;
 use crate::ivfc_level::IvfcLevel;
 use crate::misc::*;
 use crate::random_access_file::*;
@@ -458,6 +459,129 @@ impl DifiPartition {
             ivfc_level4,
         })
     }
+
+    /// Sets the byte that fills data failing verification on read, on all IVFC levels.
+    pub fn set_fill(&self, fill: u8) {
+        for level in [
+            &self.ivfc_level1,
+            &self.ivfc_level2,
+            &self.ivfc_level3,
+            &self.ivfc_level4,
+        ] {
+            level.set_fill(fill);
+        }
+    }
+
+    /// Returns the level-4 blocks found broken so far, as tuples of the block index and
+    /// the byte range within the partition. See `IvfcLevel::broken_blocks`.
+    pub fn broken_blocks(&self) -> Vec<(usize, std::ops::Range<usize>)> {
+        self.ivfc_level4.broken_blocks()
+    }
+
+    /// Dumps the DIFI header and the IVFC and DPFS descriptors stored in `descriptor`, followed
+    /// by the partition parameters and sizes derived from them. `label` prefixes the names.
+    pub fn dump_descriptor(
+        descriptor: &dyn RandomAccessFile,
+        label: &str,
+    ) -> Result<Vec<HeaderDump>, Error> {
+        let header: DifiHeader = read_struct(descriptor, 0)?;
+        let ivfc: IvfcDescriptor = read_struct(descriptor, header.ivfc_descriptor_offset as usize)?;
+        let dpfs: DpfsDescriptor = read_struct(descriptor, header.dpfs_descriptor_offset as usize)?;
+
+        let param = DifiPartitionParam {
+            dpfs_level2_block_len: 1 << dpfs.level2_block_log,
+            dpfs_level3_block_len: 1 << dpfs.level3_block_log,
+            ivfc_level1_block_len: 1 << ivfc.level1_block_log,
+            ivfc_level2_block_len: 1 << ivfc.level2_block_log,
+            ivfc_level3_block_len: 1 << ivfc.level3_block_log,
+            ivfc_level4_block_len: 1 << ivfc.level4_block_log,
+            data_len: ivfc.level4_size as usize,
+            external_ivfc_level4: header.external_ivfc_level4 != 0,
+        };
+        let (descriptor_len, partition_len) = DifiPartition::calculate_size(&param);
+
+        Ok(vec![
+            dump_fields!(
+                &format!("{} DifiHeader", label),
+                header,
+                magic,
+                version,
+                ivfc_descriptor_offset,
+                ivfc_descriptor_size,
+                dpfs_descriptor_offset,
+                dpfs_descriptor_size,
+                partition_hash_offset,
+                partition_hash_size,
+                external_ivfc_level4,
+                dpfs_selector,
+                padding,
+                ivfc_level4_offset,
+            ),
+            dump_fields!(
+                &format!("{} IvfcDescriptor", label),
+                ivfc,
+                magic,
+                version,
+                master_hash_size,
+                level1_offset,
+                level1_size,
+                level1_block_log,
+                padding1,
+                level2_offset,
+                level2_size,
+                level2_block_log,
+                padding2,
+                level3_offset,
+                level3_size,
+                level3_block_log,
+                padding3,
+                level4_offset,
+                level4_size,
+                level4_block_log,
+                padding4,
+                ivfc_descritor_size,
+            ),
+            dump_fields!(
+                &format!("{} DpfsDescriptor", label),
+                dpfs,
+                magic,
+                version,
+                level1_offset,
+                level1_size,
+                level1_block_log,
+                padding1,
+                level2_offset,
+                level2_size,
+                level2_block_log,
+                padding2,
+                level3_offset,
+                level3_size,
+                level3_block_log,
+                padding3,
+            ),
+            HeaderDump::new(&format!("{} DifiPartitionParam (derived)", label))
+                .field("dpfs_level2_block_len", param.dpfs_level2_block_len as u64)
+                .field("dpfs_level3_block_len", param.dpfs_level3_block_len as u64)
+                .field("ivfc_level1_block_len", param.ivfc_level1_block_len as u64)
+                .field("ivfc_level2_block_len", param.ivfc_level2_block_len as u64)
+                .field("ivfc_level3_block_len", param.ivfc_level3_block_len as u64)
+                .field("ivfc_level4_block_len", param.ivfc_level4_block_len as u64)
+                .field("data_len", param.data_len as u64)
+                .field("external_ivfc_level4", param.external_ivfc_level4 as u8)
+                .field("descriptor_len", descriptor_len as u64)
+                .field("partition_len", partition_len as u64),
+        ])
+    }
+
+    /// Returns the DPFS copy selection of all three levels, for debugging.
+    /// Level 1 is a single dual-image file, so only its selector and modified flag are returned.
+    pub fn dpfs_debug_state(&self) -> Result<((u8, bool), DpfsLevelState, DpfsLevelState), Error> {
+        Ok((
+            self.dpfs_level1.debug_state()?,
+            self.dpfs_level2.debug_state()?,
+            self.dpfs_level3.debug_state()?,
+        ))
+    }
 }
 
 impl RandomAccessFile for DifiPartition {
@@ -471,13 +595,19 @@ impl RandomAccessFile for DifiPartition {
         self.ivfc_level4.len()
     }
     fn commit(&self) -> Result<(), Error> {
-        self.ivfc_level4.commit()?;
-        self.ivfc_level3.commit()?;
-        self.ivfc_level2.commit()?;
-        self.ivfc_level1.commit()?;
-        self.dpfs_level3.commit()?;
-        self.dpfs_level2.commit()?;
-        self.dpfs_level1.commit()
+        let ivfc = || -> Result<(), Error> {
+            self.ivfc_level4.commit()?;
+            self.ivfc_level3.commit()?;
+            self.ivfc_level2.commit()?;
+            self.ivfc_level1.commit()
+        };
+        ivfc().map_err(|e| e.in_commit_stage(CommitStage::Ivfc))?;
+        let dpfs = || -> Result<(), Error> {
+            self.dpfs_level3.commit()?;
+            self.dpfs_level2.commit()?;
+            self.dpfs_level1.commit()
+        };
+        dpfs().map_err(|e| e.in_commit_stage(CommitStage::Dpfs))
     }
 }
 
@@ -1,13 +1,16 @@
 use crate::difi_partition::*;
 use crate::dual_file::DualFile;
 use crate::error::*;
+use crate::header_dump::*;
 use crate::ivfc_level::IvfcLevel;
+use crate::memory_file::MemoryFile;
 use crate::misc::*;
 use crate::random_access_file::*;
 use crate::signed_file::*;
 use crate::sub_file::SubFile;
 use byte_struct::*;
 use log::*;
+use sha2::*;
 use std::ops::Index;
 use std::rc::Rc;
 
@@ -42,6 +45,9 @@ struct DisaHeader {
 
 /// DISA container format that contains one or two DIFI partitions.
 pub struct Disa {
+    file: Rc<dyn RandomAccessFile>,
+    header_raw: Rc<dyn RandomAccessFile>,
+    header_buffer: Rc<MemoryFile>,
     header_file: Rc<dyn RandomAccessFile>,
     table_upper: Rc<DualFile>,
     table_lower: Rc<IvfcLevel>,
@@ -204,18 +210,92 @@ impl Disa {
         Ok(())
     }
 
+    /// Returns the length of the DISA image at the beginning of `file`, as recorded in its
+    /// header. This is the end of the header, partition tables, or partitions, whichever is the
+    /// farthest. Anything after it doesn't belong to the DISA.
+    pub fn image_len(file: &dyn RandomAccessFile) -> Result<usize, Error> {
+        let header: DisaHeader = read_struct(file, 0x100)?;
+        if header.magic != *b"DISA" || header.partition_count > 2 {
+            error!("Not a DISA image");
+            return make_error(Error::MagicMismatch);
+        }
+        let table_end = std::cmp::max(header.primary_table_offset, header.secondary_table_offset)
+            + header.table_size;
+        let len = header.partition[0..header.partition_count as usize]
+            .iter()
+            .map(|p| p.offset + p.size)
+            .fold(std::cmp::max(table_end, 0x200), std::cmp::max);
+        Ok(len as usize)
+    }
+
+    /// Recalculates the signature of the DISA header in `file` with `signer` and writes it back,
+    /// replacing whatever was there. The rest of the image is not verified.
+    pub fn sign(
+        file: Rc<dyn RandomAccessFile>,
+        (signer, key): (Box<dyn Signer>, [u8; 16]),
+    ) -> Result<(), Error> {
+        let header: DisaHeader = read_struct(file.as_ref(), 0x100)?;
+        if header.magic != *b"DISA" || header.version != 0x40000 {
+            error!(
+                "Unexpected DISA magic {:?} {:X}",
+                header.magic, header.version
+            );
+            return make_error(Error::MagicMismatch);
+        }
+        SignedFile::new_unverified(
+            Rc::new(SubFile::new(file.clone(), 0, 0x10)?),
+            Rc::new(SubFile::new(file.clone(), 0x100, 0x100)?),
+            signer,
+            key,
+        )?
+        .commit()?;
+        file.commit()
+    }
+
     pub fn new(
         file: Rc<dyn RandomAccessFile>,
         signer: Option<(Box<dyn Signer>, [u8; 16])>,
     ) -> Result<Disa, Error> {
-        let header_file_bare = Rc::new(SubFile::new(file.clone(), 0x100, 0x100)?);
+        Disa::new_impl(file, signer, None)
+    }
+
+    /// Opens the DISA using the partition table copy `table_index`
+    /// (0 for primary, 1 for secondary), regardless of which one is active.
+    ///
+    /// This is for forensics only: the non-active copy is usually the state before the last
+    /// commit, which can help recovering data after an interrupted write. Its hash is not
+    /// verified against the header, and the result must not be modified or committed.
+    pub fn new_with_table(
+        file: Rc<dyn RandomAccessFile>,
+        signer: Option<(Box<dyn Signer>, [u8; 16])>,
+        table_index: usize,
+    ) -> Result<Disa, Error> {
+        if table_index > 1 {
+            return make_error(Error::InvalidValue);
+        }
+        Disa::new_impl(file, signer, Some(table_index))
+    }
+
+    fn new_impl(
+        file: Rc<dyn RandomAccessFile>,
+        signer: Option<(Box<dyn Signer>, [u8; 16])>,
+        table_index: Option<usize>,
+    ) -> Result<Disa, Error> {
+        // The header is buffered and written back in one piece on commit, so that the hash and
+        // the selector of the new partition table reach the host file together. Otherwise an
+        // interrupted commit could leave the hash of the new table next to the old selector.
+        let header_raw: Rc<dyn RandomAccessFile> =
+            Rc::new(SubFile::new(file.clone(), 0x100, 0x100)?);
+        let header_buffer = Rc::new(MemoryFile::from_file(header_raw.as_ref())?);
+        let header_file_bare = header_buffer.clone();
         let header_file: Rc<dyn RandomAccessFile> = match signer {
             None => header_file_bare,
-            Some((signer, key)) => Rc::new(SignedFile::new(
+            Some((signer, key)) => Rc::new(SignedFile::new_with_magic(
                 Rc::new(SubFile::new(file.clone(), 0, 0x10)?),
                 header_file_bare,
                 signer,
                 key,
+                b"DISA",
             )?),
         };
 
@@ -232,9 +312,11 @@ impl Disa {
             return make_error(Error::InvalidValue);
         }
 
-        let table_selector = Rc::new(SubFile::new(header_file.clone(), 0x68, 1)?);
+        let mut table_selector: Rc<dyn RandomAccessFile> =
+            Rc::new(SubFile::new(header_file.clone(), 0x68, 1)?);
 
-        let table_hash = Rc::new(SubFile::new(header_file.clone(), 0x6C, 0x20)?);
+        let mut table_hash: Rc<dyn RandomAccessFile> =
+            Rc::new(SubFile::new(header_file.clone(), 0x6C, 0x20)?);
 
         let table_pair: [Rc<dyn RandomAccessFile>; 2] = [
             Rc::new(SubFile::new(
@@ -249,6 +331,23 @@ impl Disa {
             )?),
         ];
 
+        if let Some(table_index) = table_index {
+            // Pin the selector to the requested copy, and trust whatever it contains
+            let mut table = vec![0; header.table_size as usize];
+            table_pair[table_index].read(0, &mut table)?;
+            let hash = Sha256::digest(&table);
+            let mut hash_stored = [0; 0x20];
+            table_hash.read(0, &mut hash_stored)?;
+            if hash[..] != hash_stored[..] {
+                warn!(
+                    "DISA partition table {} doesn't match the recorded hash",
+                    table_index
+                );
+            }
+            table_selector = Rc::new(MemoryFile::new(vec![table_index as u8]));
+            table_hash = Rc::new(MemoryFile::new(hash.to_vec()));
+        }
+
         let table_upper = Rc::new(DualFile::new(table_selector, table_pair)?);
 
         let table_lower = Rc::new(IvfcLevel::new(
@@ -275,6 +374,9 @@ impl Disa {
         }
 
         Ok(Disa {
+            file,
+            header_raw,
+            header_buffer,
             header_file,
             table_upper,
             table_lower,
@@ -286,14 +388,82 @@ impl Disa {
         for partition in self.partitions.iter() {
             partition.commit()?;
         }
-        self.table_lower.commit()?;
-        self.table_upper.commit()?;
-        self.header_file.commit()
+        self.table_lower
+            .commit()
+            .map_err(|e| e.in_commit_stage(CommitStage::Ivfc))?;
+        let switch = || -> Result<(), Error> {
+            self.table_upper.commit()?;
+            let mut header = vec![0; self.header_raw.len()];
+            self.header_buffer.read(0, &mut header)?;
+            self.header_raw.write(0, &header)
+        };
+        switch().map_err(|e| e.in_commit_stage(CommitStage::Dpfs))?;
+        self.header_file
+            .commit()
+            .map_err(|e| e.in_commit_stage(CommitStage::Signature))?;
+        self.file
+            .commit()
+            .map_err(|e| e.in_commit_stage(CommitStage::Io))
     }
 
     pub fn partition_count(&self) -> usize {
         self.partitions.len()
     }
+
+    /// Sets the byte that fills data failing verification on read, on the partition table
+    /// and all partitions.
+    pub fn set_fill(&self, fill: u8) {
+        self.table_lower.set_fill(fill);
+        for partition in &self.partitions {
+            partition.set_fill(fill);
+        }
+    }
+
+    /// Dumps the DISA header and the descriptors of all partitions, without the CMAC.
+    pub fn dump_headers(&self) -> Result<Vec<HeaderDump>, Error> {
+        let header: DisaHeader = read_struct(self.header_file.as_ref(), 0)?;
+        let mut dump = dump_fields!(
+            "DisaHeader",
+            header,
+            magic,
+            version,
+            partition_count,
+            padding1,
+            secondary_table_offset,
+            primary_table_offset,
+            table_size,
+        );
+        for (i, d) in header.partition_descriptor.iter().enumerate() {
+            dump = dump
+                .field(&format!("partition_descriptor[{}].offset", i), d.offset)
+                .field(&format!("partition_descriptor[{}].size", i), d.size);
+        }
+        for (i, p) in header.partition.iter().enumerate() {
+            dump = dump
+                .field(&format!("partition[{}].offset", i), p.offset)
+                .field(&format!("partition[{}].size", i), p.size);
+        }
+        let mut dumps = vec![dump.field("active_table", header.active_table)];
+
+        for i in 0..self.partitions.len() {
+            let d = &header.partition_descriptor[i];
+            let descriptor =
+                SubFile::new(self.table_lower.clone(), d.offset as usize, d.size as usize)?;
+            dumps.extend(DifiPartition::dump_descriptor(
+                &descriptor,
+                &format!("Partition {}", i),
+            )?);
+        }
+        Ok(dumps)
+    }
+
+    /// Returns the index of the active partition table copy recorded in the header
+    /// (0 for primary, 1 for secondary).
+    pub fn active_table(&self) -> Result<usize, Error> {
+        let mut select = [0; 1];
+        self.header_file.read(0x68, &mut select)?;
+        Ok(select[0] as usize)
+    }
 }
 
 impl Index<usize> for Disa {
@@ -306,6 +476,7 @@ impl Index<usize> for Disa {
 #[cfg(test)]
 mod test {
     use crate::disa::*;
+    use crate::faulty_file::FaultyFile;
     use crate::memory_file::MemoryFile;
     use crate::signed_file::test::SimpleSigner;
     use rand::distributions::Standard;
@@ -362,6 +533,7 @@ mod test {
             let outer_len = Disa::calculate_size(&param, None);
             let outer = Rc::new(MemoryFile::new(vec![0; outer_len]));
             Disa::format(outer.clone(), Some((signer.clone(), key)), &param, None).unwrap();
+            assert_eq!(Disa::image_len(outer.as_ref()).unwrap(), outer_len);
             fuzz_one_file(outer, 0, Some((signer.clone(), key)));
         }
     }
@@ -382,8 +554,41 @@ mod test {
                 Some(&param_b),
             )
             .unwrap();
+            assert_eq!(Disa::image_len(outer.as_ref()).unwrap(), outer_len);
             fuzz_one_file(outer.clone(), 0, Some((signer.clone(), key)));
             fuzz_one_file(outer, 1, Some((signer.clone(), key)));
         }
     }
+
+    #[test]
+    fn commit_stage() {
+        let signer = Box::new(SimpleSigner::new());
+        let key = rand::thread_rng().gen();
+        let param = DifiPartitionParam::random();
+        let info = Disa::calculate_info(&param, None);
+        let file = Rc::new(FaultyFile::new(vec![0; info.end]));
+        Disa::format(file.clone(), Some((signer.clone(), key)), &param, None).unwrap();
+        let disa = Disa::new(file.clone(), Some((signer, key))).unwrap();
+
+        let partition_range =
+            info.partition_a_offset..info.partition_a_offset + info.partition_a_len;
+        let faults: [(&dyn Fn(), CommitStage); 3] = [
+            (
+                &|| file.fail_writes(partition_range.clone()),
+                CommitStage::Ivfc,
+            ),
+            (&|| file.fail_writes(0..0x10), CommitStage::Signature),
+            (&|| file.fail_commit(), CommitStage::Io),
+        ];
+        for (inject, stage) in &faults {
+            disa[0].write(0, &[rand::thread_rng().gen()]).unwrap();
+            inject();
+            let e = disa.commit().unwrap_err();
+            assert!(matches!(e, Error::CommitFailed(s, _) if s == *stage));
+            assert!(matches!(e.inner(), Error::IO(_)));
+            assert!(std::error::Error::source(&e).is_some());
+            file.heal();
+        }
+        disa.commit().unwrap();
+    }
 }
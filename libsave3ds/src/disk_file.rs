@@ -1,22 +1,199 @@
 use crate::error::*;
 use crate::random_access_file::*;
-use std::cell::RefCell;
-use std::fs::File;
+use log::*;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fs::{File, TryLockError};
 use std::io::prelude::*;
+use std::rc::Rc;
+
+/// Number of bytes of pending writes buffered by default before they are flushed to the host file.
+pub const DEFAULT_WRITE_BUFFER_LEN: usize = 0x10000;
 
 pub struct DiskFile {
     file: RefCell<File>,
-    len: usize,
+    len: Cell<usize>,
+    write: bool,
+    // Pending writes in the order they are made, which is the order they reach the host file
+    log: RefCell<Vec<(usize, Vec<u8>)>>,
+    // The newest pending data by position, for reads. Ranges never overlap or touch each other.
+    // Ranges already flushed may stay until the log is empty, as they match the host file.
+    pending: RefCell<BTreeMap<usize, Vec<u8>>>,
+    // Number of bytes in `log`
+    pending_len: Cell<usize>,
+    buffer_len: Cell<usize>,
+    #[cfg(test)]
+    writes_left: Cell<Option<usize>>,
 }
 
 impl DiskFile {
-    pub fn new(file: File) -> std::io::Result<DiskFile> {
+    /// Wraps a host file. `write` should match the mode the file is opened in.
+    ///
+    /// An advisory lock is taken on the file until this is dropped: an exclusive lock if `write`
+    /// is set, or a shared lock otherwise. `Error::Busy` is returned if a conflicting lock is
+    /// held, e.g. by another save3ds instance. Programs that don't lock the file are not stopped.
+    ///
+    /// If `write` is not set, the file is never written or flushed, so it also works on read-only
+    /// media. Writes fail with `Error::ReadOnly` before reaching the host file.
+    pub fn new(file: File, write: bool) -> Result<DiskFile, Error> {
+        let lock = if write {
+            file.try_lock()
+        } else {
+            file.try_lock_shared()
+        };
+        match lock {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                error!("The file is locked by another program");
+                return make_error(Error::Busy);
+            }
+            // Some host file systems don't support locking. Proceed without it.
+            Err(TryLockError::Error(e)) => warn!("Failed to lock the file: {}", e),
+        }
         let len = file.metadata()?.len() as usize;
         Ok(DiskFile {
             file: RefCell::new(file),
-            len,
+            len: Cell::new(len),
+            write,
+            log: RefCell::new(vec![]),
+            pending: RefCell::new(BTreeMap::new()),
+            pending_len: Cell::new(0),
+            buffer_len: Cell::new(DEFAULT_WRITE_BUFFER_LEN),
+            #[cfg(test)]
+            writes_left: Cell::new(None),
         })
     }
+
+    /// Sets how many bytes of writes are buffered, coalescing adjacent ones, before they are
+    /// written to the host file. Buffered writes are also written on `commit` and on drop.
+    /// `0` disables the buffer, so that every write goes to the host file immediately.
+    /// [`DEFAULT_WRITE_BUFFER_LEN`] is used if this is never called.
+    ///
+    /// Buffered writes reach the host file in the order they are made, so a flush interrupted
+    /// by a crash leaves the file as if the process had been stopped in the middle of the
+    /// unbuffered writes. The upper layers rely on this to write their headers last.
+    pub fn set_write_buffer_len(&self, len: usize) {
+        self.buffer_len.set(len);
+    }
+
+    /// Shrinks the physical file to `len` bytes, dropping the bytes after it.
+    pub fn truncate(&self, len: usize) -> Result<(), Error> {
+        if !self.write {
+            return make_error(Error::ReadOnly);
+        }
+        if len > self.len() {
+            return make_error(Error::OutOfBound);
+        }
+        self.flush_pending()?;
+        self.file.borrow_mut().set_len(len as u64)?;
+        self.len.set(len);
+        Ok(())
+    }
+
+    /// Writes all buffered writes to the host file, in the order they were made.
+    fn flush_pending(&self) -> Result<(), Error> {
+        let mut log = self.log.borrow_mut();
+        let mut file = self.file.borrow_mut();
+        for (flushed, (pos, data)) in log.iter().enumerate() {
+            let result = self.check_injected_fault().and_then(|_| {
+                file.seek(std::io::SeekFrom::Start(*pos as u64))
+                    .and_then(|_| file.write_all(data))
+                    .map_err(Error::from)
+            });
+            if let Err(e) = result {
+                // Keeps the failed write and the ones after it so that a later commit can retry
+                log.drain(..flushed);
+                return Err(e);
+            }
+            self.pending_len.set(self.pending_len.get() - data.len());
+        }
+        log.clear();
+        self.pending.borrow_mut().clear();
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn check_injected_fault(&self) -> Result<(), Error> {
+        match self.writes_left.get() {
+            Some(0) => make_error(Error::IO(std::io::Error::other("injected fault"))),
+            Some(n) => {
+                self.writes_left.set(Some(n - 1));
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(not(test))]
+    fn check_injected_fault(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Lets the next `count` writes to the host file succeed, and fails all writes after them,
+    /// as if the process stopped there.
+    #[cfg(test)]
+    fn fail_writes_after(&self, count: usize) {
+        self.writes_left.set(Some(count));
+    }
+
+    /// Adds a write to the buffer. It is merged into the last write in the log if the two
+    /// overlap or touch, which keeps the order of the log.
+    fn buffer_write(&self, pos: usize, buf: &[u8]) {
+        let end = pos + buf.len();
+        let mut log = self.log.borrow_mut();
+        match log.last_mut() {
+            Some((last_pos, last)) if *last_pos <= end && pos <= *last_pos + last.len() => {
+                let begin = std::cmp::min(*last_pos, pos);
+                let merged_end = std::cmp::max(*last_pos + last.len(), end);
+                let old_len = last.len();
+                if begin < *last_pos {
+                    last.splice(0..0, std::iter::repeat_n(0, *last_pos - begin));
+                }
+                last.resize(merged_end - begin, 0);
+                last[pos - begin..end - begin].copy_from_slice(buf);
+                *last_pos = begin;
+                self.pending_len
+                    .set(self.pending_len.get() + last.len() - old_len);
+            }
+            _ => {
+                log.push((pos, buf.to_vec()));
+                self.pending_len.set(self.pending_len.get() + buf.len());
+            }
+        }
+
+        let mut pending = self.pending.borrow_mut();
+        let mut begin = pos;
+        let mut merged_end = end;
+        let touched: Vec<usize> = pending
+            .range(..=end)
+            .rev()
+            .take_while(|(p, data)| **p + data.len() >= pos)
+            .map(|(p, _)| *p)
+            .collect();
+        let mut ranges = vec![];
+        for p in touched {
+            let data = pending.remove(&p).unwrap();
+            begin = std::cmp::min(begin, p);
+            merged_end = std::cmp::max(merged_end, p + data.len());
+            ranges.push((p, data));
+        }
+
+        let mut merged = vec![0; merged_end - begin];
+        for (p, data) in ranges {
+            merged[p - begin..p - begin + data.len()].copy_from_slice(&data);
+        }
+        merged[pos - begin..end - begin].copy_from_slice(buf);
+        pending.insert(begin, merged);
+    }
+
+    /// Puts this file behind an `Rc` for the upper layers. With the `metrics` feature, all
+    /// accesses are counted.
+    pub fn into_shared(self) -> Rc<dyn RandomAccessFile> {
+        #[cfg(feature = "metrics")]
+        return Rc::new(crate::metrics::MeteredFile::new(Rc::new(self)));
+        #[cfg(not(feature = "metrics"))]
+        Rc::new(self)
+    }
 }
 
 impl RandomAccessFile for DiskFile {
@@ -27,22 +204,190 @@ impl RandomAccessFile for DiskFile {
         let mut file = self.file.borrow_mut();
         file.seek(std::io::SeekFrom::Start(pos as u64))?;
         file.read_exact(buf)?;
+
+        // Buffered writes take precedence over the host file
+        let end = pos + buf.len();
+        for (&p, data) in self.pending.borrow().range(..end).rev() {
+            let data_end = p + data.len();
+            if data_end <= pos {
+                break;
+            }
+            let begin = std::cmp::max(p, pos);
+            let copy_end = std::cmp::min(data_end, end);
+            buf[begin - pos..copy_end - pos].copy_from_slice(&data[begin - p..copy_end - p]);
+        }
         Ok(())
     }
     fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        if !self.write {
+            error!(
+                "Attempted to write 0x{:X} bytes at 0x{:X} of a read-only file",
+                buf.len(),
+                pos
+            );
+            return make_error(Error::ReadOnly);
+        }
         if pos + buf.len() > self.len() {
             return make_error(Error::OutOfBound);
         }
-        let mut file = self.file.borrow_mut();
-        file.seek(std::io::SeekFrom::Start(pos as u64))?;
-        file.write_all(buf)?;
+        if self.buffer_len.get() == 0 {
+            // Writes left from before the buffer was disabled go first
+            self.flush_pending()?;
+            self.check_injected_fault()?;
+            let mut file = self.file.borrow_mut();
+            file.seek(std::io::SeekFrom::Start(pos as u64))?;
+            file.write_all(buf)?;
+            return Ok(());
+        }
+        self.buffer_write(pos, buf);
+        if self.pending_len.get() > self.buffer_len.get() {
+            self.flush_pending()?;
+        }
         Ok(())
     }
+    /// Returns the length of the physical file at the time it was opened, or after `truncate`.
     fn len(&self) -> usize {
-        self.len
+        self.len.get()
     }
     fn commit(&self) -> Result<(), Error> {
+        if !self.write {
+            return Ok(());
+        }
+        self.flush_pending()?;
         self.file.borrow_mut().flush()?;
         Ok(())
     }
 }
+
+impl Drop for DiskFile {
+    fn drop(&mut self) {
+        // Unbuffered writes would have reached the host file without a commit, so do buffered ones,
+        // in the same order. After a failed commit, this writes what an unbuffered file would
+        // already have written before the failure.
+        if let Err(e) = self.flush_pending() {
+            error!("Failed to write buffered data: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::disk_file::DiskFile;
+    use crate::random_access_file::*;
+    use crate::test_util::TempPath;
+
+    #[test]
+    fn write_buffer() {
+        use rand::prelude::*;
+
+        let mut rng = rand::thread_rng();
+        let len = 0x2000;
+        let init: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+        let open = |name: &str| {
+            let path = TempPath::new(&format!("libsave3ds_disk_file_{}", name));
+            std::fs::write(&path, &init).unwrap();
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .unwrap();
+            (path, DiskFile::new(file, true).unwrap())
+        };
+
+        let (baseline_path, baseline) = open("baseline");
+        baseline.set_write_buffer_len(0);
+        let (buffered_path, buffered) = open("buffered");
+        buffered.set_write_buffer_len(0x100);
+        let (dropped_path, dropped) = open("dropped");
+
+        for _ in 0..1000 {
+            let pos = rng.gen_range(0..len);
+            let data_len = rng.gen_range(1..=std::cmp::min(8, len - pos));
+            let data: Vec<u8> = (0..data_len).map(|_| rng.gen()).collect();
+            baseline.write(pos, &data).unwrap();
+            buffered.write(pos, &data).unwrap();
+            dropped.write(pos, &data).unwrap();
+
+            // Reads see the pending writes
+            let pos = rng.gen_range(0..len);
+            let read_len = rng.gen_range(0..=std::cmp::min(0x40, len - pos));
+            let mut expected = vec![0; read_len];
+            baseline.read(pos, &mut expected).unwrap();
+            let mut actual = vec![0; read_len];
+            buffered.read(pos, &mut actual).unwrap();
+            assert_eq!(actual, expected);
+        }
+
+        baseline.commit().unwrap();
+        buffered.commit().unwrap();
+        let expected = std::fs::read(&baseline_path).unwrap();
+        assert_eq!(std::fs::read(&buffered_path).unwrap(), expected);
+
+        // Dropping without commit still writes everything, as an unbuffered file does
+        drop(dropped);
+        assert_eq!(std::fs::read(&dropped_path).unwrap(), expected);
+    }
+
+    #[test]
+    fn interrupted_flush() {
+        use crate::file_system::*;
+        use crate::memory_file::MemoryFile;
+        use crate::save_data::*;
+        use crate::test_util::*;
+        use std::rc::Rc;
+
+        let path = TempPath::new("libsave3ds_disk_file_interrupted");
+        let resource = resource();
+        resource
+            .format_bare_save(path.as_str(), &format_param(), 0x20000)
+            .unwrap();
+        let name = *b"file\0\0\0\0\0\0\0\0\0\0\0\0";
+        let save = resource.open_bare_save(path.as_str(), true).unwrap();
+        let file = save.open_root().unwrap().new_sub_file(name, 600).unwrap();
+        file.write(0, &[1; 600]).unwrap();
+        save.commit().unwrap();
+        drop(file);
+        drop(save);
+        let image = std::fs::read(&path).unwrap();
+
+        let read_back = || -> Vec<u8> {
+            let raw = Rc::new(MemoryFile::new(std::fs::read(&path).unwrap()));
+            let save = SaveData::new(raw, SaveDataType::Bare, false).unwrap();
+            let file = save.open_root().unwrap().open_sub_file(name).unwrap();
+            let mut buf = vec![0; file.len()];
+            file.read(0, &mut buf).unwrap();
+            buf
+        };
+
+        // Stops the flush of the commit after each possible number of host writes. The header is
+        // written last, so the last commit stays readable until the flush completes.
+        let mut interrupt_after = 0;
+        loop {
+            std::fs::write(&path, &image).unwrap();
+            let host = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .unwrap();
+            let disk = Rc::new(DiskFile::new(host, true).unwrap());
+            let save = SaveData::new(disk.clone(), SaveDataType::Bare, true).unwrap();
+            let mut file = save.open_root().unwrap().open_sub_file(name).unwrap();
+            file.resize(1000).unwrap();
+            file.write(0, &[2; 1000]).unwrap();
+            file.commit().unwrap();
+            disk.fail_writes_after(interrupt_after);
+            let result = save.commit();
+            drop(file);
+            drop(save);
+            drop(disk);
+
+            if result.is_ok() {
+                assert_eq!(read_back(), [2; 1000]);
+                break;
+            }
+            assert_eq!(read_back(), [1; 600]);
+            interrupt_after += 1;
+        }
+        assert!(interrupt_after > 1);
+    }
+}
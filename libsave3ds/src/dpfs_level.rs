@@ -44,6 +44,52 @@ impl DpfsLevel {
             dirty: RefCell::new(vec![0; chunk_count]),
         })
     }
+
+    /// Returns the selector contents and the copy each block currently reads from,
+    /// for debugging. This doesn't change any state.
+    pub fn debug_state(&self) -> Result<DpfsLevelState, Error> {
+        let block_count = divide_up(self.len, self.block_len);
+        let dirty = self.dirty.borrow();
+        let mut raw = vec![0; dirty.len() * 4];
+        self.selector.read(0, &mut raw)?;
+        let selector: Vec<u32> = raw
+            .chunks(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect();
+        let mut active = Vec::with_capacity(block_count);
+        let mut modified = Vec::with_capacity(block_count);
+        for block_i in 0..block_count {
+            let chunk_i = block_i / 32;
+            let shift = 31 - (block_i - chunk_i * 32);
+            let dirty_bit = (dirty[chunk_i] >> shift) & 1;
+            active.push((((selector[chunk_i] ^ dirty[chunk_i]) >> shift) & 1) as u8);
+            modified.push(dirty_bit == 1);
+        }
+        Ok(DpfsLevelState {
+            block_len: self.block_len,
+            selector,
+            active,
+            modified,
+        })
+    }
+}
+
+/// A snapshot of the copy selection in a DPFS level. See `DpfsLevel::debug_state`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DpfsLevelState {
+    /// Length of a block in bytes. Each block has its own selector bit.
+    pub block_len: usize,
+
+    /// Raw selector contents, as 32-bit MSB-first chunks.
+    pub selector: Vec<u32>,
+
+    /// The copy (0 or 1) each block is read from. For blocks modified since the last commit,
+    /// this is the opposite of the selector bit.
+    pub active: Vec<u8>,
+
+    /// Whether each block has been modified since the last commit.
+    pub modified: Vec<bool>,
 }
 
 impl RandomAccessFile for DpfsLevel {
@@ -187,10 +233,8 @@ impl RandomAccessFile for DpfsLevel {
 
 #[cfg(test)]
 mod test {
-    use crate::dpfs_level::DpfsLevel;
+    use crate::dpfs_level::*;
     use crate::memory_file::MemoryFile;
-    use crate::misc::*;
-    use crate::random_access_file::*;
     use std::rc::Rc;
 
     #[test] #[rustfmt::skip]
@@ -260,6 +304,33 @@ mod test {
         assert_eq!(buf3, [0xFF, 0x11, 0x22, 0x33, 0x44, 0x55, 0x00]);
     }
 
+    #[test]
+    fn debug_state() {
+        let selector = Rc::new(MemoryFile::new(vec![0x00, 0x00, 0x00, 0x80]));
+        let pair: [Rc<dyn RandomAccessFile>; 2] = [
+            Rc::new(MemoryFile::new(vec![0; 12])),
+            Rc::new(MemoryFile::new(vec![0; 12])),
+        ];
+        let file = DpfsLevel::new(selector, pair, 4).unwrap();
+        let state = file.debug_state().unwrap();
+        assert_eq!(state.block_len, 4);
+        assert_eq!(state.selector, vec![0x8000_0000]);
+        assert_eq!(state.active, vec![1, 0, 0]);
+        assert_eq!(state.modified, vec![false; 3]);
+
+        file.write(5, &[1]).unwrap();
+        let state = file.debug_state().unwrap();
+        assert_eq!(state.selector, vec![0x8000_0000]);
+        assert_eq!(state.active, vec![1, 1, 0]);
+        assert_eq!(state.modified, vec![false, true, false]);
+
+        file.commit().unwrap();
+        let state = file.debug_state().unwrap();
+        assert_eq!(state.selector, vec![0xC000_0000]);
+        assert_eq!(state.active, vec![1, 1, 0]);
+        assert_eq!(state.modified, vec![false; 3]);
+    }
+
     #[test]
     fn fuzz() {
         use rand::distributions::Standard;
@@ -33,6 +33,14 @@ impl DualFile {
             len,
         })
     }
+
+    /// Returns the committed selector and whether the file has been modified since the last
+    /// commit, for debugging. Reads come from the other copy when modified.
+    pub fn debug_state(&self) -> Result<(u8, bool), Error> {
+        let mut select = [0; 1];
+        self.selector.read(0, &mut select)?;
+        Ok((select[0], self.modified.get() == 1))
+    }
 }
 
 impl RandomAccessFile for DualFile {
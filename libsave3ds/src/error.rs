@@ -1,6 +1,38 @@
 use log::*;
 use std::fmt;
 
+/// Stages of committing an archive, reported by
+/// [`Error::CommitFailed`](enum.Error.html#variant.CommitFailed).
+///
+/// The stages run in this order. Host file writes happen in all of them, so the inner error
+/// tells what went wrong (e.g. `Error::IO` for a full disk), and the stage tells how far
+/// the commit got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CommitStage {
+    /// Rehashing the IVFC levels of a partition or of the partition table.
+    Ivfc,
+    /// Switching the DPFS copies of a partition or of the partition table to the new state.
+    Dpfs,
+    /// Signing the header with the CMAC.
+    Signature,
+    /// Updating the wear-leveling journal of a cartridge save.
+    WearLeveling,
+    /// Flushing the host file.
+    Io,
+}
+
+impl fmt::Display for CommitStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommitStage::Ivfc => write!(f, "IVFC rehash"),
+            CommitStage::Dpfs => write!(f, "DPFS switch"),
+            CommitStage::Signature => write!(f, "CMAC signing"),
+            CommitStage::WearLeveling => write!(f, "wear-leveling journal"),
+            CommitStage::Io => write!(f, "host file flush"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     IO(std::io::Error),
@@ -17,13 +49,18 @@ pub enum Error {
     SignatureMismatch,
     MissingBoot9,
     MissingSd,
+    MissingMovable,
     MissingNand,
     MissingGame,
     MissingPriv,
     MissingKeyY2F,
     MissingKeyX19,
     MissingKeyX1A,
+    MissingKeyX19X1A,
     MissingOtp,
+    MissingSecureInfo,
+    MissingLfcs,
+    LfcsMismatch,
     BrokenSd,
     NotEmpty,
     Unsupported,
@@ -31,6 +68,16 @@ pub enum Error {
     BrokenOtp,
     Busy,
     BrokenGame,
+    LikelyWrongKey,
+    /// A commit failed at the given stage, because of the inner error.
+    ///
+    /// Commits used to return the inner error as-is. Code that matched it, e.g.
+    /// `Err(Error::IO(_))` for a full disk, should match `Error::inner` instead, which also
+    /// works for errors from anything other than a commit.
+    CommitFailed(CommitStage, Box<Error>),
+    NameTooLong,
+    InvalidName,
+    ReadOnly,
 }
 
 impl fmt::Display for Error {
@@ -53,13 +100,30 @@ impl fmt::Display for Error {
             Error::SignatureMismatch => write!(f, "Signature mismatch, caused by corrupted data"),
             Error::MissingBoot9 => write!(f, "Missing boot9.bin"),
             Error::MissingSd => write!(f, "Cannot open SD due to missing SD or movable.sed"),
+            Error::MissingMovable => write!(f, "Missing movable.sed"),
             Error::MissingNand => write!(f, "Missing NAND"),
             Error::MissingGame => write!(f, "Missing game"),
             Error::MissingPriv => write!(f, "Missing private header"),
             Error::MissingKeyY2F => write!(f, "Missing 0x2F key Y"),
-            Error::MissingKeyX19 => write!(f, "Missing 0x19 key X"),
-            Error::MissingKeyX1A => write!(f, "Missing 0x1A key X"),
+            Error::MissingKeyX19 => write!(
+                f,
+                "Missing 0x19 key X, which the save of a New 3DS exclusive cartridge needs"
+            ),
+            Error::MissingKeyX1A => write!(
+                f,
+                "Missing 0x1A key X, which the save of a New 3DS exclusive cartridge needs"
+            ),
+            Error::MissingKeyX19X1A => write!(
+                f,
+                "Missing 0x19 and 0x1A key X, which the save of a New 3DS exclusive cartridge needs"
+            ),
             Error::MissingOtp => write!(f, "Missing OTP"),
+            Error::MissingSecureInfo => write!(f, "Missing SecureInfo_A in the NAND"),
+            Error::MissingLfcs => write!(f, "Missing LocalFriendCodeSeed_B in the NAND"),
+            Error::LfcsMismatch => write!(
+                f,
+                "LocalFriendCodeSeed_B doesn't match movable.sed, which is likely from another console"
+            ),
             Error::BrokenSd => write!(f, "Corrupted SD"),
             Error::NotEmpty => write!(f, "Trying to delete a non-empty directory"),
             Error::Unsupported => write!(f, "The operation is not supported on this archive"),
@@ -72,11 +136,33 @@ impl fmt::Display for Error {
                 "The file or directory is currently used by other program"
             ),
             Error::BrokenGame => write!(f, "Provided game file is broken"),
+            Error::LikelyWrongKey => write!(
+                f,
+                "Decryption or signature check failed although the data looks intact. \
+                 Check that boot9.bin, movable.sed and otp.bin belong to this console"
+            ),
+            Error::CommitFailed(stage, inner) => {
+                write!(f, "Commit failed at the {} stage: {}", stage, inner)
+            }
+            Error::NameTooLong => write!(f, "The name is longer than 16 bytes"),
+            Error::InvalidName => write!(f, "The name is empty or not a valid encoding"),
+            Error::ReadOnly => write!(
+                f,
+                "A write is needed to proceed, but the archive is opened read-only"
+            ),
         }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::CommitFailed(_, inner) => Some(inner.as_ref()),
+            Error::IO(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Error {
@@ -85,6 +171,28 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl Error {
+    /// Returns the inner error of a `CommitFailed` error, or the error itself otherwise.
+    pub fn inner(&self) -> &Error {
+        match self {
+            Error::CommitFailed(_, inner) => inner,
+            e => e,
+        }
+    }
+
+    /// Tags an error from committing with the stage it happened in.
+    /// An error that is already tagged keeps its innermost stage.
+    pub(crate) fn in_commit_stage(self, stage: CommitStage) -> Error {
+        match self {
+            e @ Error::CommitFailed(..) => e,
+            e => {
+                error!("Commit failed at the {} stage: {}", stage, e);
+                Error::CommitFailed(stage, Box::new(e))
+            }
+        }
+    }
+}
+
 pub(crate) fn make_error<T>(e: Error) -> Result<T, Error> {
     info!("Error thrown: {:?}", e);
     Err(e)
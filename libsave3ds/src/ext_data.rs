@@ -4,6 +4,7 @@ use crate::error::*;
 use crate::fat::*;
 use crate::file_system::*;
 use crate::fs_meta::{self, FileInfo, FsInfo, OffsetOrFatFile};
+use crate::header_dump::*;
 use crate::misc::*;
 use crate::random_access_file::*;
 use crate::save_ext_common::*;
@@ -12,7 +13,11 @@ use crate::signed_file::*;
 use crate::sub_file::SubFile;
 use byte_struct::*;
 use log::*;
-use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+pub use crate::save_data::default_buckets;
 
 #[derive(ByteStruct, Clone)]
 #[byte_struct_le]
@@ -71,6 +76,16 @@ struct ExtHeader {
     mount_path: [[u8; 0x10]; 0x10],
 }
 
+/// Number of files per host directory, recorded as `dir_capacity` in `Quota.dat`.
+/// The data of the file with index `i` is stored in directory `i / DIR_CAPACITY`,
+/// as file `i % DIR_CAPACITY`.
+const DIR_CAPACITY: u32 = 126;
+
+/// Returns the host directory and file indices of the data of the file with `file_index`.
+fn file_data_index(file_index: u32) -> (u32, u32) {
+    (file_index / DIR_CAPACITY, file_index % DIR_CAPACITY)
+}
+
 #[derive(ByteStruct, Debug)]
 #[byte_struct_le]
 struct Quota {
@@ -97,12 +112,35 @@ struct Quota {
 /// This is similar to parameters of
 /// [`FS:CreateExtSaveData`](https://www.3dbrew.org/wiki/FS:CreateExtSaveData),
 /// except the hash table bucket count is provided by the user.
+/// [`default_buckets`](fn.default_buckets.html) gives a reasonable choice for it.
 #[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ExtDataFormatParam {
     pub max_dir: usize,
     pub dir_buckets: usize,
     pub max_file: usize,
     pub file_buckets: usize,
+
+    /// Initial content of the mount path table in the extdata header. Each entry is a
+    /// 16-byte name, and the path ends at the first all-zero entry.
+    ///
+    /// 3dbrew describes this table as the path of the file last mounted by the FS module.
+    /// Extdata created by the 3DS have it all zero, which is the recommended value.
+    pub mount_path: [[u8; 0x10]; 0x10],
+}
+
+/// Returns the DIFF parameters for the container of a file with `len` bytes of data.
+fn file_param(len: usize) -> DifiPartitionParam {
+    DifiPartitionParam {
+        dpfs_level2_block_len: 128,
+        dpfs_level3_block_len: 4096,
+        ivfc_level1_block_len: 512,
+        ivfc_level2_block_len: 512,
+        ivfc_level3_block_len: 4096,
+        ivfc_level4_block_len: 4096,
+        data_len: len,
+        external_ivfc_level4: true,
+    }
 }
 
 struct ExtDataInner {
@@ -114,6 +152,10 @@ struct ExtDataInner {
     quota_file: Option<Diff>,
     key: [u8; 16],
     write: bool,
+    fill: Cell<u8>,
+    // Data of the open files by inode. All handles of a file share its data, so that the host
+    // file is only opened, and locked, once.
+    open_data: RefCell<HashMap<u32, Weak<Diff>>>,
 }
 
 /// Implements [`FileSystem`](../file_system/trait.FileSystem.html) for extdata.
@@ -201,7 +243,7 @@ impl ExtData {
                 0x01234567_89ABCDEF,
             )?;
 
-            let quota_file = Diff::new(sd_nand.open(&quota_path, true)?, Some((signer, key)))?;
+            let quota_file = Diff::new(quota_raw, Some((signer, key)))?;
             write_struct(
                 quota_file.partition().as_ref(),
                 0,
@@ -209,7 +251,7 @@ impl ExtData {
                     magic: *b"QUOT",
                     version: 0x30000,
                     block_len: 0x1000,
-                    dir_capacity: 126,
+                    dir_capacity: DIR_CAPACITY,
                     p0: 0,
                     max_block: capacity,
                     p1: 0,
@@ -304,7 +346,7 @@ impl ExtData {
             unknown2: 0,
             mount_id: 0,
             unknown3: 0,
-            mount_path: [[0; 0x10]; 0x10],
+            mount_path: param.mount_path,
         };
 
         write_struct(meta_file.partition().as_ref(), 0, header)?;
@@ -354,16 +396,37 @@ impl ExtData {
             .chain([id_high.as_str(), id_low.as_str()].iter().cloned())
             .collect();
 
+        let mut write = write;
         let quota_file = if has_quota {
             let mut quota_path = ext_path.clone();
             quota_path.push("Quota.dat");
-            Some(Diff::new(
-                sd_nand.open(&quota_path, write)?,
-                Some((Box::new(ExtSigner { id, sub_id: None }), key)),
-            )?)
+            match sd_nand.open(&quota_path, write) {
+                Ok(quota_raw) => Some(Diff::new(
+                    quota_raw,
+                    Some((Box::new(ExtSigner { id, sub_id: None }), key)),
+                )?),
+                Err(Error::NotFound) => None,
+                Err(Error::IO(e)) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            }
         } else {
             None
         };
+        if has_quota && quota_file.is_none() {
+            warn!("Quota.dat is missing. Opening the extdata read-only without quota accounting");
+            write = false;
+        }
+        if let Some(quota_file) = &quota_file {
+            let quota: Quota = read_struct(quota_file.partition().as_ref(), 0)?;
+            if quota.dir_capacity != DIR_CAPACITY {
+                error!(
+                    "Unexpected dir_capacity {} in Quota.dat. Only {} is supported, \
+                     otherwise file data would be looked up in the wrong directories",
+                    quota.dir_capacity, DIR_CAPACITY
+                );
+                return make_error(Error::Unsupported);
+            }
+        }
 
         let mut meta_path = ext_path;
         meta_path.push("00000000");
@@ -388,6 +451,13 @@ impl ExtData {
             );
             return make_error(Error::MagicMismatch);
         }
+        if header.action != 0 {
+            warn!(
+                "Extdata {:016X} has action={}, which the console leaves behind if it is \
+                 interrupted in the middle of an operation",
+                id, header.action
+            );
+        }
         let fs_info: FsInfo = read_struct(
             meta_file.partition().as_ref(),
             header.fs_info_offset as usize,
@@ -446,16 +516,289 @@ impl ExtData {
                 quota_file,
                 key,
                 write,
+                fill: Cell::new(crate::ivfc_level::DEFAULT_FILL),
+                open_data: RefCell::new(HashMap::new()),
             }),
         })
     }
 }
 
+impl ExtData {
+    /// Sets the byte that fills data failing hash verification on read, such as
+    /// uninitialized file data. This applies to
+    /// the meta file and to files opened afterwards. `0xDD` is used if this is never called.
+    pub fn set_fill_byte(&self, fill: u8) {
+        self.center.fill.set(fill);
+        self.center.meta_file.set_fill(fill);
+        if let Some(quota_file) = self.center.quota_file.as_ref() {
+            quota_file.set_fill(fill);
+        }
+    }
+
+    /// Flushes changes made to the data of file `ino` together with all file system changes.
+    ///
+    /// Each file in extdata is a separate DIFF container, so the two commits can't be made
+    /// atomic as a whole. Instead, the file data is committed first, followed by the meta file.
+    /// Each of the two steps is atomic on its own, which gives the following guarantees
+    /// if the process is interrupted:
+    ///  - before the first step finishes, both the file data and the file system are left at
+    ///    the state of their own last commits.
+    ///  - between the two steps, the file data is new while the file system is old. The unique ID
+    ///    stored in the file system entry is never changed by a commit, so the file can still be
+    ///    opened without `Error::UniqueIdMismatch`.
+    ///
+    /// The data is shared by all open handles of the file, so their changes are committed
+    /// together. If no handle of the file is open, only the file system is committed.
+    pub fn commit_file(&self, ino: u32) -> Result<(), Error> {
+        FileMeta::open_ino(self.center.fs.clone(), ino)?.get_info()?;
+        let data = self
+            .center
+            .open_data
+            .borrow()
+            .get(&ino)
+            .and_then(Weak::upgrade);
+        if let Some(data) = data {
+            data.commit()?;
+        }
+        self.center.meta_file.commit()
+    }
+
+    /// Returns the mount path recorded in the extdata header, as a list of 16-byte names.
+    /// See [`ExtDataFormatParam::mount_path`](struct.ExtDataFormatParam.html#structfield.mount_path).
+    pub fn mount_path(&self) -> Result<Vec<[u8; 0x10]>, Error> {
+        let header: ExtHeader = read_struct(self.center.meta_file.partition().as_ref(), 0)?;
+        Ok(header
+            .mount_path
+            .iter()
+            .take_while(|name| name.iter().any(|&c| c != 0))
+            .cloned()
+            .collect())
+    }
+
+    /// Returns whether the extdata is accounted in `Quota.dat`. If not, `stat` reports no
+    /// free blocks.
+    pub fn has_quota(&self) -> bool {
+        self.center.quota_file.is_some()
+    }
+
+    /// Returns the number of quota blocks a file with `len` bytes of data takes. This is the
+    /// size of its DIFF container in 0x1000-byte units, and 0 for an empty file, which has no
+    /// container.
+    pub fn quota_blocks(len: usize) -> usize {
+        if len == 0 {
+            0
+        } else {
+            divide_up(Diff::calculate_size(&file_param(len)), 0x1000)
+        }
+    }
+
+    /// Returns the `action` field of the extdata header. It is 0 for an extdata that is not in
+    /// the middle of an operation, such as a format or delete the console was interrupted in.
+    pub fn action(&self) -> Result<u32, Error> {
+        let header: ExtHeader = read_struct(self.center.meta_file.partition().as_ref(), 0)?;
+        Ok(header.action)
+    }
+
+    /// Zeroes the `action` field of the extdata header, after walking every directory and file
+    /// to check that the extdata is otherwise readable. The change is committed.
+    /// Nothing is changed if the field is already 0, or if the walk fails, whose error is
+    /// returned.
+    ///
+    /// Returns `Error::Unsupported` if the extdata is opened read-only.
+    pub fn reset_action(&self) -> Result<(), Error> {
+        if !self.center.write {
+            return make_error(Error::Unsupported);
+        }
+        let partition = self.center.meta_file.partition();
+        let mut header: ExtHeader = read_struct(partition.as_ref(), 0)?;
+        if header.action == 0 {
+            return Ok(());
+        }
+        crate::file_system::content_entries(self)?;
+        warn!(
+            "Resetting action={} of extdata {:016X}",
+            header.action, self.center.id
+        );
+        header.action = 0;
+        write_struct(partition.as_ref(), 0, header)?;
+        self.center.meta_file.commit()
+    }
+
+    /// Dumps every header structure of the extdata field by field, for bug reports:
+    /// the DIFF container of the metadata file, the VSXE header, the file system information,
+    /// and the DIFF container and content of the quota file if it exists.
+    /// The CMACs are not included.
+    pub fn dump_headers(&self) -> Result<Vec<HeaderDump>, Error> {
+        let meta = &self.center.meta_file;
+        let mut dumps = meta.dump_headers("Meta")?;
+        let header: ExtHeader = read_struct(meta.partition().as_ref(), 0)?;
+        let mut mount_path = vec![];
+        for name in header.mount_path.iter() {
+            mount_path.extend_from_slice(name);
+        }
+        dumps.push(
+            dump_fields!(
+                "ExtHeader",
+                header,
+                magic,
+                version,
+                fs_info_offset,
+                image_size,
+                image_block_len,
+                padding,
+                unknown,
+                action,
+                unknown2,
+                mount_id,
+                unknown3,
+            )
+            .field("mount_path", FieldValue::Bytes(mount_path)),
+        );
+        let fs_info: FsInfo =
+            read_struct(meta.partition().as_ref(), header.fs_info_offset as usize)?;
+        dumps.push(fs_info.dump());
+
+        if let Some(quota_file) = &self.center.quota_file {
+            dumps.extend(quota_file.dump_headers("Quota")?);
+            let quota: Quota = read_struct(quota_file.partition().as_ref(), 0)?;
+            dumps.push(dump_fields!(
+                "Quota",
+                quota,
+                magic,
+                version,
+                block_len,
+                dir_capacity,
+                p0,
+                max_block,
+                p1,
+                free_block,
+                p2,
+                p3,
+                potential_free_block,
+                p4,
+                mount_id,
+                p5,
+                p6,
+                p7,
+                mount_len,
+            ));
+        }
+        Ok(dumps)
+    }
+
+    /// Returns both copies of the unique ID of the file `ino`. Unlike `open_file`, this doesn't
+    /// fail with `Error::UniqueIdMismatch` if they differ.
+    pub fn file_unique_id(&self, ino: u32) -> Result<FileUniqueId, Error> {
+        let meta = FileMeta::open_ino(self.center.fs.clone(), ino)?;
+        let file = File::from_meta_unchecked(self.center.clone(), meta, None)?;
+        Ok(FileUniqueId {
+            meta: file.meta.get_info()?.unique_id,
+            diff: file.data.as_ref().map(|data| data.unique_id()),
+        })
+    }
+
+    /// Makes the two copies of the unique ID of the file `ino` match again, by rewriting one of
+    /// them with the other as chosen by `source`, so that the file can be opened.
+    /// The change is committed. Nothing is changed if the copies already match.
+    ///
+    /// Returns `Error::Unsupported` if the extdata is opened read-only.
+    pub fn repair_unique_id(&self, ino: u32, source: UniqueIdSource) -> Result<(), Error> {
+        if !self.center.write {
+            return make_error(Error::Unsupported);
+        }
+        let meta = FileMeta::open_ino(self.center.fs.clone(), ino)?;
+        let file = File::from_meta_unchecked(self.center.clone(), meta, None)?;
+        let mut info = file.meta.get_info()?;
+        let data = match file.data.as_ref() {
+            Some(data) if data.unique_id() != info.unique_id => data,
+            _ => return Ok(()),
+        };
+        warn!(
+            "Repairing unique ID of file {}: {:016X} in the entry, {:016X} in the file data",
+            ino,
+            info.unique_id,
+            data.unique_id()
+        );
+        match source {
+            UniqueIdSource::Meta => {
+                data.set_unique_id(info.unique_id)?;
+                data.commit()
+            }
+            UniqueIdSource::Diff => {
+                info.unique_id = data.unique_id();
+                file.meta.set_info(info)?;
+                self.center.meta_file.commit()
+            }
+        }
+    }
+
+    /// Name of the file in the root directory that holds the icon of the extdata.
+    ///
+    /// The file is created by `FS:CreateExtSaveData` with the SMDH data provided by the
+    /// creator, and is read by the Home Menu to show the extdata in data management.
+    pub const ICON_NAME: [u8; 16] = *b"icon\0\0\0\0\0\0\0\0\0\0\0\0";
+
+    /// Reads the raw content of the icon file. See [`ICON_NAME`](#associatedconstant.ICON_NAME).
+    ///
+    /// Returns `Error::NotFound` if the extdata has no icon file.
+    pub fn read_icon(&self) -> Result<Vec<u8>, Error> {
+        let file = self.open_root()?.open_sub_file(ExtData::ICON_NAME)?;
+        let mut data = vec![0; file.len()];
+        file.read(0, &mut data)?;
+        Ok(data)
+    }
+
+    /// Replaces the content of the icon file with `data`, creating the file if it doesn't exist.
+    /// The change is committed together with all file system changes.
+    /// See [`ICON_NAME`](#associatedconstant.ICON_NAME).
+    ///
+    /// Because 3DS refuses to open zero-size files, `Error::InvalidValue` is returned if `data`
+    /// is empty.
+    pub fn write_icon(&self, data: &[u8]) -> Result<(), Error> {
+        if data.is_empty() {
+            return make_error(Error::InvalidValue);
+        }
+        let root = self.open_root()?;
+        match root.open_sub_file(ExtData::ICON_NAME) {
+            Ok(file) => file.delete()?,
+            Err(Error::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+        let file = root.new_sub_file(ExtData::ICON_NAME, data.len())?;
+        file.write(0, data)?;
+        self.commit_file(file.get_ino())
+    }
+}
+
+/// The unique ID of an extdata file, which is stored both in the file system entry and in the
+/// DIFF header of the file data. See [`ExtData::file_unique_id`](struct.ExtData.html#method.file_unique_id).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileUniqueId {
+    /// The unique ID in the file system entry.
+    pub meta: u64,
+
+    /// The unique ID in the DIFF header, or `None` for a zero-size file, which has no file data.
+    pub diff: Option<u64>,
+}
+
+/// Which copy of the unique ID to keep. See
+/// [`ExtData::repair_unique_id`](struct.ExtData.html#method.repair_unique_id).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum UniqueIdSource {
+    /// Keeps the unique ID in the file system entry, and rewrites the DIFF header.
+    Meta,
+
+    /// Keeps the unique ID in the DIFF header, and rewrites the file system entry.
+    Diff,
+}
+
 /// Implements [`FileSystemFile`](../file_system/trait.FileSystemFile.html) for extdata file.
 pub struct File {
     center: Rc<ExtDataInner>,
     meta: FileMeta,
-    data: Option<Diff>,
+    data: Option<Rc<Diff>>,
 }
 
 impl File {
@@ -464,11 +807,41 @@ impl File {
         meta: FileMeta,
         new: Option<(usize, u64)>,
     ) -> Result<File, Error> {
+        let file = File::from_meta_unchecked(center, meta, new)?;
+        if let Some(data) = file.data.as_ref() {
+            if file.meta.get_info()?.unique_id != data.unique_id() {
+                error!("Unique ID mismatch");
+                return make_error(Error::UniqueIdMismatch);
+            }
+        }
+        Ok(file)
+    }
+
+    /// Same as `from_meta`, but doesn't check the unique ID.
+    fn from_meta_unchecked(
+        center: Rc<ExtDataInner>,
+        meta: FileMeta,
+        new: Option<(usize, u64)>,
+    ) -> Result<File, Error> {
+        if new.is_none() {
+            let shared = center
+                .open_data
+                .borrow()
+                .get(&meta.get_ino())
+                .and_then(Weak::upgrade);
+            if let Some(data) = shared {
+                return Ok(File {
+                    center,
+                    meta,
+                    data: Some(data),
+                });
+            }
+        }
+
         let file_index = meta.get_ino() + 1;
         let id_high = format!("{:08x}", center.id >> 32);
         let id_low = format!("{:08x}", center.id & 0xFFFF_FFFF);
-        let fid_high = file_index / 126;
-        let fid_low = file_index % 126;
+        let (fid_high, fid_low) = file_data_index(file_index);
         let fid_high_s = format!("{:08x}", fid_high);
         let fid_low_s = format!("{:08x}", fid_low);
         let path: Vec<&str> = center
@@ -484,26 +857,29 @@ impl File {
 
         let mut param = None;
         if let Some((len, _)) = new {
+            if !center.write {
+                return make_error(Error::Unsupported);
+            }
             if len != 0 {
-                param = Some(DifiPartitionParam {
-                    dpfs_level2_block_len: 128,
-                    dpfs_level3_block_len: 4096,
-                    ivfc_level1_block_len: 512,
-                    ivfc_level2_block_len: 512,
-                    ivfc_level3_block_len: 4096,
-                    ivfc_level4_block_len: 4096,
-                    data_len: len,
-                    external_ivfc_level4: true,
-                });
+                param = Some(file_param(len));
 
                 let physical_len = Diff::calculate_size(param.as_ref().unwrap());
 
-                if let Some(quota_file) = center.quota_file.as_ref() {
-                    let mut quota: Quota = read_struct(quota_file.partition().as_ref(), 0)?;
+                let quota = if let Some(quota_file) = center.quota_file.as_ref() {
+                    let quota: Quota = read_struct(quota_file.partition().as_ref(), 0)?;
                     let block = (divide_up(physical_len, 0x1000)) as u32;
                     if quota.free_block < block {
                         return make_error(Error::NoSpace);
                     }
+                    Some((quota_file, quota, block))
+                } else {
+                    None
+                };
+
+                // Only charge the quota once the file is actually created
+                center.sd_nand.create(&path, physical_len)?;
+
+                if let Some((quota_file, mut quota, block)) = quota {
                     quota.mount_id = file_index;
                     quota.mount_len = physical_len as u64;
                     quota.potential_free_block = quota.free_block;
@@ -511,11 +887,15 @@ impl File {
                     write_struct(quota_file.partition().as_ref(), 0, quota)?;
                     quota_file.commit()?;
                 }
-
-                center.sd_nand.create(&path, physical_len)?
             }
         }
-        let file = center.sd_nand.open(&path, center.write).ok();
+        let file = match center.sd_nand.open(&path, center.write) {
+            Ok(file) => Some(file),
+            // Zero-size files have no container
+            Err(Error::NotFound) => None,
+            Err(Error::IO(e)) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
         let signer = Box::new(ExtSigner {
             id: center.id,
             sub_id: Some((u64::from(fid_high) << 32) | u64::from(fid_low)),
@@ -534,26 +914,30 @@ impl File {
 
         let data = file
             .map(|file| Diff::new(file, Some((signer, center.key))))
-            .transpose()?;
-
-        let info = meta.get_info()?;
-        if data.is_some() && info.unique_id != data.as_ref().unwrap().unique_id() {
-            error!("Unique ID mismatch");
-            return make_error(Error::UniqueIdMismatch);
+            .transpose()?
+            .map(Rc::new);
+        if let Some(data) = data.as_ref() {
+            data.set_fill(center.fill.get());
+            let mut open_data = center.open_data.borrow_mut();
+            open_data.retain(|_, data| data.strong_count() != 0);
+            open_data.insert(meta.get_ino(), Rc::downgrade(data));
         }
+
         Ok(File { center, meta, data })
     }
 
     fn delete_data(&mut self) -> Result<(), Error> {
+        if !self.center.write {
+            return make_error(Error::Unsupported);
+        }
         let file_index = self.meta.get_ino() + 1;
-        let physical_len = self.data.as_ref().map_or(0, Diff::parent_len);
+        let physical_len = self.physical_len();
 
         if let Some(file) = self.data.take() {
             std::mem::drop(file); // close the file first
             let id_high = format!("{:08x}", self.center.id >> 32);
             let id_low = format!("{:08x}", self.center.id & 0xFFFF_FFFF);
-            let fid_high = file_index / 126;
-            let fid_low = file_index % 126;
+            let (fid_high, fid_low) = file_data_index(file_index);
             let fid_high_s = format!("{:08x}", fid_high);
             let fid_low_s = format!("{:08x}", fid_low);
             let path: Vec<&str> = self
@@ -583,6 +967,15 @@ impl File {
 
         Ok(())
     }
+
+    /// Returns the size of the DIFF container backing this file on the host file system.
+    ///
+    /// This is the size charged against the extdata quota, and includes the DIFF header and
+    /// hash tree in addition to the file data returned by [`len`](#method.len).
+    /// Returns 0 for a zero-size file, which has no backing container.
+    pub fn physical_len(&self) -> usize {
+        self.data.as_ref().map_or(0, |data| data.parent_len())
+    }
 }
 
 impl FileSystemFile for File {
@@ -615,6 +1008,17 @@ impl FileSystemFile for File {
 
         self.meta.check_exclusive()?;
 
+        // The old data is deleted before the new one is created, so check the quota first
+        // to not lose the data on failure
+        if let Some(quota_file) = self.center.quota_file.as_ref() {
+            let quota: Quota = read_struct(quota_file.partition().as_ref(), 0)?;
+            let old_block = divide_up(self.physical_len(), 0x1000);
+            let new_block = ExtData::quota_blocks(len);
+            if quota.free_block as usize + old_block < new_block {
+                return make_error(Error::NoSpace);
+            }
+        }
+
         let mut buf = vec![0; len];
         match self.read(0, &mut buf[0..std::cmp::min(len, self.len())]) {
             Ok(()) | Err(Error::HashMismatch) => {}
@@ -632,6 +1036,8 @@ impl FileSystemFile for File {
     }
 
     fn delete(mut self) -> Result<(), Error> {
+        // Checked before the data is removed, as other handles may still use it
+        self.meta.check_exclusive()?;
         self.delete_data()?;
         self.meta.delete()?;
         Ok(())
@@ -711,12 +1117,12 @@ impl FileSystemDir for Dir {
         File::from_meta(self.center.clone(), self.meta.open_sub_file(name)?, None)
     }
 
-    fn list_sub_dir(&self) -> Result<Vec<([u8; 16], u32)>, Error> {
-        self.meta.list_sub_dir()
+    fn iter_sub_dir(&self) -> Result<EntryIter<[u8; 16]>, Error> {
+        Ok(Box::new(self.meta.iter_sub_dir()?))
     }
 
-    fn list_sub_file(&self) -> Result<Vec<([u8; 16], u32)>, Error> {
-        self.meta.list_sub_file()
+    fn iter_sub_file(&self) -> Result<EntryIter<[u8; 16]>, Error> {
+        Ok(Box::new(self.meta.iter_sub_file()?))
     }
 
     fn new_sub_dir(&self, name: [u8; 16]) -> Result<Self, Error> {
@@ -753,7 +1159,12 @@ impl FileSystemDir for Dir {
                 padding2: 0,
             },
         )?;
-        File::from_meta(self.center.clone(), meta, Some((len, unique_id)))
+        let ino = meta.get_ino();
+        File::from_meta(self.center.clone(), meta, Some((len, unique_id))).or_else(|e| {
+            // Don't leave an empty entry behind, e.g. when the quota is exceeded
+            FileMeta::open_ino(self.center.fs.clone(), ino)?.delete()?;
+            Err(e)
+        })
     }
 
     fn delete(self) -> Result<(), Error> {
@@ -786,19 +1197,35 @@ impl FileSystem for ExtData {
     /// the change roll back to the state the last time `commit` is called.
     /// The flush behavior of changing file data is controlled by
     /// [`File::commit`](struct.File.html).
+    ///
+    /// This is a no-op if the extdata is opened read-only.
     fn commit(&self) -> Result<(), Error> {
+        if !self.center.write {
+            return Ok(());
+        }
         self.center.meta_file.commit()
     }
 
     /// Returns the capacity information of the archive.
     ///
-    /// `block_len`, `total_blocks` and `free_blocks` are set to 0.
+    /// Reports the blocks of `Quota.dat` in 0x1000-byte units. Without a quota, there is no
+    /// capacity limit to report, so the blocks are those of the metadata partition, with
+    /// none free.
     fn stat(&self) -> Result<Stat, Error> {
         let meta_stat = self.center.fs.stat()?;
+        let (total_blocks, free_blocks) = if let Some(quota_file) = &self.center.quota_file {
+            let quota: Quota = read_struct(quota_file.partition().as_ref(), 0)?;
+            (quota.max_block as usize, quota.free_block as usize)
+        } else {
+            (
+                divide_up(self.center.meta_file.partition().len(), 0x1000),
+                0,
+            )
+        };
         Ok(Stat {
-            block_len: 0,
-            total_blocks: 0,
-            free_blocks: 0,
+            block_len: 0x1000,
+            total_blocks,
+            free_blocks,
             total_files: meta_stat.files.total,
             free_files: meta_stat.files.free,
             total_dirs: meta_stat.dirs.total,
@@ -848,6 +1275,7 @@ mod test {
                 dir_buckets: rng.gen_range(10..100),
                 max_file: rng.gen_range(10..100),
                 file_buckets: rng.gen_range(10..100),
+                mount_path: [[0; 0x10]; 0x10],
             };
 
             ExtData::format(nand.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
@@ -862,4 +1290,632 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn commit_file() {
+        let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            mount_path: [[0; 0x10]; 0x10],
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
+
+        let name = [b'a', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let data = [0x42; 1000];
+        {
+            let ext = ExtData::new(nand.clone(), &[], 0, [0; 16], false, true).unwrap();
+            let file = ext.open_root().unwrap().new_sub_file(name, 1000).unwrap();
+            file.write(0, &data).unwrap();
+            ext.commit_file(file.get_ino()).unwrap();
+
+            assert!(ext.commit_file(100).is_err());
+        }
+
+        let ext = ExtData::new(nand.clone(), &[], 0, [0; 16], false, true).unwrap();
+        let file = ext.open_root().unwrap().open_sub_file(name).unwrap();
+        let mut buf = [0; 1000];
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf[..], data[..]);
+    }
+
+    #[test]
+    fn dump_headers() {
+        let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let mut param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            mount_path: [[0; 0x10]; 0x10],
+        };
+        param.mount_path[0][0] = b'a';
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], Some(100), &param).unwrap();
+        let ext = ExtData::new(nand, &[], 0, [0; 16], true, false).unwrap();
+        let dumps = ext.dump_headers().unwrap();
+
+        let names: Vec<&str> = dumps.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "Meta DiffHeader",
+                "Meta partition DifiHeader",
+                "Meta partition IvfcDescriptor",
+                "Meta partition DpfsDescriptor",
+                "Meta partition DifiPartitionParam (derived)",
+                "ExtHeader",
+                "FsInfo",
+                "Quota DiffHeader",
+                "Quota partition DifiHeader",
+                "Quota partition IvfcDescriptor",
+                "Quota partition DpfsDescriptor",
+                "Quota partition DifiPartitionParam (derived)",
+                "Quota",
+            ]
+        );
+        let field = |dump: usize, name: &str| {
+            dumps[dump]
+                .fields
+                .iter()
+                .find(|(n, _)| n == name)
+                .unwrap()
+                .1
+                .clone()
+        };
+        assert_eq!(field(0, "magic"), FieldValue::Bytes(b"DIFF".to_vec()));
+        assert_eq!(field(5, "magic"), FieldValue::Bytes(b"VSXE".to_vec()));
+        let mut mount_path = vec![0; 0x100];
+        mount_path[0] = b'a';
+        assert_eq!(field(5, "mount_path"), FieldValue::Bytes(mount_path));
+        assert_eq!(field(6, "block_len"), FieldValue::Int(4096));
+        assert_eq!(field(12, "magic"), FieldValue::Bytes(b"QUOT".to_vec()));
+        assert_eq!(field(12, "max_block"), FieldValue::Int(100));
+    }
+
+    #[test]
+    fn icon() {
+        let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            mount_path: [[0; 0x10]; 0x10],
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
+        {
+            let ext = ExtData::new(nand.clone(), &[], 0, [0; 16], false, true).unwrap();
+            assert!(matches!(ext.read_icon(), Err(Error::NotFound)));
+            assert!(matches!(ext.write_icon(&[]), Err(Error::InvalidValue)));
+            ext.write_icon(&[0x11; 0x36C0]).unwrap();
+            assert_eq!(ext.read_icon().unwrap(), vec![0x11; 0x36C0]);
+            ext.write_icon(&[0x22; 0x100]).unwrap();
+        }
+
+        let ext = ExtData::new(nand, &[], 0, [0; 16], false, true).unwrap();
+        assert_eq!(ext.read_icon().unwrap(), vec![0x22; 0x100]);
+        let root = ext.open_root().unwrap();
+        assert_eq!(root.list_sub_file().unwrap().len(), 1);
+        let file = root
+            .open_sub_file(*b"icon\0\0\0\0\0\0\0\0\0\0\0\0")
+            .unwrap();
+        assert_eq!(file.len(), 0x100);
+    }
+
+    #[test]
+    fn physical_len() {
+        let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            mount_path: [[0; 0x10]; 0x10],
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
+        let ext = ExtData::new(nand, &[], 0, [0; 16], false, true).unwrap();
+        let root = ext.open_root().unwrap();
+
+        let file = root
+            .new_sub_file([b'a', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 10000)
+            .unwrap();
+        assert_eq!(file.len(), 10000);
+        assert_eq!(
+            file.physical_len(),
+            Diff::calculate_size(&DifiPartitionParam {
+                dpfs_level2_block_len: 128,
+                dpfs_level3_block_len: 4096,
+                ivfc_level1_block_len: 512,
+                ivfc_level2_block_len: 512,
+                ivfc_level3_block_len: 4096,
+                ivfc_level4_block_len: 4096,
+                data_len: 10000,
+                external_ivfc_level4: true,
+            })
+        );
+
+        let empty = root
+            .new_sub_file([b'b', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 0)
+            .unwrap();
+        assert_eq!(empty.physical_len(), 0);
+    }
+
+    #[test]
+    fn open_twice() {
+        let path = crate::test_util::TempPath::new("libsave3ds_ext_open");
+        let nand = Rc::new(crate::nand::Nand::new(path.as_str()).unwrap());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            mount_path: [[0; 0x10]; 0x10],
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
+        let name = [b'a', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let empty_name = [b'b', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        {
+            let ext = ExtData::new(nand.clone(), &[], 0, [0; 16], false, true).unwrap();
+            let root = ext.open_root().unwrap();
+            let file = root.new_sub_file(name, 1000).unwrap();
+            file.write(0, &[0x42; 1000]).unwrap();
+            file.commit().unwrap();
+
+            // Other opens share the data of the first one instead of opening the host file again
+            let other = root.open_sub_file(name).unwrap();
+            let mut buf = [0; 1000];
+            other.read(0, &mut buf).unwrap();
+            assert_eq!(buf, [0x42; 1000]);
+            let by_ino = ext.open_file(file.get_ino()).unwrap();
+            assert_eq!(by_ino.len(), 1000);
+
+            // Changes still need the only open handle
+            assert!(matches!(file.write(0, &[0x43]), Err(Error::Busy)));
+            assert!(matches!(by_ino.delete(), Err(Error::Busy)));
+            std::mem::drop(other);
+            file.write(0, &[0x43; 1000]).unwrap();
+            let other = root.open_sub_file(name).unwrap();
+            other.read(0, &mut buf).unwrap();
+            assert_eq!(buf, [0x43; 1000]);
+            std::mem::drop(other);
+            file.commit().unwrap();
+            std::mem::drop(file);
+
+            // Zero-size files have no container to lock
+            let empty = root.new_sub_file(empty_name, 0).unwrap();
+            assert_eq!(root.open_sub_file(empty_name).unwrap().len(), 0);
+            std::mem::drop(empty);
+            ext.commit().unwrap();
+        }
+
+        let ext = ExtData::new(nand.clone(), &[], 0, [0; 16], false, false).unwrap();
+        let root = ext.open_root().unwrap();
+        let file = root.open_sub_file(name).unwrap();
+        let other = root.open_sub_file(name).unwrap();
+        assert_eq!(file.len(), 1000);
+        assert_eq!(other.len(), 1000);
+    }
+
+    #[test]
+    fn quota_fuzz() {
+        use rand::prelude::*;
+        let mut rng = rand::thread_rng();
+
+        let read_quota = |ext: &ExtData| -> Quota {
+            read_struct(
+                ext.center.quota_file.as_ref().unwrap().partition().as_ref(),
+                0,
+            )
+            .unwrap()
+        };
+
+        for _ in 0..5 {
+            let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+            let param = ExtDataFormatParam {
+                max_dir: 10,
+                dir_buckets: 10,
+                max_file: 10,
+                file_buckets: 10,
+                mount_path: [[0; 0x10]; 0x10],
+            };
+            // the metadata of this format takes 10 blocks, plus 2 blocks for the quota file
+            let capacity = rng.gen_range(12..60);
+            ExtData::format(nand.as_ref(), &[], 0, [0; 16], Some(capacity), &param).unwrap();
+            let ext = ExtData::new(nand.clone(), &[], 0, [0; 16], true, true).unwrap();
+            let initial_free = read_quota(&ext).free_block;
+            let root = ext.open_root().unwrap();
+
+            for _ in 0..100 {
+                let name = gen_name();
+                match rng.gen_range(0..3) {
+                    0 => match root.new_sub_file(name, gen_len()) {
+                        Ok(_) | Err(Error::AlreadyExist) => {}
+                        Err(Error::NoSpace) => {
+                            assert!(matches!(root.open_sub_file(name), Err(Error::NotFound)))
+                        }
+                        Err(e) => panic!("{:?}", e),
+                    },
+                    1 => {
+                        if let Ok(file) = root.open_sub_file(name) {
+                            file.delete().unwrap();
+                        }
+                    }
+                    _ => {
+                        if let Ok(mut file) = root.open_sub_file(name) {
+                            let len = file.len();
+                            match file.resize(gen_len()) {
+                                Ok(()) => {}
+                                Err(Error::NoSpace) => assert_eq!(file.len(), len),
+                                Err(e) => panic!("{:?}", e),
+                            }
+                        }
+                    }
+                }
+                ext.commit().unwrap();
+
+                let used: u32 = root
+                    .list_sub_file()
+                    .unwrap()
+                    .into_iter()
+                    .map(|(name, _)| {
+                        let file = root.open_sub_file(name).unwrap();
+                        divide_up(file.physical_len(), 0x1000) as u32
+                    })
+                    .sum();
+                let quota = read_quota(&ext);
+                assert_eq!(quota.free_block + used, initial_free);
+                assert!(quota.potential_free_block >= quota.free_block);
+                assert!(quota.potential_free_block <= capacity - 2);
+            }
+        }
+    }
+
+    #[test]
+    fn missing_quota() {
+        let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            mount_path: [[0; 0x10]; 0x10],
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], Some(1000), &param).unwrap();
+        {
+            let ext = ExtData::new(nand.clone(), &[], 0, [0; 16], true, true).unwrap();
+            let file = ext
+                .open_root()
+                .unwrap()
+                .new_sub_file([b'a', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 3)
+                .unwrap();
+            file.write(0, &[1, 2, 3]).unwrap();
+            ext.commit_file(file.get_ino()).unwrap();
+        }
+
+        nand.remove(&["00000000", "00000000", "Quota.dat"]).unwrap();
+
+        let ext = ExtData::new(nand.clone(), &[], 0, [0; 16], true, true).unwrap();
+        let root = ext.open_root().unwrap();
+        let mut file = root
+            .open_sub_file([b'a', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+        let mut buf = [0; 3];
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+
+        assert!(matches!(
+            root.new_sub_file([b'b', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 3),
+            Err(Error::Unsupported)
+        ));
+        assert!(matches!(file.resize(10), Err(Error::Unsupported)));
+        assert!(matches!(file.delete(), Err(Error::Unsupported)));
+        ext.commit().unwrap();
+        drop(root);
+        drop(ext);
+
+        let ext = ExtData::new(nand, &[], 0, [0; 16], true, false).unwrap();
+        assert_eq!(ext.open_root().unwrap().list_sub_file().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn dir_capacity() {
+        let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 200,
+            file_buckets: 50,
+            mount_path: [[0; 0x10]; 0x10],
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], Some(100_000), &param).unwrap();
+        let name = |i: usize| {
+            let mut name = [0; 16];
+            name[0..3].copy_from_slice(format!("{:03}", i).as_bytes());
+            name
+        };
+        let mut inos = vec![];
+        {
+            let ext = ExtData::new(nand.clone(), &[], 0, [0; 16], true, true).unwrap();
+            let root = ext.open_root().unwrap();
+            for i in 0..130 {
+                let file = root.new_sub_file(name(i), 1).unwrap();
+                file.write(0, &[i as u8]).unwrap();
+                ext.commit_file(file.get_ino()).unwrap();
+                inos.push(file.get_ino());
+            }
+            ext.commit().unwrap();
+        }
+
+        // The file with index 126 (inode 125) is the first one in the second directory
+        let i = inos.iter().position(|&ino| ino == 125).unwrap();
+        assert!(nand
+            .open(&["00000000", "00000000", "00000001", "00000000"], false)
+            .is_ok());
+        assert!(nand
+            .open(&["00000000", "00000000", "00000000", "0000007d"], false)
+            .is_ok());
+        nand.remove(&["00000000", "00000000", "00000001", "00000000"])
+            .unwrap();
+        let ext = ExtData::new(nand.clone(), &[], 0, [0; 16], true, false).unwrap();
+        let root = ext.open_root().unwrap();
+        for j in 0..inos.len() {
+            let mut buf = [0];
+            let result = root
+                .open_sub_file(name(j))
+                .and_then(|file| file.read(0, &mut buf));
+            if j == i {
+                assert!(result.is_err());
+            } else {
+                result.unwrap();
+                assert_eq!(buf, [j as u8]);
+            }
+        }
+        drop(root);
+
+        let quota_file = ext.center.quota_file.as_ref().unwrap();
+        let mut quota: Quota = read_struct(quota_file.partition().as_ref(), 0).unwrap();
+        quota.dir_capacity = 125;
+        write_struct(quota_file.partition().as_ref(), 0, quota).unwrap();
+        quota_file.commit().unwrap();
+        drop(ext);
+        assert!(matches!(
+            ExtData::new(nand, &[], 0, [0; 16], true, false),
+            Err(Error::Unsupported)
+        ));
+    }
+
+    #[test]
+    fn reset_action() {
+        let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            mount_path: [[0; 0x10]; 0x10],
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
+        let open = |write| ExtData::new(nand.clone(), &[], 0, [0; 16], false, write).unwrap();
+        let ino = {
+            let ext = open(true);
+            assert_eq!(ext.action().unwrap(), 0);
+            let file = ext
+                .open_root()
+                .unwrap()
+                .new_sub_file(*b"a\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", 100)
+                .unwrap();
+            file.write(0, &[0x42; 100]).unwrap();
+            ext.commit_file(file.get_ino()).unwrap();
+
+            let partition = ext.center.meta_file.partition();
+            let mut header: ExtHeader = read_struct(partition.as_ref(), 0).unwrap();
+            header.action = 3;
+            write_struct(partition.as_ref(), 0, header).unwrap();
+            ext.commit().unwrap();
+            file.get_ino()
+        };
+
+        let ext = open(false);
+        assert_eq!(ext.action().unwrap(), 3);
+        let dump = ext.dump_headers().unwrap();
+        let header = dump.iter().find(|dump| dump.name == "ExtHeader").unwrap();
+        assert!(header
+            .fields
+            .iter()
+            .any(|(name, value)| name == "action" && *value == FieldValue::Int(3)));
+        assert!(matches!(ext.reset_action(), Err(Error::Unsupported)));
+        drop(ext);
+
+        // An inconsistent extdata keeps the action
+        {
+            let ext = open(true);
+            let file = ext.open_file(ino).unwrap();
+            let mut info = file.meta.get_info().unwrap();
+            info.unique_id ^= 1;
+            file.meta.set_info(info).unwrap();
+            ext.commit().unwrap();
+        }
+        let ext = open(true);
+        assert!(matches!(ext.reset_action(), Err(Error::UniqueIdMismatch)));
+        assert_eq!(ext.action().unwrap(), 3);
+        ext.repair_unique_id(ino, UniqueIdSource::Diff).unwrap();
+        ext.reset_action().unwrap();
+        assert_eq!(ext.action().unwrap(), 0);
+        ext.reset_action().unwrap();
+        drop(ext);
+
+        let ext = open(false);
+        assert_eq!(ext.action().unwrap(), 0);
+        let mut buf = [0; 100];
+        ext.open_file(ino).unwrap().read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0x42; 100]);
+    }
+
+    #[test]
+    fn repair_unique_id() {
+        let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            mount_path: [[0; 0x10]; 0x10],
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
+        let a = [b'a', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let b = [b'b', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let (ino, empty_ino, unique_id) = {
+            let ext = ExtData::new(nand.clone(), &[], 0, [0; 16], false, true).unwrap();
+            let root = ext.open_root().unwrap();
+            let file = root.new_sub_file(a, 100).unwrap();
+            file.write(0, &[0x42; 100]).unwrap();
+            ext.commit_file(file.get_ino()).unwrap();
+            let empty = root.new_sub_file(b, 0).unwrap();
+            ext.commit().unwrap();
+            let unique_id = file.meta.get_info().unwrap().unique_id;
+            (file.get_ino(), empty.get_ino(), unique_id)
+        };
+        let open = |write| ExtData::new(nand.clone(), &[], 0, [0; 16], false, write).unwrap();
+        let corrupt = || {
+            let ext = open(true);
+            let file = ext.open_file(ino).unwrap();
+            let mut info = file.meta.get_info().unwrap();
+            info.unique_id ^= 1;
+            file.meta.set_info(info).unwrap();
+            ext.commit().unwrap();
+        };
+        let check_content = |ext: &ExtData| {
+            let mut buf = [0; 100];
+            ext.open_file(ino).unwrap().read(0, &mut buf).unwrap();
+            assert_eq!(buf, [0x42; 100]);
+        };
+
+        let ext = open(false);
+        assert_eq!(ext.file_unique_id(empty_ino).unwrap().diff, None);
+        assert_eq!(
+            ext.file_unique_id(ino).unwrap(),
+            FileUniqueId {
+                meta: unique_id,
+                diff: Some(unique_id)
+            }
+        );
+        drop(ext);
+
+        corrupt();
+        let ext = open(false);
+        assert!(matches!(ext.open_file(ino), Err(Error::UniqueIdMismatch)));
+        assert_eq!(
+            ext.file_unique_id(ino).unwrap(),
+            FileUniqueId {
+                meta: unique_id ^ 1,
+                diff: Some(unique_id)
+            }
+        );
+        assert!(matches!(
+            ext.repair_unique_id(ino, UniqueIdSource::Diff),
+            Err(Error::Unsupported)
+        ));
+        drop(ext);
+
+        open(true)
+            .repair_unique_id(ino, UniqueIdSource::Diff)
+            .unwrap();
+        let ext = open(false);
+        assert_eq!(ext.file_unique_id(ino).unwrap().meta, unique_id);
+        check_content(&ext);
+        drop(ext);
+
+        corrupt();
+        let ext = open(true);
+        ext.repair_unique_id(ino, UniqueIdSource::Meta).unwrap();
+        // Repairing again is a no-op
+        ext.repair_unique_id(ino, UniqueIdSource::Diff).unwrap();
+        drop(ext);
+        let ext = open(false);
+        assert_eq!(
+            ext.file_unique_id(ino).unwrap(),
+            FileUniqueId {
+                meta: unique_id ^ 1,
+                diff: Some(unique_id ^ 1)
+            }
+        );
+        check_content(&ext);
+    }
+
+    #[test]
+    fn mount_path() {
+        let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let mut param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            mount_path: [[0; 0x10]; 0x10],
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
+        let ext = ExtData::new(nand.clone(), &[], 0, [0; 16], false, false).unwrap();
+        assert!(ext.mount_path().unwrap().is_empty());
+        drop(ext);
+
+        param.mount_path[0] = *b"dir\0\0\0\0\0\0\0\0\0\0\0\0\0";
+        param.mount_path[1] = *b"file\0\0\0\0\0\0\0\0\0\0\0\0";
+        param.mount_path[3] = *b"ignored\0\0\0\0\0\0\0\0\0";
+        ExtData::format(nand.as_ref(), &[], 1, [0; 16], None, &param).unwrap();
+        let ext = ExtData::new(nand, &[], 1, [0; 16], false, false).unwrap();
+        assert_eq!(
+            ext.mount_path().unwrap(),
+            vec![param.mount_path[0], param.mount_path[1]]
+        );
+    }
+
+    #[test]
+    fn stat() {
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            mount_path: [[0; 0x10]; 0x10],
+        };
+        let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], Some(100), &param).unwrap();
+        let ext = ExtData::new(nand, &[], 0, [0; 16], true, true).unwrap();
+        assert!(ext.has_quota());
+        let initial = ext.stat().unwrap();
+        assert_eq!(initial.block_len, 0x1000);
+        assert_eq!(initial.total_blocks, 100);
+        assert!(initial.free_blocks < 100);
+        assert_eq!(initial.total_files, 10);
+        assert_eq!(initial.total_dirs, 11);
+
+        let root = ext.open_root().unwrap();
+        let lens = [1, 0x1000, 10000, 0];
+        for (i, &len) in lens.iter().enumerate() {
+            let mut name = [0; 16];
+            name[0] = b'a' + i as u8;
+            root.new_sub_file(name, len).unwrap();
+        }
+        root.new_sub_dir(*b"z\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0")
+            .unwrap();
+        let used: usize = lens.iter().map(|&len| ExtData::quota_blocks(len)).sum();
+        assert!(used > 3);
+        let stat = ext.stat().unwrap();
+        assert_eq!(stat.free_blocks, initial.free_blocks - used);
+        assert_eq!(stat.total_blocks, 100);
+        assert_eq!(stat.free_files, initial.free_files - 4);
+        assert_eq!(stat.free_dirs, initial.free_dirs - 1);
+
+        // Without a quota, only the table counts are meaningful
+        let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
+        let ext = ExtData::new(nand, &[], 0, [0; 16], false, true).unwrap();
+        assert!(!ext.has_quota());
+        let stat = ext.stat().unwrap();
+        assert_eq!(stat.block_len, 0x1000);
+        assert!(stat.total_blocks > 0);
+        assert_eq!(stat.free_blocks, 0);
+        assert_eq!(stat.total_files, 10);
+    }
 }
@@ -305,7 +305,7 @@ impl Fat {
     ) -> Result<Rc<Fat>, Error> {
         let table_len = table.len();
         let data_len = data.len();
-        if table_len % 8 != 0 {
+        if !table_len.is_multiple_of(8) {
             return make_error(Error::SizeMismatch);
         }
         let block_count = table_len / 8 - 1;
@@ -331,6 +331,11 @@ impl Fat {
     pub fn free_blocks(&self) -> usize {
         self.free_blocks.get()
     }
+
+    /// Feeds the raw allocation table to `hasher`.
+    pub fn hash_raw(&self, hasher: &mut sha2::Sha256) -> Result<(), Error> {
+        crate::fs_meta::hash_raw(self.table.as_ref(), hasher)
+    }
 }
 
 /// A handle to a file in `Fat` that implements resizing, releasing, reading and writing.
@@ -529,6 +534,8 @@ impl RandomAccessFile for FatFile {
         }
         Ok(())
     }
+    /// Returns the size of all allocated blocks, which is the file size recorded in the
+    /// file system rounded up to whole blocks.
     fn len(&self) -> usize {
         self.block_list.len() * self.fat.block_len
     }
@@ -588,6 +595,7 @@ mod test {
                                 .take(file_block_count * block_len)
                                 .collect();
                             fat_file.write(0, &image).unwrap();
+                            crate::random_access_file::check_boundary(&fat_file);
                             files.push(File {
                                 image,
                                 fat_file,
@@ -664,9 +672,11 @@ mod test {
                         let delta = file_block_count - new_block_count;
                         file.fat_file.resize(new_block_count).unwrap();
                         file.image.truncate(new_block_count * block_len);
+                        crate::random_access_file::check_boundary(&file.fat_file);
                         free_block_count += delta;
                     }
                 }
+                assert_eq!(fat.free_blocks(), free_block_count);
             }
         }
     }
@@ -0,0 +1,153 @@
+use crate::error::*;
+use crate::memory_file::MemoryFile;
+use crate::random_access_file::*;
+use std::cell::Cell;
+use std::ops::Range;
+
+/// Implements `RandomAccessFile` over a `MemoryFile`, failing accesses on demand with
+/// `Error::IO`. For testing how the upper layers handle host IO failures.
+///
+/// No fault is injected until one of the `fail_*` methods is called.
+pub struct FaultyFile {
+    data: MemoryFile,
+    fail_reads: Cell<Option<(usize, usize)>>,
+    fail_writes: Cell<Option<(usize, usize)>>,
+    writes_left: Cell<Option<usize>>,
+    fail_commit: Cell<bool>,
+    writes: Cell<usize>,
+}
+
+fn overlaps(range: Option<(usize, usize)>, pos: usize, len: usize) -> bool {
+    match range {
+        Some((begin, end)) => pos < end && pos + len > begin,
+        None => false,
+    }
+}
+
+fn injected_fault() -> Result<(), Error> {
+    make_error(Error::IO(std::io::Error::other("injected fault")))
+}
+
+impl FaultyFile {
+    pub fn new(data: Vec<u8>) -> FaultyFile {
+        FaultyFile {
+            data: MemoryFile::new(data),
+            fail_reads: Cell::new(None),
+            fail_writes: Cell::new(None),
+            writes_left: Cell::new(None),
+            fail_commit: Cell::new(false),
+            writes: Cell::new(0),
+        }
+    }
+
+    /// Fails reads that touch any byte in `range`.
+    pub fn fail_reads(&self, range: Range<usize>) {
+        self.fail_reads.set(Some((range.start, range.end)));
+    }
+
+    /// Fails writes that touch any byte in `range`. Failed writes don't change the data.
+    pub fn fail_writes(&self, range: Range<usize>) {
+        self.fail_writes.set(Some((range.start, range.end)));
+    }
+
+    /// Lets the next `count` writes succeed, and fails all writes after them.
+    pub fn fail_writes_after(&self, count: usize) {
+        self.writes_left.set(Some(count));
+    }
+
+    /// Fails all commits.
+    pub fn fail_commit(&self) {
+        self.fail_commit.set(true);
+    }
+
+    /// Stops injecting faults.
+    pub fn heal(&self) {
+        self.fail_reads.set(None);
+        self.fail_writes.set(None);
+        self.writes_left.set(None);
+        self.fail_commit.set(false);
+    }
+
+    /// Returns the number of successful writes so far.
+    pub fn writes(&self) -> usize {
+        self.writes.get()
+    }
+
+    /// Returns a copy of the whole data.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut data = vec![0; self.data.len()];
+        self.data.read(0, &mut data).unwrap();
+        data
+    }
+}
+
+impl RandomAccessFile for FaultyFile {
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        if overlaps(self.fail_reads.get(), pos, buf.len()) {
+            return injected_fault();
+        }
+        self.data.read(pos, buf)
+    }
+    fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        if overlaps(self.fail_writes.get(), pos, buf.len()) {
+            return injected_fault();
+        }
+        match self.writes_left.get() {
+            Some(0) => return injected_fault(),
+            Some(n) => self.writes_left.set(Some(n - 1)),
+            None => (),
+        }
+        self.data.write(pos, buf)?;
+        self.writes.set(self.writes.get() + 1);
+        Ok(())
+    }
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+    fn commit(&self) -> Result<(), Error> {
+        if self.fail_commit.get() {
+            return injected_fault();
+        }
+        self.data.commit()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::faulty_file::*;
+
+    #[test]
+    fn inject() {
+        let file = FaultyFile::new(vec![0; 0x20]);
+        check_boundary(&file);
+
+        file.fail_writes(0x10..0x18);
+        assert!(file.write(0x8, &[1; 8]).is_ok());
+        assert!(file.write(0xC, &[1; 8]).is_err());
+        assert!(file.write(0x17, &[1]).is_err());
+        assert!(file.write(0x18, &[1]).is_ok());
+        let mut buf = [0; 0x20];
+        file.read(0, &mut buf).unwrap();
+
+        file.fail_reads(0x1F..0x20);
+        assert!(file.read(0, &mut buf).is_err());
+        assert!(file.read(0, &mut buf[0..0x1F]).is_ok());
+
+        file.heal();
+        file.fail_writes_after(2);
+        let writes = file.writes();
+        let old = file.snapshot()[2];
+        assert!(file.write(0, &[2]).is_ok());
+        assert!(file.write(1, &[2]).is_ok());
+        assert!(file.write(2, &[2]).is_err());
+        assert_eq!(file.writes(), writes + 2);
+        assert_eq!(&file.snapshot()[0..3], &[2, 2, old]);
+
+        assert!(file.commit().is_ok());
+        file.fail_commit();
+        assert!(file.commit().is_err());
+        file.heal();
+        assert!(file.commit().is_ok());
+        assert!(file.write(2, &[2]).is_ok());
+    }
+}
@@ -1,4 +1,6 @@
 use crate::error::*;
+use sha2::*;
+use std::collections::HashMap;
 
 /// The interface for a file opened from [`FileSystem`](trait.FileSystem.html).
 pub trait FileSystemFile {
@@ -25,12 +27,36 @@ pub trait FileSystemFile {
 
     /// Reads bytes at position `pos` into `buf`. The lenth is determined by `buf.len()`.
     /// If the read range contains uninitialized data, Error::HashMismatch is returned,
-    /// and the unintialized region will be filled with `0xDD`.
+    /// and the unintialized region will be filled with `0xDD`, or the byte set with
+    /// `set_fill_byte` of the archive.
+    ///
+    /// For both read and write, if `pos + buf.len()` exceeds `len()`, `Error::OutOfBound`
+    /// is returned. Otherwise, an operation with an empty `buf` always succeeds.
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error>;
 
     /// Writes bytes to position `pos` from `buf`. The lenth is determined by `buf.len()`.
     fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error>;
 
+    /// Reads the whole file. Uninitialized data is filled in the same way as `read` does,
+    /// but `Error::HashMismatch` is not returned for it.
+    fn read_all(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0; self.len()];
+        match self.read(0, &mut buf) {
+            Ok(()) | Err(Error::HashMismatch) => Ok(buf),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes bytes to position `pos` from `buf`, first growing the file if the range goes
+    /// past the end. The content of the gap between the old end and `pos` is unspecified.
+    fn write_all_resize(&mut self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        let end = pos.checked_add(buf.len()).ok_or(Error::OutOfBound)?;
+        if !buf.is_empty() && end > self.len() {
+            self.resize(end)?;
+        }
+        self.write(pos, buf)
+    }
+
     /// Returns the length of this file.
     fn len(&self) -> usize;
 
@@ -45,6 +71,10 @@ pub trait FileSystemFile {
     fn commit(&self) -> Result<(), Error>;
 }
 
+/// An iterator over directory entries, yielding tuples of names and inodes.
+/// See [`FileSystemDir::iter_sub_dir`](trait.FileSystemDir.html#tymethod.iter_sub_dir).
+pub type EntryIter<NameType> = Box<dyn Iterator<Item = Result<(NameType, u32), Error>>>;
+
 /// The interface for a directory opened from [`FileSystem`](trait.FileSystem.html).
 pub trait FileSystemDir {
     /// The type of names the parent archive uses to name files and directories.
@@ -71,11 +101,59 @@ pub trait FileSystemDir {
     /// Opens the sub file with the specified name.
     fn open_sub_file(&self, name: Self::NameType) -> Result<Self::FileType, Error>;
 
+    /// Iterates over all sub directories, yielding tuples of names and inodes.
+    ///
+    /// Entries are read from the archive as the iterator advances, so it is cheaper than
+    /// `list_sub_dir` for finding one entry in a large directory. The iterator keeps the archive
+    /// alive, but shouldn't be used across modifications to this directory.
+    fn iter_sub_dir(&self) -> Result<EntryIter<Self::NameType>, Error>;
+
+    /// Iterates over all sub files, yielding tuples of names and inodes.
+    /// See [`iter_sub_dir`](#tymethod.iter_sub_dir).
+    fn iter_sub_file(&self) -> Result<EntryIter<Self::NameType>, Error>;
+
     /// Lists all sub directories. The returned `Vec` contains tuples of names and inodes.
-    fn list_sub_dir(&self) -> Result<Vec<(Self::NameType, u32)>, Error>;
+    fn list_sub_dir(&self) -> Result<Vec<(Self::NameType, u32)>, Error> {
+        self.iter_sub_dir()?.collect()
+    }
 
     /// Lists all sub files The returned `Vec` contains tuples of names and inodes.
-    fn list_sub_file(&self) -> Result<Vec<(Self::NameType, u32)>, Error>;
+    fn list_sub_file(&self) -> Result<Vec<(Self::NameType, u32)>, Error> {
+        self.iter_sub_file()?.collect()
+    }
+
+    /// Returns whether the specified name is a sub directory or a sub file, or `None` if it is
+    /// neither.
+    ///
+    /// The default implementation tries `open_sub_dir` and then `open_sub_file`. Only
+    /// `Error::NotFound` is taken as absence: other errors, such as IO errors or a file that
+    /// fails to open, are returned.
+    fn sub_metadata(&self, name: Self::NameType) -> Result<Option<InodeKind>, Error>
+    where
+        Self: Sized,
+        Self::NameType: Clone,
+    {
+        match self.open_sub_dir(name.clone()) {
+            Ok(_) => return Ok(Some(InodeKind::Dir)),
+            Err(Error::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+        match self.open_sub_file(name) {
+            Ok(_) => Ok(Some(InodeKind::File)),
+            Err(Error::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns whether a sub directory or a sub file has the specified name.
+    /// See [`sub_metadata`](#method.sub_metadata).
+    fn sub_exists(&self, name: Self::NameType) -> Result<bool, Error>
+    where
+        Self: Sized,
+        Self::NameType: Clone,
+    {
+        Ok(self.sub_metadata(name)?.is_some())
+    }
 
     /// Creates a new sub directory with the specified name, and opens it.
     fn new_sub_dir(&self, name: Self::NameType) -> Result<Self, Error>
@@ -85,12 +163,41 @@ pub trait FileSystemDir {
     /// Creates a new sub file with the specified name and initial length, and opens it.
     fn new_sub_file(&self, name: Self::NameType, len: usize) -> Result<Self::FileType, Error>;
 
+    /// Creates multiple sub files with the specified names and initial lengths, and opens them.
+    /// Either all files are created, or none of them.
+    ///
+    /// The default implementation creates the files one by one, and deletes the created ones if
+    /// any fails.
+    fn new_sub_files(
+        &self,
+        entries: &[(Self::NameType, usize)],
+    ) -> Result<Vec<Self::FileType>, Error>
+    where
+        Self::FileType: FileSystemFile,
+        Self::NameType: Clone,
+    {
+        let mut files = vec![];
+        for (name, len) in entries {
+            match self.new_sub_file(name.clone(), *len) {
+                Ok(file) => files.push(file),
+                Err(e) => {
+                    for file in files {
+                        file.delete()?;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(files)
+    }
+
     /// Deletes this directory. The directory must contains no sub files or sub directories.
     fn delete(self) -> Result<(), Error>;
 }
 
 /// Describes the capacity of a [`FileSystem`](trait.FileSystem.html).
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Stat {
     /// Size in bytes of a block.
     pub block_len: usize,
@@ -144,6 +251,757 @@ pub trait FileSystem {
 
     /// Returns the capacity information of the archive.
     fn stat(&self) -> Result<Stat, Error>;
+
+    /// Returns the path of every directory and file in the archive, keyed by inode.
+    /// The tree is walked once with [`walk`](fn.walk.html). Nothing is written to the archive.
+    fn inode_map(&self) -> Result<InodeMap<Self::NameType>, Error>
+    where
+        Self: Sized,
+        Self::NameType: Clone,
+    {
+        let mut map = InodeMap {
+            dirs: HashMap::new(),
+            files: HashMap::new(),
+        };
+        map.dirs.insert(1, vec![]);
+        walk(self, |path, kind, ino| {
+            match kind {
+                InodeKind::Dir => map.dirs.insert(ino, path.to_vec()),
+                InodeKind::File => map.files.insert(ino, path.to_vec()),
+            };
+            Ok(())
+        })?;
+        Ok(map)
+    }
+}
+
+/// Whether an inode belongs to a directory or a file. Directories and files are numbered
+/// separately, so the same inode can refer to one of each.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum InodeKind {
+    /// A directory inode, as passed to `FileSystem::open_dir`.
+    Dir,
+
+    /// A file inode, as passed to `FileSystem::open_file`.
+    File,
+}
+
+/// Paths of all directories and files of an archive, keyed by inode.
+/// See [`FileSystem::inode_map`](trait.FileSystem.html#method.inode_map).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InodeMap<NameType> {
+    /// Paths of directories, as names from the root. The root directory has inode 1 and an
+    /// empty path.
+    pub dirs: HashMap<u32, Vec<NameType>>,
+
+    /// Paths of files, as names from the root.
+    pub files: HashMap<u32, Vec<NameType>>,
+}
+
+impl<NameType> InodeMap<NameType> {
+    /// Returns the path of the directory or file with the specified inode.
+    pub fn get(&self, kind: InodeKind, ino: u32) -> Option<&[NameType]> {
+        match kind {
+            InodeKind::Dir => self.dirs.get(&ino),
+            InodeKind::File => self.files.get(&ino),
+        }
+        .map(|path| path.as_slice())
+    }
+}
+
+/// Walks the whole archive once, calling `visit` with the path, kind and inode of every
+/// directory and file. A directory is visited before its content. The root directory is not
+/// visited. Stops at the first error, from the archive or from `visit`.
+pub fn walk<T: FileSystem>(
+    file_system: &T,
+    mut visit: impl FnMut(&[T::NameType], InodeKind, u32) -> Result<(), Error>,
+) -> Result<(), Error>
+where
+    T::NameType: Clone,
+{
+    let mut pending = vec![(vec![], file_system.open_root()?)];
+    while let Some((path, dir)) = pending.pop() {
+        for (name, ino) in dir.list_sub_dir()? {
+            let mut sub_path = path.clone();
+            sub_path.push(name);
+            visit(&sub_path, InodeKind::Dir, ino)?;
+            pending.push((sub_path, file_system.open_dir(ino)?));
+        }
+        for (name, ino) in dir.list_sub_file()? {
+            let mut sub_path = path.clone();
+            sub_path.push(name);
+            visit(&sub_path, InodeKind::File, ino)?;
+        }
+    }
+    Ok(())
+}
+
+/// A file or directory name of any archive type, as used by
+/// [`DynFileSystem`](trait.DynFileSystem.html).
+#[derive(PartialEq, Eq, Clone, Hash, Debug)]
+pub enum ArchiveName {
+    /// A 16-byte name, used by save data and extdata.
+    Bytes([u8; 16]),
+
+    /// A 64-bit ID, used by title databases.
+    Id(u64),
+}
+
+impl std::fmt::Display for ArchiveName {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ArchiveName::Bytes(name) => write!(f, "{}", <[u8; 16]>::name_3ds_to_str(name)),
+            ArchiveName::Id(name) => write!(f, "{}", u64::name_3ds_to_str(name)),
+        }
+    }
+}
+
+/// Name types that can be converted to and from [`ArchiveName`](enum.ArchiveName.html).
+pub trait ArchiveNameType: NameConvert + Sized {
+    /// Wraps the name in an `ArchiveName`.
+    fn to_archive_name(&self) -> ArchiveName;
+
+    /// Unwraps an `ArchiveName`. Returns `None` if it is of a different kind.
+    fn from_archive_name(name: &ArchiveName) -> Option<Self>;
+}
+
+impl ArchiveNameType for [u8; 16] {
+    fn to_archive_name(&self) -> ArchiveName {
+        ArchiveName::Bytes(*self)
+    }
+
+    fn from_archive_name(name: &ArchiveName) -> Option<[u8; 16]> {
+        match name {
+            ArchiveName::Bytes(name) => Some(*name),
+            ArchiveName::Id(_) => None,
+        }
+    }
+}
+
+impl ArchiveNameType for u64 {
+    fn to_archive_name(&self) -> ArchiveName {
+        ArchiveName::Id(*self)
+    }
+
+    fn from_archive_name(name: &ArchiveName) -> Option<u64> {
+        match name {
+            ArchiveName::Bytes(_) => None,
+            ArchiveName::Id(name) => Some(*name),
+        }
+    }
+}
+
+/// An object-safe subset of [`FileSystem`](trait.FileSystem.html), allowing archives of
+/// different types to be used behind a `Box<dyn DynFileSystem>`, for example in one `Vec`.
+///
+/// Files and directories are addressed by inode. Names are exposed either as strings using
+/// [`NameConvert`](trait.NameConvert.html), or losslessly as
+/// [`ArchiveName`](enum.ArchiveName.html). Passing an `ArchiveName` of the wrong kind for the
+/// archive results in `Error::Unsupported`. It is implemented for all `FileSystem` whose name
+/// type implements [`ArchiveNameType`](trait.ArchiveNameType.html).
+pub trait DynFileSystem {
+    /// Lists all sub directories of the directory with the specified inode.
+    /// The returned `Vec` contains tuples of names and inodes.
+    fn list_sub_dir(&self, ino: u32) -> Result<Vec<(String, u32)>, Error>;
+
+    /// Lists all sub files of the directory with the specified inode.
+    /// The returned `Vec` contains tuples of names and inodes.
+    fn list_sub_file(&self, ino: u32) -> Result<Vec<(String, u32)>, Error>;
+
+    /// Same as `list_sub_dir`, but without converting names to strings.
+    fn list_sub_dir_names(&self, ino: u32) -> Result<Vec<(ArchiveName, u32)>, Error>;
+
+    /// Same as `list_sub_file`, but without converting names to strings.
+    fn list_sub_file_names(&self, ino: u32) -> Result<Vec<(ArchiveName, u32)>, Error>;
+
+    /// Returns the inode of the sub directory `name` of the directory with the specified inode.
+    fn lookup_dir(&self, parent: u32, name: &ArchiveName) -> Result<u32, Error>;
+
+    /// Returns the inode of the sub file `name` of the directory with the specified inode.
+    fn lookup_file(&self, parent: u32, name: &ArchiveName) -> Result<u32, Error>;
+
+    /// Creates a sub directory in the directory with the specified inode, and returns its inode.
+    fn create_dir(&self, parent: u32, name: &ArchiveName) -> Result<u32, Error>;
+
+    /// Creates a sub file with the specified length in the directory with the specified inode,
+    /// and returns its inode.
+    fn create_file(&self, parent: u32, name: &ArchiveName, len: usize) -> Result<u32, Error>;
+
+    /// Deletes the directory with the specified inode, which must be empty.
+    fn remove_dir(&self, ino: u32) -> Result<(), Error>;
+
+    /// Deletes the file with the specified inode.
+    fn remove_file(&self, ino: u32) -> Result<(), Error>;
+
+    /// Returns the length of the file with the specified inode.
+    fn file_len(&self, ino: u32) -> Result<usize, Error>;
+
+    /// Resizes the file with the specified inode, and commits the file.
+    fn resize_file(&self, ino: u32, len: usize) -> Result<(), Error>;
+
+    /// Reads bytes from the file with the specified inode.
+    /// See [`FileSystemFile::read`](trait.FileSystemFile.html#tymethod.read).
+    fn read_file(&self, ino: u32, pos: usize, buf: &mut [u8]) -> Result<(), Error>;
+
+    /// Writes bytes to the file with the specified inode, and commits the file.
+    fn write_file(&self, ino: u32, pos: usize, buf: &[u8]) -> Result<(), Error>;
+
+    /// Same as `FileSystem::commit`.
+    fn commit_archive(&self) -> Result<(), Error>;
+
+    /// Same as `FileSystem::stat`.
+    fn stat_archive(&self) -> Result<Stat, Error>;
+}
+
+fn list_to_str<N: NameConvert>(list: Vec<(N, u32)>) -> Vec<(String, u32)> {
+    list.into_iter()
+        .map(|(name, ino)| (N::name_3ds_to_str(&name), ino))
+        .collect()
+}
+
+fn list_to_archive_name<N: ArchiveNameType>(list: Vec<(N, u32)>) -> Vec<(ArchiveName, u32)> {
+    list.into_iter()
+        .map(|(name, ino)| (name.to_archive_name(), ino))
+        .collect()
+}
+
+fn from_archive_name<N: ArchiveNameType>(name: &ArchiveName) -> Result<N, Error> {
+    N::from_archive_name(name).ok_or(Error::Unsupported)
+}
+
+impl<T: FileSystem> DynFileSystem for T
+where
+    T::NameType: ArchiveNameType,
+{
+    fn list_sub_dir(&self, ino: u32) -> Result<Vec<(String, u32)>, Error> {
+        Ok(list_to_str(self.open_dir(ino)?.list_sub_dir()?))
+    }
+
+    fn list_sub_file(&self, ino: u32) -> Result<Vec<(String, u32)>, Error> {
+        Ok(list_to_str(self.open_dir(ino)?.list_sub_file()?))
+    }
+
+    fn list_sub_dir_names(&self, ino: u32) -> Result<Vec<(ArchiveName, u32)>, Error> {
+        Ok(list_to_archive_name(self.open_dir(ino)?.list_sub_dir()?))
+    }
+
+    fn list_sub_file_names(&self, ino: u32) -> Result<Vec<(ArchiveName, u32)>, Error> {
+        Ok(list_to_archive_name(self.open_dir(ino)?.list_sub_file()?))
+    }
+
+    fn lookup_dir(&self, parent: u32, name: &ArchiveName) -> Result<u32, Error> {
+        let dir = self.open_dir(parent)?;
+        Ok(dir.open_sub_dir(from_archive_name(name)?)?.get_ino())
+    }
+
+    fn lookup_file(&self, parent: u32, name: &ArchiveName) -> Result<u32, Error> {
+        let dir = self.open_dir(parent)?;
+        Ok(dir.open_sub_file(from_archive_name(name)?)?.get_ino())
+    }
+
+    fn create_dir(&self, parent: u32, name: &ArchiveName) -> Result<u32, Error> {
+        let dir = self.open_dir(parent)?;
+        Ok(dir.new_sub_dir(from_archive_name(name)?)?.get_ino())
+    }
+
+    fn create_file(&self, parent: u32, name: &ArchiveName, len: usize) -> Result<u32, Error> {
+        let dir = self.open_dir(parent)?;
+        let file = dir.new_sub_file(from_archive_name(name)?, len)?;
+        file.commit()?;
+        Ok(file.get_ino())
+    }
+
+    fn remove_dir(&self, ino: u32) -> Result<(), Error> {
+        self.open_dir(ino)?.delete()
+    }
+
+    fn remove_file(&self, ino: u32) -> Result<(), Error> {
+        self.open_file(ino)?.delete()
+    }
+
+    fn file_len(&self, ino: u32) -> Result<usize, Error> {
+        Ok(self.open_file(ino)?.len())
+    }
+
+    fn resize_file(&self, ino: u32, len: usize) -> Result<(), Error> {
+        let mut file = self.open_file(ino)?;
+        file.resize(len)?;
+        file.commit()
+    }
+
+    fn read_file(&self, ino: u32, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.open_file(ino)?.read(pos, buf)
+    }
+
+    fn write_file(&self, ino: u32, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        let file = self.open_file(ino)?;
+        file.write(pos, buf)?;
+        file.commit()
+    }
+
+    fn commit_archive(&self) -> Result<(), Error> {
+        self.commit()
+    }
+
+    fn stat_archive(&self) -> Result<Stat, Error> {
+        self.stat()
+    }
+}
+
+fn is_legal_char(c: u8) -> bool {
+    (32..127).contains(&c) && c != b'/' && c != b'\\'
+}
+
+/// Schemes for encoding 16-byte archive names as host file names, used by
+/// [`NameConvert::name_3ds_to_str_as`](trait.NameConvert.html#method.name_3ds_to_str_as).
+///
+/// All schemes are reversible. Trailing zero bytes are stripped before encoding, and restored
+/// when decoding.
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+pub enum NameEncoding {
+    /// Printable ASCII is kept, and other bytes, `/` and `\` are written as `\xNN`.
+    /// This is the scheme of `NameConvert::name_3ds_to_str`.
+    Escape,
+
+    /// Every byte is written as two lowercase hexadecimal digits.
+    /// Safe on case-insensitive file systems.
+    Hex,
+
+    /// URL-safe base64 without padding (RFC 4648 section 5). Shorter than `Hex`, but only safe
+    /// on case-sensitive file systems.
+    Base64,
+
+    /// Same as `Escape`, but bytes are written as `%NN`, and `%` itself is also escaped.
+    /// This avoids backslashes, which are path separators on Windows.
+    Quoted,
+}
+
+impl std::fmt::Display for NameEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            NameEncoding::Escape => "escape",
+            NameEncoding::Hex => "hex",
+            NameEncoding::Base64 => "base64",
+            NameEncoding::Quoted => "quoted",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for NameEncoding {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<NameEncoding, Error> {
+        match s {
+            "escape" => Ok(NameEncoding::Escape),
+            "hex" => Ok(NameEncoding::Hex),
+            "base64" => Ok(NameEncoding::Base64),
+            "quoted" => Ok(NameEncoding::Quoted),
+            _ => make_error(Error::InvalidValue),
+        }
+    }
+}
+
+/// Length in bytes of a directory or file name in save data and extdata. Shorter names are
+/// padded with zeros.
+///
+/// The format doesn't limit the depth of paths. Every directory takes one entry in the
+/// directory table, so the depth is only limited by the `max_dir` given when formatting.
+pub const MAX_NAME_LEN: usize = 16;
+
+/// Conversion between archive names and human-readable strings.
+pub trait NameConvert {
+    /// Converts an archive name to a string that is safe to use as a host file name.
+    fn name_3ds_to_str(name: &Self) -> String;
+
+    /// Converts a string produced by `name_3ds_to_str` back to an archive name.
+    /// Returns `None` if the string is not a valid encoding.
+    fn name_str_to_3ds(name: &str) -> Option<Self>
+    where
+        Self: Sized;
+
+    /// Same as `name_3ds_to_str`, using the specified encoding for 16-byte names.
+    /// Other name types ignore the encoding.
+    fn name_3ds_to_str_as(name: &Self, encoding: NameEncoding) -> String {
+        let _ = encoding;
+        Self::name_3ds_to_str(name)
+    }
+
+    /// Converts a string produced by `name_3ds_to_str_as` with the same encoding back to an
+    /// archive name.
+    fn name_str_to_3ds_as(name: &str, encoding: NameEncoding) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        let _ = encoding;
+        Self::name_str_to_3ds(name)
+    }
+
+    /// Same as `name_str_to_3ds_as`, but tells why `name` is rejected: `Error::NameTooLong` if
+    /// it decodes to more than [`MAX_NAME_LEN`](constant.MAX_NAME_LEN.html) bytes, or
+    /// `Error::InvalidName` otherwise.
+    fn validate_name(name: &str, encoding: NameEncoding) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        match Self::name_str_to_3ds_as(name, encoding) {
+            Some(name) => Ok(name),
+            None => make_error(Error::InvalidName),
+        }
+    }
+}
+
+/// Converts each name in `path`, separated by `/`, with `NameConvert::validate_name`.
+/// Empty and `.` components, such as from a leading or a doubled `/`, are skipped.
+pub fn validate_path<T: NameConvert>(path: &str, encoding: NameEncoding) -> Result<Vec<T>, Error> {
+    path.split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .map(|name| T::validate_name(name, encoding))
+        .collect()
+}
+
+/// Title database entries are named by their 64-bit ID in hexadecimal.
+impl NameConvert for u64 {
+    fn name_3ds_to_str(name: &u64) -> String {
+        format!("{:016x}", name)
+    }
+
+    fn name_str_to_3ds(name: &str) -> Option<u64> {
+        u64::from_str_radix(name, 16).ok()
+    }
+}
+
+/// Returns the name without trailing zeros, keeping at least one byte.
+fn trim_name(name: &[u8; 16]) -> &[u8] {
+    let len = name.iter().rposition(|&c| c != 0).map_or(1, |i| i + 1);
+    &name[0..len]
+}
+
+/// Pads decoded bytes back to a 16-byte name.
+fn pad_name(bytes: &[u8]) -> Option<[u8; 16]> {
+    if bytes.len() > MAX_NAME_LEN {
+        return None;
+    }
+    let mut name = [0; 16];
+    name[0..bytes.len()].copy_from_slice(bytes);
+    Some(name)
+}
+
+fn parse_hex_byte(s: Option<&[u8]>) -> Option<u8> {
+    u8::from_str_radix(std::str::from_utf8(s?).ok()?, 16).ok()
+}
+
+/// Encodes with `\xNN` (`Escape`) or `%NN` (`Quoted`) for bytes that are not kept.
+fn escape_name(name: &[u8; 16], prefix: &str, keep: impl Fn(u8) -> bool) -> String {
+    trim_name(name)
+        .iter()
+        .map(|&c| {
+            if keep(c) {
+                (c as char).to_string()
+            } else {
+                format!("{}{:02x}", prefix, c)
+            }
+        })
+        .collect()
+}
+
+fn unescape_name(name: &str, prefix: &[u8]) -> Option<Vec<u8>> {
+    let bytes = name.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i..].starts_with(&prefix[0..1]) {
+            if !bytes[i..].starts_with(prefix) {
+                return None;
+            }
+            i += prefix.len();
+            decoded.push(parse_hex_byte(bytes.get(i..i + 2))?);
+            i += 2;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(decoded)
+}
+
+/// Decodes a name encoded with `encoding`, without padding it.
+fn decode_name(name: &str, encoding: NameEncoding) -> Option<Vec<u8>> {
+    match encoding {
+        NameEncoding::Escape => unescape_name(name, b"\\x"),
+        NameEncoding::Hex => {
+            if !name.len().is_multiple_of(2) {
+                return None;
+            }
+            (0..name.len() / 2)
+                .map(|i| parse_hex_byte(name.as_bytes().get(i * 2..i * 2 + 2)))
+                .collect()
+        }
+        NameEncoding::Base64 => base64_decode(name),
+        NameEncoding::Quoted => unescape_name(name, b"%"),
+    }
+}
+
+const BASE64_URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let mut buf = [0; 3];
+        buf[0..chunk.len()].copy_from_slice(chunk);
+        let v = (buf[0] as u32) << 16 | (buf[1] as u32) << 8 | buf[2] as u32;
+        for i in 0..=chunk.len() {
+            encoded.push(BASE64_URL[(v >> (18 - i * 6)) as usize & 0x3F] as char);
+        }
+    }
+    encoded
+}
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 4 == 1 {
+        return None;
+    }
+    let mut decoded = vec![];
+    for chunk in s.as_bytes().chunks(4) {
+        let mut v = 0;
+        for (i, c) in chunk.iter().enumerate() {
+            let digit = BASE64_URL.iter().position(|x| x == c)? as u32;
+            v |= digit << (18 - i * 6);
+        }
+        for i in 0..chunk.len() - 1 {
+            decoded.push((v >> (16 - i * 8)) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+/// Uses `NameEncoding::Escape`. Trailing zeros are stripped, and bytes other than printable
+/// ASCII, `/` and `\` are escaped in the form of `\xNN`.
+impl NameConvert for [u8; 16] {
+    fn name_3ds_to_str(name: &[u8; 16]) -> String {
+        Self::name_3ds_to_str_as(name, NameEncoding::Escape)
+    }
+
+    fn name_str_to_3ds(name: &str) -> Option<[u8; 16]> {
+        Self::name_str_to_3ds_as(name, NameEncoding::Escape)
+    }
+
+    fn name_3ds_to_str_as(name: &[u8; 16], encoding: NameEncoding) -> String {
+        match encoding {
+            NameEncoding::Escape => escape_name(name, "\\x", is_legal_char),
+            NameEncoding::Hex => trim_name(name)
+                .iter()
+                .map(|c| format!("{:02x}", c))
+                .collect(),
+            NameEncoding::Base64 => base64_encode(trim_name(name)),
+            NameEncoding::Quoted => escape_name(name, "%", |c| is_legal_char(c) && c != b'%'),
+        }
+    }
+
+    fn name_str_to_3ds_as(name: &str, encoding: NameEncoding) -> Option<[u8; 16]> {
+        pad_name(&decode_name(name, encoding)?)
+    }
+
+    fn validate_name(name: &str, encoding: NameEncoding) -> Result<[u8; 16], Error> {
+        match decode_name(name, encoding) {
+            Some(bytes) if bytes.is_empty() => make_error(Error::InvalidName),
+            Some(bytes) if bytes.len() > MAX_NAME_LEN => make_error(Error::NameTooLong),
+            Some(bytes) => Ok(pad_name(&bytes).unwrap()),
+            None => make_error(Error::InvalidName),
+        }
+    }
+}
+
+fn format_tree_impl<T: FileSystem>(
+    file_system: &T,
+    dir: &T::DirType,
+    indent: usize,
+    writer: &mut impl std::fmt::Write,
+) -> Result<(), Error>
+where
+    T::NameType: NameConvert,
+{
+    let map_fmt_error = |_| Error::from(std::io::Error::from(std::io::ErrorKind::Other));
+    for (name, ino) in dir.list_sub_dir()? {
+        writeln!(
+            writer,
+            "{:indent$}+{}",
+            "",
+            T::NameType::name_3ds_to_str(&name),
+            indent = indent
+        )
+        .map_err(map_fmt_error)?;
+        format_tree_impl(file_system, &file_system.open_dir(ino)?, indent + 1, writer)?;
+    }
+    for (name, _) in dir.list_sub_file()? {
+        writeln!(
+            writer,
+            "{:indent$}-{}",
+            "",
+            T::NameType::name_3ds_to_str(&name),
+            indent = indent
+        )
+        .map_err(map_fmt_error)?;
+    }
+    Ok(())
+}
+
+/// Writes an indented listing of all directories and files in the archive to `writer`.
+///
+/// Each line contains one entry, prefixed with `+` for a directory or `-` for a file,
+/// and indented by one space per level of depth. Sub directories of a directory are listed
+/// before its sub files. Names are encoded with [`NameConvert`](trait.NameConvert.html).
+pub fn format_tree<T: FileSystem>(
+    file_system: &T,
+    writer: &mut impl std::fmt::Write,
+) -> Result<(), Error>
+where
+    T::NameType: NameConvert,
+{
+    format_tree_impl(file_system, &file_system.open_root()?, 0, writer)
+}
+
+/// Totals of the content of an archive. See [`summarize`](fn.summarize.html).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Summary {
+    /// Number of directories, not counting the root directory.
+    pub dirs: usize,
+
+    /// Number of files.
+    pub files: usize,
+
+    /// Sum of the sizes of all files.
+    pub bytes: usize,
+
+    /// Capacity of the archive, as returned by `FileSystem::stat`.
+    pub stat: Stat,
+}
+
+/// Walks the whole archive and counts its directories, files and total file size.
+/// Nothing is written to the archive.
+pub fn summarize<T: FileSystem>(file_system: &T) -> Result<Summary, Error> {
+    let mut summary = Summary {
+        dirs: 0,
+        files: 0,
+        bytes: 0,
+        stat: file_system.stat()?,
+    };
+    let mut pending = vec![file_system.open_root()?];
+    while let Some(dir) = pending.pop() {
+        for (_, ino) in dir.list_sub_dir()? {
+            summary.dirs += 1;
+            pending.push(file_system.open_dir(ino)?);
+        }
+        for (_, ino) in dir.list_sub_file()? {
+            summary.files += 1;
+            summary.bytes += file_system.open_file(ino)?.len();
+        }
+    }
+    Ok(summary)
+}
+
+/// A directory or file found by [`content_entries`](fn.content_entries.html).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ContentEntry {
+    /// Path from the archive root, with names separated and prefixed by `/`.
+    /// Names are encoded with [`NameConvert`](trait.NameConvert.html).
+    pub path: String,
+
+    /// For a file, its size and the SHA-256 of its data. `None` for a directory.
+    pub file: Option<(usize, [u8; 32])>,
+}
+
+/// Walks the whole archive and lists all directories and files with the hashes of file data,
+/// sorted by path. The root directory is not listed.
+/// Uninitialized file data is hashed as the fill bytes `read` returns for it.
+pub fn content_entries<T: FileSystem>(file_system: &T) -> Result<Vec<ContentEntry>, Error>
+where
+    T::NameType: NameConvert,
+{
+    content_entries_as(file_system, NameEncoding::Escape)
+}
+
+/// Same as [`content_entries`](fn.content_entries.html), with names in paths encoded using
+/// the specified encoding.
+pub fn content_entries_as<T: FileSystem>(
+    file_system: &T,
+    encoding: NameEncoding,
+) -> Result<Vec<ContentEntry>, Error>
+where
+    T::NameType: NameConvert,
+{
+    let mut entries = vec![];
+    let mut pending = vec![(String::new(), file_system.open_root()?)];
+    while let Some((path, dir)) = pending.pop() {
+        for (name, ino) in dir.list_sub_dir()? {
+            let sub_path = format!(
+                "{}/{}",
+                path,
+                T::NameType::name_3ds_to_str_as(&name, encoding)
+            );
+            entries.push(ContentEntry {
+                path: sub_path.clone(),
+                file: None,
+            });
+            pending.push((sub_path, file_system.open_dir(ino)?));
+        }
+        for (name, ino) in dir.list_sub_file()? {
+            let file = file_system.open_file(ino)?;
+            let mut hasher = Sha256::new();
+            let mut buf = vec![0; 0x10000];
+            let mut pos = 0;
+            while pos < file.len() {
+                let chunk = std::cmp::min(buf.len(), file.len() - pos);
+                match file.read(pos, &mut buf[0..chunk]) {
+                    Ok(()) | Err(Error::HashMismatch) => (),
+                    Err(e) => return Err(e),
+                }
+                hasher.update(&buf[0..chunk]);
+                pos += chunk;
+            }
+            entries.push(ContentEntry {
+                path: format!(
+                    "{}/{}",
+                    path,
+                    T::NameType::name_3ds_to_str_as(&name, encoding)
+                ),
+                file: Some((file.len(), hasher.finalize().into())),
+            });
+        }
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// Computes a SHA-256 fingerprint of the logical content of the archive.
+///
+/// Only the directory tree, the file names, sizes and data are hashed, so two archives with
+/// the same content have the same fingerprint regardless of their format parameters
+/// or where their data is physically stored. Entries are hashed in the order given by
+/// [`content_entries`](fn.content_entries.html).
+pub fn content_fingerprint<T: FileSystem>(file_system: &T) -> Result<[u8; 32], Error>
+where
+    T::NameType: NameConvert,
+{
+    let mut hasher = Sha256::new();
+    for entry in content_entries(file_system)? {
+        match entry.file {
+            None => {
+                hasher.update(b"D");
+                hasher.update(entry.path.as_bytes());
+                hasher.update([0]);
+            }
+            Some((len, hash)) => {
+                hasher.update(b"F");
+                hasher.update(entry.path.as_bytes());
+                hasher.update([0]);
+                hasher.update((len as u64).to_le_bytes());
+                hasher.update(hash);
+            }
+        }
+    }
+    Ok(hasher.finalize().into())
 }
 
 #[cfg(test)]
@@ -246,7 +1104,7 @@ pub mod test {
                 assert_eq!(dir.get_ino(), dir_mirror.ino);
                 let parent_ino = dir.get_parent_ino().unwrap();
                 if dir_mirror.ino == 1 {
-                    assert_eq!(parent_ino, 0);
+                    assert_eq!(parent_ino, 1);
                 } else {
                     let mut parent_path = dir_mirror.path.clone();
                     parent_path.pop().unwrap();
@@ -282,6 +1140,14 @@ pub mod test {
 
                 assert_eq!(sub_file_list, sub_file_mirror);
 
+                // the lazy iterators yield the same entries in the same order
+                let sub_dir_iter: Vec<_> =
+                    dir.iter_sub_dir().unwrap().map(Result::unwrap).collect();
+                assert_eq!(sub_dir_iter, dir.list_sub_dir().unwrap());
+                let sub_file_iter: Vec<_> =
+                    dir.iter_sub_file().unwrap().map(Result::unwrap).collect();
+                assert_eq!(sub_file_iter, dir.list_sub_file().unwrap());
+
                 for _ in 0..10 {
                     let dir_mirror = &dir_mirrors[dir_index];
                     match rng.gen_range(0i32..9) {
@@ -347,10 +1213,15 @@ pub mod test {
                             let new_parent_index = rng.gen_range(0..dir_mirrors.len());
                             let new_parent_mirror = &dir_mirrors[new_parent_index];
                             let new_name = gen_name();
+                            let new_parent = file_system.open_dir(new_parent_mirror.ino).unwrap();
                             if is_prefix(&dir_mirror.path, &new_parent_mirror.path) {
+                                // moving into itself or its descendant
+                                match dir.rename(&new_parent, new_name) {
+                                    Err(Error::InvalidValue) | Err(Error::AlreadyExist) => (),
+                                    _ => unreachable!(),
+                                }
                                 continue;
                             }
-                            let new_parent = file_system.open_dir(new_parent_mirror.ino).unwrap();
                             if new_parent_mirror.ino == dir.get_parent_ino().unwrap()
                                 && new_name == *dir_mirror.path.last().unwrap()
                             {
@@ -511,10 +1382,18 @@ pub mod test {
                         }
                         2..=4 => {
                             // read/write
-                            if file_mirrors[file_index].data.is_empty() {
+                            let len = file_mirrors[file_index].data.len();
+                            file.read(len, &mut []).unwrap();
+                            file.write(len, &[]).unwrap();
+                            assert!(matches!(file.read(len, &mut [0]), Err(Error::OutOfBound)));
+                            assert!(matches!(file.write(len, &[0]), Err(Error::OutOfBound)));
+                            assert!(matches!(
+                                file.read(len + 1, &mut []),
+                                Err(Error::OutOfBound)
+                            ));
+                            if len == 0 {
                                 continue;
                             }
-                            let len = file_mirrors[file_index].data.len();
                             let pos = rng.gen_range(0..len);
                             let data_len = rng.gen_range(1..len - pos + 1);
                             if rng.gen() {
@@ -573,4 +1452,176 @@ pub mod test {
             }
         }
     }
+
+    #[test]
+    fn validate_name_boundary() {
+        use crate::file_system::*;
+        let validate = |s: &str, encoding| <[u8; 16]>::validate_name(s, encoding);
+        let full = [b'a'; MAX_NAME_LEN];
+        assert_eq!(
+            validate(&"a".repeat(16), NameEncoding::Escape).unwrap(),
+            full
+        );
+        assert!(matches!(
+            validate(&"a".repeat(17), NameEncoding::Escape),
+            Err(Error::NameTooLong)
+        ));
+        assert_eq!(
+            validate(&"\\x00".repeat(16), NameEncoding::Escape).unwrap(),
+            [0; 16]
+        );
+        assert!(matches!(
+            validate(&"\\x00".repeat(17), NameEncoding::Escape),
+            Err(Error::NameTooLong)
+        ));
+        assert_eq!(validate(&"61".repeat(16), NameEncoding::Hex).unwrap(), full);
+        assert!(matches!(
+            validate(&"61".repeat(17), NameEncoding::Hex),
+            Err(Error::NameTooLong)
+        ));
+        assert_eq!(
+            validate(&"%61".repeat(16), NameEncoding::Quoted).unwrap(),
+            full
+        );
+        assert!(matches!(
+            validate(&"%61".repeat(17), NameEncoding::Quoted),
+            Err(Error::NameTooLong)
+        ));
+        let base64 = <[u8; 16]>::name_3ds_to_str_as(&full, NameEncoding::Base64);
+        assert_eq!(validate(&base64, NameEncoding::Base64).unwrap(), full);
+        assert!(matches!(
+            validate(&(base64 + "YQ"), NameEncoding::Base64),
+            Err(Error::NameTooLong)
+        ));
+
+        for (s, encoding) in [
+            ("", NameEncoding::Escape),
+            ("a\\x1", NameEncoding::Escape),
+            ("a\\", NameEncoding::Escape),
+            ("616", NameEncoding::Hex),
+            ("a%", NameEncoding::Quoted),
+        ] {
+            assert!(matches!(validate(s, encoding), Err(Error::InvalidName)));
+        }
+        assert!(matches!(
+            u64::validate_name("xyz", NameEncoding::Escape),
+            Err(Error::InvalidName)
+        ));
+
+        assert_eq!(
+            validate_path::<[u8; 16]>("/a/./b//c/", NameEncoding::Escape).unwrap(),
+            vec![
+                *b"a\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
+                *b"b\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
+                *b"c\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0"
+            ]
+        );
+        assert!(validate_path::<[u8; 16]>("", NameEncoding::Escape)
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            validate_path::<[u8; 16]>(&format!("a/{}/b", "c".repeat(17)), NameEncoding::Escape),
+            Err(Error::NameTooLong)
+        ));
+    }
+
+    #[test]
+    fn name_encoding_round_trip() {
+        use crate::file_system::*;
+        use rand::prelude::*;
+        let mut rng = rand::thread_rng();
+        let mut names = vec![[0; 16], [0xFF; 16], *b"%\\x41/\\ \0\0abcdef"];
+        for _ in 0..1000 {
+            let mut name = [0; 16];
+            let len = rng.gen_range(0..=16);
+            for c in &mut name[0..len] {
+                *c = if rng.gen() {
+                    rng.gen()
+                } else {
+                    rng.gen_range(0x20..0x7F)
+                };
+            }
+            names.push(name);
+        }
+        let encodings = [
+            NameEncoding::Escape,
+            NameEncoding::Hex,
+            NameEncoding::Base64,
+            NameEncoding::Quoted,
+        ];
+        for &encoding in &encodings {
+            assert_eq!(
+                encoding.to_string().parse::<NameEncoding>().unwrap(),
+                encoding
+            );
+            for name in &names {
+                let s = <[u8; 16]>::name_3ds_to_str_as(name, encoding);
+                assert!(!s.is_empty() && !s.contains('/'));
+                if encoding != NameEncoding::Escape {
+                    assert!(!s.contains('\\'));
+                }
+                assert_eq!(<[u8; 16]>::name_str_to_3ds_as(&s, encoding), Some(*name));
+            }
+        }
+
+        let name = *b"save.bin\0\0\0\0\0\0\0\0";
+        let encode = |encoding| <[u8; 16]>::name_3ds_to_str_as(&name, encoding);
+        assert_eq!(encode(NameEncoding::Escape), "save.bin");
+        assert_eq!(encode(NameEncoding::Hex), "736176652e62696e");
+        assert_eq!(encode(NameEncoding::Base64), "c2F2ZS5iaW4");
+        assert_eq!(encode(NameEncoding::Quoted), "save.bin");
+        let mut name = [0; 16];
+        name[0..3].copy_from_slice(&[b'%', 1, b'\\']);
+        assert_eq!(
+            <[u8; 16]>::name_3ds_to_str_as(&name, NameEncoding::Quoted),
+            "%25%01%5c"
+        );
+        assert_eq!(
+            <[u8; 16]>::name_str_to_3ds_as("abc", NameEncoding::Hex),
+            None
+        );
+        assert_eq!(
+            <[u8; 16]>::name_str_to_3ds_as("a", NameEncoding::Base64),
+            None
+        );
+        assert_eq!(
+            <[u8; 16]>::name_str_to_3ds_as("%4", NameEncoding::Quoted),
+            None
+        );
+        assert_eq!(
+            u64::name_3ds_to_str_as(&0x1234, NameEncoding::Base64),
+            "0000000000001234"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn stat_serde() {
+        use crate::file_system::*;
+        let stat = Stat {
+            block_len: 512,
+            total_blocks: 100,
+            free_blocks: 42,
+            total_files: 10,
+            free_files: 9,
+            total_dirs: 10,
+            free_dirs: 10,
+        };
+        let json = serde_json::to_string(&stat).unwrap();
+        assert_eq!(
+            json,
+            "{\"block_len\":512,\"total_blocks\":100,\"free_blocks\":42,\"total_files\":10,\
+             \"free_files\":9,\"total_dirs\":10,\"free_dirs\":10}"
+        );
+        assert_eq!(serde_json::from_str::<Stat>(&json).unwrap(), stat);
+
+        let summary = Summary {
+            dirs: 1,
+            files: 2,
+            bytes: 3,
+            stat,
+        };
+        let json = serde_json::to_string(&summary).unwrap();
+        assert_eq!(serde_json::from_str::<Summary>(&json).unwrap(), summary);
+    }
 }
@@ -1,7 +1,9 @@
 use crate::byte_struct_common::*;
 use crate::error::*;
+use crate::header_dump::*;
 use crate::random_access_file::*;
 use byte_struct::*;
+use sha2::*;
 use std::cell::*;
 use std::collections::HashMap;
 use std::marker::PhantomData;
@@ -52,6 +54,38 @@ pub struct FsInfo {
     pub p5: u32,
 }
 
+impl FsInfo {
+    /// Dumps the fields. See [`HeaderDump`](../header_dump/struct.HeaderDump.html).
+    pub fn dump(&self) -> HeaderDump {
+        dump_fields!(
+            "FsInfo",
+            self,
+            unknown,
+            block_len,
+            dir_hash_offset,
+            dir_buckets,
+            p0,
+            file_hash_offset,
+            file_buckets,
+            p1,
+            fat_offset,
+            fat_size,
+            p2,
+            data_offset,
+            data_block_count,
+            p3,
+        )
+        .field("dir_table.block_index", self.dir_table.block_index)
+        .field("dir_table.block_count", self.dir_table.block_count)
+        .field("max_dir", self.max_dir)
+        .field("p4", self.p4)
+        .field("file_table.block_index", self.file_table.block_index)
+        .field("file_table.block_count", self.file_table.block_count)
+        .field("max_file", self.max_file)
+        .field("p5", self.p5)
+    }
+}
+
 struct RefTicket<KeyType, InfoType> {
     index: u32,
     ref_count: Rc<RefCell<HashMap<u32, u32>>>,
@@ -105,7 +139,25 @@ struct MetaTable<KeyType, InfoType> {
     phantom_info: PhantomData<InfoType>,
 }
 
+/// Feeds the whole content of `file` to `hasher`. Unused entries of a table might never have
+/// been written, in which case they are hashed as the fill byte.
+pub(crate) fn hash_raw(file: &dyn RandomAccessFile, hasher: &mut Sha256) -> Result<(), Error> {
+    let mut buf = vec![0; file.len()];
+    match file.read(0, &mut buf) {
+        Ok(()) | Err(Error::HashMismatch) => (),
+        Err(e) => return Err(e),
+    }
+    hasher.update(&buf);
+    Ok(())
+}
+
 impl<KeyType: ByteStruct + PartialEq, InfoType: ByteStruct> MetaTable<KeyType, InfoType> {
+    /// Feeds the raw hash buckets and entries to `hasher`.
+    fn hash_raw(&self, hasher: &mut Sha256) -> Result<(), Error> {
+        hash_raw(self.hash.as_ref(), hasher)?;
+        hash_raw(self.table.as_ref(), hasher)
+    }
+
     fn format(
         hash: &dyn RandomAccessFile,
         table: &dyn RandomAccessFile,
@@ -140,7 +192,7 @@ impl<KeyType: ByteStruct + PartialEq, InfoType: ByteStruct> MetaTable<KeyType, I
     ) -> Result<MetaTable<KeyType, InfoType>, Error> {
         assert!(KeyType::BYTE_LEN % 4 == 0);
 
-        if hash.len() % 4 != 0 {
+        if !hash.len().is_multiple_of(4) {
             return make_error(Error::SizeMismatch);
         }
 
@@ -164,12 +216,13 @@ impl<KeyType: ByteStruct + PartialEq, InfoType: ByteStruct> MetaTable<KeyType, I
     }
 
     // Gets the bucket index in the hash table given the key.
+    // The console computes the same index to look up entries, so this can't be changed.
     fn hash(&self, key: &KeyType) -> usize {
         let mut h = 0x1234_5678;
         let mut bytes = vec![0; KeyType::BYTE_LEN];
         key.write_bytes(&mut bytes);
         for i in (0..KeyType::BYTE_LEN).step_by(4) {
-            h = (h >> 1) | (h << 31);
+            h = u32::rotate_right(h, 1);
             h ^= u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
         }
         h as usize % self.buckets
@@ -405,6 +458,12 @@ impl<
             files: self.files.stat()?,
         })
     }
+
+    /// Feeds the raw directory and file tables to `hasher`.
+    pub fn hash_raw(&self, hasher: &mut Sha256) -> Result<(), Error> {
+        self.dirs.hash_raw(hasher)?;
+        self.files.hash_raw(hasher)
+    }
 }
 
 /// A handle to a file entry in the meta table.
@@ -520,11 +579,27 @@ impl<
     }
 
     /// Renames and/or change the parent of the directory.
+    ///
+    /// Returns `Error::InvalidValue` if `parent` is this directory or one of its descendants.
     pub fn rename(
         &mut self,
         parent: &DirMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>,
         name: DirKeyType::NameType,
     ) -> Result<(), Error> {
+        // Walk up from the new parent to make sure we are not moving into our own subtree,
+        // which would detach it from the root
+        let mut ancestor = parent.ticket.index;
+        loop {
+            if ancestor == self.ticket.index {
+                return make_error(Error::InvalidValue);
+            }
+            if ancestor == 1 {
+                break;
+            }
+            let (_, key) = self.fs.dirs.get_at(ancestor)?;
+            ancestor = key.get_parent();
+        }
+
         let (info, _) = self.fs.dirs.get_at(self.ticket.index)?;
         // Note: we don't check_exclusive on rename
         // because the consecutive delete-new operation preserves ino
@@ -534,6 +609,10 @@ impl<
     }
 
     pub fn get_parent_ino(&self) -> Result<u32, Error> {
+        // The root directory is stored with parent 0, but is reported as its own parent
+        if self.ticket.index == 1 {
+            return Ok(1);
+        }
         let (_, key) = self.fs.dirs.get_at(self.ticket.index)?;
         Ok(key.get_parent())
     }
@@ -565,28 +644,24 @@ impl<
         })
     }
 
-    pub fn list_sub_dir(&self) -> Result<Vec<(DirKeyType::NameType, u32)>, Error> {
+    pub fn iter_sub_dir(
+        &self,
+    ) -> Result<SubDirIter<DirKeyType, DirInfoType, FileKeyType, FileInfoType>, Error> {
         let (self_info, _) = self.fs.dirs.get_at(self.ticket.index)?;
-        let mut index = self_info.get_sub_dir();
-        let mut result = vec![];
-        while index != 0 {
-            let (info, key) = self.fs.dirs.get_at(index)?;
-            result.push((key.get_name(), index));
-            index = info.get_next();
-        }
-        Ok(result)
+        Ok(SubDirIter {
+            fs: self.fs.clone(),
+            index: self_info.get_sub_dir(),
+        })
     }
 
-    pub fn list_sub_file(&self) -> Result<Vec<(FileKeyType::NameType, u32)>, Error> {
+    pub fn iter_sub_file(
+        &self,
+    ) -> Result<SubFileIter<DirKeyType, DirInfoType, FileKeyType, FileInfoType>, Error> {
         let (self_info, _) = self.fs.dirs.get_at(self.ticket.index)?;
-        let mut index = self_info.get_sub_file();
-        let mut result = vec![];
-        while index != 0 {
-            let (info, key) = self.fs.files.get_at(index)?;
-            result.push((key.get_name(), index));
-            index = info.get_next();
-        }
-        Ok(result)
+        Ok(SubFileIter {
+            fs: self.fs.clone(),
+            index: self_info.get_sub_file(),
+        })
     }
 
     pub fn new_sub_dir(
@@ -638,6 +713,49 @@ impl<
         })
     }
 
+    /// Creates multiple sub files at once. The directory entry is only updated once, and either
+    /// all files are created or none.
+    ///
+    /// The files are linked in the same order as if they were created one by one with
+    /// `new_sub_file`.
+    pub fn new_sub_files(
+        &self,
+        entries: Vec<(FileKeyType::NameType, FileInfoType)>,
+    ) -> Result<Vec<FileMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>, Error> {
+        if self.fs.files.stat()?.free < entries.len() {
+            return make_error(Error::NoSpace);
+        }
+        let (mut self_info, _) = self.fs.dirs.get_at(self.ticket.index)?;
+        let mut head = self_info.get_sub_file();
+        let mut added = vec![];
+        for (name, mut info) in entries {
+            let key = FileKeyType::new(self.ticket.index, name);
+            info.set_next(head);
+            match self.fs.files.add(key, info) {
+                Ok(pos) => {
+                    added.push(pos);
+                    head = pos;
+                }
+                Err(e) => {
+                    // Nothing links to the new entries yet, so they can simply be removed
+                    for pos in added {
+                        self.fs.files.remove(pos)?;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        self_info.set_sub_file(head);
+        self.fs.dirs.set(self.ticket.index, self_info)?;
+        Ok(added
+            .into_iter()
+            .map(|pos| FileMeta {
+                ticket: self.fs.files.acquire_ticket(pos),
+                fs: self.fs.clone(),
+            })
+            .collect())
+    }
+
     pub fn delete(self) -> Result<(), Error> {
         self.ticket.check_exclusive()?;
         let (self_info, _) = self.fs.dirs.get_at(self.ticket.index)?;
@@ -681,6 +799,72 @@ impl<
     }
 }
 
+/// Walks the sub directory list of a directory, reading one entry at a time.
+/// Stops after yielding the first error.
+pub struct SubDirIter<DirKeyType, DirInfoType, FileKeyType, FileInfoType> {
+    fs: Rc<FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>,
+    index: u32,
+}
+
+impl<
+        DirKeyType: ParentedKey,
+        DirInfoType: DirInfo,
+        FileKeyType: ParentedKey,
+        FileInfoType: FileInfo,
+    > Iterator for SubDirIter<DirKeyType, DirInfoType, FileKeyType, FileInfoType>
+{
+    type Item = Result<(DirKeyType::NameType, u32), Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index == 0 {
+            return None;
+        }
+        let index = self.index;
+        match self.fs.dirs.get_at(index) {
+            Ok((info, key)) => {
+                self.index = info.get_next();
+                Some(Ok((key.get_name(), index)))
+            }
+            Err(e) => {
+                self.index = 0;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Walks the sub file list of a directory, reading one entry at a time.
+/// Stops after yielding the first error.
+pub struct SubFileIter<DirKeyType, DirInfoType, FileKeyType, FileInfoType> {
+    fs: Rc<FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>,
+    index: u32,
+}
+
+impl<
+        DirKeyType: ParentedKey,
+        DirInfoType: DirInfo,
+        FileKeyType: ParentedKey,
+        FileInfoType: FileInfo,
+    > Iterator for SubFileIter<DirKeyType, DirInfoType, FileKeyType, FileInfoType>
+{
+    type Item = Result<(FileKeyType::NameType, u32), Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index == 0 {
+            return None;
+        }
+        let index = self.index;
+        match self.fs.files.get_at(index) {
+            Ok((info, key)) => {
+                self.index = info.get_next();
+                Some(Ok((key.get_name(), index)))
+            }
+            Err(e) => {
+                self.index = 0;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::fs_meta::*;
@@ -858,16 +1042,15 @@ mod test {
                         }
                     }
                     3 => {
-                        // list_sub_dir
+                        // iter_sub_dir
                         let index = rng.gen_range(0..dirs.len());
                         assert_eq!(
                             HashSet::from_iter(
                                 dirs[index]
                                     .meta
-                                    .list_sub_dir()
+                                    .iter_sub_dir()
                                     .unwrap()
-                                    .into_iter()
-                                    .map(|n| n.0)
+                                    .map(|n| n.unwrap().0)
                             ),
                             dirs[index].sub_dir_name
                         );
@@ -875,10 +1058,9 @@ mod test {
                             HashSet::from_iter(
                                 dirs[index]
                                     .meta
-                                    .list_sub_file()
+                                    .iter_sub_file()
                                     .unwrap()
-                                    .into_iter()
-                                    .map(|n| n.0)
+                                    .map(|n| n.unwrap().0)
                             ),
                             dirs[index].sub_file_name
                         );
@@ -968,7 +1150,18 @@ mod test {
                         let index = rng.gen_range(1..dirs.len());
 
                         let parent = rng.gen_range(0..dirs.len());
-                        if parent == index {
+                        let mut ancestor = parent;
+                        while ancestor != 0 && ancestor != index {
+                            ancestor = dirs[ancestor].parent;
+                        }
+                        if ancestor == index {
+                            // moving into itself or its descendant
+                            let parent_meta =
+                                DirMeta::open_ino(fs.clone(), dirs[parent].meta.get_ino()).unwrap();
+                            match dirs[index].meta.rename(&parent_meta, [0; 16]) {
+                                Err(Error::InvalidValue) => (),
+                                _ => unreachable!(),
+                            }
                             continue;
                         }
 
@@ -995,6 +1188,80 @@ mod test {
         }
     }
 
+    #[derive(ByteStruct, PartialEq, Clone, Debug, Hash, Eq)]
+    #[byte_struct_le]
+    struct NamedKey {
+        parent: u32,
+        name: [u8; 16],
+    }
+
+    fn named_key(parent: u32, name: &[u8]) -> NamedKey {
+        let mut key = NamedKey {
+            parent,
+            name: [0; 16],
+        };
+        key.name[0..name.len()].copy_from_slice(name);
+        key
+    }
+
+    // Returns the longest chain and the average number of entries walked by a successful
+    // lookup, after putting all keys in a table sized by default_buckets.
+    fn chain_stat(keys: &[NamedKey]) -> (usize, f64) {
+        let buckets = crate::save_data::default_buckets(keys.len());
+        let hash = Rc::new(MemoryFile::new(vec![0; buckets * 4]));
+        let table = Rc::new(MemoryFile::new(vec![]));
+        let meta = MetaTable::<NamedKey, U32le>::new(hash, table).unwrap();
+        let mut chains = vec![0; buckets];
+        for key in keys {
+            chains[meta.hash(key)] += 1;
+        }
+        let longest = *chains.iter().max().unwrap();
+        let walk: usize = chains.iter().map(|c| c * (c + 1) / 2).sum();
+        (longest, walk as f64 / keys.len() as f64)
+    }
+
+    // The hash is part of the format, so this only guards against the bucket sizing going bad.
+    // A uniform hash walks about 1.5 entries on average at this load.
+    fn check_distribution(keys: &[NamedKey]) {
+        let (longest, walk) = chain_stat(keys);
+        assert!(
+            longest <= 8,
+            "longest chain {} for {} keys",
+            longest,
+            keys.len()
+        );
+        assert!(walk < 2.0, "average walk {} for {} keys", walk, keys.len());
+    }
+
+    #[test]
+    fn hash_distribution() {
+        let mut rng = StdRng::seed_from_u64(705);
+        for &count in &[20, 100, 1000, 5000] {
+            let sequential: Vec<NamedKey> = (0..count)
+                .map(|i| named_key(1, format!("file{:04}", i).as_bytes()))
+                .collect();
+            check_distribution(&sequential);
+
+            let numbered: Vec<NamedKey> = (0..count)
+                .map(|i| named_key(2, format!("{}.bin", i).as_bytes()))
+                .collect();
+            check_distribution(&numbered);
+
+            let spread: Vec<NamedKey> = (0..count)
+                .map(|i| named_key(i as u32 % 8 + 1, format!("data{}", i / 8).as_bytes()))
+                .collect();
+            check_distribution(&spread);
+
+            let mut random: HashSet<NamedKey> = HashSet::new();
+            while random.len() < count {
+                let len = rng.gen_range(1..=16);
+                let name: Vec<u8> = (0..len).map(|_| rng.gen_range(b'a'..=b'z')).collect();
+                random.insert(named_key(rng.gen_range(1..10), &name));
+            }
+            check_distribution(&random.into_iter().collect::<Vec<_>>());
+        }
+    }
+
     #[test]
     fn meta_fuzz() {
         let mut rng = rand::thread_rng();
@@ -0,0 +1,70 @@
+//! Field-by-field dumps of the on-disk header structures of an archive, for debugging.
+//!
+//! See [`SaveData::dump_headers`](../save_data/struct.SaveData.html#method.dump_headers) and
+//! [`ExtData::dump_headers`](../ext_data/struct.ExtData.html#method.dump_headers).
+
+/// The value of a header field.
+#[derive(PartialEq, Eq, Clone, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FieldValue {
+    /// An integer field.
+    Int(u64),
+
+    /// A byte array field, such as a magic, a hash or a name.
+    Bytes(Vec<u8>),
+}
+
+macro_rules! int_field_value {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for FieldValue {
+                fn from(v: $t) -> FieldValue {
+                    FieldValue::Int(v as u64)
+                }
+            }
+        )*
+    };
+}
+
+int_field_value!(u8, u16, u32, u64);
+
+impl<const N: usize> From<[u8; N]> for FieldValue {
+    fn from(v: [u8; N]) -> FieldValue {
+        FieldValue::Bytes(v.to_vec())
+    }
+}
+
+/// A header structure, with its fields in the on-disk order.
+#[derive(PartialEq, Eq, Clone, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HeaderDump {
+    /// Name of the structure, including which part of the archive it belongs to.
+    pub name: String,
+
+    /// Names and values of the fields. Nested structures are flattened as `outer.inner`.
+    pub fields: Vec<(String, FieldValue)>,
+}
+
+impl HeaderDump {
+    pub(crate) fn new(name: &str) -> HeaderDump {
+        HeaderDump {
+            name: name.to_owned(),
+            fields: vec![],
+        }
+    }
+
+    pub(crate) fn field(mut self, name: &str, value: impl Into<FieldValue>) -> HeaderDump {
+        self.fields.push((name.to_owned(), value.into()));
+        self
+    }
+}
+
+/// Builds a `HeaderDump` from the listed fields of a struct, using the field names as-is.
+macro_rules! dump_fields {
+    ($name:expr, $s:expr, $($field:ident),* $(,)?) => {
+        crate::header_dump::HeaderDump::new($name)
+            $(.field(stringify!($field), $s.$field))*
+    };
+}
+
+pub(crate) use dump_fields;
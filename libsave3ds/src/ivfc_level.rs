@@ -1,8 +1,11 @@
 use crate::error::*;
 use crate::misc::*;
 use crate::random_access_file::*;
+use log::*;
 use sha2::*;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+use std::ops::Range;
 use std::rc::Rc;
 
 // Values for block status
@@ -11,6 +14,9 @@ const BLOCK_VERIFIED: u8 = 1;
 const BLOCK_MODIFIED: u8 = 2;
 const BLOCK_BROKEN: u8 = 3;
 
+/// The byte that fills data failing verification, unless changed with `IvfcLevel::set_fill`.
+pub const DEFAULT_FILL: u8 = 0xDD;
+
 /// Implements `RandomAccessFile` layer for a IVFC level.
 ///
 /// An IVFC level consists of a hash file and a data file as the underlying files.
@@ -21,7 +27,9 @@ pub struct IvfcLevel {
     data: Rc<dyn RandomAccessFile>,
     block_len: usize,
     len: usize,
-    status: RefCell<Vec<u8>>, // Array of u2. Status of each block.
+    status: RefCell<Vec<u8>>,           // Array of u2. Status of each block.
+    modified: RefCell<BTreeSet<usize>>, // Blocks with BLOCK_MODIFIED, so commit doesn't scan
+    fill: Cell<u8>,
 }
 
 impl IvfcLevel {
@@ -42,9 +50,16 @@ impl IvfcLevel {
             block_len,
             len,
             status: RefCell::new(vec![BLOCK_UNVERIFIED; chunk_count]),
+            modified: RefCell::new(BTreeSet::new()),
+            fill: Cell::new(DEFAULT_FILL),
         })
     }
 
+    /// Sets the byte that fills data failing verification on read.
+    pub fn set_fill(&self, fill: u8) {
+        self.fill.set(fill);
+    }
+
     pub fn get_status(&self, block_index: usize) -> u8 {
         (self.status.borrow()[block_index / 4] >> ((block_index % 4) * 2)) & 3
     }
@@ -56,6 +71,30 @@ impl IvfcLevel {
         status_list[i] &= !(3 << j);
         status_list[i] |= status << j;
     }
+
+    /// Returns the byte range within this level of the specified block.
+    fn block_range(&self, block_index: usize) -> Range<usize> {
+        block_index * self.block_len..std::cmp::min((block_index + 1) * self.block_len, self.len)
+    }
+
+    fn mark_broken(&self, block_index: usize) {
+        let range = self.block_range(block_index);
+        warn!(
+            "IVFC hash mismatch at block {} (bytes 0x{:X}..0x{:X})",
+            block_index, range.start, range.end
+        );
+        self.set_status(block_index, BLOCK_BROKEN);
+    }
+
+    /// Returns the blocks found broken so far, as tuples of the block index and the byte range
+    /// within this level. A block is only verified when it is first read, so blocks that
+    /// haven't been read are never reported.
+    pub fn broken_blocks(&self) -> Vec<(usize, Range<usize>)> {
+        (0..divide_up(self.len, self.block_len))
+            .filter(|&i| self.get_status(i) == BLOCK_BROKEN)
+            .map(|i| (i, self.block_range(i)))
+            .collect()
+    }
 }
 
 impl RandomAccessFile for IvfcLevel {
@@ -84,7 +123,7 @@ impl RandomAccessFile for IvfcLevel {
                 // Fill the region if we know the block is already broken
                 result = make_error(Error::HashMismatch);
                 for i in buf[data_begin - pos..data_end - pos].iter_mut() {
-                    *i = 0xDD;
+                    *i = self.fill.get();
                 }
             } else if status == BLOCK_VERIFIED || status == BLOCK_MODIFIED {
                 // Just read the data directly if the block is already verified/modified
@@ -101,14 +140,16 @@ impl RandomAccessFile for IvfcLevel {
                 let mut hash_stored = [0; 0x20];
                 if self.hash.read(i * 0x20, &mut hash_stored).is_err() {
                     // If the upper level fails, we just assume a broken block
-                    self.set_status(i, BLOCK_BROKEN);
+                    self.mark_broken(i);
                     result = make_error(Error::HashMismatch);
                     for i in buf[data_begin - pos..data_end - pos].iter_mut() {
-                        *i = 0xDD;
+                        *i = self.fill.get();
                     }
                     continue;
                 }
 
+                #[cfg(feature = "metrics")]
+                crate::metrics::record_sha256();
                 let mut hasher = Sha256::new();
                 hasher.update(&block_buf);
                 let hash = hasher.finalize();
@@ -121,10 +162,10 @@ impl RandomAccessFile for IvfcLevel {
                     );
                 } else {
                     // The block is broken
-                    self.set_status(i, BLOCK_BROKEN);
+                    self.mark_broken(i);
                     result = make_error(Error::HashMismatch);
                     for i in buf[data_begin - pos..data_end - pos].iter_mut() {
-                        *i = 0xDD;
+                        *i = self.fill.get();
                     }
                 }
             }
@@ -143,8 +184,10 @@ impl RandomAccessFile for IvfcLevel {
         let begin_block = pos / self.block_len;
         let end_block = divide_up(end, self.block_len);
 
+        let mut modified = self.modified.borrow_mut();
         for i in begin_block..end_block {
             self.set_status(i, BLOCK_MODIFIED);
+            modified.insert(i);
         }
 
         Ok(())
@@ -153,20 +196,23 @@ impl RandomAccessFile for IvfcLevel {
         self.len
     }
     fn commit(&self) -> Result<(), Error> {
-        // Recalculate the hash for modified blocks
-        let block_count = divide_up(self.len, self.block_len);
-        for i in 0..block_count {
-            if self.get_status(i) == BLOCK_MODIFIED {
-                let mut buf = vec![0; self.block_len];
-                let begin = i * self.block_len;
-                let end = std::cmp::min((i + 1) * self.block_len, self.len);
-                self.data.read(begin, &mut buf[0..end - begin])?;
-                let mut hasher = Sha256::new();
-                hasher.update(buf);
-                let hash = hasher.finalize();
-                self.hash.write(i * 0x20, &hash)?;
-                self.set_status(i, BLOCK_VERIFIED);
-            }
+        // Recalculate the hash for modified blocks. Only their hashes change, which in turn only
+        // marks the blocks holding them modified in the level above.
+        let mut buf = vec![0; self.block_len];
+        let blocks: Vec<usize> = self.modified.borrow().iter().cloned().collect();
+        for i in blocks {
+            // Unfilled bytes of the last block are hashed as zeros
+            buf.iter_mut().for_each(|b| *b = 0);
+            let range = self.block_range(i);
+            self.data.read(range.start, &mut buf[0..range.len()])?;
+            #[cfg(feature = "metrics")]
+            crate::metrics::record_sha256();
+            let mut hasher = Sha256::new();
+            hasher.update(&buf);
+            let hash = hasher.finalize();
+            self.hash.write(i * 0x20, &hash)?;
+            self.set_status(i, BLOCK_VERIFIED);
+            self.modified.borrow_mut().remove(&i);
         }
         Ok(())
     }
@@ -217,4 +263,93 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn commit_modified_only() {
+        use rand::distributions::Standard;
+        use rand::prelude::*;
+
+        let mut rng = rand::thread_rng();
+        let (len, block_len) = (1000, 64);
+        let block_count = divide_up(len, block_len);
+        let init: Vec<u8> = (&mut rng).sample_iter(&Standard).take(len).collect();
+        let make = || {
+            let hash = Rc::new(MemoryFile::new(vec![0; block_count * 0x20]));
+            let data = Rc::new(MemoryFile::new(vec![0; len]));
+            let level = IvfcLevel::new(hash.clone(), data.clone(), block_len).unwrap();
+            level.write(0, &init).unwrap();
+            level.commit().unwrap();
+            (hash, data, level)
+        };
+        let snapshot = |file: &MemoryFile| {
+            let mut buf = vec![0; file.len()];
+            file.read(0, &mut buf).unwrap();
+            buf
+        };
+
+        // A change within one block, including the short last block, gives the same hashes as
+        // rewriting and rehashing everything
+        for pos in [0, 100, 130, 990] {
+            let (hash, data, level) = make();
+            let (full_hash, full_data, full_level) = make();
+            let before = snapshot(&hash);
+            level.write(pos, &[0x12, 0x34]).unwrap();
+            level.commit().unwrap();
+            full_level.write(0, &init).unwrap();
+            full_level.write(pos, &[0x12, 0x34]).unwrap();
+            full_level.commit().unwrap();
+            assert_eq!(snapshot(&data), snapshot(&full_data));
+            assert_eq!(snapshot(&hash), snapshot(&full_hash));
+
+            // Only the hash of the changed block is rewritten
+            let after = snapshot(&hash);
+            let changed: Vec<usize> = (0..block_count)
+                .filter(|i| before[i * 0x20..(i + 1) * 0x20] != after[i * 0x20..(i + 1) * 0x20])
+                .collect();
+            assert_eq!(changed, vec![pos / block_len]);
+
+            // Nothing is left to commit
+            hash.write(0, &[0; 0x20]).unwrap();
+            level.commit().unwrap();
+            assert_eq!(snapshot(&hash)[0..0x20], [0; 0x20]);
+        }
+    }
+
+    #[test]
+    fn broken_blocks() {
+        let hash = Rc::new(MemoryFile::new(vec![0; 5 * 0x20]));
+        let data = Rc::new(MemoryFile::new(vec![0; 450]));
+        let ivfc_level = IvfcLevel::new(hash.clone(), data.clone(), 100).unwrap();
+        ivfc_level.write(0, &[0x55; 450]).unwrap();
+        ivfc_level.commit().unwrap();
+
+        data.write(250, &[0]).unwrap();
+        data.write(420, &[0]).unwrap();
+        let ivfc_level = IvfcLevel::new(hash.clone(), data.clone(), 100).unwrap();
+
+        let mut buf = vec![0; 200];
+        ivfc_level.read(0, &mut buf).unwrap();
+        assert!(ivfc_level.broken_blocks().is_empty());
+
+        let mut buf = vec![0; 450];
+        match ivfc_level.read(0, &mut buf) {
+            Err(Error::HashMismatch) => (),
+            _ => unreachable!(),
+        }
+        assert_eq!(
+            ivfc_level.broken_blocks(),
+            vec![(2, 200..300), (4, 400..450)]
+        );
+        assert!(buf[200..300].iter().all(|&b| b == 0xDD));
+        assert!(buf[300..400].iter().all(|&b| b == 0x55));
+
+        ivfc_level.set_fill(0xA5);
+        assert!(ivfc_level.read(0, &mut buf).is_err());
+        assert!(buf[200..300].iter().all(|&b| b == 0xA5));
+        assert!(buf[400..450].iter().all(|&b| b == 0xA5));
+
+        // Rewriting a broken block fixes it
+        ivfc_level.write(200, &[0x55; 100]).unwrap();
+        assert_eq!(ivfc_level.broken_blocks(), vec![(4, 400..450)]);
+    }
 }
@@ -1,4 +1,4 @@
-/// Emulates 3DS AES key scrambler engine.
+//! Emulates 3DS AES key scrambler engine.
 
 fn lrot128(a: [u8; 16], rot: usize) -> [u8; 16] {
     let mut out = [0; 16];
@@ -14,20 +14,30 @@ mod dual_file;
 pub mod error;
 pub mod ext_data;
 mod fat;
+#[cfg(test)]
+mod faulty_file;
 pub mod file_system;
 mod fs_meta;
+pub mod header_dump;
 mod ivfc_level;
 mod key_engine;
 mod memory_file;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 mod misc;
 mod nand;
+pub mod overlay;
 mod random_access_file;
 pub mod save_data;
 mod save_ext_common;
+mod scratch_file;
 mod sd;
 mod sd_nand_common;
 mod signed_file;
 mod sub_file;
+#[cfg(any(test, feature = "test-util"))]
+#[doc(hidden)]
+pub mod test_util;
 mod wear_leveling;
 
 use aes::*;
@@ -36,19 +46,308 @@ use db::*;
 use disk_file::DiskFile;
 use error::*;
 use ext_data::*;
+use file_system::*;
 use key_engine::*;
+use log::*;
 use misc::*;
 use nand::Nand;
+use overlay::Overlay;
 use save_data::*;
 use sd::Sd;
 use sd_nand_common::*;
 use sha2::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::io::{Read, Seek, SeekFrom};
 use std::path::*;
 use std::rc::Rc;
 
+/// Number of bytes copied at a time when moving data within a host file.
+const COPY_CHUNK_LEN: usize = 0x10000;
+
+/// Identifies an archive to open with [`Resource::open_any`](struct.Resource.html#method.open_any).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ArchiveSpec {
+    /// Save data on SD, identified by the title ID. See `Resource::open_sd_save`.
+    SdSave(u64),
+    /// Save data on NAND, identified by the save ID. See `Resource::open_nand_save`.
+    NandSave(u32),
+    /// Stand-alone save data at the given path. See `Resource::open_bare_save`.
+    BareSave(String),
+    /// Save data on cartridge at the given path. See `Resource::open_cart_save`.
+    CartSave(String),
+    /// Extdata on SD, identified by the extdata ID. See `Resource::open_sd_ext`.
+    SdExt(u64),
+    /// Extdata on NAND, identified by the extdata ID. See `Resource::open_nand_ext`.
+    NandExt(u64),
+    /// Title database. See `Resource::open_db`.
+    Db(DbType),
+}
+
+/// Archive kinds that can be opened with the resources given to `Resource::new`.
+/// See [`Resource::capabilities`](struct.Resource.html#method.capabilities).
+///
+/// Bare save data needs no key, so it is always available and not listed here.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Capabilities {
+    /// Save data on SD. Needs SD, `movable.sed` and boot9.
+    pub sd_save: bool,
+
+    /// Extdata on SD. Needs SD, `movable.sed` and boot9.
+    pub sd_ext: bool,
+
+    /// Title databases on SD. Needs SD, `movable.sed` and boot9.
+    pub sd_db: bool,
+
+    /// Save data on NAND. Needs NAND and boot9.
+    pub nand_save: bool,
+
+    /// Extdata on NAND. Needs NAND and boot9.
+    pub nand_ext: bool,
+
+    /// Title databases on NAND. Needs NAND, OTP and boot9.
+    pub nand_db: bool,
+
+    /// Save data on cartridge. Needs the game image, the private header and boot9.
+    /// Depending on the crypto version of the game, additional keys might be required.
+    pub cart_save: bool,
+}
+
+/// The higher half of the ID of all shared extdata.
+///
+/// Shared extdata are stored on NAND under `data/<ID0>/extdata/00048000/<ID>`, and are used by
+/// system applications. Like other NAND extdata, they have a `Quota.dat`. Some known IDs
+/// (the lower half) are:
+///  - `0xF0000001`: photos taken by the Camera application on NAND.
+///  - `0xF0000002`: recordings made by the Sound application on NAND.
+///  - `0xF0000009`: SpotPass notification content.
+///  - `0xF000000B`: Play Coins (`gamecoin.dat`), Mii data and other shared system data.
+///  - `0xF000000C`: StreetPass data (`bashotorya.dat`).
+///  - `0xF000000D`: HOME Menu SpotPass content.
+///  - `0xF000000E`: update notification data.
+pub const SHARED_EXT_ID_HIGH: u32 = 0x0004_8000;
+
+fn shared_ext_id(id: u32) -> u64 {
+    (u64::from(SHARED_EXT_ID_HIGH) << 32) | u64::from(id)
+}
+
+/// Reports `Error::LikelyWrongKey` for an archive on SD that fails its first check.
+///
+/// SD files are encrypted in AES-CTR mode, which doesn't spread corruption, so a damaged file
+/// still decrypts to the expected magic unless the magic itself is damaged. A wrong magic right
+/// after decryption almost always means that movable.sed doesn't belong to this SD card.
+fn check_sd_key<T>(result: Result<T, Error>) -> Result<T, Error> {
+    match result {
+        Err(Error::MagicMismatch) | Err(Error::SignatureMismatch) => {
+            error!("Failed to decrypt the SD file. movable.sed is likely wrong");
+            make_error(Error::LikelyWrongKey)
+        }
+        result => result,
+    }
+}
+
+/// Locates the executable NCCH (CXI) in a CCI/NCSD game image, returning its offset and length.
+///
+/// All eight partitions in the NCSD table are checked, and the first NCCH with the
+/// "executable" content type flag is picked. This is usually partition 0, but not always.
+fn find_cxi(game: &dyn random_access_file::RandomAccessFile) -> Result<(usize, usize), Error> {
+    use byte_struct_common::*;
+    use random_access_file::*;
+    if read_struct::<Magic>(game, 0x100)?.v != *b"NCSD" {
+        return Err(Error::BrokenGame);
+    }
+
+    for i in 0..8 {
+        let offset = read_struct::<U32le>(game, 0x120 + i * 8)?.v as usize * 0x200;
+        let len = read_struct::<U32le>(game, 0x124 + i * 8)?.v as usize * 0x200;
+        if len == 0 || offset + len > game.len() {
+            continue;
+        }
+        if read_struct::<Magic>(game, offset + 0x100)?.v != *b"NCCH" {
+            continue;
+        }
+        let mut content_type = [0];
+        game.read(offset + 0x18D, &mut content_type)?;
+        if content_type[0] & 2 != 0 {
+            return Ok((offset, len));
+        }
+    }
+
+    error!("No executable partition found in the game");
+    Err(Error::BrokenGame)
+}
+
+/// Title information of a cartridge, read from its CCI/NCSD header and the header of
+/// its executable NCCH. See [`Resource::cart_info`](struct.Resource.html#method.cart_info).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CartInfo {
+    /// Program ID, which is also the title ID for cartridge games.
+    pub program_id: u64,
+
+    /// Product code, e.g. `CTR-P-ABCE`.
+    pub product_code: String,
+
+    /// Two-character maker code.
+    pub maker_code: String,
+
+    /// Version of the NCCH format.
+    pub ncch_version: u16,
+
+    /// Version of the cartridge save crypto (0, 2, 6 or 9).
+    pub crypto_version: u8,
+
+    /// Whether the save chip uses wear leveling (Card1).
+    pub wear_leveling: bool,
+}
+
+/// Parses the CCI/NCSD and CXI headers of a game image.
+/// Returns the title information, and the offset and length of the CXI.
+fn read_cart_info(
+    game: &dyn random_access_file::RandomAccessFile,
+) -> Result<(CartInfo, usize, usize), Error> {
+    use byte_struct_common::*;
+    use random_access_file::*;
+    if read_struct::<Magic>(game, 0x100)?.v != *b"NCSD" {
+        return Err(Error::BrokenGame);
+    }
+
+    let mut cci_flags = [0; 8];
+    game.read(0x188, &mut cci_flags)?;
+
+    let wear_leveling = match cci_flags[5] {
+        1 => true,
+        2 => false,
+        _ => return Err(Error::BrokenGame),
+    };
+
+    let crypto_version = if cci_flags[7] != 0 {
+        2
+    } else if cci_flags[3] != 0 {
+        match cci_flags[1] {
+            0 => 2,
+            1 => 6,
+            10 => 9,
+            _ => return Err(Error::BrokenGame),
+        }
+    } else {
+        0
+    };
+
+    let (cxi_offset, cxi_len) = find_cxi(game)?;
+    if read_struct::<Magic>(game, cxi_offset + 0x100)?.v != *b"NCCH" {
+        return Err(Error::BrokenGame);
+    }
+
+    let read_ascii = |pos: usize, len: usize| -> Result<String, Error> {
+        let mut buf = vec![0; len];
+        game.read(cxi_offset + pos, &mut buf)?;
+        let end = buf.iter().position(|&c| c == 0).unwrap_or(len);
+        Ok(buf[0..end].iter().map(|&c| c as char).collect())
+    };
+
+    let mut program_id = [0; 8];
+    game.read(cxi_offset + 0x118, &mut program_id)?;
+
+    let info = CartInfo {
+        program_id: u64::from_le_bytes(program_id),
+        product_code: read_ascii(0x150, 0x10)?,
+        maker_code: read_ascii(0x110, 2)?,
+        ncch_version: read_struct::<U16le>(game, cxi_offset + 0x112)?.v,
+        crypto_version,
+        wear_leveling,
+    };
+    Ok((info, cxi_offset, cxi_len))
+}
+
+/// Console information stored in `rw/sys/SecureInfo_A` on NAND.
+/// See [`Resource::secure_info`](struct.Resource.html#method.secure_info).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SecureInfo {
+    /// Region of the console: 0 for Japan, 1 for Americas, 2 for Europe, 3 for Australia,
+    /// 4 for China, 5 for Korea and 6 for Taiwan.
+    pub region: u8,
+
+    /// Serial number of the console, as printed on its label.
+    pub serial: String,
+}
+
+/// Reads the first existing file of `names` from `rw/sys` of the NAND.
+/// Returns `None` if none of them exist.
+fn read_nand_sys_file(
+    nand_path: &str,
+    names: &[&str],
+    len: usize,
+) -> Result<Option<Vec<u8>>, Error> {
+    for name in names {
+        let path = PathBuf::from(nand_path).join("rw").join("sys").join(name);
+        if path.exists() {
+            let mut data = vec![0; len];
+            std::fs::File::open(path)?.read_exact(&mut data)?;
+            return Ok(Some(data));
+        }
+    }
+    Ok(None)
+}
+
+/// An entry in the SD tree. See [`Resource::sd_map`](struct.Resource.html#method.sd_map).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SdMapEntry {
+    /// Path relative to the `Nintendo 3DS/<ID0>/<ID1>` directory, separated by `/`,
+    /// for example `title/00040000/00012345/data/00000001.sav`.
+    pub path: String,
+
+    /// Whether the entry is a directory.
+    pub is_dir: bool,
+
+    /// Length of the file. 0 for directories.
+    pub len: u64,
+
+    /// The magic at offset 0x100 of the decrypted file, if it is one of `DISA` (save data),
+    /// `DIFF` (extdata files and title databases) or `NCCH` (title contents).
+    /// `None` for directories and files without a recognized magic.
+    pub magic: Option<String>,
+}
+
+/// The structure of the SD tree as seen with the given `movable.sed`.
+/// See [`Resource::sd_map`](struct.Resource.html#method.sd_map).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SdMap {
+    /// Name of the ID0 directory, derived from `movable.sed`.
+    pub id0: String,
+
+    /// Name of the ID1 directory in use.
+    pub id1: String,
+
+    /// All directories and files under `Nintendo 3DS/<ID0>/<ID1>`, in depth-first order.
+    pub entries: Vec<SdMapEntry>,
+}
+
+/// Where a stand-alone save data is going to be put back, which decides how its header is signed.
+/// See [`Resource::sign_bare_save`](struct.Resource.html#method.sign_bare_save).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BareSignTarget {
+    /// Save data on SD, with the title ID. Needs `movable.sed` and boot9.
+    Sd(u64),
+
+    /// Save data on NAND, with the save ID. Needs `movable.sed` and boot9.
+    Nand(u32),
+
+    /// Save data on cartridge, decrypted and without wear leveling. Needs the game, the
+    /// cartridge private header and boot9, the same as `open_cart_save`.
+    Cart,
+}
+
 /// Represents all resource associated with a 3DS console.
 /// Works as the root object to access all archives on the console.
+///
+/// Archives opened with `write: false` never write to, or flush, their host files at any layer,
+/// so files on read-only media can be opened and verified. If a layer would need to write to
+/// proceed, the operation fails with `Error::ReadOnly`.
 pub struct Resource {
     sd: Option<Rc<Sd>>,
     nand: Option<Rc<Nand>>,
@@ -64,6 +363,13 @@ pub struct Resource {
     x2f_key_y: Option<[u8; 16]>,
     x19_key_x: Option<[u8; 16]>,
     x1a_key_x: Option<[u8; 16]>,
+    secure_info: Option<SecureInfo>,
+    lfcs: Option<u64>,
+    memory_limit: usize,
+    fill_byte: u8,
+    cache_pages: usize,
+    write_buffer_len: usize,
+    overlay: Option<Rc<Overlay>>,
 }
 
 impl Resource {
@@ -80,6 +386,7 @@ impl Resource {
     /// - `x2f_key_y`: key Y of AES engine slot 0x2F.
     /// - `x19_key_x`: key X of AES engine slot 0x19.
     /// - `x1a_key_x`: key X of AES engine slot 0x1A.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         boot9_path: Option<String>,
         movable_path: Option<String>,
@@ -157,6 +464,42 @@ impl Resource {
             None
         };
 
+        // SecureInfo_B and LocalFriendCodeSeed_A are the backup copies
+        let (secure_info, lfcs) = if let Some(nand_path) = &nand_path {
+            let secure_info =
+                read_nand_sys_file(nand_path, &["SecureInfo_A", "SecureInfo_B"], 0x111)?.map(
+                    |data| SecureInfo {
+                        region: data[0x100],
+                        serial: data[0x102..0x111]
+                            .iter()
+                            .take_while(|&&c| c != 0)
+                            .map(|&c| c as char)
+                            .collect(),
+                    },
+                );
+            let lfcs = read_nand_sys_file(
+                nand_path,
+                &["LocalFriendCodeSeed_B", "LocalFriendCodeSeed_A"],
+                0x110,
+            )?
+            .map(|data| {
+                let mut lfcs = [0; 8];
+                lfcs.copy_from_slice(&data[0x108..0x110]);
+                u64::from_le_bytes(lfcs)
+            });
+            (secure_info, lfcs)
+        } else {
+            (None, None)
+        };
+
+        // The first half of the movable.sed key Y is a copy of LocalFriendCodeSeed. If they
+        // differ, the ID0 derived from the key Y won't match the directories of this console.
+        if let (Some(lfcs), Some(key_y)) = (lfcs, key_y) {
+            if lfcs.to_le_bytes() != key_y[0..8] {
+                return make_error(Error::LfcsMismatch);
+            }
+        }
+
         let id0 = key_y.map(hash_movable);
 
         let key_sign = (|| Some(scramble(key_x_sign?, key_y?)))();
@@ -242,9 +585,106 @@ impl Resource {
             x2f_key_y,
             x19_key_x,
             x1a_key_x,
+            secure_info,
+            lfcs,
+            memory_limit: scratch_file::DEFAULT_MEMORY_LIMIT,
+            fill_byte: ivfc_level::DEFAULT_FILL,
+            cache_pages: aes_ctr_file::DEFAULT_CACHE_PAGES,
+            write_buffer_len: disk_file::DEFAULT_WRITE_BUFFER_LEN,
+            overlay: None,
         })
     }
 
+    /// Sets the largest temporary image, such as the scratch format made by `format_preflight`
+    /// or the new image built by `trim_bare_save`, that is kept in memory. Larger ones are kept
+    /// in a temporary host file instead, so that huge images don't exhaust the memory. 64 MiB is
+    /// used if this is never called.
+    pub fn set_memory_limit(&mut self, len: usize) {
+        self.memory_limit = len;
+    }
+
+    /// Sets the byte that fills data failing hash verification, such as uninitialized file
+    /// data, in archives opened afterwards. `0xDD` is used if this is never called.
+    /// See [`SaveData::set_fill_byte`](save_data/struct.SaveData.html#method.set_fill_byte).
+    pub fn set_fill_byte(&mut self, fill: u8) {
+        self.fill_byte = fill;
+    }
+
+    /// Sets how many 512-byte pages of decrypted data each encrypted file opened afterwards
+    /// caches to serve repeated reads. This covers cartridge saves, the game read for their
+    /// keys, and the files of SD archives. NAND files are not encrypted. `0` disables the cache
+    /// for memory-constrained callers. 16 pages are cached if this is never called.
+    /// See [`CartSaveData::set_cache_pages`](cart_save_data/struct.CartSaveData.html#method.set_cache_pages).
+    pub fn set_cache_pages(&mut self, pages: usize) {
+        self.cache_pages = pages;
+        if let Some(sd) = &self.sd {
+            sd.set_cache_pages(pages);
+        }
+    }
+
+    /// Sets how many bytes of writes to each host file opened afterwards are buffered, merging
+    /// adjacent small writes, before they reach the file. The rest is written on commit.
+    /// `0` makes every write reach the file immediately. 64 KiB is used if this is never called.
+    pub fn set_write_buffer_len(&mut self, len: usize) {
+        self.write_buffer_len = len;
+        if let Some(sd) = &self.sd {
+            sd.set_write_buffer_len(len);
+        }
+        if let Some(nand) = &self.nand {
+            nand.set_write_buffer_len(len);
+        }
+    }
+
+    /// Sets the overlay that keeps the changes of archives opened afterwards in memory, until
+    /// they are persisted or discarded through it. Archives opened for reading over the overlay
+    /// show the content last persisted. `None` opens host files directly, which is the default.
+    /// See [`Overlay`](overlay/struct.Overlay.html).
+    pub fn set_overlay(&mut self, overlay: Option<Rc<Overlay>>) {
+        if let Some(sd) = &self.sd {
+            sd.set_overlay(overlay.clone());
+        }
+        if let Some(nand) = &self.nand {
+            nand.set_overlay(overlay.clone());
+        }
+        self.overlay = overlay;
+    }
+
+    /// Returns the console information from `rw/sys/SecureInfo_A` (or its backup
+    /// `SecureInfo_B`) of the NAND. Fails with `Error::MissingSecureInfo` if no NAND is given or
+    /// the NAND dump doesn't include the file.
+    pub fn secure_info(&self) -> Result<SecureInfo, Error> {
+        self.secure_info.clone().ok_or(Error::MissingSecureInfo)
+    }
+
+    /// Returns the LocalFriendCodeSeed from `rw/sys/LocalFriendCodeSeed_B` (or its backup
+    /// `LocalFriendCodeSeed_A`) of the NAND. Fails with `Error::MissingLfcs` if no NAND is given
+    /// or the NAND dump doesn't include the file.
+    ///
+    /// When it is present, `new` also checks it against `movable.sed` of the same NAND, and fails
+    /// with `Error::LfcsMismatch` if they are from different consoles.
+    pub fn local_friend_code_seed(&self) -> Result<u64, Error> {
+        self.lfcs.ok_or(Error::MissingLfcs)
+    }
+
+    /// Reports which kinds of archive can be opened with the resources provided.
+    ///
+    /// Opening an archive not listed as available fails with one of the `Missing*` errors.
+    pub fn capabilities(&self) -> Capabilities {
+        let sd = self.sd.is_some() && self.key_sign.is_some();
+        let nand = self.nand.is_some() && self.id0.is_some() && self.key_sign.is_some();
+        Capabilities {
+            sd_save: sd,
+            sd_ext: sd,
+            sd_db: sd,
+            nand_save: nand,
+            nand_ext: nand,
+            nand_db: self.nand.is_some() && self.key_db.is_some(),
+            cart_save: self.game_path.is_some()
+                && self.cart_id_long.is_some()
+                && self.key_x_ncch.is_some(),
+        }
+    }
+
     /// Formats an extdata on SD.
     pub fn format_sd_ext(&self, id: u64, param: &ExtDataFormatParam) -> Result<(), Error> {
         ExtData::format(
@@ -259,14 +699,15 @@ impl Resource {
 
     /// Opens an extdata on SD.
     pub fn open_sd_ext(&self, id: u64, write: bool) -> Result<ExtData, Error> {
-        ExtData::new(
+        check_sd_key(ExtData::new(
             self.sd.as_ref().ok_or(Error::MissingSd)?.clone(),
             &["extdata"],
             id,
             self.key_sign.ok_or(Error::MissingBoot9)?,
             false,
             write,
-        )
+        ))
+        .inspect(|archive| archive.set_fill_byte(self.fill_byte))
     }
 
     /// Formats a save data on SD.
@@ -305,10 +746,52 @@ impl Resource {
             .ok_or(Error::MissingSd)?
             .open(&sub_path, write)?;
 
-        SaveData::new(
+        check_sd_key(SaveData::new(
             dec_file,
             SaveDataType::Sd(self.key_sign.ok_or(Error::MissingBoot9)?, id),
-        )
+            write,
+        ))
+        .inspect(|archive| archive.set_fill_byte(self.fill_byte))
+    }
+
+    /// Reads the file at `path` in the SD tree and returns its decrypted content.
+    ///
+    /// `path` is relative to the `Nintendo 3DS/<ID0>/<ID1>` directory, for example
+    /// `/title/00040000/00012345/data/00000001.sav`. Only the SD encryption layer is removed,
+    /// so this works for any file in the tree, and the result can be handed to other tools.
+    pub fn decrypt_sd_file(&self, path: &str) -> Result<Vec<u8>, Error> {
+        let sub_path: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let file = self
+            .sd
+            .as_ref()
+            .ok_or(Error::MissingSd)?
+            .open(&sub_path, false)?;
+        let mut data = vec![0; file.len()];
+        file.read(0, &mut data)?;
+        Ok(data)
+    }
+
+    /// Walks the SD tree without opening any archive, and checks whether each file decrypts
+    /// to a recognized magic. For diagnosing an SD setup: if most files have no recognized
+    /// magic, `movable.sed` likely doesn't belong to this SD card.
+    pub fn sd_map(&self) -> Result<SdMap, Error> {
+        let sd = self.sd.as_ref().ok_or(Error::MissingSd)?;
+        Ok(SdMap {
+            id0: self.id0.clone().ok_or(Error::MissingSd)?,
+            id1: sd.id1(),
+            entries: sd.map()?,
+        })
+    }
+
+    /// Encrypts `data` and writes it to the file at `path` in the SD tree, replacing the file
+    /// if it exists. This is the reverse of `decrypt_sd_file`.
+    pub fn encrypt_sd_file(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        let sub_path: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let sd = self.sd.as_ref().ok_or(Error::MissingSd)?;
+        sd.create(&sub_path, data.len())?;
+        let file = sd.open(&sub_path, true)?;
+        file.write(0, data)?;
+        file.commit()
     }
 
     /// Formats a save data on NAND.
@@ -354,7 +837,50 @@ impl Resource {
         SaveData::new(
             file,
             SaveDataType::Nand(self.key_sign.ok_or(Error::MissingBoot9)?, id),
+            write,
         )
+        .inspect(|archive| archive.set_fill_byte(self.fill_byte))
+    }
+
+    /// Lists the IDs of all save data on NAND, in ascending order.
+    /// Directories whose names are not save IDs are ignored.
+    pub fn list_nand_saves(&self) -> Result<Vec<u32>, Error> {
+        let nand = self.nand.as_ref().ok_or(Error::MissingNand)?;
+        let id0 = self.id0.as_ref().ok_or(Error::MissingNand)?;
+        let mut ids: Vec<u32> = nand
+            .list(&["data", id0, "sysdata"])?
+            .iter()
+            .filter(|name| name.len() == 8)
+            .filter_map(|name| u32::from_str_radix(name, 16).ok())
+            .collect();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// Lists the IDs of all extdata on NAND, in ascending order.
+    /// Directories whose names are not extdata IDs are ignored.
+    pub fn list_nand_exts(&self) -> Result<Vec<u64>, Error> {
+        let nand = self.nand.as_ref().ok_or(Error::MissingNand)?;
+        let id0 = self.id0.as_ref().ok_or(Error::MissingNand)?;
+        let parse = |name: &str| {
+            if name.len() == 8 {
+                u32::from_str_radix(name, 16).ok()
+            } else {
+                None
+            }
+        };
+        let mut ids = vec![];
+        for high in nand.list(&["data", id0, "extdata"])? {
+            if let Some(id_high) = parse(&high) {
+                for low in nand.list(&["data", id0, "extdata", &high])? {
+                    if let Some(id_low) = parse(&low) {
+                        ids.push((u64::from(id_high) << 32) | u64::from(id_low));
+                    }
+                }
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
     }
 
     /// Formats an extdata on NAND.
@@ -374,6 +900,10 @@ impl Resource {
     }
 
     /// Opens an extdata on NAND.
+    ///
+    /// If the `Quota.dat` file of the extdata is missing, the extdata is opened read-only
+    /// without quota accounting regardless of `write`, and operations that create, resize or
+    /// delete files return `Error::Unsupported`.
     pub fn open_nand_ext(&self, id: u64, write: bool) -> Result<ExtData, Error> {
         ExtData::new(
             self.nand.as_ref().ok_or(Error::MissingNand)?.clone(),
@@ -387,14 +917,28 @@ impl Resource {
             true,
             write,
         )
+        .inspect(|archive| archive.set_fill_byte(self.fill_byte))
+    }
+
+    /// Formats a shared extdata on NAND. `id` is the lower half of the extdata ID, e.g.
+    /// `0xF000000B`. See [`SHARED_EXT_ID_HIGH`](constant.SHARED_EXT_ID_HIGH.html).
+    pub fn format_shared_ext(&self, id: u32, param: &ExtDataFormatParam) -> Result<(), Error> {
+        self.format_nand_ext(shared_ext_id(id), param)
+    }
+
+    /// Opens a shared extdata on NAND. `id` is the lower half of the extdata ID, e.g.
+    /// `0xF000000B`. See [`SHARED_EXT_ID_HIGH`](constant.SHARED_EXT_ID_HIGH.html).
+    ///
+    /// This is the same as `open_nand_ext` with the full ID.
+    pub fn open_shared_ext(&self, id: u32, write: bool) -> Result<ExtData, Error> {
+        self.open_nand_ext(shared_ext_id(id), write)
     }
 
     /// Formats a stand-alone save data.
     ///
     /// Warning: because no crypto information can be provided for a stand-alone save data,
     /// save data created by this function never has a correct signature, and must be fixed
-    /// using other tools to be usable on 3DS. Because of this limitation, this function is
-    /// mostly for test purpose.
+    /// with `sign_bare_save` to be usable on 3DS.
     pub fn format_bare_save(
         &self,
         path: &str,
@@ -403,72 +947,302 @@ impl Resource {
     ) -> Result<(), Error> {
         std::fs::File::create(path)?.set_len(len as u64)?;
 
-        let file = Rc::new(DiskFile::new(
-            std::fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(path)?,
-        )?);
+        let file = self.open_host_file(path, true)?;
 
         SaveData::format(file, SaveDataType::Bare, param)?;
 
         Ok(())
     }
 
+    /// Checks whether a save data of `len` bytes can be formatted with `param`, and reports the
+    /// resulting capacity, without writing anything.
+    ///
+    /// `len` is the same value as passed to `format_bare_save`, `format_sd_save` and
+    /// `format_nand_save`. For a Card1 cartridge save, it is the image size after the
+    /// wear-leveling layer, which is smaller than the chip size. The scratch format is kept in
+    /// the temporary directory if it exceeds the limit set by `set_memory_limit`.
+    pub fn format_preflight(
+        &self,
+        param: &SaveDataFormatParam,
+        len: usize,
+    ) -> Result<FormatReport, Error> {
+        SaveData::format_preflight(param, len, self.memory_limit)
+    }
+
     /// Opens a stand-alone save data.
     ///
     /// Warning: because no crypto information can be provided for a stand-alone save data,
     /// the signature will be invalid if the content of the save data is modified, and must be
-    /// fixed using other tools to be usable on 3DS. Because of this limitation, this function is
-    /// mostly for test purpose.
+    /// fixed with `sign_bare_save` to be usable on 3DS.
+    ///
+    /// If the file is larger than the DISA image recorded in its header, the bytes after the image
+    /// are ignored and preserved.
+    ///
+    /// With `write` unset, the file is only locked shared, so it can be opened read-only any
+    /// number of times at once, in this process or in others.
     pub fn open_bare_save(&self, path: &str, write: bool) -> Result<SaveData, Error> {
-        let file = Rc::new(DiskFile::new(
+        SaveData::new(self.open_bare_file(path, write)?, SaveDataType::Bare, write)
+            .inspect(|archive| archive.set_fill_byte(self.fill_byte))
+    }
+
+    /// Opens the stand-alone save data at `path`, formatting it first with `param` and `len` if
+    /// the file doesn't exist.
+    ///
+    /// If the file exists but can't be opened as a save data, the error is returned and the file
+    /// is left untouched, unless `force` is set, in which case the file is formatted over.
+    /// Host IO errors are always returned, even with `force`.
+    ///
+    /// The same warning as `format_bare_save` and `open_bare_save` applies.
+    pub fn open_or_format_bare_save(
+        &self,
+        path: &str,
+        param: &SaveDataFormatParam,
+        len: usize,
+        write: bool,
+        force: bool,
+    ) -> Result<SaveData, Error> {
+        if Path::new(path).exists() {
+            match self.open_bare_save(path, write) {
+                Err(Error::IO(e)) => return Err(Error::IO(e)),
+                Err(e) if !force => return Err(e),
+                Err(e) => warn!("Formatting over {} that fails to open: {}", path, e),
+                result => return result,
+            }
+        }
+        self.format_bare_save(path, param, len)?;
+        self.open_bare_save(path, write)
+    }
+
+    /// Shrinks the stand-alone save data at `path` to the smallest image that holds its
+    /// current content, and returns the new length of the DISA image.
+    ///
+    /// The save data is reformatted with the same parameters and just enough data blocks for
+    /// the blocks in use, and the file tree is copied into it, so inodes may change. Bytes after
+    /// the DISA image are kept after the new image. Nothing is changed if the image is already
+    /// minimal, and the file is only written once the new image is complete, which fails with
+    /// `Error::NoSpace` if the content doesn't fit. The new image is built in memory, or in a
+    /// temporary file next to `path` if it exceeds the limit set by `set_memory_limit`.
+    ///
+    /// The same warning as `format_bare_save` and `open_bare_save` applies.
+    pub fn trim_bare_save(&self, path: &str) -> Result<usize, Error> {
+        use random_access_file::*;
+        let disk = Rc::new(DiskFile::new(
             std::fs::OpenOptions::new()
                 .read(true)
-                .write(write)
+                .write(true)
                 .open(path)?,
+            true,
         )?);
-
-        SaveData::new(file, SaveDataType::Bare)
+        let old_len = disa::Disa::image_len(disk.as_ref())?;
+        let save = SaveData::new(
+            Rc::new(sub_file::SubFile::new(disk.clone(), 0, old_len)?),
+            SaveDataType::Bare,
+            false,
+        )?;
+        save.set_fill_byte(self.fill_byte);
+        let new_len = save.trimmed_len()?;
+        if new_len >= old_len {
+            return Ok(old_len);
+        }
+        let dir = match Path::new(path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let image = Rc::new(scratch_file::ScratchFile::new(
+            new_len,
+            self.memory_limit,
+            dir,
+        )?);
+        save.trim_into(image.clone())?;
+        drop(save);
+
+        // The trailer moves towards the beginning, so copying forward never overwrites what is
+        // yet to be copied
+        let mut buf = vec![0; COPY_CHUNK_LEN];
+        for pos in (0..new_len).step_by(COPY_CHUNK_LEN) {
+            let chunk = &mut buf[..std::cmp::min(COPY_CHUNK_LEN, new_len - pos)];
+            image.read(pos, chunk)?;
+            disk.write(pos, chunk)?;
+        }
+        drop(image);
+        let trailer_len = disk.len() - old_len;
+        for pos in (0..trailer_len).step_by(COPY_CHUNK_LEN) {
+            let chunk = &mut buf[..std::cmp::min(COPY_CHUNK_LEN, trailer_len - pos)];
+            disk.read(old_len + pos, chunk)?;
+            disk.write(new_len + pos, chunk)?;
+        }
+        disk.truncate(new_len + trailer_len)?;
+        disk.commit()?;
+        info!(
+            "Trimmed {} from 0x{:X} to 0x{:X} bytes",
+            path, old_len, new_len
+        );
+        Ok(new_len)
     }
 
-    fn get_cart_format(&self) -> Result<CartFormat, Error> {
-        let game = disk_file::DiskFile::new(std::fs::File::open(
-            self.game_path.as_ref().ok_or(Error::MissingGame)?,
-        )?)?;
+    /// Same as `open_bare_save`, but also opens a save data whose FAT has more entries than
+    /// its data region has blocks, ignoring the extra entries with a warning. Such slack is
+    /// benign as long as nothing in the FAT refers to it, which is checked as the FAT is used.
+    pub fn open_bare_save_allow_inconsistent(
+        &self,
+        path: &str,
+        write: bool,
+    ) -> Result<SaveData, Error> {
+        SaveData::new_allow_inconsistent(
+            self.open_bare_file(path, write)?,
+            SaveDataType::Bare,
+            write,
+        )
+        .inspect(|archive| archive.set_fill_byte(self.fill_byte))
+    }
 
-        use byte_struct_common::*;
-        use random_access_file::*;
-        if read_struct::<Magic>(&game, 0x100)?.v != *b"NCSD" {
-            return Err(Error::BrokenGame);
-        }
+    /// Opens a stand-alone save data read-only, using the DISA partition table copy
+    /// `table_index` (0 for primary, 1 for secondary) instead of the active one.
+    ///
+    /// This is for forensics only. The non-active copy usually holds the state before the
+    /// last commit, and may allow recovering the previous content if the active copy points
+    /// at corrupted data. See [`SaveData::active_table`](save_data/struct.SaveData.html#method.active_table).
+    pub fn open_bare_save_with_table(
+        &self,
+        path: &str,
+        table_index: usize,
+    ) -> Result<SaveData, Error> {
+        SaveData::new_with_table(
+            self.open_bare_file(path, false)?,
+            SaveDataType::Bare,
+            table_index,
+            false,
+        )
+        .inspect(|archive| archive.set_fill_byte(self.fill_byte))
+    }
 
-        let mut cci_flags = [0; 8];
-        game.read(0x188, &mut cci_flags)?;
+    /// Opens a stand-alone save data read-only, interpreting its FAT and data region with the
+    /// block length of `block_type` instead of the recorded one.
+    ///
+    /// This is a recovery tool for saves whose recorded block length is corrupted while the
+    /// data is intact. The recorded values can be checked with `dump_headers`. A wrong
+    /// `block_type` produces garbage, or fails to open.
+    pub fn open_bare_save_with_block_type(
+        &self,
+        path: &str,
+        block_type: SaveDataBlockType,
+    ) -> Result<SaveData, Error> {
+        SaveData::new_with_block_type(
+            self.open_bare_file(path, false)?,
+            SaveDataType::Bare,
+            block_type,
+        )
+        .inspect(|archive| archive.set_fill_byte(self.fill_byte))
+    }
 
-        let wear_leveling = match cci_flags[5] {
-            1 => true,
-            2 => false,
-            _ => return Err(Error::BrokenGame),
+    /// Recalculates the CMAC of the stand-alone save data at `path` for `target`, so that it can
+    /// be put back to where it belongs, and be opened there as a normal save data.
+    ///
+    /// This only signs the header, which covers the rest of the image by hashes. The save data
+    /// itself is not verified, and should have been committed.
+    pub fn sign_bare_save(&self, path: &str, target: BareSignTarget) -> Result<(), Error> {
+        let save_data_type = match target {
+            BareSignTarget::Sd(id) => {
+                SaveDataType::Sd(self.key_sign.ok_or(Error::MissingBoot9)?, id)
+            }
+            BareSignTarget::Nand(id) => {
+                SaveDataType::Nand(self.key_sign.ok_or(Error::MissingBoot9)?, id)
+            }
+            BareSignTarget::Cart => SaveDataType::Cart(self.get_cart_format()?.key_cmac),
         };
+        SaveData::sign(self.open_bare_file(path, true)?, save_data_type)
+    }
 
-        let cxi_offset = read_struct::<U32le>(&game, 0x120)?.v * 0x200;
-        let cxi_len = read_struct::<U32le>(&game, 0x124)?.v * 0x200;
-        let cxi = sub_file::SubFile::new(Rc::new(game), cxi_offset as usize, cxi_len as usize)?;
+    /// Reads the title information from the CCI/NCSD game image at `game_path`.
+    ///
+    /// This doesn't need any key, and can be used to check which game a cartridge save
+    /// belongs to before opening it.
+    pub fn cart_info(game_path: &str) -> Result<CartInfo, Error> {
+        let game = DiskFile::new(std::fs::File::open(game_path)?, false)?;
+        Ok(read_cart_info(&game)?.0)
+    }
 
-        if read_struct::<Magic>(&cxi, 0x100)?.v != *b"NCCH" {
-            return Err(Error::BrokenGame);
+    /// Fails early if the keys only New 3DS exclusive cartridges use are missing, naming the
+    /// missing keys, instead of failing later on some other missing input.
+    fn check_cart_keys(&self, crypto_version: u8) -> Result<(), Error> {
+        if crypto_version != 9 {
+            return Ok(());
+        }
+        match (self.x19_key_x, self.x1a_key_x) {
+            (None, None) => make_error(Error::MissingKeyX19X1A),
+            (None, Some(_)) => make_error(Error::MissingKeyX19),
+            (Some(_), None) => make_error(Error::MissingKeyX1A),
+            (Some(_), Some(_)) => Ok(()),
         }
+    }
 
-        let mut program_id = [0; 8];
-        cxi.read(0x118, &mut program_id)?;
+    /// Opens a host file, with writes buffered as set by `set_write_buffer_len`, through the
+    /// overlay set by `set_overlay` if any.
+    fn open_host_file(
+        &self,
+        path: &str,
+        write: bool,
+    ) -> Result<Rc<dyn random_access_file::RandomAccessFile>, Error> {
+        let open = |write| {
+            let file = DiskFile::new(
+                std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(write)
+                    .open(path)?,
+                write,
+            )?;
+            file.set_write_buffer_len(self.write_buffer_len);
+            Ok(file.into_shared())
+        };
+        match &self.overlay {
+            Some(overlay) => overlay.open(Path::new(path), write, open),
+            None => open(write),
+        }
+    }
+
+    /// Opens the host file of a stand-alone save data, limited to the DISA image recorded in its
+    /// header. Dumped saves may contain padding or footers after the image, which are ignored here
+    /// and left untouched when the save data is written to.
+    fn open_bare_file(
+        &self,
+        path: &str,
+        write: bool,
+    ) -> Result<Rc<dyn random_access_file::RandomAccessFile>, Error> {
+        let file = self.open_host_file(path, write)?;
+        let len = disa::Disa::image_len(file.as_ref())?;
+        if len >= file.len() {
+            return Ok(file);
+        }
+        info!(
+            "Ignoring 0x{:X} bytes after the DISA image of 0x{:X} bytes",
+            file.len() - len,
+            len
+        );
+        Ok(Rc::new(sub_file::SubFile::new(file, 0, len)?))
+    }
+
+    fn get_cart_format(&self) -> Result<CartFormat, Error> {
+        let game = disk_file::DiskFile::new(
+            std::fs::File::open(self.game_path.as_ref().ok_or(Error::MissingGame)?)?,
+            false,
+        )?
+        .into_shared();
+
+        use byte_struct_common::*;
+        use random_access_file::*;
+        let (info, cxi_offset, cxi_len) = read_cart_info(game.as_ref())?;
+        self.check_cart_keys(info.crypto_version)?;
+        let cxi = sub_file::SubFile::new(game, cxi_offset, cxi_len)?;
+
+        let wear_leveling = info.wear_leveling;
+        let program_id = info.program_id.to_le_bytes();
+        let ncch_version = info.ncch_version;
 
         let mut key_y_ncch = [0; 16];
         cxi.read(0, &mut key_y_ncch)?;
         let ncch_key =
             key_engine::scramble(self.key_x_ncch.ok_or(Error::MissingBoot9)?, key_y_ncch);
 
-        let ncch_version = read_struct::<U16le>(&cxi, 0x112)?.v;
         let exefs_offset = read_struct::<U32le>(&cxi, 0x1A0)?.v * 0x200;
 
         // CTR calculation below should use partition ID.
@@ -498,6 +1272,7 @@ impl Resource {
             ctr_exheader,
             false,
         );
+        exheader.set_cache_pages(self.cache_pages);
         let mut exheader_signature = [0; 8];
         exheader.read(0x400, &mut exheader_signature)?;
 
@@ -507,6 +1282,7 @@ impl Resource {
             ctr_exefs,
             false,
         );
+        exefs.set_cache_pages(self.cache_pages);
 
         let mut exefs_hash = [0; 32];
         let mut found = false;
@@ -522,22 +1298,14 @@ impl Resource {
             return Err(Error::BrokenGame);
         }
 
-        let crypto_version = if cci_flags[7] != 0 {
-            2
-        } else if cci_flags[3] != 0 {
-            match cci_flags[1] {
-                0 => 2,
-                1 => 6,
-                10 => 9,
-                _ => return Err(Error::BrokenGame),
-            }
-        } else {
-            0
-        };
+        let crypto_version = info.crypto_version;
 
         let mut key_y = [0; 16];
         let mut repeat_ctr = false;
         match crypto_version {
+            // Before system version 2.0. The key Y is used as is, and the keystream of the save
+            // repeats every 0x200 bytes, which is how the 3DS wrote these saves and so must be
+            // emulated to read them, rather than a flaw of this library
             0 => {
                 let mut key_y_block = vec![];
                 key_y_block.extend_from_slice(&exheader_signature);
@@ -545,8 +1313,7 @@ impl Resource {
                 key_y[..].copy_from_slice(&key_y_block[..]);
                 repeat_ctr = true;
             }
-            2 | 9 => {
-                // TODO: version 9 is unverified yet
+            2 => {
                 let mut key_y_block = vec![];
                 key_y_block.extend_from_slice(&exheader_signature);
                 key_y_block.extend_from_slice(&self.cart_id_long.ok_or(Error::MissingPriv)?);
@@ -558,7 +1325,9 @@ impl Resource {
                 key_y[..].copy_from_slice(&hash[0..16]);
                 // TODO: is there case where repeat_ctr = true for version 2?
             }
-            6 => {
+            // Version 9 (New 3DS exclusive) derives the key Y in the same way as version 6,
+            // but uses the key X of slot 0x1A and 0x19 instead, below
+            6 | 9 => {
                 let mut key_y_block = vec![];
                 key_y_block.extend_from_slice(&exheader_signature);
                 key_y_block.extend_from_slice(&self.cart_id_long.ok_or(Error::MissingPriv)?);
@@ -614,12 +1383,7 @@ impl Resource {
     ) -> Result<(), Error> {
         std::fs::File::create(path)?.set_len(len as u64)?;
 
-        let file = Rc::new(DiskFile::new(
-            std::fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(path)?,
-        )?);
+        let file = self.open_host_file(path, true)?;
 
         CartSaveData::format(file, &self.get_cart_format()?, param)?;
 
@@ -628,18 +1392,80 @@ impl Resource {
 
     /// Opens a save data on cartridge.
     pub fn open_cart_save(&self, path: &str, write: bool) -> Result<CartSaveData, Error> {
-        let file = Rc::new(DiskFile::new(
-            std::fs::OpenOptions::new()
-                .read(true)
-                .write(write)
-                .open(path)?,
-        )?);
+        let file = self.open_host_file(path, write)?;
+
+        CartSaveData::new(file, &self.get_cart_format()?, write).inspect(|archive| {
+            archive.set_fill_byte(self.fill_byte);
+            archive.set_cache_pages(self.cache_pages);
+        })
+    }
 
-        CartSaveData::new(file, &self.get_cart_format()?)
+    /// Opens a save data on cartridge as a flat image, skipping the wear-leveling layer even if
+    /// the cartridge chip type uses one.
+    ///
+    /// This is for Card1 save dumps whose wear-leveling layer has already been removed by other
+    /// tools. If the image doesn't look like a save data, the error is logged with a hint that it
+    /// may need the wear-leveling layer, i.e. `open_cart_save`.
+    pub fn open_cart_save_flat(&self, path: &str, write: bool) -> Result<CartSaveData, Error> {
+        let file = self.open_host_file(path, write)?;
+
+        let format = CartFormat {
+            wear_leveling: false,
+            ..self.get_cart_format()?
+        };
+        CartSaveData::new(file, &format, write)
+            .inspect(|archive| {
+                archive.set_fill_byte(self.fill_byte);
+                archive.set_cache_pages(self.cache_pages);
+            })
+            .map_err(|e| {
+                if let Error::MagicMismatch | Error::SignatureMismatch = e {
+                    error!(
+                        "{} doesn't contain a flat save data. It may need wear leveling",
+                        path
+                    );
+                }
+                e
+            })
+    }
+
+    /// Checks the inputs `open_db` needs for `db_type`, and returns an error naming the first
+    /// missing one.
+    fn check_db_resources(&self, db_type: DbType) -> Result<(), Error> {
+        match db_type {
+            DbType::SdTitle | DbType::SdImport => {
+                if self.key_x_sign.is_none() {
+                    return make_error(Error::MissingBoot9);
+                }
+                if self.id0.is_none() {
+                    return make_error(Error::MissingMovable);
+                }
+                if self.sd.is_none() {
+                    return make_error(Error::MissingSd);
+                }
+            }
+            _ => {
+                if self.nand.is_none() {
+                    return make_error(Error::MissingNand);
+                }
+                // Resource::new already requires boot9 for the OTP
+                if self.key_db.is_none() {
+                    return make_error(Error::MissingOtp);
+                }
+            }
+        }
+        Ok(())
     }
 
     /// Opens a title database.
+    ///
+    /// Databases on NAND are signed with a key derived from the OTP, so they need the NAND,
+    /// the OTP and boot9. Databases on SD are signed with the same key as SD save data, so they
+    /// need the SD, movable.sed and boot9. If any of them is missing, `Error::MissingNand`,
+    /// `Error::MissingOtp`, `Error::MissingSd`, `Error::MissingMovable` or `Error::MissingBoot9`
+    /// is returned accordingly, before touching any file.
     pub fn open_db(&self, db_type: DbType, write: bool) -> Result<Db, Error> {
+        self.check_db_resources(db_type)?;
         let (file, key) = match db_type {
             DbType::NandTitle => (
                 self.nand
@@ -692,6 +1518,1303 @@ impl Resource {
             ),
         };
 
-        Db::new(file, db_type, key)
+        let db = Db::new(file, db_type, key, write)
+            .inspect(|archive| archive.set_fill_byte(self.fill_byte));
+        match db_type {
+            DbType::SdTitle | DbType::SdImport => check_sd_key(db),
+            _ => db,
+        }
+    }
+
+    /// Opens any archive described by `spec`, as a trait object.
+    pub fn open_any(
+        &self,
+        spec: &ArchiveSpec,
+        write: bool,
+    ) -> Result<Box<dyn DynFileSystem>, Error> {
+        Ok(match spec {
+            ArchiveSpec::SdSave(id) => Box::new(self.open_sd_save(*id, write)?),
+            ArchiveSpec::NandSave(id) => Box::new(self.open_nand_save(*id, write)?),
+            ArchiveSpec::BareSave(path) => Box::new(self.open_bare_save(path, write)?),
+            ArchiveSpec::CartSave(path) => Box::new(self.open_cart_save(path, write)?),
+            ArchiveSpec::SdExt(id) => Box::new(self.open_sd_ext(*id, write)?),
+            ArchiveSpec::NandExt(id) => Box::new(self.open_nand_ext(*id, write)?),
+            ArchiveSpec::Db(db_type) => Box::new(self.open_db(*db_type, write)?),
+        })
+    }
+
+    /// Starts a session for opening many archives. See [`Batch`](struct.Batch.html).
+    pub fn batch(&self) -> Batch<'_> {
+        Batch {
+            resource: self,
+            opened: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+/// A session of opening many archives from one `Resource`.
+///
+/// The SD and NAND directories are already scanned once in `Resource::new`, and the SD key is
+/// expanded once, so every open only touches the archive's own files. On top of that, a `Batch`
+/// keeps read-only archives open and hands the same one out again when it is asked for
+/// twice, so that listing and then reading a set of saves opens and verifies each of them once.
+///
+/// Archives opened for writing are never kept. All kept archives are released when the `Batch`
+/// is dropped, or earlier with `release`.
+pub struct Batch<'a> {
+    resource: &'a Resource,
+    opened: RefCell<HashMap<ArchiveSpec, Rc<dyn DynFileSystem>>>,
+}
+
+impl<'a> Batch<'a> {
+    /// Opens the archive described by `spec` read-only, or returns the one opened before.
+    pub fn open(&self, spec: &ArchiveSpec) -> Result<Rc<dyn DynFileSystem>, Error> {
+        if let Some(archive) = self.opened.borrow().get(spec) {
+            return Ok(archive.clone());
+        }
+        let archive: Rc<dyn DynFileSystem> = Rc::from(self.resource.open_any(spec, false)?);
+        self.opened
+            .borrow_mut()
+            .insert(spec.clone(), archive.clone());
+        Ok(archive)
+    }
+
+    /// Opens the archive described by `spec` for writing. The read-only archive kept for
+    /// `spec`, if any, is released first, as it wouldn't see the changes.
+    ///
+    /// Handles to that archive returned by `open` earlier must be dropped before committing.
+    pub fn open_write(&self, spec: &ArchiveSpec) -> Result<Box<dyn DynFileSystem>, Error> {
+        self.release(spec);
+        self.resource.open_any(spec, true)
+    }
+
+    /// Releases the read-only archive kept for `spec`. Returns whether there was one.
+    pub fn release(&self, spec: &ArchiveSpec) -> bool {
+        self.opened.borrow_mut().remove(spec).is_some()
+    }
+
+    /// Returns the number of read-only archives currently kept.
+    pub fn open_count(&self) -> usize {
+        self.opened.borrow().len()
+    }
+
+    /// Returns the `Resource` the archives are opened from.
+    pub fn resource(&self) -> &'a Resource {
+        self.resource
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::*;
+    use crate::*;
+
+    #[test]
+    fn open_any() {
+        let path = TempPath::new("libsave3ds_open_any");
+        let path = path.to_str().unwrap().to_owned();
+        let resource = resource();
+        let param = format_param();
+        resource.format_bare_save(&path, &param, 0x20000).unwrap();
+
+        let save = resource.open_bare_save(&path, true).unwrap();
+        let file = save
+            .open_root()
+            .unwrap()
+            .new_sub_file(<[u8; 16]>::name_str_to_3ds("a").unwrap(), 3)
+            .unwrap();
+        let ino = file.get_ino();
+        drop(file);
+        save.commit().unwrap();
+        drop(save);
+
+        let spec = ArchiveSpec::BareSave(path.clone());
+        let archive = resource.open_any(&spec, true).unwrap();
+        assert_eq!(
+            archive.list_sub_file(1).unwrap(),
+            vec![("a".to_owned(), ino)]
+        );
+        assert!(archive.list_sub_dir(1).unwrap().is_empty());
+        assert_eq!(archive.file_len(ino).unwrap(), 3);
+        archive.write_file(ino, 0, &[1, 2, 3]).unwrap();
+        archive.commit_archive().unwrap();
+        drop(archive);
+
+        let archive = resource.open_any(&spec, false).unwrap();
+        let mut buf = [0; 3];
+        archive.read_file(ino, 0, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+        assert_eq!(archive.stat_archive().unwrap().total_files, 10);
+        drop(archive);
+
+        assert!(resource.open_any(&ArchiveSpec::SdSave(0), false).is_err());
+    }
+
+    #[test]
+    fn trim_bare_save() {
+        let resource = resource();
+        let param = SaveDataFormatParam {
+            duplicate_data: false,
+            ..format_param()
+        };
+        let path = TempPath::new("libsave3ds_trim");
+        let path = path.to_str().unwrap();
+        resource.format_bare_save(path, &param, 0x80000).unwrap();
+        let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let fingerprint = {
+            let save = resource.open_bare_save(path, true).unwrap();
+            let root = save.open_root().unwrap();
+            let dir = root
+                .new_sub_dir(<[u8; 16]>::name_str_to_3ds("dir").unwrap())
+                .unwrap();
+            dir.new_sub_file(<[u8; 16]>::name_str_to_3ds("a").unwrap(), data.len())
+                .unwrap()
+                .write(0, &data)
+                .unwrap();
+            root.new_sub_file(<[u8; 16]>::name_str_to_3ds("empty").unwrap(), 0)
+                .unwrap();
+            // Grows, then shrinks the content
+            let big = root
+                .new_sub_file(<[u8; 16]>::name_str_to_3ds("big").unwrap(), 0x40000)
+                .unwrap();
+            big.delete().unwrap();
+            save.commit().unwrap();
+            save.content_fingerprint().unwrap()
+        };
+
+        // A footer after the image is kept
+        let mut raw = std::fs::read(path).unwrap();
+        raw.extend_from_slice(b"footer");
+        std::fs::write(path, &raw).unwrap();
+
+        // Building the new image on disk gives the same result, and leaves no temporary file
+        let disk_dir = TempPath::new("libsave3ds_trim_disk");
+        std::fs::create_dir(&disk_dir).unwrap();
+        let disk_path = disk_dir.join("save.bin");
+        std::fs::write(&disk_path, &raw).unwrap();
+        let mut disk_resource = test_util::resource();
+        disk_resource.set_memory_limit(0);
+        let disk_len = disk_resource
+            .trim_bare_save(disk_path.to_str().unwrap())
+            .unwrap();
+        assert_eq!(std::fs::read_dir(&disk_dir).unwrap().count(), 1);
+
+        let len = resource.trim_bare_save(path).unwrap();
+        let raw = std::fs::read(path).unwrap();
+        assert_eq!(disk_len, len);
+        assert_eq!(std::fs::read(&disk_path).unwrap(), raw);
+        assert!(len < 0x80000);
+        assert_eq!(raw.len(), len + 6);
+        assert_eq!(&raw[len..], b"footer");
+        assert_eq!(resource.trim_bare_save(path).unwrap(), len);
+
+        let save = resource.open_bare_save(path, true).unwrap();
+        assert_eq!(save.content_fingerprint().unwrap(), fingerprint);
+        assert_eq!(save.format_param().unwrap(), param);
+        let stat = save.stat().unwrap();
+        assert_eq!(stat.free_blocks, 0);
+        let file = save
+            .open_root()
+            .unwrap()
+            .open_sub_dir(<[u8; 16]>::name_str_to_3ds("dir").unwrap())
+            .unwrap()
+            .open_sub_file(<[u8; 16]>::name_str_to_3ds("a").unwrap())
+            .unwrap();
+        assert_eq!(file.read_all().unwrap(), data);
+        drop(file);
+        drop(save);
+
+        // Locked by another open
+        let save = resource.open_bare_save(path, false).unwrap();
+        assert!(matches!(resource.trim_bare_save(path), Err(Error::Busy)));
+        drop(save);
+    }
+
+    #[test]
+    fn open_or_format_bare_save() {
+        let resource = resource();
+        let param = format_param();
+        let path = TempPath::new("libsave3ds_open_or_format");
+        let path = path.to_str().unwrap();
+        let name = <[u8; 16]>::name_str_to_3ds("file").unwrap();
+
+        // Missing
+        let save = resource
+            .open_or_format_bare_save(path, &param, 0x20000, true, false)
+            .unwrap();
+        save.open_root().unwrap().new_sub_file(name, 5).unwrap();
+        save.commit().unwrap();
+        drop(save);
+
+        // Valid
+        let save = resource
+            .open_or_format_bare_save(path, &param, 0x20000, false, false)
+            .unwrap();
+        assert!(save.open_root().unwrap().open_sub_file(name).is_ok());
+        drop(save);
+
+        // Corrupt
+        std::fs::write(path, vec![0x55; 0x20000]).unwrap();
+        assert!(matches!(
+            resource.open_or_format_bare_save(path, &param, 0x20000, true, false),
+            Err(Error::MagicMismatch)
+        ));
+        assert_eq!(std::fs::read(path).unwrap(), vec![0x55; 0x20000]);
+        let save = resource
+            .open_or_format_bare_save(path, &param, 0x20000, true, true)
+            .unwrap();
+        assert!(matches!(
+            save.open_root().unwrap().open_sub_file(name),
+            Err(Error::NotFound)
+        ));
+        drop(save);
+    }
+
+    #[test]
+    fn batch() {
+        let resource = resource();
+        let param = format_param();
+        let paths: Vec<TempPath> = (0..50)
+            .map(|i| TempPath::new(&format!("libsave3ds_batch_{}", i)))
+            .collect();
+        let specs: Vec<ArchiveSpec> = paths
+            .iter()
+            .map(|path| {
+                resource
+                    .format_bare_save(path.as_str(), &param, 0x20000)
+                    .unwrap();
+                ArchiveSpec::BareSave(path.as_str().to_owned())
+            })
+            .collect();
+
+        let batch = resource.batch();
+        let archives: Vec<_> = specs.iter().map(|s| batch.open(s).unwrap()).collect();
+        assert_eq!(batch.open_count(), 50);
+        for (spec, archive) in specs.iter().zip(archives.iter()) {
+            assert!(Rc::ptr_eq(&batch.open(spec).unwrap(), archive));
+        }
+        assert_eq!(batch.open_count(), 50);
+        drop(archives);
+
+        let archive = batch.open_write(&specs[0]).unwrap();
+        assert_eq!(batch.open_count(), 49);
+        let file = archive
+            .create_file(
+                1,
+                &ArchiveName::Bytes(<[u8; 16]>::name_str_to_3ds("a").unwrap()),
+                0,
+            )
+            .unwrap();
+        archive.commit_archive().unwrap();
+        drop(archive);
+        assert_eq!(
+            batch.open(&specs[0]).unwrap().list_sub_file(1).unwrap(),
+            vec![("a".to_owned(), file)]
+        );
+
+        assert!(batch.release(&specs[1]));
+        assert!(!batch.release(&specs[1]));
+        assert_eq!(batch.open_count(), 49);
+        assert!(batch
+            .open(&ArchiveSpec::BareSave(
+                std::env::temp_dir()
+                    .join("libsave3ds_batch_missing")
+                    .to_str()
+                    .unwrap()
+                    .to_owned()
+            ))
+            .is_err());
+        assert_eq!(batch.open_count(), 49);
+    }
+
+    #[test]
+    fn dyn_archive_names() {
+        let resource = resource();
+        let mut paths = vec![];
+        let mut archives: Vec<Box<dyn DynFileSystem>> = vec![];
+        for &block_type in &[SaveDataBlockType::Small, SaveDataBlockType::Large] {
+            let path = TempPath::new(&format!("libsave3ds_dyn_archive_names_{}", paths.len()));
+            let param = SaveDataFormatParam {
+                block_type,
+                ..format_param()
+            };
+            resource
+                .format_bare_save(path.as_str(), &param, 0x80000)
+                .unwrap();
+            archives.push(
+                resource
+                    .open_any(&ArchiveSpec::BareSave(path.as_str().to_owned()), true)
+                    .unwrap(),
+            );
+            paths.push(path);
+        }
+
+        let dir_name = ArchiveName::Bytes(<[u8; 16]>::name_str_to_3ds("dir").unwrap());
+        let file_name = ArchiveName::Bytes(<[u8; 16]>::name_str_to_3ds("a\\x01").unwrap());
+        for archive in &archives {
+            let dir = archive.create_dir(1, &dir_name).unwrap();
+            let file = archive.create_file(dir, &file_name, 2).unwrap();
+            assert_eq!(archive.lookup_dir(1, &dir_name).unwrap(), dir);
+            assert_eq!(archive.lookup_file(dir, &file_name).unwrap(), file);
+            assert_eq!(
+                archive.list_sub_dir_names(1).unwrap(),
+                vec![(dir_name.clone(), dir)]
+            );
+            assert_eq!(
+                archive.list_sub_file_names(dir).unwrap(),
+                vec![(file_name.clone(), file)]
+            );
+            assert_eq!(file_name.to_string(), "a\\x01");
+            assert!(matches!(
+                archive.lookup_file(dir, &ArchiveName::Id(1)),
+                Err(Error::Unsupported)
+            ));
+
+            archive.resize_file(file, 5).unwrap();
+            assert_eq!(archive.file_len(file).unwrap(), 5);
+            assert!(matches!(archive.remove_dir(dir), Err(Error::NotEmpty)));
+            archive.remove_file(file).unwrap();
+            archive.remove_dir(dir).unwrap();
+            assert!(matches!(
+                archive.lookup_dir(1, &dir_name),
+                Err(Error::NotFound)
+            ));
+            archive.commit_archive().unwrap();
+        }
+    }
+
+    #[test]
+    fn commit_read_only() {
+        let path = TempPath::new("libsave3ds_commit_read_only");
+        let path = path.to_str().unwrap().to_owned();
+        let resource = resource();
+        let param = format_param();
+        resource.format_bare_save(&path, &param, 0x20000).unwrap();
+
+        let save = resource.open_bare_save(&path, false).unwrap();
+        save.commit().unwrap();
+        drop(save);
+
+        // Read-only host files, such as on read-only media, can be opened and verified
+        let mut permissions = std::fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&path, permissions.clone()).unwrap();
+        let image = std::fs::read(&path).unwrap();
+        let save = resource.open_bare_save(&path, false).unwrap();
+        content_fingerprint(&save).unwrap();
+        save.commit().unwrap();
+        drop(save);
+        assert_eq!(std::fs::read(&path).unwrap(), image);
+
+        let file = DiskFile::new(std::fs::File::open(&path).unwrap(), false).unwrap();
+        assert!(matches!(
+            random_access_file::RandomAccessFile::write(&file, 0, &[0]),
+            Err(Error::ReadOnly)
+        ));
+
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        std::fs::set_permissions(&path, permissions).unwrap();
+    }
+
+    #[test]
+    fn lock() {
+        let path = TempPath::new("libsave3ds_lock");
+        let path = path.to_str().unwrap().to_owned();
+        let resource = resource();
+        let param = format_param();
+        resource.format_bare_save(&path, &param, 0x20000).unwrap();
+
+        let save = resource.open_bare_save(&path, true).unwrap();
+        let f = [b'f', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        save.open_root()
+            .unwrap()
+            .new_sub_file(f, data.len())
+            .unwrap()
+            .write(0, &data)
+            .unwrap();
+        save.commit().unwrap();
+        assert!(matches!(
+            resource.open_bare_save(&path, true),
+            Err(Error::Busy)
+        ));
+        assert!(matches!(
+            resource.open_bare_save(&path, false),
+            Err(Error::Busy)
+        ));
+        drop(save);
+
+        let image = std::fs::read(&path).unwrap();
+        let save_a = resource.open_bare_save(&path, false).unwrap();
+        let save_b = resource.open_bare_save(&path, false).unwrap();
+        assert!(matches!(
+            resource.open_bare_save(&path, true),
+            Err(Error::Busy)
+        ));
+
+        // Both read the same data with interleaved reads, and none of them writes
+        let file_a = save_a.open_root().unwrap().open_sub_file(f).unwrap();
+        let file_b = save_b.open_root().unwrap().open_sub_file(f).unwrap();
+        for pos in (0..data.len()).step_by(500) {
+            let mut buf_a = [0; 500];
+            let mut buf_b = [0; 500];
+            file_a.read(pos, &mut buf_a).unwrap();
+            file_b.read(pos, &mut buf_b).unwrap();
+            assert_eq!(buf_a[..], data[pos..pos + 500]);
+            assert_eq!(buf_a, buf_b);
+        }
+        drop(file_a);
+        drop(file_b);
+        drop(save_a);
+        drop(save_b);
+        assert_eq!(std::fs::read(&path).unwrap(), image);
+
+        resource.open_bare_save(&path, true).unwrap();
+    }
+
+    #[test]
+    fn overlay() {
+        let path = TempPath::new("libsave3ds_overlay");
+        let path = path.to_str().unwrap().to_owned();
+        let mut resource = resource();
+        let param = format_param();
+        resource.format_bare_save(&path, &param, 0x20000).unwrap();
+        let image = std::fs::read(&path).unwrap();
+
+        let overlay = Rc::new(Overlay::new());
+        resource.set_overlay(Some(overlay.clone()));
+        let f = [b'f', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let add_file = |save: &SaveData, len: usize| {
+            save.open_root()
+                .unwrap()
+                .new_sub_file(f, len)
+                .unwrap()
+                .write(0, &vec![7; len])
+                .unwrap();
+            save.commit().unwrap();
+        };
+        let has_file = |save: &SaveData| save.open_root().unwrap().open_sub_file(f).is_ok();
+
+        // Committed changes stay in memory, and reading shows the persisted state
+        let save = resource.open_bare_save(&path, true).unwrap();
+        add_file(&save, 3000);
+        assert!(overlay.is_dirty());
+        assert_eq!(std::fs::read(&path).unwrap(), image);
+        assert!(!has_file(&resource.open_bare_save(&path, false).unwrap()));
+
+        // Discarding goes back to the persisted state after reopening
+        drop(save);
+        overlay.discard();
+        assert!(!overlay.is_dirty());
+        let save = resource.open_bare_save(&path, true).unwrap();
+        assert!(!has_file(&save));
+        assert_eq!(std::fs::read(&path).unwrap(), image);
+
+        // Persisting writes the changes to the host file
+        add_file(&save, 5000);
+        overlay.persist().unwrap();
+        assert!(!overlay.is_dirty());
+        assert!(has_file(&resource.open_bare_save(&path, false).unwrap()));
+        drop(save);
+        resource.set_overlay(None);
+        drop(overlay);
+        let save = resource.open_bare_save(&path, true).unwrap();
+        let file = save.open_root().unwrap().open_sub_file(f).unwrap();
+        assert_eq!(file.len(), 5000);
+        let mut buf = vec![0; 5000];
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf, vec![7; 5000]);
+    }
+
+    #[test]
+    fn find_cxi_partition() {
+        use crate::memory_file::MemoryFile;
+        use crate::random_access_file::*;
+
+        let game = MemoryFile::new(vec![0; 0x4000]);
+        game.write(0x100, b"NCSD").unwrap();
+        let add_partition = |index: usize, offset: u32, len: u32, content_type: u8| {
+            game.write(0x120 + index * 8, &(offset / 0x200).to_le_bytes())
+                .unwrap();
+            game.write(0x124 + index * 8, &(len / 0x200).to_le_bytes())
+                .unwrap();
+            game.write(offset as usize + 0x100, b"NCCH").unwrap();
+            game.write(offset as usize + 0x18D, &[content_type])
+                .unwrap();
+        };
+
+        // A data-only NCCH (CFA) in partition 0, and the CXI in partition 2
+        add_partition(0, 0x1000, 0x1000, 1);
+        add_partition(2, 0x2000, 0x2000, 3);
+        assert_eq!(find_cxi(&game).unwrap(), (0x2000, 0x2000));
+
+        // A partition that runs past the end of the image is skipped
+        add_partition(1, 0x3000, 0x2000, 2);
+        assert_eq!(find_cxi(&game).unwrap(), (0x2000, 0x2000));
+
+        game.write(0x18D + 0x2000, &[1]).unwrap();
+        assert!(matches!(find_cxi(&game), Err(Error::BrokenGame)));
+
+        game.write(0x100, b"NCCH").unwrap();
+        assert!(matches!(find_cxi(&game), Err(Error::BrokenGame)));
+    }
+
+    #[test]
+    fn db_resources() {
+        let root = TempPath::new("libsave3ds_db_resources");
+        std::fs::create_dir_all(root.join("nand").join("private")).unwrap();
+        let boot9 = root.join("boot9.bin");
+        let movable = root.join("nand").join("private").join("movable.sed");
+        std::fs::write(&boot9, vec![0; 0x10000]).unwrap();
+        std::fs::write(&movable, vec![0; 0x140]).unwrap();
+        let path = |p: &std::path::Path| Some(p.to_str().unwrap().to_owned());
+        let open = |boot9, movable, nand| {
+            Resource::new(
+                boot9, movable, None, nand, None, None, None, None, None, None,
+            )
+            .unwrap()
+        };
+        let nand_types = [
+            DbType::Ticket,
+            DbType::NandTitle,
+            DbType::NandImport,
+            DbType::TmpTitle,
+            DbType::TmpImport,
+        ];
+        let sd_types = [DbType::SdTitle, DbType::SdImport];
+        let check = |resource: &Resource, types: &[DbType], expected: Error| {
+            for &db_type in types {
+                let e = resource.open_db(db_type, false).err().unwrap();
+                assert_eq!(
+                    std::mem::discriminant(&e),
+                    std::mem::discriminant(&expected),
+                    "{:?}: {}",
+                    db_type,
+                    e
+                );
+            }
+        };
+
+        let resource = open(None, None, None);
+        check(&resource, &nand_types, Error::MissingNand);
+        check(&resource, &sd_types, Error::MissingBoot9);
+
+        let resource = open(path(&boot9), None, None);
+        check(&resource, &nand_types, Error::MissingNand);
+        check(&resource, &sd_types, Error::MissingMovable);
+
+        let resource = open(path(&boot9), path(&movable), None);
+        check(&resource, &sd_types, Error::MissingSd);
+
+        // The NAND also provides movable.sed
+        let resource = open(None, None, path(&root.join("nand")));
+        check(&resource, &nand_types, Error::MissingOtp);
+        check(&resource, &sd_types, Error::MissingBoot9);
+        let resource = open(path(&boot9), None, path(&root.join("nand")));
+        check(&resource, &nand_types, Error::MissingOtp);
+        check(&resource, &sd_types, Error::MissingSd);
+    }
+
+    #[test]
+    fn nand_console_files() {
+        let root = TempPath::new("libsave3ds_nand_console_files");
+        let sys = root.join("rw").join("sys");
+        std::fs::create_dir_all(&sys).unwrap();
+        std::fs::create_dir_all(root.join("private")).unwrap();
+        let mut movable = vec![0; 0x140];
+        movable[0..4].copy_from_slice(b"SEED");
+        movable[0x110..0x120].copy_from_slice(&[
+            0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x00, 0x00, 1, 2, 3, 4, 5, 6, 7, 8,
+        ]);
+        std::fs::write(root.join("private").join("movable.sed"), &movable).unwrap();
+        let nand_path = root.to_str().unwrap().to_owned();
+        let open = || {
+            Resource::new(
+                None,
+                None,
+                None,
+                Some(nand_path.clone()),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+        };
+
+        let resource = open().unwrap();
+        assert!(matches!(
+            resource.secure_info(),
+            Err(Error::MissingSecureInfo)
+        ));
+        assert!(matches!(
+            resource.local_friend_code_seed(),
+            Err(Error::MissingLfcs)
+        ));
+
+        let mut secure_info = vec![0xFF; 0x111];
+        secure_info[0x100] = 2;
+        secure_info[0x102..0x111].copy_from_slice(b"EW123456789\0\0\0\0");
+        std::fs::write(sys.join("SecureInfo_A"), &secure_info).unwrap();
+        let mut lfcs = vec![0xFF; 0x110];
+        lfcs[0x100..0x108].copy_from_slice(&[0; 8]);
+        lfcs[0x108..0x110].copy_from_slice(&movable[0x110..0x118]);
+        std::fs::write(sys.join("LocalFriendCodeSeed_A"), &lfcs).unwrap();
+
+        let resource = open().unwrap();
+        assert_eq!(
+            resource.secure_info().unwrap(),
+            SecureInfo {
+                region: 2,
+                serial: "EW123456789".to_owned()
+            }
+        );
+        assert_eq!(
+            resource.local_friend_code_seed().unwrap(),
+            0x0000_6655_4433_2211
+        );
+
+        // LocalFriendCodeSeed_B takes precedence over the backup
+        lfcs[0x108] = 0x12;
+        std::fs::write(sys.join("LocalFriendCodeSeed_B"), &lfcs).unwrap();
+        assert!(matches!(open(), Err(Error::LfcsMismatch)));
+    }
+
+    #[test]
+    fn capabilities() {
+        let resource = resource();
+        let capabilities = resource.capabilities();
+        assert!(!capabilities.sd_save);
+        assert!(!capabilities.nand_save);
+        assert!(!capabilities.nand_db);
+        assert!(!capabilities.cart_save);
+
+        let root = TempPath::new("libsave3ds_capabilities");
+        let boot9 = root.join("boot9.bin");
+        let movable = root.join("movable.sed");
+        let sd = root.join("sd");
+        std::fs::create_dir_all(
+            sd.join("Nintendo 3DS")
+                .join(hash_movable([0; 16]))
+                .join("id1"),
+        )
+        .unwrap();
+        std::fs::write(&boot9, vec![0; 0x10000]).unwrap();
+        std::fs::write(&movable, vec![0; 0x140]).unwrap();
+
+        let resource = Resource::new(
+            Some(boot9.to_str().unwrap().to_owned()),
+            Some(movable.to_str().unwrap().to_owned()),
+            Some(sd.to_str().unwrap().to_owned()),
+            None,
+            None,
+            None,
+            Some("game.3ds".to_owned()),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let capabilities = resource.capabilities();
+        assert!(capabilities.sd_save);
+        assert!(capabilities.sd_ext);
+        assert!(capabilities.sd_db);
+        assert!(!capabilities.nand_save);
+        assert!(!capabilities.nand_ext);
+        assert!(!capabilities.nand_db);
+        assert!(!capabilities.cart_save);
+    }
+
+    #[test]
+    fn sd_file_crypto() {
+        let root = TempPath::new("libsave3ds_sd_crypto");
+        let boot9 = root.join("boot9.bin");
+        let movable = root.join("movable.sed");
+        let sd = root.join("sd");
+        let sd_tree = sd
+            .join("Nintendo 3DS")
+            .join(hash_movable([0; 16]))
+            .join("id1");
+        std::fs::create_dir_all(&sd_tree).unwrap();
+        std::fs::write(&boot9, vec![0; 0x10000]).unwrap();
+        std::fs::write(&movable, vec![0; 0x140]).unwrap();
+        let resource = Resource::new(
+            Some(boot9.to_str().unwrap().to_owned()),
+            Some(movable.to_str().unwrap().to_owned()),
+            Some(sd.to_str().unwrap().to_owned()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let plain: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        resource.encrypt_sd_file("/dir/file", &plain).unwrap();
+        let raw = std::fs::read(sd_tree.join("dir").join("file")).unwrap();
+        assert_eq!(raw.len(), plain.len());
+        assert_ne!(raw, plain);
+        assert_eq!(resource.decrypt_sd_file("dir/file").unwrap(), plain);
+
+        // The same layer is used by SD archives
+        let param = SaveDataFormatParam {
+            duplicate_data: false,
+            ..format_param()
+        };
+        resource
+            .format_sd_save(0x0004_0000_0012_3400, &param, 0x20000)
+            .unwrap();
+        let save = resource
+            .decrypt_sd_file("/title/00040000/00123400/data/00000001.sav")
+            .unwrap();
+        assert_eq!(&save[0x100..0x104], b"DISA");
+
+        assert!(matches!(
+            test_util::resource().decrypt_sd_file("/dir/file"),
+            Err(Error::MissingSd)
+        ));
+    }
+
+    #[test]
+    fn sd_map() {
+        let root = TempPath::new("libsave3ds_sd_map");
+        let boot9 = root.join("boot9.bin");
+        let movable = root.join("movable.sed");
+        let sd = root.join("sd");
+        let id0 = hash_movable([0; 16]);
+        std::fs::create_dir_all(sd.join("Nintendo 3DS").join(&id0).join("id1")).unwrap();
+        std::fs::write(&boot9, vec![0; 0x10000]).unwrap();
+        std::fs::write(&movable, vec![0; 0x140]).unwrap();
+        let open = |movable: &std::path::Path| {
+            Resource::new(
+                Some(boot9.to_str().unwrap().to_owned()),
+                Some(movable.to_str().unwrap().to_owned()),
+                Some(sd.to_str().unwrap().to_owned()),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+        };
+        let resource = open(&movable).unwrap();
+
+        let param = SaveDataFormatParam {
+            duplicate_data: false,
+            ..format_param()
+        };
+        resource
+            .format_sd_save(0x0004_0000_0012_3400, &param, 0x20000)
+            .unwrap();
+        resource.encrypt_sd_file("/dbs/note", &[1; 0x10]).unwrap();
+
+        let dir = |path: &str| SdMapEntry {
+            path: path.to_owned(),
+            is_dir: true,
+            len: 0,
+            magic: None,
+        };
+        assert_eq!(
+            resource.sd_map().unwrap(),
+            SdMap {
+                id0: id0.clone(),
+                id1: "id1".to_owned(),
+                entries: vec![
+                    dir("dbs"),
+                    SdMapEntry {
+                        path: "dbs/note".to_owned(),
+                        is_dir: false,
+                        len: 0x10,
+                        magic: None,
+                    },
+                    dir("title"),
+                    dir("title/00040000"),
+                    dir("title/00040000/00123400"),
+                    dir("title/00040000/00123400/data"),
+                    SdMapEntry {
+                        path: "title/00040000/00123400/data/00000001.sav".to_owned(),
+                        is_dir: false,
+                        len: 0x20000,
+                        magic: Some("DISA".to_owned()),
+                    },
+                ]
+            }
+        );
+
+        // A movable.sed of another console doesn't resolve to the tree at all
+        let other_movable = root.join("other_movable.sed");
+        std::fs::write(&other_movable, vec![1; 0x140]).unwrap();
+        assert!(open(&other_movable).is_err());
+    }
+
+    #[test]
+    fn sign_bare_save() {
+        let root = TempPath::new("libsave3ds_sign_bare");
+        let boot9 = root.join("boot9.bin");
+        let movable = root.join("movable.sed");
+        let path = root.join("save.bin");
+        let path = path.to_str().unwrap();
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(&boot9, vec![0; 0x10000]).unwrap();
+        std::fs::write(&movable, vec![0; 0x140]).unwrap();
+        let resource = Resource::new(
+            Some(boot9.to_str().unwrap().to_owned()),
+            Some(movable.to_str().unwrap().to_owned()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let key = resource.key_sign.unwrap();
+
+        let param = format_param();
+        resource.format_bare_save(path, &param, 0x20000).unwrap();
+        let open = |save_data_type| {
+            SaveData::new(resource.open_bare_file(path, false)?, save_data_type, false)
+        };
+
+        for (target, save_data_type, other_type) in [
+            (
+                BareSignTarget::Nand(0x0001_0011),
+                SaveDataType::Nand(key, 0x0001_0011),
+                SaveDataType::Nand(key, 0x0001_0012),
+            ),
+            (
+                BareSignTarget::Sd(0x0004_0000_0012_3400),
+                SaveDataType::Sd(key, 0x0004_0000_0012_3400),
+                SaveDataType::Nand(key, 0x0001_0011),
+            ),
+        ] {
+            // Corrupts the CMAC, as any modification of a bare save would
+            let mut data = std::fs::read(path).unwrap();
+            data[0..0x10].copy_from_slice(&[0xA5; 0x10]);
+            std::fs::write(path, &data).unwrap();
+            assert!(matches!(
+                open(save_data_type.clone()),
+                Err(Error::LikelyWrongKey)
+            ));
+
+            resource.sign_bare_save(path, target).unwrap();
+            open(save_data_type).unwrap();
+            assert!(open(other_type).is_err());
+
+            // The signature is all that changed
+            assert_eq!(std::fs::read(path).unwrap()[0x10..], data[0x10..]);
+        }
+
+        let image = std::fs::read(path).unwrap();
+        std::fs::write(path, vec![0; 0x200]).unwrap();
+        assert!(matches!(
+            resource.sign_bare_save(path, BareSignTarget::Nand(0)),
+            Err(Error::MagicMismatch)
+        ));
+        std::fs::write(path, image).unwrap();
+
+        let resource = test_util::resource();
+        assert!(matches!(
+            resource.sign_bare_save(path, BareSignTarget::Nand(0)),
+            Err(Error::MissingBoot9)
+        ));
+        assert!(resource.sign_bare_save(path, BareSignTarget::Cart).is_err());
+    }
+
+    #[test]
+    fn list_nand() {
+        let root = TempPath::new("libsave3ds_list_nand");
+        let boot9 = root.join("boot9.bin");
+        let nand = root.join("nand");
+        std::fs::create_dir_all(nand.join("private")).unwrap();
+        std::fs::write(&boot9, vec![0; 0x10000]).unwrap();
+        std::fs::write(nand.join("private").join("movable.sed"), vec![0; 0x140]).unwrap();
+        let resource = Resource::new(
+            Some(boot9.to_str().unwrap().to_owned()),
+            None,
+            None,
+            Some(nand.to_str().unwrap().to_owned()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert!(resource.list_nand_saves().unwrap().is_empty());
+        assert!(resource.list_nand_exts().unwrap().is_empty());
+
+        let save_param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Large,
+            ..format_param()
+        };
+        let ext_param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            mount_path: [[0; 0x10]; 0x10],
+        };
+        resource
+            .format_nand_save(0x0001_0017, &save_param, 0x80000)
+            .unwrap();
+        resource
+            .format_nand_save(0x0001_0011, &save_param, 0x80000)
+            .unwrap();
+        resource.format_shared_ext(0xF000_000E, &ext_param).unwrap();
+        resource.format_shared_ext(0xF000_000B, &ext_param).unwrap();
+        let data = nand.join("data").join(hash_movable([0; 16]));
+        std::fs::create_dir_all(data.join("sysdata").join("not_an_id")).unwrap();
+        std::fs::create_dir_all(data.join("extdata").join("00048000").join("junk")).unwrap();
+
+        assert_eq!(
+            resource.list_nand_saves().unwrap(),
+            vec![0x0001_0011, 0x0001_0017]
+        );
+        assert_eq!(
+            resource.list_nand_exts().unwrap(),
+            vec![0x0004_8000_F000_000B, 0x0004_8000_F000_000E]
+        );
+    }
+
+    #[test]
+    fn shared_ext() {
+        let root = TempPath::new("libsave3ds_shared_ext");
+        let boot9 = root.join("boot9.bin");
+        let nand = root.join("nand");
+        std::fs::create_dir_all(nand.join("private")).unwrap();
+        std::fs::write(&boot9, vec![0; 0x10000]).unwrap();
+        std::fs::write(nand.join("private").join("movable.sed"), vec![0; 0x140]).unwrap();
+        let resource = Resource::new(
+            Some(boot9.to_str().unwrap().to_owned()),
+            None,
+            None,
+            Some(nand.to_str().unwrap().to_owned()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            mount_path: [[0; 0x10]; 0x10],
+        };
+        resource.format_shared_ext(0xF000_000B, &param).unwrap();
+        let ext_path = nand
+            .join("data")
+            .join(hash_movable([0; 16]))
+            .join("extdata")
+            .join("00048000")
+            .join("f000000b");
+        assert!(ext_path.join("Quota.dat").is_file());
+        assert!(ext_path.join("00000000").join("00000001").is_file());
+
+        {
+            let ext = resource.open_shared_ext(0xF000_000B, true).unwrap();
+            let file = ext
+                .open_root()
+                .unwrap()
+                .new_sub_file(<[u8; 16]>::name_str_to_3ds("gamecoin.dat").unwrap(), 4)
+                .unwrap();
+            file.write(0, &[1, 2, 3, 4]).unwrap();
+            ext.commit_file(file.get_ino()).unwrap();
+        }
+
+        let ext = resource
+            .open_nand_ext(0x0004_8000_F000_000B, false)
+            .unwrap();
+        let file = ext
+            .open_root()
+            .unwrap()
+            .open_sub_file(<[u8; 16]>::name_str_to_3ds("gamecoin.dat").unwrap())
+            .unwrap();
+        let mut buf = [0; 4];
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+        drop(file);
+        drop(ext);
+    }
+
+    #[test]
+    fn cart_info() {
+        use crate::memory_file::MemoryFile;
+        use crate::random_access_file::*;
+
+        let game = MemoryFile::new(vec![0; 0x2000]);
+        game.write(0x100, b"NCSD").unwrap();
+        game.write(0x188, &[0, 1, 0, 1, 0, 1, 0, 0]).unwrap();
+        game.write(0x120, &8u32.to_le_bytes()).unwrap();
+        game.write(0x124, &8u32.to_le_bytes()).unwrap();
+
+        game.write(0x1100, b"NCCH").unwrap();
+        game.write(0x1110, b"01").unwrap();
+        game.write(0x1112, &2u16.to_le_bytes()).unwrap();
+        game.write(0x1118, &0x0004_0000_0012_3400u64.to_le_bytes())
+            .unwrap();
+        game.write(0x1150, b"CTR-P-ABCE").unwrap();
+        game.write(0x118D, &[3]).unwrap();
+
+        let (info, cxi_offset, cxi_len) = read_cart_info(&game).unwrap();
+        assert_eq!(
+            info,
+            CartInfo {
+                program_id: 0x0004_0000_0012_3400,
+                product_code: "CTR-P-ABCE".to_owned(),
+                maker_code: "01".to_owned(),
+                ncch_version: 2,
+                crypto_version: 6,
+                wear_leveling: true,
+            }
+        );
+        assert_eq!((cxi_offset, cxi_len), (0x1000, 0x1000));
+
+        game.write(0x18D, &[0]).unwrap();
+        assert!(matches!(read_cart_info(&game), Err(Error::BrokenGame)));
+    }
+
+    #[test]
+    fn cart_keys_new_3ds() {
+        let path = TempPath::new("libsave3ds_cart_keys_new_3ds");
+        let mut game = vec![0; 0x2000];
+        game[0x100..0x104].copy_from_slice(b"NCSD");
+        game[0x188..0x190].copy_from_slice(&[0, 10, 0, 1, 0, 1, 0, 0]);
+        game[0x120..0x124].copy_from_slice(&8u32.to_le_bytes());
+        game[0x124..0x128].copy_from_slice(&8u32.to_le_bytes());
+        game[0x1100..0x1104].copy_from_slice(b"NCCH");
+        game[0x118D] = 3;
+        std::fs::write(&path, &game).unwrap();
+
+        let game_path = Some(path.to_str().unwrap().to_owned());
+        assert_eq!(
+            Resource::cart_info(path.to_str().unwrap())
+                .unwrap()
+                .crypto_version,
+            9
+        );
+        let key = Some([0; 16]);
+        let open = |x19_key_x, x1a_key_x| {
+            let resource = Resource::new(
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                game_path.clone(),
+                None,
+                x19_key_x,
+                x1a_key_x,
+            )
+            .unwrap();
+            resource.get_cart_format().err().unwrap()
+        };
+        assert!(matches!(open(None, None), Error::MissingKeyX19X1A));
+        assert!(matches!(open(key, None), Error::MissingKeyX1A));
+        assert!(matches!(open(None, key), Error::MissingKeyX19));
+        assert!(matches!(open(key, key), Error::MissingBoot9));
+
+        // A version 6 cartridge doesn't need them
+        game[0x189] = 1;
+        std::fs::write(&path, &game).unwrap();
+        assert!(matches!(open(None, None), Error::MissingBoot9));
+    }
+
+    /// Builds a game image with the CCI flags `flags`, whose exheader and ExeFS header are
+    /// encrypted with the NCCH key of the all-zero boot9 and NCCH signature. The exheader
+    /// signature starts with `[0x5A; 8]`.
+    fn test_cart_game(flags: [u8; 8]) -> Vec<u8> {
+        use crate::aes_ctr_file::AesCtrFile;
+        use crate::memory_file::MemoryFile;
+        use crate::random_access_file::*;
+
+        let program_id = 0x0004_0000_0012_3400u64;
+        let game = Rc::new(MemoryFile::new(vec![0; 0x2000]));
+        game.write(0x100, b"NCSD").unwrap();
+        game.write(0x120, &8u32.to_le_bytes()).unwrap();
+        game.write(0x124, &8u32.to_le_bytes()).unwrap();
+        game.write(0x188, &flags).unwrap();
+        game.write(0x1100, b"NCCH").unwrap();
+        game.write(0x1112, &2u16.to_le_bytes()).unwrap();
+        game.write(0x1118, &program_id.to_le_bytes()).unwrap();
+        game.write(0x118D, &[3]).unwrap();
+        game.write(0x11A0, &5u32.to_le_bytes()).unwrap();
+        let ncch_key = key_engine::scramble([0; 16], [0; 16]);
+        let mut ctr = [0; 16];
+        ctr[0..8].copy_from_slice(&program_id.to_be_bytes());
+        ctr[8] = 1;
+        let exheader = AesCtrFile::new(
+            Rc::new(sub_file::SubFile::new(game.clone(), 0x1200, 0x800).unwrap()),
+            ncch_key,
+            ctr,
+            false,
+        );
+        exheader.write(0x400, &[0x5A; 8]).unwrap();
+        ctr[8] = 2;
+        let exefs = AesCtrFile::new(
+            Rc::new(sub_file::SubFile::new(game.clone(), 0x1A00, 0x200).unwrap()),
+            ncch_key,
+            ctr,
+            false,
+        );
+        exefs.write(0, b".code\0\0\0").unwrap();
+        exefs.write(0x1E0, &[0xC3; 0x20]).unwrap();
+
+        let mut image = vec![0; game.len()];
+        game.read(0, &mut image).unwrap();
+        image
+    }
+
+    #[test]
+    fn cart_key_version_0() {
+        let root = TempPath::new("libsave3ds_cart_key_version_0");
+        std::fs::create_dir_all(&root).unwrap();
+        let boot9 = root.join("boot9.bin");
+        let priv_header = root.join("priv.bin");
+        let game_path = root.join("game.3ds");
+        let mut boot9_data = vec![0; 0x10000];
+        boot9_data[0xD9E0..0xD9F0].copy_from_slice(&[0xC5; 16]);
+        boot9_data[0xD9F0..0xDA00].copy_from_slice(&[0xDE; 16]);
+        std::fs::write(&boot9, boot9_data).unwrap();
+        std::fs::write(&game_path, test_cart_game([0, 0, 0, 0, 0, 2, 0, 0])).unwrap();
+        assert_eq!(
+            Resource::cart_info(game_path.to_str().unwrap())
+                .unwrap()
+                .crypto_version,
+            0
+        );
+
+        let format = |priv_data: Vec<u8>| {
+            std::fs::write(&priv_header, priv_data).unwrap();
+            let resource = Resource::new(
+                Some(boot9.to_str().unwrap().to_owned()),
+                None,
+                None,
+                None,
+                None,
+                Some(priv_header.to_str().unwrap().to_owned()),
+                Some(game_path.to_str().unwrap().to_owned()),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+            resource.get_cart_format().unwrap()
+        };
+
+        // key Y = 5A5A5A5A5A5A5A5A 4041424344454647, i.e. the first half of the exheader
+        // signature followed by the short cartridge ID. The expected keys are calculated
+        // separately with the key scrambler formula
+        let format0 = format((0..0x48).collect());
+        assert!(!format0.wear_leveling);
+        assert!(format0.repeat_ctr);
+        assert_eq!(
+            format0.key,
+            [
+                0x8A, 0x4E, 0x48, 0x59, 0xE3, 0x20, 0x8D, 0x85, 0x65, 0xF3, 0x8F, 0x92, 0x94, 0x9E,
+                0xBF, 0xE5
+            ]
+        );
+        assert_eq!(
+            format0.key_cmac,
+            [
+                0x98, 0x58, 0x52, 0x63, 0xED, 0x36, 0xA3, 0x9B, 0x7C, 0x09, 0xA5, 0xA8, 0xAA, 0xAC,
+                0xCD, 0xF3
+            ]
+        );
+
+        // The long cartridge ID is not used
+        let mut priv_data: Vec<u8> = (0..0x48).collect();
+        priv_data[0..0x40].copy_from_slice(&[0xFF; 0x40]);
+        assert_eq!(format(priv_data).key, format0.key);
+    }
+
+    #[test]
+    fn cart_save_new_3ds() {
+        let root = TempPath::new("libsave3ds_cart_save_new_3ds");
+        std::fs::create_dir_all(&root).unwrap();
+        let boot9 = root.join("boot9.bin");
+        let priv_header = root.join("priv.bin");
+        let game_path = root.join("game.3ds");
+        let save_path = root.join("save.bin");
+        std::fs::write(&boot9, vec![0; 0x10000]).unwrap();
+        std::fs::write(&priv_header, (0..0x48).collect::<Vec<u8>>()).unwrap();
+
+        let format = |flags: [u8; 8], x19_key_x, x1a_key_x| {
+            std::fs::write(&game_path, test_cart_game(flags)).unwrap();
+            let resource = Resource::new(
+                Some(boot9.to_str().unwrap().to_owned()),
+                None,
+                None,
+                None,
+                None,
+                Some(priv_header.to_str().unwrap().to_owned()),
+                Some(game_path.to_str().unwrap().to_owned()),
+                Some([0x2F; 16]),
+                x19_key_x,
+                x1a_key_x,
+            )
+            .unwrap();
+            let format = resource.get_cart_format().unwrap();
+            (resource, (format.key, format.key_cmac, format.repeat_ctr))
+        };
+
+        let (_, v2) = format([0, 0, 0, 1, 0, 1, 0, 0], None, None);
+        let (_, v6) = format([0, 1, 0, 1, 0, 1, 0, 0], None, None);
+        let (resource, v9) = format(
+            [0, 10, 0, 1, 0, 1, 0, 0],
+            Some([0x19; 16]),
+            Some([0x1A; 16]),
+        );
+        assert_ne!(v9, v2);
+        assert_ne!(v9, v6);
+
+        // With the same key X as the older versions, the key Y is the one of version 6
+        let (_, v9_as_v6) = format([0, 10, 0, 1, 0, 1, 0, 0], Some([0; 16]), Some([0; 16]));
+        assert_eq!(v9_as_v6, v6);
+
+        let param = format_param();
+        let save_path = save_path.to_str().unwrap();
+        resource
+            .format_cart_save(save_path, &param, 0x20_000)
+            .unwrap();
+        let save = resource.open_cart_save(save_path, true).unwrap();
+        let name = <[u8; 16]>::name_str_to_3ds("a").unwrap();
+        let file = save.open_root().unwrap().new_sub_file(name, 4).unwrap();
+        file.write(0, &[1, 2, 3, 4]).unwrap();
+        drop(file);
+        save.commit().unwrap();
+        drop(save);
+
+        let save = resource.open_cart_save(save_path, false).unwrap();
+        let mut buf = [0; 4];
+        save.open_root()
+            .unwrap()
+            .open_sub_file(name)
+            .unwrap()
+            .read(0, &mut buf)
+            .unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+        drop(save);
+
+        // The save doesn't open as the version 6 one of the same game
+        let (resource, _) = format([0, 1, 0, 1, 0, 1, 0, 0], None, None);
+        assert!(resource.open_cart_save(save_path, false).is_err());
     }
 }
@@ -15,6 +15,9 @@ impl MemoryFile {
     }
 
     /// Creates a `MemoryFile` that clones the content from another `RandomAccessFile`
+    ///
+    /// The whole content is buffered, so this is only meant for small structures, such as the
+    /// 0x100-byte DISA/DIFF headers and the 8-byte CRC tickets of wear leveling.
     pub fn from_file(file: &dyn RandomAccessFile) -> Result<MemoryFile, Error> {
         let mut data = vec![0; file.len()];
         file.read(0, &mut data)?;
@@ -58,4 +61,6 @@ fn test() {
     let mut buf2 = [0; 7];
     file.read(2, &mut buf2).unwrap();
     assert_eq!(buf2, [1, 3, 1, 3, 5, 7, 9]);
+    crate::random_access_file::check_boundary(&file);
+    crate::random_access_file::check_boundary(&MemoryFile::new(vec![]));
 }
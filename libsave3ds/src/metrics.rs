@@ -0,0 +1,165 @@
+//! Operation counters and timing, available with the `metrics` feature.
+//!
+//! When the feature is enabled, every host file opened by [`Resource`](../struct.Resource.html)
+//! is wrapped in a layer that counts and times reads, writes and commits. AES-CTR pad
+//! computations and IVFC SHA-256 hashes are counted as well. Counters are kept per thread,
+//! because archives can't be sent to other threads anyway.
+//!
+//! Without the feature, this module doesn't exist and no counting code is compiled in.
+
+use crate::error::*;
+use crate::random_access_file::*;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// A snapshot of the counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Metrics {
+    /// Number of reads from host files.
+    pub reads: u64,
+    /// Total bytes read from host files.
+    pub read_bytes: u64,
+    /// Total time spent reading host files.
+    pub read_time: Duration,
+    /// Number of writes to host files.
+    pub writes: u64,
+    /// Total bytes written to host files.
+    pub write_bytes: u64,
+    /// Total time spent writing host files.
+    pub write_time: Duration,
+    /// Number of commits (flushes) of host files.
+    pub commits: u64,
+    /// Total time spent committing host files.
+    pub commit_time: Duration,
+    /// Number of 16-byte AES-CTR pads computed, excluding cache hits.
+    pub aes_blocks: u64,
+    /// Number of IVFC SHA-256 hashes computed, both for verification and for commit.
+    pub sha256_hashes: u64,
+}
+
+thread_local! {
+    static METRICS: Cell<Metrics> = Cell::new(Metrics::default());
+}
+
+fn update(f: impl FnOnce(&mut Metrics)) {
+    METRICS.with(|m| {
+        let mut metrics = m.get();
+        f(&mut metrics);
+        m.set(metrics);
+    })
+}
+
+/// Returns the counters of the current thread.
+pub fn snapshot() -> Metrics {
+    METRICS.with(|m| m.get())
+}
+
+/// Resets the counters of the current thread to zero.
+pub fn reset() {
+    METRICS.with(|m| m.set(Metrics::default()))
+}
+
+pub(crate) fn record_aes_block() {
+    update(|m| m.aes_blocks += 1);
+}
+
+pub(crate) fn record_sha256() {
+    update(|m| m.sha256_hashes += 1);
+}
+
+impl std::fmt::Display for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Reads: {} ({} bytes, {:?})",
+            self.reads, self.read_bytes, self.read_time
+        )?;
+        writeln!(
+            f,
+            "Writes: {} ({} bytes, {:?})",
+            self.writes, self.write_bytes, self.write_time
+        )?;
+        writeln!(f, "Commits: {} ({:?})", self.commits, self.commit_time)?;
+        writeln!(f, "AES blocks: {}", self.aes_blocks)?;
+        write!(f, "SHA-256 hashes: {}", self.sha256_hashes)
+    }
+}
+
+/// Implements a transparent `RandomAccessFile` layer that updates the counters.
+pub(crate) struct MeteredFile {
+    data: Rc<dyn RandomAccessFile>,
+}
+
+impl MeteredFile {
+    pub fn new(data: Rc<dyn RandomAccessFile>) -> MeteredFile {
+        MeteredFile { data }
+    }
+}
+
+impl RandomAccessFile for MeteredFile {
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let start = Instant::now();
+        let result = self.data.read(pos, buf);
+        let elapsed = start.elapsed();
+        update(|m| {
+            m.reads += 1;
+            m.read_bytes += buf.len() as u64;
+            m.read_time += elapsed;
+        });
+        result
+    }
+    fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        let start = Instant::now();
+        let result = self.data.write(pos, buf);
+        let elapsed = start.elapsed();
+        update(|m| {
+            m.writes += 1;
+            m.write_bytes += buf.len() as u64;
+            m.write_time += elapsed;
+        });
+        result
+    }
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+    fn commit(&self) -> Result<(), Error> {
+        let start = Instant::now();
+        let result = self.data.commit();
+        let elapsed = start.elapsed();
+        update(|m| {
+            m.commits += 1;
+            m.commit_time += elapsed;
+        });
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::memory_file::MemoryFile;
+    use crate::metrics::*;
+
+    #[test]
+    fn count() {
+        reset();
+        let file = MeteredFile::new(Rc::new(MemoryFile::new(vec![0; 0x100])));
+        let mut buf = [0; 0x10];
+        file.read(0, &mut buf).unwrap();
+        file.read(0x10, &mut buf[0..4]).unwrap();
+        file.write(0x20, &[1, 2, 3]).unwrap();
+        file.commit().unwrap();
+        assert!(file.read(0xF8, &mut buf).is_err());
+
+        let metrics = snapshot();
+        assert_eq!(metrics.reads, 3);
+        assert_eq!(metrics.read_bytes, 0x10 + 4 + 0x10);
+        assert_eq!(metrics.writes, 1);
+        assert_eq!(metrics.write_bytes, 3);
+        assert_eq!(metrics.commits, 1);
+
+        reset();
+        assert_eq!(snapshot(), Metrics::default());
+    }
+}
@@ -1,33 +1,75 @@
-use crate::disk_file::DiskFile;
+use crate::disk_file::*;
 use crate::error::*;
+use crate::overlay::Overlay;
 use crate::random_access_file::*;
 use crate::sd_nand_common::*;
+use std::cell::{Cell, RefCell};
 use std::path::*;
 use std::rc::Rc;
 
 pub struct Nand {
     path: PathBuf,
+    write_buffer_len: Cell<usize>,
+    overlay: RefCell<Option<Rc<Overlay>>>,
 }
 
 impl Nand {
     pub fn new(nand_path: &str) -> Result<Nand, Error> {
         let path = PathBuf::from(nand_path);
-        Ok(Nand { path })
+        Ok(Nand {
+            path,
+            write_buffer_len: Cell::new(DEFAULT_WRITE_BUFFER_LEN),
+            overlay: RefCell::new(None),
+        })
+    }
+
+    /// Sets how many bytes of writes to each file opened afterwards are buffered.
+    /// See [`DiskFile::set_write_buffer_len`].
+    pub fn set_write_buffer_len(&self, len: usize) {
+        self.write_buffer_len.set(len);
+    }
+
+    /// Sets the overlay that files opened afterwards go through.
+    /// See [`Overlay`].
+    pub fn set_overlay(&self, overlay: Option<Rc<Overlay>>) {
+        *self.overlay.borrow_mut() = overlay;
+    }
+
+    /// Lists the names of the entries of a directory, sorted. A missing directory is empty.
+    pub fn list(&self, path: &[&str]) -> Result<Vec<String>, Error> {
+        let dir_path = path.iter().fold(self.path.clone(), |a, b| a.join(b));
+        if !dir_path.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut names = vec![];
+        for entry in std::fs::read_dir(dir_path)? {
+            if let Some(name) = entry?.file_name().to_str() {
+                names.push(name.to_owned());
+            }
+        }
+        names.sort();
+        Ok(names)
     }
 }
 
 impl SdNandFileSystem for Nand {
     fn open(&self, path: &[&str], write: bool) -> Result<Rc<dyn RandomAccessFile>, Error> {
         let file_path = path.iter().fold(self.path.clone(), |a, b| a.join(b));
-
-        let file = DiskFile::new(
-            std::fs::OpenOptions::new()
-                .read(true)
-                .write(write)
-                .open(file_path)?,
-        )?;
-
-        Ok(Rc::new(file))
+        let open = |write| {
+            let file = DiskFile::new(
+                std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(write)
+                    .open(&file_path)?,
+                write,
+            )?;
+            file.set_write_buffer_len(self.write_buffer_len.get());
+            Ok(file.into_shared())
+        };
+        match self.overlay.borrow().as_ref() {
+            Some(overlay) => overlay.open(&file_path, write, open),
+            None => open(write),
+        }
     }
 
     fn create(&self, path: &[&str], len: usize) -> Result<(), Error> {
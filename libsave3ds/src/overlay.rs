@@ -0,0 +1,282 @@
+use crate::error::*;
+use crate::random_access_file::*;
+use std::cell::RefCell;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// The unit in which written data is copied into memory.
+const PAGE_LEN: usize = 0x1000;
+
+/// Implements `RandomAccessFile` over a host file opened for writing, keeping the pages that are
+/// written to in memory instead of writing them to the host file.
+struct OverlayFile {
+    base: Rc<dyn RandomAccessFile>,
+    pages: RefCell<BTreeMap<usize, Vec<u8>>>,
+}
+
+impl OverlayFile {
+    /// Writes the changed pages to the host file and flushes it. Pages that fail to be written
+    /// are kept, so that this can be retried.
+    fn persist(&self) -> Result<(), Error> {
+        let mut pages = self.pages.borrow_mut();
+        while let Some((index, page)) = pages.pop_first() {
+            if let Err(e) = self.base.write(index * PAGE_LEN, &page) {
+                pages.insert(index, page);
+                return Err(e);
+            }
+        }
+        self.base.commit()
+    }
+}
+
+impl RandomAccessFile for OverlayFile {
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let end = pos + buf.len();
+        if end > self.len() {
+            return make_error(Error::OutOfBound);
+        }
+        let pages = self.pages.borrow();
+        let mut cur = pos;
+        while cur < end {
+            let index = cur / PAGE_LEN;
+            let chunk_end = match pages.range(index..).next() {
+                Some((&i, page)) if i == index => {
+                    let chunk_end = std::cmp::min(end, (index + 1) * PAGE_LEN);
+                    let offset = cur - index * PAGE_LEN;
+                    buf[cur - pos..chunk_end - pos]
+                        .copy_from_slice(&page[offset..offset + chunk_end - cur]);
+                    chunk_end
+                }
+                // Reads all unchanged pages up to the next changed one at once
+                next => {
+                    let chunk_end = match next {
+                        Some((&i, _)) => std::cmp::min(end, i * PAGE_LEN),
+                        None => end,
+                    };
+                    self.base.read(cur, &mut buf[cur - pos..chunk_end - pos])?;
+                    chunk_end
+                }
+            };
+            cur = chunk_end;
+        }
+        Ok(())
+    }
+    fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        let end = pos + buf.len();
+        if end > self.len() {
+            return make_error(Error::OutOfBound);
+        }
+        let mut pages = self.pages.borrow_mut();
+        let mut cur = pos;
+        while cur < end {
+            let index = cur / PAGE_LEN;
+            let page_pos = index * PAGE_LEN;
+            let chunk_end = std::cmp::min(end, page_pos + PAGE_LEN);
+            let page = match pages.entry(index) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let mut page = vec![0; std::cmp::min(PAGE_LEN, self.len() - page_pos)];
+                    if cur != page_pos || chunk_end != page_pos + page.len() {
+                        self.base.read(page_pos, &mut page)?;
+                    }
+                    entry.insert(page)
+                }
+            };
+            page[cur - page_pos..chunk_end - page_pos]
+                .copy_from_slice(&buf[cur - pos..chunk_end - pos]);
+            cur = chunk_end;
+        }
+        Ok(())
+    }
+    fn len(&self) -> usize {
+        self.base.len()
+    }
+    fn commit(&self) -> Result<(), Error> {
+        // Changes stay in memory until the overlay is persisted
+        Ok(())
+    }
+}
+
+/// Implements `RandomAccessFile` as a read-only view of the host file under an `OverlayFile`,
+/// which shows the content last persisted.
+struct PersistedFile {
+    base: Rc<dyn RandomAccessFile>,
+}
+
+impl RandomAccessFile for PersistedFile {
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.base.read(pos, buf)
+    }
+    fn write(&self, _pos: usize, _buf: &[u8]) -> Result<(), Error> {
+        make_error(Error::ReadOnly)
+    }
+    fn len(&self) -> usize {
+        self.base.len()
+    }
+    fn commit(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Keeps the changes to host files in memory, so that they can be persisted or discarded as a
+/// whole. Set with [`Resource::set_overlay`](../struct.Resource.html#method.set_overlay).
+///
+/// A host file opened for writing through the overlay stays open, and locked, until the overlay
+/// is dropped. Opening it for writing again returns the same file with the changes, while
+/// opening it for reading shows the content last persisted. Committing an archive opened over
+/// the overlay only commits it in memory.
+///
+/// Host files created or removed by archives, such as extdata files, are not covered, and
+/// neither is formatting, which creates the host file.
+#[derive(Default)]
+pub struct Overlay {
+    files: RefCell<HashMap<PathBuf, Rc<OverlayFile>>>,
+}
+
+impl Overlay {
+    pub fn new() -> Overlay {
+        Overlay::default()
+    }
+
+    /// Returns whether there are changes that are not persisted.
+    pub fn is_dirty(&self) -> bool {
+        self.files
+            .borrow()
+            .values()
+            .any(|file| !file.pages.borrow().is_empty())
+    }
+
+    /// Writes all changes to the host files and flushes them. Commit the archives opened over
+    /// the overlay first, so that the changes form a consistent state. On failure, the changes
+    /// not written yet are kept, so that this can be retried.
+    pub fn persist(&self) -> Result<(), Error> {
+        for file in self.files.borrow().values() {
+            file.persist()?;
+        }
+        Ok(())
+    }
+
+    /// Drops all changes that are not persisted. Archives opened for writing over the overlay
+    /// keep state that no longer matches their files, so they must be dropped and opened again.
+    pub fn discard(&self) {
+        for file in self.files.borrow().values() {
+            file.pages.borrow_mut().clear();
+        }
+    }
+
+    /// Opens the host file at `path` through the overlay. `open_host` opens the host file itself,
+    /// in the given write mode, when it is not already open in the overlay.
+    pub(crate) fn open(
+        &self,
+        path: &Path,
+        write: bool,
+        open_host: impl FnOnce(bool) -> Result<Rc<dyn RandomAccessFile>, Error>,
+    ) -> Result<Rc<dyn RandomAccessFile>, Error> {
+        let mut files = self.files.borrow_mut();
+        match (files.get(path), write) {
+            (Some(file), true) => Ok(file.clone()),
+            (Some(file), false) => Ok(Rc::new(PersistedFile {
+                base: file.base.clone(),
+            })),
+            (None, true) => {
+                let file = Rc::new(OverlayFile {
+                    base: open_host(true)?,
+                    pages: RefCell::new(BTreeMap::new()),
+                });
+                files.insert(path.to_owned(), file.clone());
+                Ok(file)
+            }
+            (None, false) => open_host(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::memory_file::MemoryFile;
+    use crate::overlay::*;
+    use rand::distributions::Standard;
+    use rand::prelude::*;
+
+    fn open_memory(
+        overlay: &Overlay,
+        path: &str,
+        write: bool,
+        host: &Rc<MemoryFile>,
+    ) -> Rc<dyn RandomAccessFile> {
+        overlay
+            .open(Path::new(path), write, |_| {
+                Ok(host.clone() as Rc<dyn RandomAccessFile>)
+            })
+            .unwrap()
+    }
+
+    fn read_all(file: &dyn RandomAccessFile) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0; file.len()];
+        file.read(0, &mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn fuzz() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            let len = rng.gen_range(1..0x5000);
+            let init: Vec<u8> = (&mut rng).sample_iter(&Standard).take(len).collect();
+            let host = Rc::new(MemoryFile::new(init.clone()));
+            let overlay = Overlay::new();
+            let file = open_memory(&overlay, "a", true, &host);
+            let plain = MemoryFile::new(init.clone());
+            for _ in 0..100 {
+                let pos = rng.gen_range(0..len);
+                let data_len = rng.gen_range(1..=len - pos);
+                if rng.gen() {
+                    let data: Vec<u8> = (&mut rng).sample_iter(&Standard).take(data_len).collect();
+                    file.write(pos, &data).unwrap();
+                    plain.write(pos, &data).unwrap();
+                } else {
+                    let mut a = vec![0; data_len];
+                    let mut b = vec![0; data_len];
+                    file.read(pos, &mut a).unwrap();
+                    plain.read(pos, &mut b).unwrap();
+                    assert_eq!(a, b);
+                }
+            }
+            check_boundary(file.as_ref());
+            assert_eq!(read_all(host.as_ref()).unwrap(), init);
+        }
+    }
+
+    #[test]
+    fn persist_and_discard() {
+        let host = Rc::new(MemoryFile::new(vec![0; 0x3000]));
+        let overlay = Overlay::new();
+        let file = open_memory(&overlay, "a", true, &host);
+        let persisted = open_memory(&overlay, "a", false, &host);
+        assert!(Rc::ptr_eq(&file, &open_memory(&overlay, "a", true, &host)));
+        assert!(!overlay.is_dirty());
+
+        file.write(0xFFE, &[1, 2, 3, 4]).unwrap();
+        file.commit().unwrap();
+        assert!(overlay.is_dirty());
+        assert_eq!(read_all(host.as_ref()).unwrap(), vec![0; 0x3000]);
+        assert_eq!(read_all(persisted.as_ref()).unwrap(), vec![0; 0x3000]);
+        assert!(matches!(persisted.write(0, &[1]), Err(Error::ReadOnly)));
+
+        overlay.persist().unwrap();
+        assert!(!overlay.is_dirty());
+        let mut expected = vec![0; 0x3000];
+        expected[0xFFE..0x1002].copy_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(read_all(host.as_ref()).unwrap(), expected);
+        assert_eq!(read_all(persisted.as_ref()).unwrap(), expected);
+
+        file.write(0x2000, &[5; 0x1000]).unwrap();
+        assert!(overlay.is_dirty());
+        overlay.discard();
+        assert!(!overlay.is_dirty());
+        assert_eq!(read_all(file.as_ref()).unwrap(), expected);
+        assert_eq!(read_all(host.as_ref()).unwrap(), expected);
+    }
+}
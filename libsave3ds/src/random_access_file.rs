@@ -1,6 +1,6 @@
 use crate::error::*;
 use byte_struct::*;
-use std::borrow::Borrow;
+use log::*;
 
 /// Interface to a file that supports random access.
 ///
@@ -12,6 +12,10 @@ use std::borrow::Borrow;
 ///
 /// Many implementations of `RandomAccessFile` act as a "layer": they transforms data
 /// between the interface level and some other `RandomAccessFile`s as the underlying storage.
+///
+/// For both read and write, if `pos + buf.len()` exceeds `len()`, `Error::OutOfBound` is returned
+/// regardless of the length of `buf`. Otherwise, an operation with an empty `buf` is a no-op
+/// that returns `Ok(())`, including at `pos == len()`.
 pub trait RandomAccessFile {
     /// Reads bytes at position `pos` into `buf`. The lenth is determined by `buf.len()`.
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error>;
@@ -20,6 +24,12 @@ pub trait RandomAccessFile {
     fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error>;
 
     /// Returns the length of this file.
+    ///
+    /// This is the logical length as seen through this layer, i.e. the range of positions
+    /// `read` and `write` accept. It can differ from the length of the underlying storage:
+    /// layers with metadata or redundancy usually expose less than they occupy.
+    /// For most implementations the length is fixed when the object is created;
+    /// `FatFile` is the exception, whose length changes on `resize`.
     fn len(&self) -> usize;
 
     /// Flushes all changes made to the file,
@@ -34,25 +44,79 @@ pub trait RandomAccessFile {
     fn commit(&self) -> Result<(), Error>;
 }
 
+/// Checks that a `T` at `pos` fits in `f`, and logs the struct type and position otherwise.
+fn check_struct_bound<T: ByteStruct>(
+    f: &dyn RandomAccessFile,
+    pos: usize,
+    action: &str,
+) -> Result<(), Error> {
+    match pos.checked_add(T::BYTE_LEN) {
+        Some(end) if end <= f.len() => Ok(()),
+        _ => {
+            error!(
+                "{} {} (0x{:X} bytes) at 0x{:X} exceeds the file length 0x{:X}",
+                action,
+                std::any::type_name::<T>(),
+                T::BYTE_LEN,
+                pos,
+                f.len()
+            );
+            make_error(Error::OutOfBound)
+        }
+    }
+}
+
 /// Helper for reading a `ByteStruct` from a `RandomAccessFile`.
+///
+/// Returns `Error::OutOfBound` if the struct doesn't fit in the file.
 pub fn read_struct<T: ByteStruct>(f: &dyn RandomAccessFile, pos: usize) -> Result<T, Error> {
+    check_struct_bound::<T>(f, pos, "Reading")?;
     let mut buf = vec![0; T::BYTE_LEN]; // array somehow broken with the associated item as size
-    f.borrow().read(pos, &mut buf)?;
+    f.read(pos, &mut buf)?;
     Ok(T::read_bytes(&buf))
 }
 
 /// Helper for writing a `ByteStruct` to a `RandomAccessFile`.
+///
+/// Returns `Error::OutOfBound` if the struct doesn't fit in the file.
 pub fn write_struct<T: ByteStruct>(
     f: &dyn RandomAccessFile,
     pos: usize,
     data: T,
 ) -> Result<(), Error> {
+    check_struct_bound::<T>(f, pos, "Writing")?;
     let mut buf = vec![0; T::BYTE_LEN]; // array somehow broken with the associated item as size
     data.write_bytes(&mut buf);
-    f.borrow().write(pos, &buf)?;
+    f.write(pos, &buf)?;
     Ok(())
 }
 
+/// Checks the boundary behaviour of a `RandomAccessFile` implementation.
+#[cfg(test)]
+pub fn check_boundary(file: &dyn RandomAccessFile) {
+    let len = file.len();
+    file.read(len, &mut []).unwrap();
+    file.write(len, &[]).unwrap();
+    assert!(matches!(file.read(len, &mut [0]), Err(Error::OutOfBound)));
+    assert!(matches!(file.write(len, &[0]), Err(Error::OutOfBound)));
+    assert!(matches!(
+        file.read(len + 1, &mut []),
+        Err(Error::OutOfBound)
+    ));
+    assert!(matches!(file.write(len + 1, &[]), Err(Error::OutOfBound)));
+    if len != 0 {
+        assert!(matches!(
+            file.read(len - 1, &mut [0, 0]),
+            Err(Error::OutOfBound)
+        ));
+        assert!(matches!(
+            file.write(len - 1, &[0, 0]),
+            Err(Error::OutOfBound)
+        ));
+    }
+    assert_eq!(file.len(), len);
+}
+
 /// Driver for fuzz test an implementation for `RandomAccessFile`.
 ///
 /// - `subject`: the object that contains the `RandomAccessFile` implementation to test.
@@ -74,6 +138,7 @@ pub fn fuzzer<Subject, SubjectFile: RandomAccessFile, Control: RandomAccessFile>
     use rand::prelude::*;
 
     let len = accessor(&subject).len();
+    check_boundary(accessor(&subject));
     let mut rng = rand::thread_rng();
     for _ in 0..1000 {
         let operation = rng.gen_range(1..10);
@@ -99,3 +164,33 @@ pub fn fuzzer<Subject, SubjectFile: RandomAccessFile, Control: RandomAccessFile>
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::byte_struct_common::*;
+    use crate::memory_file::MemoryFile;
+    use crate::random_access_file::*;
+
+    #[test]
+    fn struct_bound() {
+        let file = MemoryFile::new(vec![0; 10]);
+        write_struct(&file, 6, U32le { v: 0x1234_5678 }).unwrap();
+        assert_eq!(read_struct::<U32le>(&file, 6).unwrap().v, 0x1234_5678);
+
+        for &pos in &[7, 10, 11, usize::MAX - 2] {
+            assert!(matches!(
+                read_struct::<U32le>(&file, pos),
+                Err(Error::OutOfBound)
+            ));
+            assert!(matches!(
+                write_struct(&file, pos, U32le { v: 0 }),
+                Err(Error::OutOfBound)
+            ));
+        }
+
+        // a failed write leaves the file untouched
+        let mut buf = [0; 10];
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0, 0, 0, 0, 0, 0, 0x78, 0x56, 0x34, 0x12]);
+    }
+}
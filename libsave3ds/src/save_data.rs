@@ -1,16 +1,22 @@
 use crate::difi_partition::*;
 use crate::disa::Disa;
+pub use crate::dpfs_level::DpfsLevelState;
 use crate::error::*;
 use crate::fat::*;
 use crate::file_system::*;
 use crate::fs_meta::{self, FileInfo, FsInfo, OffsetOrFatFile};
+use crate::header_dump::*;
 use crate::misc::*;
 use crate::random_access_file::*;
 use crate::save_ext_common::*;
+use crate::scratch_file::ScratchFile;
 use crate::signed_file::*;
 use crate::sub_file::SubFile;
 use byte_struct::*;
 use log::*;
+use sha2::*;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
 use std::rc::Rc;
 
 #[derive(ByteStruct, Clone)]
@@ -93,11 +99,14 @@ struct SaveHeader {
 }
 
 struct SaveDataInner {
+    raw: Rc<dyn RandomAccessFile>,
     disa: Rc<Disa>,
     fat: Rc<Fat>,
     fs: Rc<FsMeta>,
     block_len: usize,
     block_count: usize,
+    uncommitted_files: RefCell<BTreeSet<u32>>, // files written or resized since the last commit
+    write: bool,
 }
 
 /// Implements [`FileSystem`](../file_system/trait.FileSystem.html) for game save data.
@@ -115,6 +124,7 @@ pub(crate) enum SaveDataType {
 
 /// Block types of a save data.
 #[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SaveDataBlockType {
     /// 512-byte block, for game save.
     Small,
@@ -123,10 +133,40 @@ pub enum SaveDataBlockType {
     Large,
 }
 
+/// How the directory and file tables of a save data are stored.
+/// See [`SaveData::table_storage`](struct.SaveData.html#method.table_storage).
+///
+/// In both layouts the tables are journaled, so an interrupted commit never leaves the
+/// file system half-updated. The layouts differ in what happens to file data.
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TableStorage {
+    /// The tables are at fixed offsets in the journaled metadata partition, and file data is in
+    /// a second partition that is not journaled. Uncommitted changes to file data are lost
+    /// and leave the affected region uninitialized. This is the layout of save data formatted
+    /// with `duplicate_data == false`.
+    Inline,
+
+    /// The tables are files allocated in the FAT like file data, all in one journaled
+    /// partition. Uncommitted changes to file data roll back as well, at the cost of storing
+    /// every block twice. This is the layout of save data formatted with `duplicate_data == true`.
+    Fat,
+}
+
+impl std::fmt::Display for TableStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TableStorage::Inline => write!(f, "inline"),
+            TableStorage::Fat => write!(f, "fat"),
+        }
+    }
+}
+
 /// Configuration for formatting a save data.
 /// This is similar to parameters of
 /// [`FS:FormatSaveData`](https://www.3dbrew.org/wiki/FS:FormatSaveData).
 #[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SaveDataFormatParam {
     pub block_type: SaveDataBlockType,
     pub max_dir: usize,
@@ -136,6 +176,44 @@ pub struct SaveDataFormatParam {
     pub duplicate_data: bool,
 }
 
+/// Returns a sensible hash table bucket count for a table holding up to `max_entries` entries.
+///
+/// The result is at least `max_entries` (and at least 3), and for larger tables,
+/// is not divisible by any prime below 19, which gives a good distribution of the name hash.
+/// This can be used for `dir_buckets` and `file_buckets` in both
+/// `SaveDataFormatParam` and `ExtDataFormatParam`.
+pub fn default_buckets(max_entries: usize) -> usize {
+    if max_entries < 3 {
+        3
+    } else if max_entries < 19 {
+        max_entries | 1
+    } else {
+        let mut count = max_entries;
+        while [2, 3, 5, 7, 11, 13, 17]
+            .iter()
+            .any(|p| count.is_multiple_of(*p))
+        {
+            count += 1;
+        }
+        count
+    }
+}
+
+/// The outcome of checking a `SaveDataFormatParam` against an image size without formatting.
+/// See `SaveData::format_preflight`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FormatReport {
+    /// Whether the parameters fit in the image at all.
+    pub fits: bool,
+
+    /// Number of data blocks the formatted save data would have. Zero if it doesn't fit.
+    pub block_count: usize,
+
+    /// The `Stat` a freshly formatted save data would report. `None` if it doesn't fit.
+    pub stat: Option<Stat>,
+}
+
 struct SaveDataInfo {
     block_len: usize,
     param_a: DifiPartitionParam,
@@ -278,6 +356,18 @@ impl SaveData {
         }
     }
 
+    /// Re-signs the DISA header in `file` as a save data of `save_data_type`.
+    /// See [`Disa::sign`](../disa/struct.Disa.html#method.sign).
+    pub(crate) fn sign(
+        file: Rc<dyn RandomAccessFile>,
+        save_data_type: SaveDataType,
+    ) -> Result<(), Error> {
+        match SaveData::get_signer(save_data_type) {
+            Some(signer) => Disa::sign(file, signer),
+            None => make_error(Error::InvalidValue),
+        }
+    }
+
     pub(crate) fn format(
         file: Rc<dyn RandomAccessFile>,
         save_data_type: SaveDataType,
@@ -418,11 +508,107 @@ impl SaveData {
         Ok(())
     }
 
+    /// Computes the layout that formatting an image of `disa_len` bytes with `param` would
+    /// produce, without touching the image itself.
+    ///
+    /// The stat is taken from a scratch format, so it agrees with what `format` followed by
+    /// `stat` would give. The scratch format is kept in memory if it is not longer than
+    /// `memory_limit`, and in a temporary file in the temporary directory otherwise.
+    pub fn format_preflight(
+        param: &SaveDataFormatParam,
+        disa_len: usize,
+        memory_limit: usize,
+    ) -> Result<FormatReport, Error> {
+        let block_count = SaveData::calculate_capacity(param, disa_len);
+        if block_count == 0 {
+            return Ok(FormatReport {
+                fits: false,
+                block_count,
+                stat: None,
+            });
+        }
+
+        let scratch = Rc::new(ScratchFile::new(
+            disa_len,
+            memory_limit,
+            &std::env::temp_dir(),
+        )?);
+        SaveData::format(scratch.clone(), SaveDataType::Bare, param)?;
+        let stat = SaveData::new(scratch, SaveDataType::Bare, false)?.stat()?;
+        Ok(FormatReport {
+            fits: true,
+            block_count,
+            stat: Some(stat),
+        })
+    }
+
     pub(crate) fn new(
         file: Rc<dyn RandomAccessFile>,
         save_data_type: SaveDataType,
+        write: bool,
     ) -> Result<SaveData, Error> {
-        let disa = Rc::new(Disa::new(file, SaveData::get_signer(save_data_type))?);
+        let disa = Disa::new(file.clone(), SaveData::get_signer(save_data_type))?;
+        SaveData::from_disa(file, disa, write, false, None)
+    }
+
+    /// Same as `new`, but tolerates a FAT with more entries than data blocks.
+    ///
+    /// Some saves have `fat_size > data_block_count`, with the trailing FAT entries left as
+    /// slack. The FAT is then clamped to `data_block_count` entries. This is safe because the
+    /// free list is fully walked on open and every file chain is walked when the file is
+    /// opened, so any reference into the slack fails with an error instead of being followed.
+    /// The header itself is not rewritten. `data_block_count > fat_size` is still an error.
+    pub(crate) fn new_allow_inconsistent(
+        file: Rc<dyn RandomAccessFile>,
+        save_data_type: SaveDataType,
+        write: bool,
+    ) -> Result<SaveData, Error> {
+        let disa = Disa::new(file.clone(), SaveData::get_signer(save_data_type))?;
+        SaveData::from_disa(file, disa, write, true, None)
+    }
+
+    /// Opens the save data using the DISA partition table copy `table_index`.
+    /// See `Disa::new_with_table`.
+    pub(crate) fn new_with_table(
+        file: Rc<dyn RandomAccessFile>,
+        save_data_type: SaveDataType,
+        table_index: usize,
+        write: bool,
+    ) -> Result<SaveData, Error> {
+        let disa = Disa::new_with_table(
+            file.clone(),
+            SaveData::get_signer(save_data_type),
+            table_index,
+        )?;
+        SaveData::from_disa(file, disa, write, false, None)
+    }
+
+    /// Opens the save data read-only, interpreting the FAT and the data region with the block
+    /// length of `block_type` instead of the one recorded in the file system information.
+    ///
+    /// This is for recovering saves whose recorded block length is corrupted while the data is
+    /// intact. A wrong `block_type` makes the FAT and every file read as garbage.
+    pub(crate) fn new_with_block_type(
+        file: Rc<dyn RandomAccessFile>,
+        save_data_type: SaveDataType,
+        block_type: SaveDataBlockType,
+    ) -> Result<SaveData, Error> {
+        let disa = Disa::new(file.clone(), SaveData::get_signer(save_data_type))?;
+        let block_len = match block_type {
+            SaveDataBlockType::Small => 512,
+            SaveDataBlockType::Large => 4096,
+        };
+        SaveData::from_disa(file, disa, false, false, Some(block_len))
+    }
+
+    fn from_disa(
+        file: Rc<dyn RandomAccessFile>,
+        disa: Disa,
+        write: bool,
+        allow_inconsistent: bool,
+        forced_block_len: Option<u32>,
+    ) -> Result<SaveData, Error> {
+        let disa = Rc::new(disa);
         let header: SaveHeader = read_struct(disa[0].as_ref(), 0)?;
         if header.magic != *b"SAVE" || header.version != 0x40000 {
             error!(
@@ -431,14 +617,32 @@ impl SaveData {
             );
             return make_error(Error::MagicMismatch);
         }
-        let fs_info: FsInfo = read_struct(disa[0].as_ref(), header.fs_info_offset as usize)?;
-        if fs_info.data_block_count != fs_info.fat_size {
+        let mut fs_info: FsInfo = read_struct(disa[0].as_ref(), header.fs_info_offset as usize)?;
+        if let Some(block_len) = forced_block_len {
+            if block_len != fs_info.block_len || block_len != header.image_block_len {
+                warn!(
+                    "Overriding block_len={}, image_block_len={} with {}",
+                    fs_info.block_len, header.image_block_len, block_len
+                );
+            }
+            fs_info.block_len = block_len;
+        }
+        let fat_size = if fs_info.data_block_count == fs_info.fat_size {
+            fs_info.fat_size
+        } else if allow_inconsistent && fs_info.data_block_count < fs_info.fat_size {
+            warn!(
+                "Ignoring {} FAT entries beyond data_block_count={}",
+                fs_info.fat_size - fs_info.data_block_count,
+                fs_info.data_block_count
+            );
+            fs_info.data_block_count
+        } else {
             error!(
                 "Unexpected data_block_count={}, fat_size={}",
                 fs_info.data_block_count, fs_info.fat_size
             );
             return make_error(Error::SizeMismatch);
-        }
+        };
 
         let dir_hash = Rc::new(SubFile::new(
             disa[0].clone(),
@@ -455,11 +659,19 @@ impl SaveData {
         let fat_table = Rc::new(SubFile::new(
             disa[0].clone(),
             fs_info.fat_offset as usize,
-            (fs_info.fat_size + 1) as usize * 8,
+            (fat_size + 1) as usize * 8,
         )?);
 
         let data: Rc<dyn RandomAccessFile> = if disa.partition_count() == 2 {
-            disa[1].clone()
+            if fat_size != fs_info.fat_size {
+                Rc::new(SubFile::new(
+                    disa[1].clone(),
+                    0,
+                    (fs_info.data_block_count * fs_info.block_len) as usize,
+                )?)
+            } else {
+                disa[1].clone()
+            }
         } else {
             Rc::new(SubFile::new(
                 disa[0].clone(),
@@ -496,16 +708,413 @@ impl SaveData {
 
         Ok(SaveData {
             center: Rc::new(SaveDataInner {
+                raw: file,
                 disa,
                 fat,
                 fs,
                 block_len: fs_info.block_len as usize,
                 block_count: fs_info.data_block_count as usize,
+                uncommitted_files: RefCell::new(BTreeSet::new()),
+                write,
             }),
         })
     }
 }
 
+/// A data block that failed hash verification. See
+/// [`SaveData::broken_blocks`](struct.SaveData.html#method.broken_blocks).
+#[derive(PartialEq, Eq, Clone, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BrokenBlock {
+    /// Index of the DISA partition the block belongs to.
+    pub partition: usize,
+
+    /// Index of the IVFC level-4 block within the partition.
+    pub block_index: usize,
+
+    /// Byte range of the block within the partition.
+    pub range: std::ops::Range<usize>,
+}
+
+/// The DPFS copy selection of a DISA partition. See
+/// [`SaveData::dpfs_state`](struct.SaveData.html#method.dpfs_state).
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DpfsState {
+    /// Index of the DISA partition.
+    pub partition: usize,
+
+    /// Committed selector of DPFS level 1, which is a single dual-image file.
+    pub level1_selector: u8,
+
+    /// Whether DPFS level 1 has been modified since the last commit.
+    pub level1_modified: bool,
+
+    /// Copy selection of DPFS level 2, whose selector is level 1.
+    pub level2: DpfsLevelState,
+
+    /// Copy selection of DPFS level 3, whose selector is level 2.
+    pub level3: DpfsLevelState,
+}
+
+impl SaveData {
+    /// Dumps every header structure of the save data field by field, for bug reports:
+    /// the DISA header, the DIFI/IVFC/DPFS descriptors of each partition, the SAVE header
+    /// and the file system information. The CMAC is not included.
+    pub fn dump_headers(&self) -> Result<Vec<HeaderDump>, Error> {
+        let disa = &self.center.disa;
+        let mut dumps = disa.dump_headers()?;
+        let header: SaveHeader = read_struct(disa[0].as_ref(), 0)?;
+        dumps.push(dump_fields!(
+            "SaveHeader",
+            header,
+            magic,
+            version,
+            fs_info_offset,
+            image_size,
+            image_block_len,
+            padding,
+        ));
+        let fs_info: FsInfo = read_struct(disa[0].as_ref(), header.fs_info_offset as usize)?;
+        dumps.push(fs_info.dump());
+        Ok(dumps)
+    }
+
+    /// Returns which copy is active for every DPFS block, per partition, for debugging.
+    ///
+    /// This only reads the selectors and doesn't verify any data.
+    pub fn dpfs_state(&self) -> Result<Vec<DpfsState>, Error> {
+        let disa = &self.center.disa;
+        (0..disa.partition_count())
+            .map(|partition| {
+                let ((level1_selector, level1_modified), level2, level3) =
+                    disa[partition].dpfs_debug_state()?;
+                Ok(DpfsState {
+                    partition,
+                    level1_selector,
+                    level1_modified,
+                    level2,
+                    level3,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the data blocks found broken so far, either corrupted or uninitialized.
+    ///
+    /// Blocks are verified when they are first read, so this only covers data that has been
+    /// read since the save data was opened. It can be called after a read returned
+    /// `Error::HashMismatch` to locate the failure.
+    pub fn broken_blocks(&self) -> Vec<BrokenBlock> {
+        let disa = &self.center.disa;
+        (0..disa.partition_count())
+            .flat_map(|partition| {
+                disa[partition]
+                    .broken_blocks()
+                    .into_iter()
+                    .map(move |(block_index, range)| BrokenBlock {
+                        partition,
+                        block_index,
+                        range,
+                    })
+            })
+            .collect()
+    }
+
+    /// Groups files that have identical content.
+    ///
+    /// Each returned group contains the inodes of two or more non-empty files whose data are
+    /// byte-identical, sorted in ascending order. File data is read through the normal verified
+    /// path, so any uninitialized region fails the scan with `Error::HashMismatch`.
+    ///
+    /// Identical files can't share blocks. The FAT is a set of doubly linked block chains, so
+    /// every block belongs to exactly one file, and deleting or resizing a file frees or
+    /// relinks its blocks without any reference count.
+    pub fn find_duplicates(&self) -> Result<Vec<Vec<u32>>, Error> {
+        let mut groups: HashMap<(usize, Vec<u8>), Vec<u32>> = HashMap::new();
+        let mut pending = vec![self.open_root()?];
+        while let Some(dir) = pending.pop() {
+            for (_, ino) in dir.list_sub_dir()? {
+                pending.push(self.open_dir(ino)?);
+            }
+            for (_, ino) in dir.list_sub_file()? {
+                let file = self.open_file(ino)?;
+                if file.is_empty() {
+                    continue;
+                }
+                let mut buf = vec![0; file.len()];
+                file.read(0, &mut buf)?;
+                let hash = Sha256::digest(&buf).to_vec();
+                groups.entry((file.len(), hash)).or_default().push(ino);
+            }
+        }
+
+        let mut result: Vec<Vec<u32>> = groups
+            .into_values()
+            .filter(|group| group.len() > 1)
+            .map(|mut group| {
+                group.sort_unstable();
+                group
+            })
+            .collect();
+        result.sort_unstable();
+        Ok(result)
+    }
+
+    /// Returns the index of the active DISA partition table copy (0 for primary, 1 for secondary).
+    /// The other copy usually holds the state before the last commit.
+    pub fn active_table(&self) -> Result<usize, Error> {
+        self.center.disa.active_table()
+    }
+
+    /// Returns whether file data is in the journaled partition, i.e. whether the save data was
+    /// formatted with `duplicate_data == true`. Such save data stores file data twice.
+    pub fn duplicate_data(&self) -> bool {
+        self.center.disa.partition_count() == 1
+    }
+
+    /// Returns how the directory and file tables are stored, which decides what survives an
+    /// interrupted write. See [`TableStorage`](enum.TableStorage.html).
+    pub fn table_storage(&self) -> TableStorage {
+        if self.center.disa.partition_count() == 2 {
+            TableStorage::Inline
+        } else {
+            TableStorage::Fat
+        }
+    }
+
+    /// Sets the byte that fills data failing hash verification on read, such as
+    /// uninitialized file data. `0xDD` is used if this is never called.
+    pub fn set_fill_byte(&self, fill: u8) {
+        self.center.disa.set_fill(fill);
+    }
+
+    /// Returns the number of bytes whose uncommitted changes are journaled, i.e. roll back
+    /// instead of becoming uninitialized if the save data is dropped without `commit`.
+    ///
+    /// DISA doesn't have a journal of a configurable size. Instead, DPFS keeps two copies of
+    /// the whole journaled partition, so this is the length of partition A. With
+    /// `duplicate_data == false`, this only covers the file system metadata, not the file data.
+    pub fn journal_capacity(&self) -> usize {
+        self.center.disa[0].len()
+    }
+
+    /// Computes a SHA-256 fingerprint of the file tree and file data, which doesn't depend
+    /// on the format parameters or the physical layout.
+    /// See [`content_fingerprint`](../file_system/fn.content_fingerprint.html).
+    pub fn content_fingerprint(&self) -> Result<[u8; 32], Error> {
+        crate::file_system::content_fingerprint(self)
+    }
+
+    /// Computes a SHA-256 hash of the raw directory table, file table and FAT, without
+    /// reading any file data.
+    ///
+    /// The hash changes when directories or files are added, removed, renamed or resized, but
+    /// not when file data is overwritten in place. This is much cheaper than
+    /// [`content_fingerprint`](#method.content_fingerprint) for checking whether the tree
+    /// changed. Unlike the fingerprint, it depends on the physical layout, so it is only
+    /// meaningful for comparing states of the same save data.
+    pub fn metadata_hash(&self) -> Result<[u8; 32], Error> {
+        let mut hasher = Sha256::new();
+        self.center.fs.hash_raw(&mut hasher)?;
+        self.center.fat.hash_raw(&mut hasher)?;
+        Ok(hasher.finalize().into())
+    }
+
+    /// Returns the inodes of files whose data have been written or resized since the last
+    /// `commit`, in ascending order. Deleted files are not included.
+    ///
+    /// These are the files whose changes would be lost if the save data were dropped now.
+    /// See [`commit`](#method.commit) for details.
+    pub fn uncommitted_files(&self) -> Vec<u32> {
+        self.center
+            .uncommitted_files
+            .borrow()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the parameters the save data was formatted with.
+    pub fn format_param(&self) -> Result<SaveDataFormatParam, Error> {
+        let disa = &self.center.disa;
+        let header: SaveHeader = read_struct(disa[0].as_ref(), 0)?;
+        let fs_info: FsInfo = read_struct(disa[0].as_ref(), header.fs_info_offset as usize)?;
+        let block_type = match self.center.block_len {
+            512 => SaveDataBlockType::Small,
+            4096 => SaveDataBlockType::Large,
+            _ => return make_error(Error::Unsupported),
+        };
+        Ok(SaveDataFormatParam {
+            block_type,
+            max_dir: fs_info.max_dir as usize,
+            dir_buckets: fs_info.dir_buckets as usize,
+            max_file: fs_info.max_file as usize,
+            file_buckets: fs_info.file_buckets as usize,
+            duplicate_data: self.duplicate_data(),
+        })
+    }
+
+    /// Returns the length of a bare image with the same parameters and just enough blocks for
+    /// the blocks in use. See [`trim_into`](#method.trim_into).
+    pub(crate) fn trimmed_len(&self) -> Result<usize, Error> {
+        let param = self.format_param()?;
+        let stat = self.stat()?;
+        let block_count = std::cmp::max(stat.total_blocks - stat.free_blocks, 1);
+        Ok(SaveData::calculate_size(&param, block_count))
+    }
+
+    /// Formats `image`, whose length is given by `trimmed_len`, as a bare image with the same
+    /// parameters, and copies the file tree into it.
+    ///
+    /// Inodes are reassigned in the copy. File data failing hash verification is copied as the
+    /// fill byte.
+    pub(crate) fn trim_into(&self, image: Rc<dyn RandomAccessFile>) -> Result<(), Error> {
+        fn copy_dir(from: &Dir, to: &Dir) -> Result<(), Error> {
+            for (name, _) in from.list_sub_dir()? {
+                copy_dir(&from.open_sub_dir(name)?, &to.new_sub_dir(name)?)?;
+            }
+            for (name, _) in from.list_sub_file()? {
+                let data = from.open_sub_file(name)?.read_all()?;
+                to.new_sub_file(name, data.len())?.write(0, &data)?;
+            }
+            Ok(())
+        }
+
+        let param = self.format_param()?;
+        SaveData::format(image.clone(), SaveDataType::Bare, &param)?;
+        let trimmed = SaveData::new(image, SaveDataType::Bare, true)?;
+        copy_dir(&self.open_root()?, &trimmed.open_root()?)?;
+        trimmed.commit()
+    }
+
+    fn read_image(&self) -> Result<Vec<u8>, Error> {
+        let mut image = vec![0; self.center.raw.len()];
+        self.center.raw.read(0, &mut image)?;
+        Ok(image)
+    }
+
+    /// Exports the whole (decrypted) image of the save data, together with its manifest,
+    /// which can be passed to `export_delta` later.
+    ///
+    /// Changes are only reflected in the image after `commit`.
+    pub fn export_image(&self) -> Result<(Vec<u8>, ImageManifest), Error> {
+        let image = self.read_image()?;
+        let manifest = ImageManifest::new(&image, self.center.block_len);
+        Ok((image, manifest))
+    }
+
+    /// Exports the changes made to the image since the export that produced `base`.
+    /// The result can be applied to that export using [`apply_delta`](fn.apply_delta.html).
+    ///
+    /// Changes are only reflected in the image after `commit`.
+    pub fn export_delta(&self, base: &ImageManifest) -> Result<ImageDelta, Error> {
+        let image = self.read_image()?;
+        let manifest = ImageManifest::new(&image, base.block_len);
+        if manifest.len != base.len {
+            return make_error(Error::SizeMismatch);
+        }
+
+        let mut ranges: Vec<(usize, Vec<u8>)> = vec![];
+        for (i, hash) in manifest.hashes.iter().enumerate() {
+            if *hash == base.hashes[i] {
+                continue;
+            }
+            let begin = i * manifest.block_len;
+            let end = std::cmp::min(begin + manifest.block_len, manifest.len);
+            match ranges.last_mut() {
+                Some((pos, data)) if *pos + data.len() == begin => {
+                    data.extend_from_slice(&image[begin..end])
+                }
+                _ => ranges.push((begin, image[begin..end].to_vec())),
+            }
+        }
+
+        Ok(ImageDelta {
+            base_digest: base.digest(),
+            manifest,
+            ranges,
+        })
+    }
+}
+
+/// Block-wise SHA-256 digest of an exported save data image.
+/// See [`SaveData::export_image`](struct.SaveData.html#method.export_image).
+#[derive(PartialEq, Eq, Clone, Hash, Debug)]
+pub struct ImageManifest {
+    /// Size in bytes of each hashed block.
+    pub block_len: usize,
+
+    /// Length of the image.
+    pub len: usize,
+
+    /// Hashes of each block. The last block may be shorter than `block_len`.
+    pub hashes: Vec<[u8; 0x20]>,
+}
+
+impl ImageManifest {
+    fn new(image: &[u8], block_len: usize) -> ImageManifest {
+        ImageManifest {
+            block_len,
+            len: image.len(),
+            hashes: image
+                .chunks(block_len)
+                .map(|block| Sha256::digest(block).into())
+                .collect(),
+        }
+    }
+
+    /// Returns a hash identifying the whole manifest.
+    fn digest(&self) -> [u8; 0x20] {
+        let mut hasher = Sha256::new();
+        for hash in &self.hashes {
+            hasher.update(hash);
+        }
+        hasher.finalize().into()
+    }
+}
+
+/// Changes of a save data image relative to a previous export.
+/// See [`SaveData::export_delta`](struct.SaveData.html#method.export_delta).
+#[derive(PartialEq, Eq, Clone, Hash, Debug)]
+pub struct ImageDelta {
+    /// Identifies the manifest the delta is based on.
+    pub base_digest: [u8; 0x20],
+
+    /// Manifest of the image after applying the delta.
+    pub manifest: ImageManifest,
+
+    /// Changed ranges, as tuples of the position and the new data, in ascending order.
+    pub ranges: Vec<(usize, Vec<u8>)>,
+}
+
+/// Applies `delta` to an image previously exported by `SaveData::export_image`
+/// or reconstructed by this function.
+///
+/// Returns `Error::InvalidValue` without modifying `image` if `delta` is not based on it,
+/// or `Error::HashMismatch` if the result doesn't match the manifest of `delta`.
+pub fn apply_delta(image: &mut [u8], delta: &ImageDelta) -> Result<(), Error> {
+    let block_len = delta.manifest.block_len;
+    if image.len() != delta.manifest.len {
+        return make_error(Error::SizeMismatch);
+    }
+    if ImageManifest::new(image, block_len).digest() != delta.base_digest {
+        return make_error(Error::InvalidValue);
+    }
+    for (pos, data) in &delta.ranges {
+        if pos + data.len() > image.len() {
+            return make_error(Error::OutOfBound);
+        }
+    }
+    for (pos, data) in &delta.ranges {
+        image[*pos..*pos + data.len()].copy_from_slice(data);
+    }
+    if ImageManifest::new(image, block_len) != delta.manifest {
+        return make_error(Error::HashMismatch);
+    }
+    Ok(())
+}
+
 /// Implements [`FileSystemFile`](../file_system/trait.FileSystemFile.html) for save data file.
 pub struct File {
     center: Rc<SaveDataInner>,
@@ -561,10 +1170,13 @@ impl FileSystemFile for File {
     }
 
     fn delete(self) -> Result<(), Error> {
+        let ino = self.meta.get_ino();
         if let Some(f) = self.data {
             f.delete()?;
         }
-        self.meta.delete()
+        self.meta.delete()?;
+        self.center.uncommitted_files.borrow_mut().remove(&ino);
+        Ok(())
     }
 
     fn resize(&mut self, len: usize) -> Result<(), Error> {
@@ -599,27 +1211,35 @@ impl FileSystemFile for File {
         self.meta.set_info(info)?;
 
         self.len = len;
+        self.center
+            .uncommitted_files
+            .borrow_mut()
+            .insert(self.meta.get_ino());
 
         Ok(())
     }
 
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
-        if buf.is_empty() {
-            return Ok(());
-        }
         if pos + buf.len() > self.len {
             return make_error(Error::OutOfBound);
         }
+        if buf.is_empty() {
+            return Ok(());
+        }
         self.data.as_ref().unwrap().read(pos, buf)
     }
 
     fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
-        if buf.is_empty() {
-            return Ok(());
-        }
         if pos + buf.len() > self.len {
             return make_error(Error::OutOfBound);
         }
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.center
+            .uncommitted_files
+            .borrow_mut()
+            .insert(self.meta.get_ino());
         self.data.as_ref().unwrap().write(pos, buf)
     }
 
@@ -669,12 +1289,12 @@ impl FileSystemDir for Dir {
         File::from_meta(self.center.clone(), self.meta.open_sub_file(name)?)
     }
 
-    fn list_sub_dir(&self) -> Result<Vec<([u8; 16], u32)>, Error> {
-        self.meta.list_sub_dir()
+    fn iter_sub_dir(&self) -> Result<EntryIter<[u8; 16]>, Error> {
+        Ok(Box::new(self.meta.iter_sub_dir()?))
     }
 
-    fn list_sub_file(&self) -> Result<Vec<([u8; 16], u32)>, Error> {
-        self.meta.list_sub_file()
+    fn iter_sub_file(&self) -> Result<EntryIter<[u8; 16]>, Error> {
+        Ok(Box::new(self.meta.iter_sub_file()?))
     }
 
     fn new_sub_dir(&self, name: [u8; 16]) -> Result<Self, Error> {
@@ -726,6 +1346,70 @@ impl FileSystemDir for Dir {
         }
     }
 
+    /// Checks the free file entries and blocks for all files up front, and links all new
+    /// entries to this directory in one update.
+    fn new_sub_files(&self, entries: &[([u8; 16], usize)]) -> Result<Vec<File>, Error> {
+        for (name, _) in entries {
+            if self.meta.open_sub_file(*name).is_ok() || self.meta.open_sub_dir(*name).is_ok() {
+                return make_error(Error::AlreadyExist);
+            }
+        }
+        let blocks: usize = entries
+            .iter()
+            .map(|(_, len)| divide_up(*len, self.center.block_len))
+            .sum();
+        if blocks > self.center.fat.free_blocks() {
+            return make_error(Error::NoSpace);
+        }
+
+        let mut fat_files = vec![];
+        let mut infos = vec![];
+        let mut result = Ok(());
+        for (name, len) in entries {
+            let block = if *len == 0 {
+                0x8000_0000
+            } else {
+                match FatFile::create(
+                    self.center.fat.clone(),
+                    divide_up(*len, self.center.block_len),
+                ) {
+                    Ok((fat_file, block)) => {
+                        fat_files.push(fat_file);
+                        block as u32
+                    }
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            };
+            infos.push((
+                *name,
+                SaveFile {
+                    next: 0,
+                    padding1: 0,
+                    block,
+                    size: *len as u64,
+                    padding2: 0,
+                },
+            ));
+        }
+
+        let metas = match result.and_then(|()| self.meta.new_sub_files(infos)) {
+            Ok(metas) => metas,
+            Err(e) => {
+                for f in fat_files {
+                    f.delete()?;
+                }
+                return Err(e);
+            }
+        };
+        metas
+            .into_iter()
+            .map(|meta| File::from_meta(self.center.clone(), meta))
+            .collect()
+    }
+
     fn delete(self) -> Result<(), Error> {
         self.meta.delete()
     }
@@ -759,11 +1443,21 @@ impl FileSystem for SaveData {
     /// the value of [`duplicate_data`](struct.SaveDataFormatParam.html#structfield.duplicate_data)
     /// used when formatting the save data:
     ///  - `duplicate_data == false`: changes made to the file system (new/delete/rename files/directories)
-    /// roll back to the state the last time `commit` is called. Changes to file data are dropped and the
-    /// affected region becomes uninitialized.
+    ///    roll back to the state the last time `commit` is called. Changes to file data are dropped and the
+    ///    affected region becomes uninitialized.
     ///  - `duplicate_data == true`: all data rolls back to the state the last time `commit` is called.
+    ///
+    /// [`table_storage`](struct.SaveData.html#method.table_storage) tells which case applies to
+    /// an opened save data.
+    ///
+    /// This is a no-op if the save data is opened read-only.
     fn commit(&self) -> Result<(), Error> {
-        self.center.disa.commit()
+        if !self.center.write {
+            return Ok(());
+        }
+        self.center.disa.commit()?;
+        self.center.uncommitted_files.borrow_mut().clear();
+        Ok(())
     }
 
     fn stat(&self) -> Result<Stat, Error> {
@@ -784,6 +1478,43 @@ impl FileSystem for SaveData {
 mod test {
     use crate::memory_file::*;
     use crate::save_data::*;
+
+    fn new_save(duplicate_data: bool) -> (Rc<MemoryFile>, SaveData) {
+        let param = SaveDataFormatParam {
+            duplicate_data,
+            ..crate::test_util::format_param()
+        };
+        let disa_raw = Rc::new(MemoryFile::new(vec![0; 100_000]));
+        SaveData::format(disa_raw.clone(), SaveDataType::Bare, &param).unwrap();
+        let save = SaveData::new(disa_raw.clone(), SaveDataType::Bare, true).unwrap();
+        (disa_raw, save)
+    }
+
+    #[test]
+    fn default_buckets_boundary() {
+        assert_eq!(default_buckets(0), 3);
+        assert_eq!(default_buckets(2), 3);
+        assert_eq!(default_buckets(3), 3);
+        assert_eq!(default_buckets(10), 11);
+        assert_eq!(default_buckets(11), 11);
+        assert_eq!(default_buckets(18), 19);
+        assert_eq!(default_buckets(19), 19);
+        assert_eq!(default_buckets(20), 23);
+        assert_eq!(default_buckets(100), 101);
+        assert_eq!(default_buckets(120), 127);
+        assert_eq!(default_buckets(1000), 1007);
+
+        for n in 0..10000 {
+            let buckets = default_buckets(n);
+            assert!(buckets >= n && buckets >= 3);
+            if n >= 19 {
+                assert!([2, 3, 5, 7, 11, 13, 17]
+                    .iter()
+                    .all(|p| !buckets.is_multiple_of(*p)));
+            }
+        }
+    }
+
     #[test]
     fn struct_size() {
         assert_eq!(SaveHeader::BYTE_LEN, 0x20);
@@ -830,16 +1561,998 @@ mod test {
             let disa_len = rng.gen_range(100_000..1_000_000);
             let disa_raw = Rc::new(MemoryFile::new(vec![0; disa_len]));
             SaveData::format(disa_raw.clone(), SaveDataType::Bare, &param).unwrap();
-            let file_system = SaveData::new(disa_raw.clone(), SaveDataType::Bare).unwrap();
+            let file_system = SaveData::new(disa_raw.clone(), SaveDataType::Bare, true).unwrap();
 
             crate::file_system::test::fuzzer(
                 file_system,
                 param.max_dir,
                 param.max_file,
-                || SaveData::new(disa_raw.clone(), SaveDataType::Bare).unwrap(),
+                || SaveData::new(disa_raw.clone(), SaveDataType::Bare, true).unwrap(),
                 gen_name,
                 gen_len,
             );
         }
     }
+
+    #[test]
+    fn wrong_key() {
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: false,
+        };
+        let disa_raw = Rc::new(MemoryFile::new(vec![0; 100_000]));
+        SaveData::format(
+            disa_raw.clone(),
+            SaveDataType::Nand([1; 16], 0x1234),
+            &param,
+        )
+        .unwrap();
+        SaveData::new(disa_raw.clone(), SaveDataType::Nand([1; 16], 0x1234), false).unwrap();
+        assert!(matches!(
+            SaveData::new(disa_raw.clone(), SaveDataType::Nand([2; 16], 0x1234), false),
+            Err(Error::LikelyWrongKey)
+        ));
+
+        // A broken magic is reported as corruption
+        disa_raw.write(0x100, b"disa").unwrap();
+        assert!(matches!(
+            SaveData::new(disa_raw, SaveDataType::Nand([1; 16], 0x1234), false),
+            Err(Error::SignatureMismatch)
+        ));
+    }
+
+    fn sign(signer: Box<dyn Signer>, key: [u8; 16], data: &[u8]) -> [u8; 16] {
+        let signature = Rc::new(MemoryFile::new(vec![0; 16]));
+        let data = Rc::new(MemoryFile::new(data.to_vec()));
+        SignedFile::new_unverified(signature.clone(), data, signer, key)
+            .unwrap()
+            .commit()
+            .unwrap();
+        let mut result = [0; 16];
+        signature.read(0, &mut result).unwrap();
+        result
+    }
+
+    #[test]
+    fn signer_known_values() {
+        // Reference values computed independently from the documented layouts
+        let key: [u8; 16] = std::array::from_fn(|i| i as u8);
+        let data: Vec<u8> = (0..0x100).map(|i| i as u8).collect();
+        assert_eq!(
+            sign(Box::new(NandSaveSigner { id: 0x0001_0011 }), key, &data),
+            [
+                0x33, 0xEF, 0x24, 0xE1, 0x22, 0xEF, 0x6F, 0x3B, 0x6C, 0x38, 0x6C, 0x4F, 0x6E, 0x7B,
+                0x93, 0xC8
+            ]
+        );
+        assert_eq!(
+            sign(Box::new(CtrSav0Signer {}), key, &data),
+            [
+                0x14, 0xCB, 0x65, 0xC5, 0x54, 0x63, 0xDF, 0xB5, 0x83, 0x4B, 0xEE, 0x50, 0x60, 0x86,
+                0xD8, 0xF9
+            ]
+        );
+        assert_eq!(
+            sign(
+                Box::new(SdSaveSigner {
+                    id: 0x0004_0000_0005_5D00
+                }),
+                key,
+                &data
+            ),
+            [
+                0xCC, 0x2B, 0xB9, 0x30, 0x75, 0xD6, 0xCB, 0x44, 0x62, 0x1E, 0x03, 0x9F, 0x8C, 0x5D,
+                0xDD, 0xA8
+            ]
+        );
+        assert_eq!(
+            sign(Box::new(CartSaveSigner {}), key, &data),
+            [
+                0x84, 0x45, 0x1E, 0x50, 0x47, 0x68, 0x95, 0x3A, 0xCD, 0x72, 0xC6, 0x22, 0x83, 0x65,
+                0x69, 0x97
+            ]
+        );
+    }
+
+    #[test]
+    fn signer_round_trip() {
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: true,
+        };
+        let key = [0x5A; 16];
+        let types = [
+            SaveDataType::Nand(key, 0x0001_0011),
+            SaveDataType::Sd(key, 0x0004_0000_0005_5D00),
+            SaveDataType::Cart(key),
+        ];
+        for (i, save_data_type) in types.iter().enumerate() {
+            let disa_raw = Rc::new(MemoryFile::new(vec![0; 100_000]));
+            SaveData::format(disa_raw.clone(), save_data_type.clone(), &param).unwrap();
+
+            // The header is signed by the signer of the type
+            let mut header = vec![0; 0x100];
+            disa_raw.read(0x100, &mut header).unwrap();
+            let mut signature = [0; 16];
+            disa_raw.read(0, &mut signature).unwrap();
+            let (signer, key) = SaveData::get_signer(save_data_type.clone()).unwrap();
+            assert_eq!(sign(signer, key, &header), signature);
+
+            let save = SaveData::new(disa_raw.clone(), save_data_type.clone(), true).unwrap();
+            let file = save
+                .open_root()
+                .unwrap()
+                .new_sub_file(*b"a\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", 4)
+                .unwrap();
+            file.write(0, &[1, 2, 3, 4]).unwrap();
+            let ino = file.get_ino();
+            drop(file);
+            save.commit().unwrap();
+            drop(save);
+
+            let save = SaveData::new(disa_raw.clone(), save_data_type.clone(), false).unwrap();
+            let mut buf = [0; 4];
+            save.open_file(ino).unwrap().read(0, &mut buf).unwrap();
+            assert_eq!(buf, [1, 2, 3, 4]);
+            drop(save);
+
+            // No other type accepts the signature, including the same type with another ID
+            for (j, other) in types.iter().enumerate() {
+                if i != j {
+                    assert!(SaveData::new(disa_raw.clone(), other.clone(), false).is_err());
+                }
+            }
+            let other_id = match save_data_type {
+                SaveDataType::Nand(key, id) => Some(SaveDataType::Nand(*key, id + 1)),
+                SaveDataType::Sd(key, id) => Some(SaveDataType::Sd(*key, id + 1)),
+                _ => None,
+            };
+            if let Some(other_id) = other_id {
+                assert!(matches!(
+                    SaveData::new(disa_raw.clone(), other_id, false),
+                    Err(Error::LikelyWrongKey)
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn new_sub_files() {
+        let name = |i: u8| [i + 1; 16];
+        let entries: Vec<([u8; 16], usize)> = (0..5).map(|i| (name(i), i as usize * 700)).collect();
+
+        let (_, sequential) = new_save(false);
+        let root = sequential.open_root().unwrap();
+        root.new_sub_file(name(10), 100).unwrap();
+        for (name, len) in &entries {
+            root.new_sub_file(*name, *len).unwrap();
+        }
+
+        let (_, bulk) = new_save(false);
+        let root_bulk = bulk.open_root().unwrap();
+        root_bulk.new_sub_file(name(10), 100).unwrap();
+        let files = root_bulk.new_sub_files(&entries).unwrap();
+
+        assert_eq!(
+            files.iter().map(|f| f.len()).collect::<Vec<_>>(),
+            entries.iter().map(|(_, len)| *len).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            root_bulk.list_sub_file().unwrap(),
+            root.list_sub_file().unwrap()
+        );
+        assert_eq!(bulk.stat().unwrap(), sequential.stat().unwrap());
+        drop(files);
+
+        // Failures leave the directory untouched
+        let stat = bulk.stat().unwrap();
+        let list = root_bulk.list_sub_file().unwrap();
+        let conflict = [(name(20), 10), (name(0), 10)];
+        assert!(matches!(
+            root_bulk.new_sub_files(&conflict),
+            Err(Error::AlreadyExist)
+        ));
+        let duplicate = [(name(20), 10), (name(20), 10)];
+        assert!(matches!(
+            root_bulk.new_sub_files(&duplicate),
+            Err(Error::AlreadyExist)
+        ));
+        let too_many: Vec<([u8; 16], usize)> = (20..25).map(|i| (name(i), 0)).collect();
+        assert!(matches!(
+            root_bulk.new_sub_files(&too_many),
+            Err(Error::NoSpace)
+        ));
+        let too_large = [(name(20), 10), (name(21), 100_000)];
+        assert!(matches!(
+            root_bulk.new_sub_files(&too_large),
+            Err(Error::NoSpace)
+        ));
+        assert_eq!(bulk.stat().unwrap(), stat);
+        assert_eq!(root_bulk.list_sub_file().unwrap(), list);
+    }
+
+    #[test]
+    fn find_duplicates() {
+        let (_, save) = new_save(true);
+
+        let root = save.open_root().unwrap();
+        let sub = root.new_sub_dir(*b"sub\0\0\0\0\0\0\0\0\0\0\0\0\0").unwrap();
+        let a = root
+            .new_sub_file(*b"a\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", 1000)
+            .unwrap();
+        let b = sub
+            .new_sub_file(*b"b\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", 1000)
+            .unwrap();
+        let c = root
+            .new_sub_file(*b"c\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", 1000)
+            .unwrap();
+        root.new_sub_file(*b"d\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", 0)
+            .unwrap();
+        root.new_sub_file(*b"e\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", 0)
+            .unwrap();
+        a.write(0, &[1; 1000]).unwrap();
+        b.write(0, &[1; 1000]).unwrap();
+        c.write(0, &[2; 1000]).unwrap();
+
+        let mut expected = vec![a.get_ino(), b.get_ino()];
+        expected.sort_unstable();
+        assert_eq!(save.find_duplicates().unwrap(), vec![expected]);
+    }
+
+    #[test]
+    fn broken_blocks() {
+        let (_, save) = new_save(false);
+        assert!(save.broken_blocks().is_empty());
+
+        let file = save
+            .open_root()
+            .unwrap()
+            .new_sub_file(*b"a\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", 3000)
+            .unwrap();
+        let mut buf = vec![0; 3000];
+        match file.read(0, &mut buf) {
+            Err(Error::HashMismatch) => (),
+            _ => unreachable!(),
+        }
+        let broken = save.broken_blocks();
+        assert!(!broken.is_empty());
+        for block in &broken {
+            assert_eq!(block.partition, 1);
+            assert!(block.range.start < block.range.end);
+        }
+        let filled: Vec<bool> = buf.iter().map(|&b| b == 0xDD).collect();
+        assert!(filled.iter().any(|&f| f));
+
+        // The fill byte applies to blocks already known to be broken
+        save.set_fill_byte(0x5A);
+        match file.read(0, &mut buf) {
+            Err(Error::HashMismatch) => (),
+            _ => unreachable!(),
+        }
+        for (&b, &f) in buf.iter().zip(filled.iter()) {
+            assert_eq!(b == 0x5A, f);
+        }
+
+        file.write(0, &buf).unwrap();
+        assert!(save.broken_blocks().is_empty());
+    }
+
+    #[test]
+    fn export_delta() {
+        let (_, save) = new_save(true);
+        let root = save.open_root().unwrap();
+        let a = root
+            .new_sub_file(*b"a\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", 5000)
+            .unwrap();
+        a.write(0, &[1; 5000]).unwrap();
+        save.commit().unwrap();
+
+        let (mut backup, manifest) = save.export_image().unwrap();
+        let delta = save.export_delta(&manifest).unwrap();
+        assert!(delta.ranges.is_empty());
+        assert_eq!(delta.manifest, manifest);
+
+        a.write(1000, &[2; 10]).unwrap();
+        root.new_sub_dir(*b"d\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0")
+            .unwrap();
+        save.commit().unwrap();
+
+        let delta = save.export_delta(&manifest).unwrap();
+        let (full, new_manifest) = save.export_image().unwrap();
+        assert_eq!(delta.manifest, new_manifest);
+        assert!(!delta.ranges.is_empty());
+        let delta_len: usize = delta.ranges.iter().map(|(_, data)| data.len()).sum();
+        assert!(delta_len < full.len() / 2);
+
+        let base = backup.clone();
+        apply_delta(&mut backup, &delta).unwrap();
+        assert_eq!(backup, full);
+
+        // A delta only applies to its base
+        match apply_delta(&mut backup, &delta) {
+            Err(Error::InvalidValue) => (),
+            _ => unreachable!(),
+        }
+        assert_eq!(backup, full);
+        let mut tampered = delta.clone();
+        tampered.ranges[0].1[0] ^= 1;
+        let mut image = base.clone();
+        match apply_delta(&mut image, &tampered) {
+            Err(Error::HashMismatch) => (),
+            _ => unreachable!(),
+        }
+
+        // The reconstructed image is a valid save data with the new content
+        let save =
+            SaveData::new(Rc::new(MemoryFile::new(backup)), SaveDataType::Bare, true).unwrap();
+        let mut buf = [0; 10];
+        save.open_file(a.get_ino())
+            .unwrap()
+            .read(1000, &mut buf)
+            .unwrap();
+        assert_eq!(buf, [2; 10]);
+    }
+
+    #[test]
+    fn rename_into_descendant() {
+        let (_, save) = new_save(true);
+        let mut root = save.open_root().unwrap();
+        let mut a = root
+            .new_sub_dir(*b"a\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0")
+            .unwrap();
+        let b = a.new_sub_dir(*b"b\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0").unwrap();
+        let c = b.new_sub_dir(*b"c\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0").unwrap();
+        let mut tree = String::new();
+        crate::file_system::format_tree(&save, &mut tree).unwrap();
+
+        let a_copy = save.open_dir(a.get_ino()).unwrap();
+        for parent in &[&a_copy, &b, &c] {
+            match a.rename(parent, *b"x\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0") {
+                Err(Error::InvalidValue) => (),
+                _ => unreachable!(),
+            }
+        }
+        let root_copy = save.open_root().unwrap();
+        match root.rename(&root_copy, *b"x\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0") {
+            Err(Error::InvalidValue) => (),
+            _ => unreachable!(),
+        }
+
+        let mut tree_after = String::new();
+        crate::file_system::format_tree(&save, &mut tree_after).unwrap();
+        assert_eq!(tree_after, tree);
+        assert_eq!(c.get_parent_ino().unwrap(), b.get_ino());
+
+        // Moving a sub directory up is fine
+        let mut c = c;
+        c.rename(&root, *b"c\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0")
+            .unwrap();
+        assert_eq!(c.get_parent_ino().unwrap(), 1);
+    }
+
+    #[test]
+    fn uncommitted_files() {
+        let (_, save) = new_save(false);
+        let root = save.open_root().unwrap();
+        let a = root
+            .new_sub_file(*b"a\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", 100)
+            .unwrap();
+        let mut b = root
+            .new_sub_file(*b"b\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", 0)
+            .unwrap();
+        let c = root
+            .new_sub_file(*b"c\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", 100)
+            .unwrap();
+        assert!(save.uncommitted_files().is_empty());
+
+        a.write(0, &[]).unwrap();
+        let mut buf = [0; 10];
+        let _ = c.read(0, &mut buf);
+        assert!(save.uncommitted_files().is_empty());
+
+        c.write(10, &[1; 10]).unwrap();
+        b.resize(10).unwrap();
+        let mut expected = vec![b.get_ino(), c.get_ino()];
+        expected.sort_unstable();
+        assert_eq!(save.uncommitted_files(), expected);
+
+        save.commit().unwrap();
+        assert!(save.uncommitted_files().is_empty());
+
+        a.write(0, &[1; 100]).unwrap();
+        let a_ino = a.get_ino();
+        c.write(0, &[1; 100]).unwrap();
+        assert_eq!(save.uncommitted_files().len(), 2);
+        c.delete().unwrap();
+        assert_eq!(save.uncommitted_files(), vec![a_ino]);
+    }
+
+    #[test]
+    fn inactive_table() {
+        let (disa_raw, save) = new_save(true);
+        let file = save
+            .open_root()
+            .unwrap()
+            .new_sub_file(*b"a\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", 100)
+            .unwrap();
+        let ino = file.get_ino();
+        file.write(0, &[1; 100]).unwrap();
+        save.commit().unwrap();
+        let old_table = save.active_table().unwrap();
+        file.write(0, &[2; 100]).unwrap();
+        save.commit().unwrap();
+        let new_table = save.active_table().unwrap();
+        assert_eq!(new_table, 1 - old_table);
+        drop(file);
+        drop(save);
+
+        let read = |table_index| {
+            let save =
+                SaveData::new_with_table(disa_raw.clone(), SaveDataType::Bare, table_index, true)
+                    .unwrap();
+            let mut buf = [0; 100];
+            save.open_file(ino).unwrap().read(0, &mut buf).unwrap();
+            buf
+        };
+        assert_eq!(read(new_table), [2; 100]);
+        assert_eq!(read(old_table), [1; 100]);
+        assert!(SaveData::new_with_table(disa_raw, SaveDataType::Bare, 2, true).is_err());
+    }
+
+    #[test]
+    fn format_tree() {
+        let (_, save) = new_save(true);
+
+        let root = save.open_root().unwrap();
+        let dir = root
+            .new_sub_dir([b'd', b'i', b'r', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+        dir.new_sub_dir([b's', b'u', b'b', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+        dir.new_sub_file([b'a', b'/', 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 10)
+            .unwrap();
+        root.new_sub_file([b'f', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 0)
+            .unwrap();
+
+        let mut tree = String::new();
+        crate::file_system::format_tree(&save, &mut tree).unwrap();
+        assert_eq!(tree, "+dir\n +sub\n -a\\x2f\\x01\n-f\n");
+    }
+
+    #[test]
+    fn resize_reclaim() {
+        for &duplicate_data in &[false, true] {
+            let (disa_raw, save) = new_save(duplicate_data);
+            let free_blocks = save.stat().unwrap().free_blocks;
+
+            let mut file = save
+                .open_root()
+                .unwrap()
+                .new_sub_file([b'f', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 0)
+                .unwrap();
+            let free_blocks_of = |save: &SaveData| save.stat().unwrap().free_blocks;
+            for &(len, used) in &[(1, 1), (5000, 10), (513, 2), (0, 0), (2000, 4), (100, 1)] {
+                file.resize(len).unwrap();
+                assert_eq!(free_blocks_of(&save), free_blocks - used);
+            }
+            file.resize(0).unwrap();
+            assert_eq!(free_blocks_of(&save), free_blocks);
+            drop(file);
+            save.commit().unwrap();
+            drop(save);
+
+            // the reclaimed blocks are also free in the FAT itself
+            let save = SaveData::new(disa_raw, SaveDataType::Bare, true).unwrap();
+            assert_eq!(free_blocks_of(&save), free_blocks);
+        }
+    }
+
+    #[test]
+    fn interrupted_commit() {
+        use crate::faulty_file::FaultyFile;
+        let name = *b"file\0\0\0\0\0\0\0\0\0\0\0\0";
+        let (committed, save) = new_save(true);
+        let file = save.open_root().unwrap().new_sub_file(name, 600).unwrap();
+        file.write(0, &[1; 600]).unwrap();
+        save.commit().unwrap();
+        drop(file);
+        drop(save);
+        let mut image = vec![0; committed.len()];
+        committed.read(0, &mut image).unwrap();
+
+        let read_back = |raw: Rc<dyn RandomAccessFile>| -> Vec<u8> {
+            let save = SaveData::new(raw, SaveDataType::Bare, false).unwrap();
+            let file = save.open_root().unwrap().open_sub_file(name).unwrap();
+            let mut buf = vec![0; file.len()];
+            file.read(0, &mut buf).unwrap();
+            buf
+        };
+
+        // Interrupts the commit after each possible number of writes
+        let mut interrupt_after = 0;
+        loop {
+            let raw = Rc::new(FaultyFile::new(image.clone()));
+            let save = SaveData::new(raw.clone(), SaveDataType::Bare, true).unwrap();
+            let mut file = save.open_root().unwrap().open_sub_file(name).unwrap();
+            file.resize(1000).unwrap();
+            file.write(0, &[2; 1000]).unwrap();
+            file.commit().unwrap();
+            raw.fail_writes_after(interrupt_after);
+            let result = save.commit();
+            drop(file);
+            drop(save);
+
+            let content = read_back(Rc::new(MemoryFile::new(raw.snapshot())));
+            if result.is_ok() {
+                assert_eq!(content, [2; 1000]);
+                break;
+            }
+            assert!(matches!(result, Err(Error::CommitFailed(..))));
+            assert_eq!(content, [1; 600]);
+            interrupt_after += 1;
+        }
+        assert!(interrupt_after > 0);
+    }
+
+    #[test]
+    fn journal_capacity() {
+        let mut capacity = vec![];
+        for &duplicate_data in &[false, true] {
+            let (_, save) = new_save(duplicate_data);
+            let stat = save.stat().unwrap();
+            let data_len = stat.total_blocks * stat.block_len;
+            assert_eq!(save.journal_capacity() > data_len, duplicate_data);
+            assert_eq!(save.duplicate_data(), duplicate_data);
+            assert_eq!(
+                save.table_storage(),
+                if duplicate_data {
+                    TableStorage::Fat
+                } else {
+                    TableStorage::Inline
+                }
+            );
+            capacity.push(save.journal_capacity());
+        }
+        assert!(capacity[0] < capacity[1]);
+    }
+
+    #[test]
+    fn dump_headers() {
+        use crate::header_dump::FieldValue;
+        let (_, save) = new_save(false);
+        let dumps = save.dump_headers().unwrap();
+
+        let names: Vec<&str> = dumps.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "DisaHeader",
+                "Partition 0 DifiHeader",
+                "Partition 0 IvfcDescriptor",
+                "Partition 0 DpfsDescriptor",
+                "Partition 0 DifiPartitionParam (derived)",
+                "Partition 1 DifiHeader",
+                "Partition 1 IvfcDescriptor",
+                "Partition 1 DpfsDescriptor",
+                "Partition 1 DifiPartitionParam (derived)",
+                "SaveHeader",
+                "FsInfo",
+            ]
+        );
+        let field = |dump: usize, name: &str| {
+            dumps[dump]
+                .fields
+                .iter()
+                .find(|(n, _)| n == name)
+                .unwrap()
+                .1
+                .clone()
+        };
+        assert_eq!(field(0, "magic"), FieldValue::Bytes(b"DISA".to_vec()));
+        assert_eq!(field(0, "partition_count"), FieldValue::Int(2));
+        assert_eq!(field(8, "ivfc_level4_block_len"), FieldValue::Int(512));
+        assert_eq!(field(8, "external_ivfc_level4"), FieldValue::Int(1));
+        assert_eq!(field(9, "magic"), FieldValue::Bytes(b"SAVE".to_vec()));
+        assert_eq!(field(10, "block_len"), FieldValue::Int(512));
+        assert_eq!(field(10, "max_file"), FieldValue::Int(10));
+        let stat = save.stat().unwrap();
+        assert_eq!(
+            field(10, "data_block_count"),
+            FieldValue::Int(stat.total_blocks as u64)
+        );
+    }
+
+    #[test]
+    fn padded_fat() {
+        let (disa_raw, save) = new_save(true);
+        let free_blocks = save.stat().unwrap().free_blocks;
+        drop(save);
+
+        // Claim one more FAT entry, taking the zero padding between the FAT and the data region
+        {
+            let disa = Disa::new(disa_raw.clone(), None).unwrap();
+            let header: SaveHeader = read_struct(disa[0].as_ref(), 0).unwrap();
+            let offset = header.fs_info_offset as usize;
+            let mut fs_info: FsInfo = read_struct(disa[0].as_ref(), offset).unwrap();
+            assert!(fs_info.fat_offset + (fs_info.fat_size as u64 + 2) * 8 <= fs_info.data_offset);
+            fs_info.fat_size += 1;
+            write_struct(disa[0].as_ref(), offset, fs_info).unwrap();
+            disa.commit().unwrap();
+        }
+
+        assert!(matches!(
+            SaveData::new(disa_raw.clone(), SaveDataType::Bare, false),
+            Err(Error::SizeMismatch)
+        ));
+
+        let save =
+            SaveData::new_allow_inconsistent(disa_raw.clone(), SaveDataType::Bare, true).unwrap();
+        assert_eq!(save.stat().unwrap().free_blocks, free_blocks);
+        let file = save
+            .open_root()
+            .unwrap()
+            .new_sub_file(*b"file\0\0\0\0\0\0\0\0\0\0\0\0", 1000)
+            .unwrap();
+        file.write(0, &[0xAB; 1000]).unwrap();
+        file.commit().unwrap();
+        drop(file);
+        save.commit().unwrap();
+        drop(save);
+
+        let save = SaveData::new_allow_inconsistent(disa_raw, SaveDataType::Bare, false).unwrap();
+        let file = save
+            .open_root()
+            .unwrap()
+            .open_sub_file(*b"file\0\0\0\0\0\0\0\0\0\0\0\0")
+            .unwrap();
+        let mut buf = [0; 1000];
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf[..], [0xAB; 1000][..]);
+    }
+
+    #[test]
+    fn force_block_type() {
+        let (disa_raw, save) = new_save(true);
+        let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        {
+            let root = save.open_root().unwrap();
+            root.new_sub_dir(*b"dir\0\0\0\0\0\0\0\0\0\0\0\0\0").unwrap();
+            let file = root
+                .new_sub_file(*b"file\0\0\0\0\0\0\0\0\0\0\0\0", data.len())
+                .unwrap();
+            file.write(0, &data).unwrap();
+            file.commit().unwrap();
+            drop(file);
+            save.commit().unwrap();
+        }
+
+        // Flip a bit of the block length, both in the SAVE header and in the file system info
+        {
+            let disa = Disa::new(disa_raw.clone(), None).unwrap();
+            let mut header: SaveHeader = read_struct(disa[0].as_ref(), 0).unwrap();
+            let offset = header.fs_info_offset as usize;
+            let mut fs_info: FsInfo = read_struct(disa[0].as_ref(), offset).unwrap();
+            assert_eq!((header.image_block_len, fs_info.block_len), (512, 512));
+            header.image_block_len ^= 0x1000;
+            fs_info.block_len ^= 0x1000;
+            write_struct(disa[0].as_ref(), 0, header).unwrap();
+            write_struct(disa[0].as_ref(), offset, fs_info).unwrap();
+            disa.commit().unwrap();
+        }
+        assert!(SaveData::new(disa_raw.clone(), SaveDataType::Bare, false).is_err());
+
+        let save = SaveData::new_with_block_type(
+            disa_raw.clone(),
+            SaveDataType::Bare,
+            SaveDataBlockType::Small,
+        )
+        .unwrap();
+        let root = save.open_root().unwrap();
+        assert_eq!(
+            root.list_sub_dir().unwrap(),
+            [(*b"dir\0\0\0\0\0\0\0\0\0\0\0\0\0", 2)]
+        );
+        let file = root
+            .open_sub_file(*b"file\0\0\0\0\0\0\0\0\0\0\0\0")
+            .unwrap();
+        let mut buf = vec![0; data.len()];
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        drop(file);
+        drop(root);
+        drop(save);
+
+        // The wrong block length reads garbage, if it opens at all
+        if let Ok(save) =
+            SaveData::new_with_block_type(disa_raw, SaveDataType::Bare, SaveDataBlockType::Large)
+        {
+            let read = save
+                .open_root()
+                .and_then(|root| root.open_sub_file(*b"file\0\0\0\0\0\0\0\0\0\0\0\0"))
+                .and_then(|file| file.read(0, &mut buf));
+            assert!(read.is_err() || buf != data);
+        }
+    }
+
+    #[test]
+    fn dpfs_state() {
+        let (_, save) = new_save(false);
+
+        let before = save.dpfs_state().unwrap();
+        assert_eq!(before.len(), 2);
+        for (i, state) in before.iter().enumerate() {
+            assert_eq!(state.partition, i);
+            assert!(!state.level1_modified);
+            for level in &[&state.level2, &state.level3] {
+                assert!(level.active.len() <= level.selector.len() * 32);
+                assert!(level.modified.iter().all(|&m| !m));
+            }
+        }
+
+        save.open_root()
+            .unwrap()
+            .new_sub_file(*b"file\0\0\0\0\0\0\0\0\0\0\0\0", 10)
+            .unwrap();
+        let dirty = save.dpfs_state().unwrap();
+        assert!(!dirty[0].level1_modified);
+        assert!(dirty[0].level3.modified.iter().any(|&m| m));
+        assert_eq!(dirty[0].level3.selector, before[0].level3.selector);
+
+        save.commit().unwrap();
+        let after = save.dpfs_state().unwrap();
+        assert!(!after[0].level1_modified);
+        assert_ne!(after[0].level1_selector, before[0].level1_selector);
+        assert!(after[0].level3.modified.iter().all(|&m| !m));
+        assert_eq!(after[0].level3.active, dirty[0].level3.active);
+        assert_ne!(after[0].level3.selector, before[0].level3.selector);
+    }
+
+    #[test]
+    fn summarize() {
+        let (_, save) = new_save(false);
+
+        let root = save.open_root().unwrap();
+        let dir = root
+            .new_sub_dir([b'd', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+        dir.new_sub_dir([b's', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+        dir.new_sub_file([b'a', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 1000)
+            .unwrap();
+        root.new_sub_file([b'b', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 0)
+            .unwrap();
+        root.new_sub_file([b'c', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 1)
+            .unwrap();
+
+        let summary = crate::file_system::summarize(&save).unwrap();
+        assert_eq!(summary.dirs, 2);
+        assert_eq!(summary.files, 3);
+        assert_eq!(summary.bytes, 1001);
+        assert_eq!(summary.stat, save.stat().unwrap());
+        assert_eq!(summary.stat.total_blocks - summary.stat.free_blocks, 2 + 1);
+    }
+
+    #[test]
+    fn inode_map() {
+        let (_, save) = new_save(false);
+
+        let d = [b'd', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let s = [b's', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let a = [b'a', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let b = [b'b', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let root = save.open_root().unwrap();
+        let dir = root.new_sub_dir(d).unwrap();
+        let sub_dir = dir.new_sub_dir(s).unwrap();
+        let file_a = dir.new_sub_file(a, 10).unwrap();
+        let file_b = root.new_sub_file(b, 0).unwrap();
+
+        let map = save.inode_map().unwrap();
+        assert_eq!(map.dirs.len(), 3);
+        assert_eq!(map.files.len(), 2);
+        assert_eq!(map.get(InodeKind::Dir, 1), Some(&[][..]));
+        assert_eq!(map.get(InodeKind::Dir, dir.get_ino()), Some(&[d][..]));
+        assert_eq!(
+            map.get(InodeKind::Dir, sub_dir.get_ino()),
+            Some(&[d, s][..])
+        );
+        assert_eq!(
+            map.get(InodeKind::File, file_a.get_ino()),
+            Some(&[d, a][..])
+        );
+        assert_eq!(map.get(InodeKind::File, file_b.get_ino()), Some(&[b][..]));
+        assert_eq!(map.get(InodeKind::File, 1000), None);
+    }
+
+    #[test]
+    fn write_all_resize() {
+        let (_, save) = new_save(false);
+
+        let f = [b'f', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut file = save.open_root().unwrap().new_sub_file(f, 0).unwrap();
+        assert_eq!(file.read_all().unwrap(), Vec::<u8>::new());
+
+        let data: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        file.write_all_resize(0, &data).unwrap();
+        assert_eq!(file.len(), 1500);
+        assert_eq!(file.read_all().unwrap(), data);
+
+        // writing inside the file doesn't resize it
+        file.write_all_resize(100, &[0xEE; 10]).unwrap();
+        assert_eq!(file.len(), 1500);
+
+        // writing past the end leaves a gap
+        file.write_all_resize(2000, &[0xCC; 100]).unwrap();
+        assert_eq!(file.len(), 2100);
+        file.write_all_resize(3000, &[]).unwrap_err();
+        assert_eq!(file.len(), 2100);
+
+        let mut expected = data;
+        expected[100..110].copy_from_slice(&[0xEE; 10]);
+        let all = file.read_all().unwrap();
+        assert_eq!(all.len(), 2100);
+        assert_eq!(&all[0..1500], &expected[..]);
+        assert_eq!(&all[2000..2100], &[0xCC; 100][..]);
+    }
+
+    #[test]
+    fn content_fingerprint() {
+        let make_save = |duplicate_data, reverse| {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 10,
+                dir_buckets: if duplicate_data { 10 } else { 7 },
+                max_file: 10,
+                file_buckets: if duplicate_data { 10 } else { 13 },
+                duplicate_data,
+            };
+            let disa_raw = Rc::new(MemoryFile::new(vec![0; 100_000]));
+            SaveData::format(disa_raw.clone(), SaveDataType::Bare, &param).unwrap();
+            let save = SaveData::new(disa_raw, SaveDataType::Bare, true).unwrap();
+            let root = save.open_root().unwrap();
+            let dir = root
+                .new_sub_dir([b'd', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+                .unwrap();
+            let mut names = vec![b'a', b'b', b'c'];
+            if reverse {
+                names.reverse();
+            }
+            for name in names {
+                let file = dir
+                    .new_sub_file([name, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 1000)
+                    .unwrap();
+                file.write(0, &[name; 1000]).unwrap();
+            }
+            save
+        };
+
+        let save = make_save(true, false);
+        let fingerprint = save.content_fingerprint().unwrap();
+        assert_eq!(
+            make_save(false, true).content_fingerprint().unwrap(),
+            fingerprint
+        );
+
+        let root = save.open_root().unwrap();
+        let dir = root
+            .open_sub_dir([b'd', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+        let file = dir
+            .open_sub_file([b'b', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+        file.write(999, &[0]).unwrap();
+        let changed = save.content_fingerprint().unwrap();
+        assert_ne!(changed, fingerprint);
+
+        file.write(999, b"b").unwrap();
+        assert_eq!(save.content_fingerprint().unwrap(), fingerprint);
+        root.new_sub_dir([b'e', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+        assert_ne!(save.content_fingerprint().unwrap(), fingerprint);
+    }
+
+    #[test]
+    fn format_preflight() {
+        for &duplicate_data in &[false, true] {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 10,
+                dir_buckets: 11,
+                max_file: 20,
+                file_buckets: 23,
+                duplicate_data,
+            };
+            let len = 100_000;
+            let report = SaveData::format_preflight(&param, len, len).unwrap();
+            assert!(report.fits);
+            assert_eq!(SaveData::format_preflight(&param, len, 0).unwrap(), report);
+
+            let disa_raw = Rc::new(MemoryFile::new(vec![0; len]));
+            SaveData::format(disa_raw.clone(), SaveDataType::Bare, &param).unwrap();
+            let save = SaveData::new(disa_raw, SaveDataType::Bare, true).unwrap();
+            assert_eq!(report.stat, Some(save.stat().unwrap()));
+            assert_eq!(
+                report.block_count,
+                SaveData::calculate_capacity(&param, len)
+            );
+
+            let report = SaveData::format_preflight(&param, 1000, 1000).unwrap();
+            assert_eq!(
+                report,
+                FormatReport {
+                    fits: false,
+                    block_count: 0,
+                    stat: None
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn sub_metadata() {
+        use crate::faulty_file::FaultyFile;
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: false,
+        };
+        let dir_name = *b"dir\0\0\0\0\0\0\0\0\0\0\0\0\0";
+        let file_name = *b"file\0\0\0\0\0\0\0\0\0\0\0\0";
+        let other_name = *b"other\0\0\0\0\0\0\0\0\0\0\0";
+        let raw = Rc::new(FaultyFile::new(vec![0; 100_000]));
+        SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+        let save = SaveData::new(raw.clone(), SaveDataType::Bare, true).unwrap();
+        let root = save.open_root().unwrap();
+        root.new_sub_dir(dir_name).unwrap();
+        root.new_sub_file(file_name, 10).unwrap();
+
+        assert_eq!(root.sub_metadata(dir_name).unwrap(), Some(InodeKind::Dir));
+        assert_eq!(root.sub_metadata(file_name).unwrap(), Some(InodeKind::File));
+        assert_eq!(root.sub_metadata(other_name).unwrap(), None);
+        assert!(root.sub_exists(dir_name).unwrap());
+        assert!(root.sub_exists(file_name).unwrap());
+        assert!(!root.sub_exists(other_name).unwrap());
+
+        // An IO error is not mistaken for absence
+        raw.fail_reads(0..raw.len());
+        assert!(matches!(root.sub_metadata(other_name), Err(Error::IO(_))));
+        assert!(matches!(root.sub_exists(other_name), Err(Error::IO(_))));
+    }
+
+    #[test]
+    fn metadata_hash() {
+        for &duplicate_data in &[false, true] {
+            let (_, save) = new_save(duplicate_data);
+            let root = save.open_root().unwrap();
+            let empty = save.metadata_hash().unwrap();
+
+            let name = *b"file\0\0\0\0\0\0\0\0\0\0\0\0";
+            let mut file = root.new_sub_file(name, 1000).unwrap();
+            file.write(0, &[1; 1000]).unwrap();
+            let added = save.metadata_hash().unwrap();
+            assert_ne!(added, empty);
+
+            // Same size, different content
+            file.write(0, &[2; 1000]).unwrap();
+            save.commit().unwrap();
+            assert_eq!(save.metadata_hash().unwrap(), added);
+
+            file.rename(&root, *b"renamed\0\0\0\0\0\0\0\0\0").unwrap();
+            let renamed = save.metadata_hash().unwrap();
+            assert_ne!(renamed, added);
+
+            file.resize(2000).unwrap();
+            assert_ne!(save.metadata_hash().unwrap(), renamed);
+        }
+    }
 }
@@ -0,0 +1,131 @@
+use crate::disk_file::DiskFile;
+use crate::error::*;
+use crate::memory_file::MemoryFile;
+use crate::random_access_file::*;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Largest scratch file kept in memory by default.
+pub const DEFAULT_MEMORY_LIMIT: usize = 0x400_0000;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+enum Backing {
+    Memory(MemoryFile),
+    Disk(DiskFile),
+}
+
+/// Implements `RandomAccessFile` for a temporary image, such as the scratch format made by
+/// `SaveData::format_preflight`, or a trimmed save data before it is copied to its
+/// destination.
+///
+/// The image is kept in memory if it is not longer than the memory limit. Otherwise it is kept
+/// in a temporary host file, which is removed when this is dropped.
+pub struct ScratchFile {
+    backing: Option<Backing>,
+    path: Option<PathBuf>,
+}
+
+impl ScratchFile {
+    /// Creates a zero-filled scratch file of `len` bytes. If it doesn't fit in `memory_limit`,
+    /// the temporary host file is created in `dir`.
+    pub fn new(len: usize, memory_limit: usize, dir: &Path) -> Result<ScratchFile, Error> {
+        if len <= memory_limit {
+            return Ok(ScratchFile {
+                backing: Some(Backing::Memory(MemoryFile::new(vec![0; len]))),
+                path: None,
+            });
+        }
+
+        let path = dir.join(format!(
+            ".save3ds_scratch_{}_{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let mut scratch = ScratchFile {
+            backing: None,
+            path: Some(path),
+        };
+        file.set_len(len as u64)?;
+        scratch.backing = Some(Backing::Disk(DiskFile::new(file, true)?));
+        Ok(scratch)
+    }
+
+    #[cfg(test)]
+    fn in_memory(&self) -> bool {
+        matches!(self.backing, Some(Backing::Memory(_)))
+    }
+
+    fn file(&self) -> &dyn RandomAccessFile {
+        match self.backing.as_ref().unwrap() {
+            Backing::Memory(file) => file,
+            Backing::Disk(file) => file,
+        }
+    }
+}
+
+impl RandomAccessFile for ScratchFile {
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.file().read(pos, buf)
+    }
+    fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        self.file().write(pos, buf)
+    }
+    fn len(&self) -> usize {
+        self.file().len()
+    }
+    fn commit(&self) -> Result<(), Error> {
+        // Nothing needs to survive this file
+        Ok(())
+    }
+}
+
+impl Drop for ScratchFile {
+    fn drop(&mut self) {
+        // Closes the host file before removing it
+        self.backing.take();
+        if let Some(path) = self.path.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::random_access_file::*;
+    use crate::scratch_file::ScratchFile;
+    use crate::test_util::TempPath;
+
+    #[test]
+    fn backing() {
+        let dir = TempPath::new("libsave3ds_scratch");
+        std::fs::create_dir(&dir).unwrap();
+        let count = || std::fs::read_dir(&dir).unwrap().count();
+
+        let memory = ScratchFile::new(0x1000, 0x1000, &dir).unwrap();
+        assert!(memory.in_memory());
+        assert_eq!(count(), 0);
+
+        let disk = ScratchFile::new(0x1001, 0x1000, &dir).unwrap();
+        assert!(!disk.in_memory());
+        assert_eq!(count(), 1);
+
+        for file in [&memory, &disk] {
+            let mut buf = [0xFF; 4];
+            file.read(0x800, &mut buf).unwrap();
+            assert_eq!(buf, [0; 4]);
+            file.write(0x800, &[1, 2, 3, 4]).unwrap();
+            file.read(0x800, &mut buf).unwrap();
+            assert_eq!(buf, [1, 2, 3, 4]);
+            check_boundary(file);
+        }
+
+        drop(disk);
+        assert_eq!(count(), 0);
+    }
+}
@@ -1,26 +1,38 @@
-use crate::aes_ctr_file::AesCtrFile;
-use crate::disk_file::DiskFile;
+use crate::aes_ctr_file::*;
+use crate::disk_file::*;
 use crate::error::*;
 use crate::key_engine::*;
 use crate::misc::*;
+use crate::overlay::Overlay;
 use crate::random_access_file::*;
 use crate::sd_nand_common::*;
+use crate::SdMapEntry;
+use aes::cipher::KeyInit;
+use aes::Aes128;
+use log::*;
 use sha2::*;
+use std::cell::{Cell, RefCell};
 use std::path::*;
 use std::rc::Rc;
 
 pub struct Sd {
     path: PathBuf,
-    key: [u8; 16],
+    aes128: Aes128,
+    write_buffer_len: Cell<usize>,
+    cache_pages: Cell<usize>,
+    overlay: RefCell<Option<Rc<Overlay>>>,
 }
 
 impl Sd {
     pub fn new(sd_path: &str, key_x: [u8; 16], key_y: [u8; 16]) -> Result<Sd, Error> {
-        let path = std::fs::read_dir(
-            PathBuf::from(sd_path)
-                .join("Nintendo 3DS")
-                .join(hash_movable(key_y)),
-        )?
+        let id0 = hash_movable(key_y);
+        let path = std::fs::read_dir(PathBuf::from(sd_path).join("Nintendo 3DS").join(&id0))
+            .inspect_err(|_| {
+                error!(
+                    "Nintendo 3DS/{} not found on the SD. movable.sed likely doesn't belong to this SD card",
+                    id0
+                )
+            })?
         .find(|a| {
             a.as_ref()
                 .map(|a| a.file_type().map(|a| a.is_dir()).unwrap_or(false))
@@ -29,19 +41,115 @@ impl Sd {
         .ok_or(Error::BrokenSd)??
         .path();
         let key = scramble(key_x, key_y);
-        Ok(Sd { path, key })
+        let aes128 = Aes128::new(key[..].into());
+        Ok(Sd {
+            path,
+            aes128,
+            write_buffer_len: Cell::new(DEFAULT_WRITE_BUFFER_LEN),
+            cache_pages: Cell::new(DEFAULT_CACHE_PAGES),
+            overlay: RefCell::new(None),
+        })
+    }
+
+    /// Sets how many bytes of writes to each file opened afterwards are buffered.
+    /// See [`DiskFile::set_write_buffer_len`].
+    pub fn set_write_buffer_len(&self, len: usize) {
+        self.write_buffer_len.set(len);
+    }
+
+    /// Sets how many pages of decrypted data each file opened afterwards caches.
+    /// See [`AesCtrFile::set_cache_pages`].
+    pub fn set_cache_pages(&self, pages: usize) {
+        self.cache_pages.set(pages);
+    }
+
+    /// Sets the overlay that files opened afterwards go through.
+    /// See [`Overlay`].
+    pub fn set_overlay(&self, overlay: Option<Rc<Overlay>>) {
+        *self.overlay.borrow_mut() = overlay;
+    }
+
+    /// Returns the name of the ID1 directory in use.
+    pub fn id1(&self) -> String {
+        self.path
+            .file_name()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// Walks the whole tree under the ID1 directory, with entries of each directory sorted by
+    /// name. Files are decrypted just enough to check their magic.
+    pub fn map(&self) -> Result<Vec<SdMapEntry>, Error> {
+        let mut entries = vec![];
+        self.map_dir(&mut vec![], &mut entries)?;
+        Ok(entries)
+    }
+
+    fn map_dir(&self, path: &mut Vec<String>, entries: &mut Vec<SdMapEntry>) -> Result<(), Error> {
+        let dir_path = path.iter().fold(self.path.clone(), |a, b| a.join(b));
+        let mut children = std::fs::read_dir(dir_path)?.collect::<Result<Vec<_>, _>>()?;
+        children.sort_by_key(|child| child.file_name());
+        for child in children {
+            let name = if let Some(name) = child.file_name().to_str() {
+                name.to_owned()
+            } else {
+                warn!("Skipping non-UTF-8 name {:?}", child.file_name());
+                continue;
+            };
+            path.push(name);
+            let metadata = child.metadata()?;
+            if metadata.is_dir() {
+                entries.push(SdMapEntry {
+                    path: path.join("/"),
+                    is_dir: true,
+                    len: 0,
+                    magic: None,
+                });
+                self.map_dir(path, entries)?;
+            } else {
+                let sub_path: Vec<&str> = path.iter().map(|s| s.as_str()).collect();
+                let file = self.open(&sub_path, false)?;
+                let mut magic = [0; 4];
+                let magic = if file.len() >= 0x104 {
+                    file.read(0x100, &mut magic)?;
+                    [b"DISA", b"DIFF", b"NCCH"]
+                        .iter()
+                        .find(|&&known| *known == magic)
+                        .map(|known| String::from_utf8_lossy(*known).into_owned())
+                } else {
+                    None
+                };
+                entries.push(SdMapEntry {
+                    path: path.join("/"),
+                    is_dir: false,
+                    len: metadata.len(),
+                    magic,
+                });
+            }
+            path.pop();
+        }
+        Ok(())
     }
 }
 
 impl SdNandFileSystem for Sd {
     fn open(&self, path: &[&str], write: bool) -> Result<Rc<dyn RandomAccessFile>, Error> {
         let file_path = path.iter().fold(self.path.clone(), |a, b| a.join(b));
-        let file = Rc::new(DiskFile::new(
-            std::fs::OpenOptions::new()
-                .read(true)
-                .write(write)
-                .open(file_path)?,
-        )?);
+        let open = |write| {
+            let file = DiskFile::new(
+                std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(write)
+                    .open(&file_path)?,
+                write,
+            )?;
+            file.set_write_buffer_len(self.write_buffer_len.get());
+            Ok(file.into_shared())
+        };
+        let file = match self.overlay.borrow().as_ref() {
+            Some(overlay) => overlay.open(&file_path, write, open)?,
+            None => open(write)?,
+        };
 
         let hash_path: Vec<u8> = path
             .iter()
@@ -58,7 +166,9 @@ impl SdNandFileSystem for Sd {
             *c = hash[i] ^ hash[i + 16];
         }
 
-        Ok(Rc::new(AesCtrFile::new(file, self.key, ctr, false)))
+        let file = AesCtrFile::with_cipher(file, self.aes128.clone(), ctr, false);
+        file.set_cache_pages(self.cache_pages.get());
+        Ok(Rc::new(file))
     }
 
     fn create(&self, path: &[&str], len: usize) -> Result<(), Error> {
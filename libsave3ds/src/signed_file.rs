@@ -10,7 +10,7 @@ use std::rc::Rc;
 pub trait Signer {
     fn hash(&self, data: Vec<u8>) -> Vec<u8> {
         let mut hasher = Sha256::new();
-        hasher.update(&self.block(data));
+        hasher.update(self.block(data));
         hasher.finalize().into_iter().collect()
     }
     fn block(&self, data: Vec<u8>) -> Vec<u8>;
@@ -74,6 +74,33 @@ impl SignedFile {
         Ok(file)
     }
 
+    /// Same as `new`, but if the signature doesn't match while `data` still starts with the
+    /// expected `magic`, returns `Error::LikelyWrongKey` instead of `Error::SignatureMismatch`.
+    ///
+    /// An intact magic means the data was read (and decrypted, if applicable) correctly, so the
+    /// mismatch is more likely caused by a wrong CMAC key than by corruption.
+    pub fn new_with_magic(
+        signature: Rc<dyn RandomAccessFile>,
+        data: Rc<dyn RandomAccessFile>,
+        signer: Box<dyn Signer>,
+        key: [u8; 16],
+        magic: &[u8],
+    ) -> Result<SignedFile, Error> {
+        match SignedFile::new(signature, data.clone(), signer, key) {
+            Err(Error::SignatureMismatch) => {
+                let mut actual = vec![0; magic.len()];
+                data.read(0, &mut actual)?;
+                if actual == magic {
+                    error!("The data looks intact, but the signature doesn't match. The key is likely wrong");
+                    make_error(Error::LikelyWrongKey)
+                } else {
+                    make_error(Error::SignatureMismatch)
+                }
+            }
+            result => result,
+        }
+    }
+
     fn calculate_signature(&self) -> Result<[u8; 16], Error> {
         let mut data = vec![0; self.len];
         self.data.read(0, &mut data)?;
@@ -130,6 +157,42 @@ pub mod test {
         }
     }
 
+    #[test]
+    fn wrong_key() {
+        let signer = Box::new(SimpleSigner::new());
+        let data = Rc::new(MemoryFile::new(b"MAGIC and some data".to_vec()));
+        let signature = Rc::new(MemoryFile::new(vec![0; 16]));
+        SignedFile::new_unverified(signature.clone(), data.clone(), signer.clone(), [1; 16])
+            .unwrap()
+            .commit()
+            .unwrap();
+
+        SignedFile::new_with_magic(
+            signature.clone(),
+            data.clone(),
+            signer.clone(),
+            [1; 16],
+            b"MAGIC",
+        )
+        .unwrap();
+        assert!(matches!(
+            SignedFile::new_with_magic(
+                signature.clone(),
+                data.clone(),
+                signer.clone(),
+                [2; 16],
+                b"MAGIC"
+            ),
+            Err(Error::LikelyWrongKey)
+        ));
+
+        data.write(0, b"m").unwrap();
+        assert!(matches!(
+            SignedFile::new_with_magic(signature, data, signer, [1; 16], b"MAGIC"),
+            Err(Error::SignatureMismatch)
+        ));
+    }
+
     #[test]
     fn fuzz() {
         use rand::distributions::Standard;
@@ -42,3 +42,20 @@ impl RandomAccessFile for SubFile {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::memory_file::MemoryFile;
+    use crate::random_access_file::*;
+    use crate::sub_file::SubFile;
+    use std::rc::Rc;
+
+    #[test]
+    fn boundary() {
+        let parent = Rc::new(MemoryFile::new(vec![0; 10]));
+        for &(begin, len) in &[(0, 10), (3, 4), (10, 0)] {
+            check_boundary(&SubFile::new(parent.clone(), begin, len).unwrap());
+        }
+        assert!(SubFile::new(parent, 3, 8).is_err());
+    }
+}
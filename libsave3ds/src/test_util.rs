@@ -0,0 +1,71 @@
+//! Setup shared by the tests of this crate and of the frontends, which enable it with the
+//! `test-util` feature.
+
+use crate::save_data::*;
+use crate::Resource;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A path in the temporary directory, unique to the process. Whatever is at the path, file or
+/// directory, is removed when this is created and when this is dropped.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    /// Makes the path for `name`, which should be unique among the tests.
+    pub fn new(name: &str) -> TempPath {
+        let path = TempPath(std::env::temp_dir().join(format!("{}_{}", name, std::process::id())));
+        path.remove();
+        path
+    }
+
+    /// Returns the path as a string, for the APIs that take one.
+    pub fn as_str(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    fn remove(&self) {
+        if self.0.is_dir() {
+            let _ = std::fs::remove_dir_all(&self.0);
+        } else {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+/// Returns a `Resource` without any key or console file, which can only open bare and
+/// cartridge saves, and SD/NAND archives that don't need keys.
+pub fn resource() -> Resource {
+    Resource::new(None, None, None, None, None, None, None, None, None, None).unwrap()
+}
+
+/// Returns the small format parameter most tests use: 10 directories and 10 files with
+/// duplicate data.
+pub fn format_param() -> SaveDataFormatParam {
+    SaveDataFormatParam {
+        block_type: SaveDataBlockType::Small,
+        max_dir: 10,
+        dir_buckets: 11,
+        max_file: 10,
+        file_buckets: 11,
+        duplicate_data: true,
+    }
+}
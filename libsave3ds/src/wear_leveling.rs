@@ -162,17 +162,57 @@ struct WearLevelingBlock {
     allocate_count: u8,
     initialized: bool,
     dirty: bool,
+    // The physical block was given up by a relocation since the last commit. It still holds
+    // committed data until the relocation is recorded, so it can't be reused before that.
+    freed: bool,
     crc_ticket: Option<Rc<MemoryFile>>,
     data: Vec<Box<dyn RandomAccessFile>>,
 }
 
+/// A journal record, pending until the next commit, in the form of
+/// `[virtual_block, virtual_block_prev, physical_block, physical_block_prev, allocate_count,
+/// allocate_count_prev]`. This is followed by the CRC ticket of `virtual_block` on disk.
+type JournalEntry = [u8; 6];
+
+const JOURNAL_ENTRY_LEN: usize = 0x20;
+
 pub struct WearLeveling {
+    parent: Rc<dyn RandomAccessFile>,
     block_map: Rc<CrcFile<SimpleCrcStub<SubFile>, SubFile>>,
     journal_list: Rc<SubFile>,
+    journal_len: Cell<usize>,
+    pending_journal: RefCell<Vec<JournalEntry>>,
+    // Some block was modified in place, which can only be recorded by rewriting the block map
+    squash: Cell<bool>,
     blocks: RefCell<Vec<WearLevelingBlock>>,
     large_save: bool,
 }
 
+fn block_chunks(
+    parent: &Rc<dyn RandomAccessFile>,
+    physical_block: u8,
+    crc_ticket: Option<&Rc<MemoryFile>>,
+    initialized: bool,
+) -> Result<Vec<Box<dyn RandomAccessFile>>, Error> {
+    let mut data_list: Vec<Box<dyn RandomAccessFile>> = vec![];
+    for i in 0..8 {
+        let offset = i * 0x200 + physical_block as usize * 0x1000;
+        let data = SubFile::new(parent.clone(), offset, 0x200)?;
+        let data: Box<dyn RandomAccessFile> = if let Some(crc_ticket) = crc_ticket {
+            let crc = Rc::new(SubFile::new(crc_ticket.clone(), i, 1)?);
+            Box::new(CrcFile::new(
+                XorCrcStub::new(crc)?,
+                Rc::new(data),
+                initialized,
+            )?)
+        } else {
+            Box::new(data)
+        };
+        data_list.push(data);
+    }
+    Ok(data_list)
+}
+
 impl WearLeveling {
     pub fn format(parent: Rc<dyn RandomAccessFile>) -> Result<(), Error> {
         let len = parent.len();
@@ -302,7 +342,8 @@ impl WearLeveling {
             0x1000 - journal_start,
         )?);
 
-        for offset in (0..journal_list.len()).step_by(0x20) {
+        let mut journal_len = 0;
+        for offset in (0..journal_list.len()).step_by(JOURNAL_ENTRY_LEN) {
             let journal0 = Rc::new(SubFile::new(journal_list.clone(), offset, 14)?);
             let journal1 = Rc::new(SubFile::new(journal_list.clone(), offset + 14, 14)?);
             let journal = MirroredFile::new(journal0, journal1)?;
@@ -347,8 +388,8 @@ impl WearLeveling {
                 return Err(Error::InvalidValue);
             }
 
-            // Wrapping???
-            if blocks[virtual_block_prev].allocate_count != allocate_count - 1 {
+            // Relocation never wraps the count around
+            if allocate_count.checked_sub(1) != Some(blocks[virtual_block_prev].allocate_count) {
                 return Err(Error::InvalidValue);
             }
 
@@ -366,6 +407,7 @@ impl WearLeveling {
                     &(SubFile::new(Rc::new(journal), 6, 8)?),
                 )?);
             }
+            journal_len += 1;
         }
 
         if blocks.last().unwrap().initialized {
@@ -374,40 +416,126 @@ impl WearLeveling {
 
         let mut final_blocks = vec![];
         for block in blocks {
-            let mut data_list: Vec<Box<dyn RandomAccessFile>> = vec![];
             let crc_ticket = block.crc_ticket.map(Rc::new);
-            for i in 0..8 {
-                let offset = i * 0x200 + block.physical_block as usize * 0x1000;
-                let data = SubFile::new(parent.clone(), offset, 0x200)?;
-                let data: Box<dyn RandomAccessFile> = if let Some(crc_ticket) = crc_ticket.clone() {
-                    let crc = Rc::new(SubFile::new(crc_ticket.clone(), i, 1)?);
-                    Box::new(CrcFile::new(
-                        XorCrcStub::new(crc)?,
-                        Rc::new(data),
-                        block.initialized,
-                    )?)
-                } else {
-                    Box::new(data)
-                };
-                data_list.push(data);
-            }
+            let data = block_chunks(
+                &parent,
+                block.physical_block,
+                crc_ticket.as_ref(),
+                block.initialized,
+            )?;
             final_blocks.push(WearLevelingBlock {
                 physical_block: block.physical_block,
                 allocate_count: block.allocate_count,
                 initialized: block.initialized,
                 dirty: false,
+                freed: false,
                 crc_ticket,
-                data: data_list,
+                data,
             });
         }
 
         Ok(WearLeveling {
+            parent,
             block_map,
             journal_list,
+            journal_len: Cell::new(journal_len),
+            pending_journal: RefCell::new(vec![]),
+            squash: Cell::new(false),
             blocks: RefCell::new(final_blocks),
             large_save,
         })
     }
+
+    /// Prepares the virtual block `index` for its first modification since the last commit.
+    ///
+    /// The block is moved to the physical block of an uninitialized virtual block with the
+    /// lowest allocation count, which takes over the old physical block in return, so that the
+    /// committed data stays intact until the move is recorded in the journal on commit.
+    /// If there is no such block to move to, the block is modified in place instead.
+    fn relocate(&self, blocks: &mut [WearLevelingBlock], index: usize) -> Result<(), Error> {
+        let max_allocate_count = if self.large_save { 0x7F } else { 0xFF };
+        let target = blocks
+            .iter()
+            .enumerate()
+            .filter(|(i, block)| {
+                *i != index
+                    && !block.initialized
+                    && !block.freed
+                    && block.allocate_count < max_allocate_count
+            })
+            .min_by_key(|(_, block)| block.allocate_count)
+            .map(|(i, _)| i);
+
+        let target = if let Some(target) = target {
+            target
+        } else {
+            let block = &mut blocks[index];
+            if !block.initialized {
+                block.initialized = true;
+                if block.allocate_count == 0 {
+                    block.allocate_count = 1;
+                }
+                for chunk in block.data.iter() {
+                    chunk.write(0, &CHUNK_INIT)?;
+                }
+            }
+            self.squash.set(true);
+            return Ok(());
+        };
+
+        let physical_block = blocks[target].physical_block;
+        let physical_block_prev = blocks[index].physical_block;
+        let allocate_count = blocks[target].allocate_count + 1;
+        let allocate_count_prev = blocks[index].allocate_count;
+
+        let mut content = vec![0xFF; 0x1000];
+        if blocks[index].initialized {
+            for (i, chunk) in blocks[index].data.iter().enumerate() {
+                chunk.read(0, &mut content[i * 0x200..(i + 1) * 0x200])?;
+            }
+        }
+
+        let crc_ticket = blocks[index].crc_ticket.clone();
+        let data = block_chunks(&self.parent, physical_block, crc_ticket.as_ref(), false)?;
+        for (i, chunk) in data.iter().enumerate() {
+            chunk.write(0, &content[i * 0x200..(i + 1) * 0x200])?;
+        }
+        blocks[index] = WearLevelingBlock {
+            physical_block,
+            allocate_count,
+            initialized: true,
+            dirty: false,
+            freed: false,
+            crc_ticket,
+            data,
+        };
+
+        let crc_ticket = (!self.large_save).then(|| Rc::new(MemoryFile::new(vec![0; 8])));
+        blocks[target] = WearLevelingBlock {
+            physical_block: physical_block_prev,
+            allocate_count: allocate_count_prev,
+            initialized: false,
+            dirty: false,
+            freed: true,
+            data: block_chunks(
+                &self.parent,
+                physical_block_prev,
+                crc_ticket.as_ref(),
+                false,
+            )?,
+            crc_ticket,
+        };
+
+        self.pending_journal.borrow_mut().push([
+            index as u8,
+            target as u8,
+            physical_block,
+            physical_block_prev,
+            allocate_count,
+            allocate_count_prev,
+        ]);
+        Ok(())
+    }
 }
 
 const CHUNK_INIT: [u8; 0x200] = [0xFF; 0x200];
@@ -415,6 +543,9 @@ const CHUNK_INIT: [u8; 0x200] = [0xFF; 0x200];
 impl RandomAccessFile for WearLeveling {
     fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
         let end = pos + buf.len();
+        if end > self.len() {
+            return make_error(Error::OutOfBound);
+        }
 
         // chunk index range the operation covers
         let begin_chunk = pos / 0x200;
@@ -446,9 +577,10 @@ impl RandomAccessFile for WearLeveling {
         Ok(())
     }
     fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
-        // TODO: implement proper reallocating
-
         let end = pos + buf.len();
+        if end > self.len() {
+            return make_error(Error::OutOfBound);
+        }
 
         // chunk index range the operation covers
         let begin_chunk = pos / 0x200;
@@ -463,17 +595,11 @@ impl RandomAccessFile for WearLeveling {
             let data_begin = std::cmp::max(data_begin_as_chunk, pos);
             let data_end = std::cmp::min(data_end_as_chunk, end);
 
-            let block = &mut self.blocks.borrow_mut()[i / 8];
-            if !block.initialized {
-                block.initialized = true;
-                if block.allocate_count == 0 {
-                    block.allocate_count = 1;
-                }
-                for chunk in block.data.iter() {
-                    chunk.write(0, &CHUNK_INIT)?;
-                }
+            let blocks = &mut self.blocks.borrow_mut();
+            if !blocks[i / 8].dirty {
+                self.relocate(blocks, i / 8)?;
             }
-
+            let block = &mut blocks[i / 8];
             block.dirty = true;
 
             let chunk = i % 8;
@@ -485,22 +611,48 @@ impl RandomAccessFile for WearLeveling {
 
         Ok(())
     }
+    /// Returns the virtual length of the save data, which is one block (0x1000 bytes) less than
+    /// the number of blocks in the block map, because one physical block is always reserved
+    /// for relocation. This is smaller than the length of the underlying chip image.
     fn len(&self) -> usize {
         // -1 for the reserved block
         (self.blocks.borrow().len() - 1) * 0x1000
     }
+    /// Records the relocations since the last commit by appending them to the journal, after
+    /// the CRC of the modified data. If the journal is full, or some block had to be modified
+    /// in place, the whole block map is rewritten instead, and the journal is cleared.
     fn commit(&self) -> Result<(), Error> {
-        // TODO: implement proper reallocating and journal recording.
-        // we now simply squash the journal.
-        let item_len = if self.large_save { 2 } else { 10 };
-        for (i, block) in self.blocks.borrow_mut().iter_mut().enumerate() {
+        let mut blocks = self.blocks.borrow_mut();
+        for block in blocks.iter_mut() {
             if block.initialized && block.dirty {
                 for data in block.data.iter() {
                     data.commit()?;
                 }
-                block.dirty = false;
             }
+            block.dirty = false;
+            block.freed = false;
+        }
 
+        let pending_journal = std::mem::take(&mut *self.pending_journal.borrow_mut());
+        let journal_capacity = self.journal_list.len() / JOURNAL_ENTRY_LEN;
+        let journal_len = self.journal_len.get();
+        if !self.squash.get() && journal_len + pending_journal.len() <= journal_capacity {
+            for (i, entry) in pending_journal.iter().enumerate() {
+                let mut record = [0; 14];
+                record[0..6].copy_from_slice(entry);
+                if let Some(crc_ticket) = &blocks[entry[0] as usize].crc_ticket {
+                    crc_ticket.read(0, &mut record[6..14])?;
+                }
+                let offset = (journal_len + i) * JOURNAL_ENTRY_LEN;
+                self.journal_list.write(offset, &record)?;
+                self.journal_list.write(offset + 14, &record)?;
+            }
+            self.journal_len.set(journal_len + pending_journal.len());
+            return Ok(());
+        }
+
+        let item_len = if self.large_save { 2 } else { 10 };
+        for (i, block) in blocks.iter().enumerate() {
             let buf = if self.large_save {
                 [
                     block.allocate_count + ((block.initialized as u8) << 7),
@@ -524,9 +676,10 @@ impl RandomAccessFile for WearLeveling {
 
         self.block_map.commit()?;
 
-        for offset in 0..self.journal_list.len() {
-            self.journal_list.write(offset, &[0xFF])?;
-        }
+        self.journal_list
+            .write(0, &vec![0xFF; self.journal_list.len()])?;
+        self.journal_len.set(0);
+        self.squash.set(false);
 
         Ok(())
     }
@@ -704,4 +857,70 @@ pub mod test {
             );
         }
     }
+
+    #[test]
+    fn fuzz_wear_leveling_journal() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..3 {
+            let len = *[0x20_000, 0x80_000, 0x100_000].choose(&mut rng).unwrap();
+            let init = Rc::new(MemoryFile::new(vec![0xFF; len]));
+            WearLeveling::format(init.clone()).unwrap();
+            let plain = MemoryFile::new(vec![0xFF; len - 0x2000]);
+            let mut appended = false;
+
+            for _ in 0..200 {
+                let file = WearLeveling::new(init.clone()).unwrap();
+                let block_map_len = file.block_map.len();
+                let mut block_map = vec![0; block_map_len];
+                init.read(0, &mut block_map).unwrap();
+                let journal_len = file.journal_len.get();
+
+                // Mostly touches a few blocks, so that they can be relocated
+                for _ in 0..rng.gen_range(1..4) {
+                    let pos = rng.gen_range(0..plain.len());
+                    let data_len = rng.gen_range(1..std::cmp::min(0x1800, plain.len() - pos + 1));
+                    let data: Vec<u8> = (&mut rng).sample_iter(&Standard).take(data_len).collect();
+                    file.write(pos, &data).unwrap();
+                    plain.write(pos, &data).unwrap();
+                }
+
+                let pending = file.pending_journal.borrow().clone();
+                for entry in pending.iter() {
+                    assert_ne!(entry[2], entry[3]);
+                    assert_eq!(
+                        file.blocks.borrow()[entry[0] as usize].physical_block,
+                        entry[2]
+                    );
+                }
+                let squash = file.squash.get();
+                file.commit().unwrap();
+
+                let mut new_block_map = vec![0; block_map_len];
+                init.read(0, &mut new_block_map).unwrap();
+                if file.journal_len.get() != 0 {
+                    assert!(!squash);
+                    assert_eq!(file.journal_len.get(), journal_len + pending.len());
+                    assert_eq!(new_block_map, block_map);
+                    appended |= !pending.is_empty();
+                }
+
+                let state = |file: &WearLeveling| -> Vec<_> {
+                    file.blocks
+                        .borrow()
+                        .iter()
+                        .map(|b| (b.physical_block, b.allocate_count, b.initialized))
+                        .collect()
+                };
+                let reopened = WearLeveling::new(init.clone()).unwrap();
+                assert_eq!(reopened.journal_len.get(), file.journal_len.get());
+                assert_eq!(state(&reopened), state(&file));
+                let mut a = vec![0; plain.len()];
+                let mut b = vec![0; plain.len()];
+                reopened.read(0, &mut a).unwrap();
+                plain.read(0, &mut b).unwrap();
+                assert_eq!(a, b);
+            }
+            assert!(appended);
+        }
+    }
 }
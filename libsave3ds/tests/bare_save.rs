@@ -0,0 +1,252 @@
+//! Tests against a bare save data image checked in under `tests/fixtures`.
+//!
+//! `bare.sav` is a 128 KiB DISA container formatted with
+//! `block_type: Small, max_dir: 10, dir_buckets: 11, max_file: 10, file_buckets: 11,
+//! duplicate_data: true`, containing
+//!  - `/data.bin`: 1000 bytes, where byte `i` is `i % 251`
+//!  - `/dir/hello.txt`: `Hello, 3DS!\n`
+//!
+//! Bare save data is not encrypted and its CMAC is not checked, so no console key is needed.
+
+use libsave3ds::error::Error;
+use libsave3ds::file_system::*;
+use libsave3ds::Resource;
+use std::path::PathBuf;
+
+const HELLO: &[u8] = b"Hello, 3DS!\n";
+
+fn fixture() -> Vec<u8> {
+    std::fs::read(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/bare.sav"
+    ))
+    .unwrap()
+}
+
+/// Copies the fixture to a temporary file, so that tests can modify it.
+fn copy_fixture(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("libsave3ds_{}_{}", name, std::process::id()));
+    std::fs::write(&path, fixture()).unwrap();
+    path
+}
+
+fn resource() -> Resource {
+    Resource::new(None, None, None, None, None, None, None, None, None, None).unwrap()
+}
+
+fn name(s: &str) -> [u8; 16] {
+    <[u8; 16]>::name_str_to_3ds(s).unwrap()
+}
+
+fn data_bin() -> Vec<u8> {
+    (0..1000u32).map(|i| (i % 251) as u8).collect()
+}
+
+fn read_all(file: &libsave3ds::save_data::File) -> Vec<u8> {
+    let mut buf = vec![0; file.len()];
+    file.read(0, &mut buf).unwrap();
+    buf
+}
+
+fn u32_at(image: &[u8], offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&image[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn u64_at(image: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&image[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+#[test]
+fn header_layout() {
+    let image = fixture();
+    assert_eq!(image.len(), 0x20000);
+
+    // The first 0x100 bytes are the CMAC, followed by the DISA header
+    assert_eq!(&image[0x100..0x104], b"DISA");
+    assert_eq!(u32_at(&image, 0x104), 0x40000);
+    assert_eq!(u32_at(&image, 0x108), 1); // partition count
+    assert_eq!(u64_at(&image, 0x110), 0x200); // secondary partition table offset
+    assert_eq!(u64_at(&image, 0x118), 0x330); // primary partition table offset
+    assert_eq!(u64_at(&image, 0x120), 0x12C); // partition table size
+
+    // Both partition tables start with a DIFI header
+    for &table in &[0x200, 0x330] {
+        assert_eq!(&image[table..table + 4], b"DIFI");
+        assert_eq!(u32_at(&image, table + 4), 0x10000);
+    }
+}
+
+#[test]
+fn read() {
+    let path = copy_fixture("fixture_read");
+    let save = resource()
+        .open_bare_save(path.to_str().unwrap(), false)
+        .unwrap();
+    let root = save.open_root().unwrap();
+
+    let mut files: Vec<String> = root
+        .list_sub_file()
+        .unwrap()
+        .iter()
+        .map(|(n, _)| <[u8; 16]>::name_3ds_to_str(n))
+        .collect();
+    files.sort();
+    assert_eq!(files, vec!["data.bin"]);
+    let dirs: Vec<String> = root
+        .list_sub_dir()
+        .unwrap()
+        .iter()
+        .map(|(n, _)| <[u8; 16]>::name_3ds_to_str(n))
+        .collect();
+    assert_eq!(dirs, vec!["dir"]);
+
+    assert_eq!(
+        read_all(&root.open_sub_file(name("data.bin")).unwrap()),
+        data_bin()
+    );
+    let dir = root.open_sub_dir(name("dir")).unwrap();
+    assert_eq!(
+        read_all(&dir.open_sub_file(name("hello.txt")).unwrap()),
+        HELLO
+    );
+
+    let stat = save.stat().unwrap();
+    assert_eq!(stat.block_len, 512);
+    assert_eq!(stat.total_files, 10);
+    assert_eq!(stat.free_files, 8);
+    assert_eq!(stat.total_dirs, 11); // including the root
+    assert_eq!(stat.free_dirs, 9);
+
+    drop(dir);
+    drop(root);
+    drop(save);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn modify() {
+    let path = copy_fixture("fixture_modify");
+    let resource = resource();
+    {
+        let save = resource
+            .open_bare_save(path.to_str().unwrap(), true)
+            .unwrap();
+        let root = save.open_root().unwrap();
+        let dir = root.open_sub_dir(name("dir")).unwrap();
+        let mut hello = dir.open_sub_file(name("hello.txt")).unwrap();
+        hello.resize(HELLO.len() + 1).unwrap();
+        hello.write(HELLO.len(), b"!").unwrap();
+        hello.commit().unwrap();
+        root.open_sub_file(name("data.bin"))
+            .unwrap()
+            .delete()
+            .unwrap();
+        let new = dir.new_sub_file(name("new"), 4).unwrap();
+        new.write(0, &[1, 2, 3, 4]).unwrap();
+        new.commit().unwrap();
+        save.commit().unwrap();
+    }
+
+    let save = resource
+        .open_bare_save(path.to_str().unwrap(), false)
+        .unwrap();
+    let root = save.open_root().unwrap();
+    assert!(root.list_sub_file().unwrap().is_empty());
+    assert!(matches!(
+        root.open_sub_file(name("data.bin")),
+        Err(Error::NotFound)
+    ));
+    let dir = root.open_sub_dir(name("dir")).unwrap();
+    assert_eq!(
+        read_all(&dir.open_sub_file(name("hello.txt")).unwrap()),
+        b"Hello, 3DS!\n!"
+    );
+    assert_eq!(
+        read_all(&dir.open_sub_file(name("new")).unwrap()),
+        [1, 2, 3, 4]
+    );
+
+    drop(dir);
+    drop(root);
+    drop(save);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn corrupted_data() {
+    let mut image = fixture();
+    // Bare save data is not encrypted, so file content can be found in the image directly.
+    // With duplicate_data, every copy must be corrupted.
+    let mut found = 0;
+    for i in 0..image.len() - HELLO.len() {
+        if &image[i..i + HELLO.len()] == HELLO {
+            image[i] ^= 0xFF;
+            found += 1;
+        }
+    }
+    assert!(found > 0);
+
+    let path = copy_fixture("fixture_corrupted");
+    std::fs::write(&path, image).unwrap();
+    // Depending on which hash blocks cover the file data, the mismatch is detected either
+    // when opening the archive or when reading the file.
+    let result = (|| {
+        let save = resource().open_bare_save(path.to_str().unwrap(), false)?;
+        let hello = save
+            .open_root()?
+            .open_sub_dir(name("dir"))?
+            .open_sub_file(name("hello.txt"))?;
+        let mut buf = vec![0; HELLO.len()];
+        hello.read(0, &mut buf)
+    })();
+    assert!(matches!(result, Err(Error::HashMismatch)));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn trailing_bytes() {
+    let footer: Vec<u8> = (0..0x1234u32).map(|i| (i % 7) as u8 + 0x80).collect();
+    let path = copy_fixture("fixture_trailing");
+    let mut image = fixture();
+    image.extend_from_slice(&footer);
+    std::fs::write(&path, &image).unwrap();
+
+    let resource = resource();
+    {
+        let save = resource
+            .open_bare_save(path.to_str().unwrap(), true)
+            .unwrap();
+        let (exported, _) = save.export_image().unwrap();
+        assert!(exported.len() <= fixture().len());
+        let root = save.open_root().unwrap();
+        let new = root.new_sub_file(name("new"), 4).unwrap();
+        new.write(0, &[1, 2, 3, 4]).unwrap();
+        new.commit().unwrap();
+        save.commit().unwrap();
+    }
+
+    let image = std::fs::read(&path).unwrap();
+    assert_eq!(image.len(), fixture().len() + footer.len());
+    assert_eq!(&image[fixture().len()..], &footer[..]);
+    let save = resource
+        .open_bare_save(path.to_str().unwrap(), false)
+        .unwrap();
+    let root = save.open_root().unwrap();
+    assert_eq!(
+        read_all(&root.open_sub_file(name("new")).unwrap()),
+        [1, 2, 3, 4]
+    );
+    assert_eq!(
+        read_all(&root.open_sub_file(name("data.bin")).unwrap()),
+        data_bin()
+    );
+
+    drop(root);
+    drop(save);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,195 @@
+/* C interface of libsave3ds.
+ *
+ * All functions returning int32_t return SAVE3DS_OK on success, or one of the
+ * SAVE3DS_ERROR_* codes. Results are passed through out-pointers, which are left
+ * untouched on failure. save3ds_last_error returns the message of the last error.
+ *
+ * Handles are not thread safe: a resource and the archives opened from it must be
+ * used from one thread. Each handle must be released exactly once with the release
+ * function of its type. Archives stay usable after their resource is released.
+ */
+
+#ifndef SAVE3DS_H
+#define SAVE3DS_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define SAVE3DS_OK 0
+#define SAVE3DS_ERROR_IO 1
+#define SAVE3DS_ERROR_HASH_MISMATCH 2
+#define SAVE3DS_ERROR_OUT_OF_BOUND 3
+#define SAVE3DS_ERROR_MAGIC_MISMATCH 4
+#define SAVE3DS_ERROR_SIZE_MISMATCH 5
+#define SAVE3DS_ERROR_INVALID_VALUE 6
+#define SAVE3DS_ERROR_BROKEN_FAT 7
+#define SAVE3DS_ERROR_NO_SPACE 8
+#define SAVE3DS_ERROR_NOT_FOUND 9
+#define SAVE3DS_ERROR_ALREADY_EXIST 10
+#define SAVE3DS_ERROR_DELETING_ROOT 11
+#define SAVE3DS_ERROR_SIGNATURE_MISMATCH 12
+#define SAVE3DS_ERROR_MISSING_BOOT9 13
+#define SAVE3DS_ERROR_MISSING_SD 14
+#define SAVE3DS_ERROR_MISSING_MOVABLE 15
+#define SAVE3DS_ERROR_MISSING_NAND 16
+#define SAVE3DS_ERROR_MISSING_GAME 17
+#define SAVE3DS_ERROR_MISSING_PRIV 18
+#define SAVE3DS_ERROR_MISSING_KEY_Y_2F 19
+#define SAVE3DS_ERROR_MISSING_KEY_X_19 20
+#define SAVE3DS_ERROR_MISSING_KEY_X_1A 21
+#define SAVE3DS_ERROR_MISSING_KEY_X_19_1A 22
+#define SAVE3DS_ERROR_MISSING_OTP 23
+#define SAVE3DS_ERROR_MISSING_SECURE_INFO 24
+#define SAVE3DS_ERROR_MISSING_LFCS 25
+#define SAVE3DS_ERROR_LFCS_MISMATCH 26
+#define SAVE3DS_ERROR_BROKEN_SD 27
+#define SAVE3DS_ERROR_NOT_EMPTY 28
+#define SAVE3DS_ERROR_UNSUPPORTED 29
+#define SAVE3DS_ERROR_UNIQUE_ID_MISMATCH 30
+#define SAVE3DS_ERROR_BROKEN_OTP 31
+#define SAVE3DS_ERROR_BUSY 32
+#define SAVE3DS_ERROR_BROKEN_GAME 33
+#define SAVE3DS_ERROR_LIKELY_WRONG_KEY 34
+#define SAVE3DS_ERROR_COMMIT_FAILED 35
+#define SAVE3DS_ERROR_NAME_TOO_LONG 36
+#define SAVE3DS_ERROR_INVALID_NAME 37
+#define SAVE3DS_ERROR_READ_ONLY 38
+/* A required pointer is null, a path is not UTF-8, or a number doesn't fit. */
+#define SAVE3DS_ERROR_INVALID_ARGUMENT 100
+/* The library panicked. The handles involved may be in an inconsistent state. */
+#define SAVE3DS_ERROR_PANIC 101
+
+/* The inode of the root directory of every archive. */
+#define SAVE3DS_ROOT_INO 1
+
+/* Database types for save3ds_open_db. */
+#define SAVE3DS_DB_TICKET 0
+#define SAVE3DS_DB_NAND_TITLE 1
+#define SAVE3DS_DB_NAND_IMPORT 2
+#define SAVE3DS_DB_TMP_TITLE 3
+#define SAVE3DS_DB_TMP_IMPORT 4
+#define SAVE3DS_DB_SD_TITLE 5
+#define SAVE3DS_DB_SD_IMPORT 6
+
+typedef struct Save3dsResource Save3dsResource;
+typedef struct Save3dsSaveData Save3dsSaveData;
+typedef struct Save3dsExtData Save3dsExtData;
+typedef struct Save3dsDb Save3dsDb;
+
+/* A directory or file in an archive whose names are 16 bytes. */
+typedef struct Save3dsEntry {
+    uint8_t name[16];
+    uint32_t ino;
+    bool is_dir;
+} Save3dsEntry;
+
+/* A file in a database, named by its ID. Databases have no sub directories. */
+typedef struct Save3dsDbEntry {
+    uint64_t id;
+    uint32_t ino;
+} Save3dsDbEntry;
+
+/* Returns the message of the last error on this thread, or NULL if there was none.
+ * The string stays valid until the next failing call on this thread. */
+const char* save3ds_last_error(void);
+
+/* Creates a resource. All paths and keys are optional and may be NULL. Keys point to
+ * 16 bytes. See libsave3ds::Resource::new for what each of them is needed for. */
+int32_t save3ds_resource_create(const char* boot9_path, const char* movable_path,
+                                const char* sd_path, const char* nand_path,
+                                const char* otp_path, const char* priv_path,
+                                const char* game_path, const uint8_t* x2f_key_y,
+                                const uint8_t* x19_key_x, const uint8_t* x1a_key_x,
+                                Save3dsResource** resource);
+void save3ds_resource_release(Save3dsResource* resource);
+
+/* Save data. With write unset, the archive is never written to, and modifications fail
+ * with SAVE3DS_ERROR_READ_ONLY. */
+int32_t save3ds_open_bare_save(Save3dsResource* resource, const char* path, bool write,
+                               Save3dsSaveData** save);
+int32_t save3ds_open_sd_save(Save3dsResource* resource, uint64_t id, bool write,
+                             Save3dsSaveData** save);
+int32_t save3ds_open_nand_save(Save3dsResource* resource, uint32_t id, bool write,
+                               Save3dsSaveData** save);
+void save3ds_save_release(Save3dsSaveData* save);
+int32_t save3ds_save_commit(Save3dsSaveData* save);
+
+/* Lists the sub directories, then the sub files, of a directory. The list must be
+ * released with save3ds_entries_release. */
+int32_t save3ds_save_list(Save3dsSaveData* save, uint32_t dir_ino, Save3dsEntry** entries,
+                          size_t* count);
+void save3ds_entries_release(Save3dsEntry* entries, size_t count);
+
+/* Names point to 16 bytes. Files are committed after creation, writes and resizes, but
+ * the archive still needs save3ds_save_commit. */
+int32_t save3ds_save_lookup(Save3dsSaveData* save, uint32_t parent_ino, const uint8_t* name,
+                            bool is_dir, uint32_t* ino);
+int32_t save3ds_save_create_dir(Save3dsSaveData* save, uint32_t parent_ino,
+                                const uint8_t* name, uint32_t* ino);
+int32_t save3ds_save_create_file(Save3dsSaveData* save, uint32_t parent_ino,
+                                 const uint8_t* name, uint64_t len, uint32_t* ino);
+int32_t save3ds_save_remove_dir(Save3dsSaveData* save, uint32_t ino);
+int32_t save3ds_save_remove_file(Save3dsSaveData* save, uint32_t ino);
+int32_t save3ds_save_file_len(Save3dsSaveData* save, uint32_t ino, uint64_t* len);
+int32_t save3ds_save_read_file(Save3dsSaveData* save, uint32_t ino, uint64_t pos,
+                               uint8_t* buf, size_t len);
+int32_t save3ds_save_write_file(Save3dsSaveData* save, uint32_t ino, uint64_t pos,
+                                const uint8_t* buf, size_t len);
+int32_t save3ds_save_resize_file(Save3dsSaveData* save, uint32_t ino, uint64_t len);
+
+/* Extdata. The operations work like the ones of save data, and lists are released with
+ * save3ds_entries_release. An extdata without Quota.dat is opened read-only regardless of
+ * write. Files of a read-only extdata can't be resized, and save3ds_ext_resize_file returns
+ * SAVE3DS_ERROR_UNSUPPORTED for them instead of SAVE3DS_ERROR_READ_ONLY. */
+int32_t save3ds_open_sd_ext(Save3dsResource* resource, uint64_t id, bool write,
+                            Save3dsExtData** ext);
+int32_t save3ds_open_nand_ext(Save3dsResource* resource, uint64_t id, bool write,
+                              Save3dsExtData** ext);
+void save3ds_ext_release(Save3dsExtData* ext);
+int32_t save3ds_ext_commit(Save3dsExtData* ext);
+int32_t save3ds_ext_list(Save3dsExtData* ext, uint32_t dir_ino, Save3dsEntry** entries,
+                         size_t* count);
+int32_t save3ds_ext_lookup(Save3dsExtData* ext, uint32_t parent_ino, const uint8_t* name,
+                           bool is_dir, uint32_t* ino);
+int32_t save3ds_ext_create_dir(Save3dsExtData* ext, uint32_t parent_ino, const uint8_t* name,
+                               uint32_t* ino);
+int32_t save3ds_ext_create_file(Save3dsExtData* ext, uint32_t parent_ino,
+                                const uint8_t* name, uint64_t len, uint32_t* ino);
+int32_t save3ds_ext_remove_dir(Save3dsExtData* ext, uint32_t ino);
+int32_t save3ds_ext_remove_file(Save3dsExtData* ext, uint32_t ino);
+int32_t save3ds_ext_file_len(Save3dsExtData* ext, uint32_t ino, uint64_t* len);
+int32_t save3ds_ext_read_file(Save3dsExtData* ext, uint32_t ino, uint64_t pos, uint8_t* buf,
+                              size_t len);
+int32_t save3ds_ext_write_file(Save3dsExtData* ext, uint32_t ino, uint64_t pos,
+                               const uint8_t* buf, size_t len);
+int32_t save3ds_ext_resize_file(Save3dsExtData* ext, uint32_t ino, uint64_t len);
+
+/* Title and ticket databases. db_type is one of SAVE3DS_DB_*. All files are in the root
+ * directory and are named by their ID. The list must be released with
+ * save3ds_db_entries_release. */
+int32_t save3ds_open_db(Save3dsResource* resource, uint32_t db_type, bool write,
+                        Save3dsDb** db);
+void save3ds_db_release(Save3dsDb* db);
+int32_t save3ds_db_commit(Save3dsDb* db);
+int32_t save3ds_db_list(Save3dsDb* db, Save3dsDbEntry** entries, size_t* count);
+void save3ds_db_entries_release(Save3dsDbEntry* entries, size_t count);
+int32_t save3ds_db_lookup(Save3dsDb* db, uint64_t id, uint32_t* ino);
+int32_t save3ds_db_create_file(Save3dsDb* db, uint64_t id, uint64_t len, uint32_t* ino);
+int32_t save3ds_db_remove_file(Save3dsDb* db, uint32_t ino);
+int32_t save3ds_db_file_len(Save3dsDb* db, uint32_t ino, uint64_t* len);
+int32_t save3ds_db_read_file(Save3dsDb* db, uint32_t ino, uint64_t pos, uint8_t* buf,
+                             size_t len);
+int32_t save3ds_db_write_file(Save3dsDb* db, uint32_t ino, uint64_t pos, const uint8_t* buf,
+                              size_t len);
+int32_t save3ds_db_resize_file(Save3dsDb* db, uint32_t ino, uint64_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
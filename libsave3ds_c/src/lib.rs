@@ -0,0 +1,1116 @@
+//! C bindings of `libsave3ds`. See `include/save3ds.h` for the interface.
+//!
+//! All functions return `SAVE3DS_OK` on success, or one of the `SAVE3DS_ERROR_*` codes, and
+//! pass their results through out-pointers, which are left untouched on failure. The message
+//! of the last error on the calling thread can be fetched with `save3ds_last_error`.
+//!
+//! # Safety
+//!
+//! Pointers passed in must either be null where the header allows it, or be valid for the
+//! access the header describes. Handles must be released exactly once, with the release
+//! function of their type, and can't be used after that. As with the archives of
+//! `libsave3ds`, handles are not thread safe: a resource and the archives opened from it must
+//! be used from one thread.
+#![allow(clippy::missing_safety_doc)]
+
+use libsave3ds::db::{Db, DbType};
+use libsave3ds::error::Error;
+use libsave3ds::ext_data::ExtData;
+use libsave3ds::file_system::*;
+use libsave3ds::save_data::SaveData;
+use libsave3ds::Resource;
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+pub const SAVE3DS_OK: i32 = 0;
+pub const SAVE3DS_ERROR_IO: i32 = 1;
+pub const SAVE3DS_ERROR_HASH_MISMATCH: i32 = 2;
+pub const SAVE3DS_ERROR_OUT_OF_BOUND: i32 = 3;
+pub const SAVE3DS_ERROR_MAGIC_MISMATCH: i32 = 4;
+pub const SAVE3DS_ERROR_SIZE_MISMATCH: i32 = 5;
+pub const SAVE3DS_ERROR_INVALID_VALUE: i32 = 6;
+pub const SAVE3DS_ERROR_BROKEN_FAT: i32 = 7;
+pub const SAVE3DS_ERROR_NO_SPACE: i32 = 8;
+pub const SAVE3DS_ERROR_NOT_FOUND: i32 = 9;
+pub const SAVE3DS_ERROR_ALREADY_EXIST: i32 = 10;
+pub const SAVE3DS_ERROR_DELETING_ROOT: i32 = 11;
+pub const SAVE3DS_ERROR_SIGNATURE_MISMATCH: i32 = 12;
+pub const SAVE3DS_ERROR_MISSING_BOOT9: i32 = 13;
+pub const SAVE3DS_ERROR_MISSING_SD: i32 = 14;
+pub const SAVE3DS_ERROR_MISSING_MOVABLE: i32 = 15;
+pub const SAVE3DS_ERROR_MISSING_NAND: i32 = 16;
+pub const SAVE3DS_ERROR_MISSING_GAME: i32 = 17;
+pub const SAVE3DS_ERROR_MISSING_PRIV: i32 = 18;
+pub const SAVE3DS_ERROR_MISSING_KEY_Y_2F: i32 = 19;
+pub const SAVE3DS_ERROR_MISSING_KEY_X_19: i32 = 20;
+pub const SAVE3DS_ERROR_MISSING_KEY_X_1A: i32 = 21;
+pub const SAVE3DS_ERROR_MISSING_KEY_X_19_1A: i32 = 22;
+pub const SAVE3DS_ERROR_MISSING_OTP: i32 = 23;
+pub const SAVE3DS_ERROR_MISSING_SECURE_INFO: i32 = 24;
+pub const SAVE3DS_ERROR_MISSING_LFCS: i32 = 25;
+pub const SAVE3DS_ERROR_LFCS_MISMATCH: i32 = 26;
+pub const SAVE3DS_ERROR_BROKEN_SD: i32 = 27;
+pub const SAVE3DS_ERROR_NOT_EMPTY: i32 = 28;
+pub const SAVE3DS_ERROR_UNSUPPORTED: i32 = 29;
+pub const SAVE3DS_ERROR_UNIQUE_ID_MISMATCH: i32 = 30;
+pub const SAVE3DS_ERROR_BROKEN_OTP: i32 = 31;
+pub const SAVE3DS_ERROR_BUSY: i32 = 32;
+pub const SAVE3DS_ERROR_BROKEN_GAME: i32 = 33;
+pub const SAVE3DS_ERROR_LIKELY_WRONG_KEY: i32 = 34;
+pub const SAVE3DS_ERROR_COMMIT_FAILED: i32 = 35;
+pub const SAVE3DS_ERROR_NAME_TOO_LONG: i32 = 36;
+pub const SAVE3DS_ERROR_INVALID_NAME: i32 = 37;
+pub const SAVE3DS_ERROR_READ_ONLY: i32 = 38;
+/// A required pointer is null, a path is not UTF-8, or a number doesn't fit on this platform.
+pub const SAVE3DS_ERROR_INVALID_ARGUMENT: i32 = 100;
+/// The library panicked. The handles involved may be in an inconsistent state.
+pub const SAVE3DS_ERROR_PANIC: i32 = 101;
+
+/// The inode of the root directory of every archive.
+pub const SAVE3DS_ROOT_INO: u32 = 1;
+
+pub const SAVE3DS_DB_TICKET: u32 = 0;
+pub const SAVE3DS_DB_NAND_TITLE: u32 = 1;
+pub const SAVE3DS_DB_NAND_IMPORT: u32 = 2;
+pub const SAVE3DS_DB_TMP_TITLE: u32 = 3;
+pub const SAVE3DS_DB_TMP_IMPORT: u32 = 4;
+pub const SAVE3DS_DB_SD_TITLE: u32 = 5;
+pub const SAVE3DS_DB_SD_IMPORT: u32 = 6;
+
+/// Opaque handle of a `libsave3ds::Resource`.
+pub struct Save3dsResource(Resource);
+
+/// Opaque handle of a `libsave3ds::save_data::SaveData`.
+pub struct Save3dsSaveData(SaveData);
+
+/// Opaque handle of a `libsave3ds::ext_data::ExtData`.
+pub struct Save3dsExtData(ExtData);
+
+/// Opaque handle of a `libsave3ds::db::Db`.
+pub struct Save3dsDb(Db);
+
+/// A directory or file in an archive whose names are 16 bytes.
+#[repr(C)]
+pub struct Save3dsEntry {
+    pub name: [u8; 16],
+    pub ino: u32,
+    pub is_dir: bool,
+}
+
+/// A file in a database, named by its ID. Databases have no sub directories.
+#[repr(C)]
+pub struct Save3dsDbEntry {
+    pub id: u64,
+    pub ino: u32,
+}
+
+enum FfiError {
+    Lib(Error),
+    InvalidArgument(&'static str),
+}
+
+impl From<Error> for FfiError {
+    fn from(e: Error) -> FfiError {
+        FfiError::Lib(e)
+    }
+}
+
+type FfiResult<T> = Result<T, FfiError>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn error_code(e: &Error) -> i32 {
+    match e {
+        Error::IO(_) => SAVE3DS_ERROR_IO,
+        Error::HashMismatch => SAVE3DS_ERROR_HASH_MISMATCH,
+        Error::OutOfBound => SAVE3DS_ERROR_OUT_OF_BOUND,
+        Error::MagicMismatch => SAVE3DS_ERROR_MAGIC_MISMATCH,
+        Error::SizeMismatch => SAVE3DS_ERROR_SIZE_MISMATCH,
+        Error::InvalidValue => SAVE3DS_ERROR_INVALID_VALUE,
+        Error::BrokenFat => SAVE3DS_ERROR_BROKEN_FAT,
+        Error::NoSpace => SAVE3DS_ERROR_NO_SPACE,
+        Error::NotFound => SAVE3DS_ERROR_NOT_FOUND,
+        Error::AlreadyExist => SAVE3DS_ERROR_ALREADY_EXIST,
+        Error::DeletingRoot => SAVE3DS_ERROR_DELETING_ROOT,
+        Error::SignatureMismatch => SAVE3DS_ERROR_SIGNATURE_MISMATCH,
+        Error::MissingBoot9 => SAVE3DS_ERROR_MISSING_BOOT9,
+        Error::MissingSd => SAVE3DS_ERROR_MISSING_SD,
+        Error::MissingMovable => SAVE3DS_ERROR_MISSING_MOVABLE,
+        Error::MissingNand => SAVE3DS_ERROR_MISSING_NAND,
+        Error::MissingGame => SAVE3DS_ERROR_MISSING_GAME,
+        Error::MissingPriv => SAVE3DS_ERROR_MISSING_PRIV,
+        Error::MissingKeyY2F => SAVE3DS_ERROR_MISSING_KEY_Y_2F,
+        Error::MissingKeyX19 => SAVE3DS_ERROR_MISSING_KEY_X_19,
+        Error::MissingKeyX1A => SAVE3DS_ERROR_MISSING_KEY_X_1A,
+        Error::MissingKeyX19X1A => SAVE3DS_ERROR_MISSING_KEY_X_19_1A,
+        Error::MissingOtp => SAVE3DS_ERROR_MISSING_OTP,
+        Error::MissingSecureInfo => SAVE3DS_ERROR_MISSING_SECURE_INFO,
+        Error::MissingLfcs => SAVE3DS_ERROR_MISSING_LFCS,
+        Error::LfcsMismatch => SAVE3DS_ERROR_LFCS_MISMATCH,
+        Error::BrokenSd => SAVE3DS_ERROR_BROKEN_SD,
+        Error::NotEmpty => SAVE3DS_ERROR_NOT_EMPTY,
+        Error::Unsupported => SAVE3DS_ERROR_UNSUPPORTED,
+        Error::UniqueIdMismatch => SAVE3DS_ERROR_UNIQUE_ID_MISMATCH,
+        Error::BrokenOtp => SAVE3DS_ERROR_BROKEN_OTP,
+        Error::Busy => SAVE3DS_ERROR_BUSY,
+        Error::BrokenGame => SAVE3DS_ERROR_BROKEN_GAME,
+        Error::LikelyWrongKey => SAVE3DS_ERROR_LIKELY_WRONG_KEY,
+        Error::CommitFailed(..) => SAVE3DS_ERROR_COMMIT_FAILED,
+        Error::NameTooLong => SAVE3DS_ERROR_NAME_TOO_LONG,
+        Error::InvalidName => SAVE3DS_ERROR_INVALID_NAME,
+        Error::ReadOnly => SAVE3DS_ERROR_READ_ONLY,
+    }
+}
+
+/// Records the error as the last error of this thread, and returns its code.
+fn smash_error(e: FfiError) -> i32 {
+    match e {
+        FfiError::Lib(e) => {
+            set_last_error(e.to_string());
+            error_code(&e)
+        }
+        FfiError::InvalidArgument(message) => {
+            set_last_error(format!("Invalid argument: {}", message));
+            SAVE3DS_ERROR_INVALID_ARGUMENT
+        }
+    }
+}
+
+/// Runs the body of an entry point, turning its result into an error code. Panics are caught,
+/// as they can't unwind across the C boundary.
+fn ffi_call(f: impl FnOnce() -> FfiResult<()>) -> i32 {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => SAVE3DS_OK,
+        Ok(Err(e)) => smash_error(e),
+        Err(_) => {
+            set_last_error("The library panicked".to_owned());
+            SAVE3DS_ERROR_PANIC
+        }
+    }
+}
+
+fn to_raw<T>(value: T) -> *mut T {
+    Box::into_raw(Box::new(value))
+}
+
+unsafe fn from_raw<'a, T>(ptr: *mut T) -> FfiResult<&'a mut T> {
+    ptr.as_mut().ok_or(FfiError::InvalidArgument("null handle"))
+}
+
+unsafe fn release<T>(ptr: *mut T) {
+    if !ptr.is_null() {
+        drop(Box::from_raw(ptr));
+    }
+}
+
+/// Checks an out-pointer before doing any work, so that a null one doesn't discard a result
+/// whose side effects already happened.
+unsafe fn out_ptr<'a, T>(ptr: *mut T) -> FfiResult<&'a mut T> {
+    ptr.as_mut()
+        .ok_or(FfiError::InvalidArgument("null out-pointer"))
+}
+
+unsafe fn opt_str(ptr: *const c_char) -> FfiResult<Option<String>> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map(|s| Some(s.to_owned()))
+        .map_err(|_| FfiError::InvalidArgument("path is not UTF-8"))
+}
+
+unsafe fn str_arg(ptr: *const c_char) -> FfiResult<String> {
+    opt_str(ptr)?.ok_or(FfiError::InvalidArgument("null path"))
+}
+
+unsafe fn opt_key(ptr: *const u8) -> Option<[u8; 16]> {
+    if ptr.is_null() {
+        return None;
+    }
+    let mut key = [0; 16];
+    key.copy_from_slice(std::slice::from_raw_parts(ptr, 16));
+    Some(key)
+}
+
+unsafe fn name_arg(ptr: *const u8) -> FfiResult<ArchiveName> {
+    if ptr.is_null() {
+        return Err(FfiError::InvalidArgument("null name"));
+    }
+    let mut name = [0; 16];
+    name.copy_from_slice(std::slice::from_raw_parts(ptr, 16));
+    Ok(ArchiveName::Bytes(name))
+}
+
+unsafe fn buf_arg<'a>(ptr: *const u8, len: usize) -> FfiResult<&'a [u8]> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    if ptr.is_null() {
+        return Err(FfiError::InvalidArgument("null buffer"));
+    }
+    Ok(std::slice::from_raw_parts(ptr, len))
+}
+
+unsafe fn buf_arg_mut<'a>(ptr: *mut u8, len: usize) -> FfiResult<&'a mut [u8]> {
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    if ptr.is_null() {
+        return Err(FfiError::InvalidArgument("null buffer"));
+    }
+    Ok(std::slice::from_raw_parts_mut(ptr, len))
+}
+
+fn usize_arg(value: u64) -> FfiResult<usize> {
+    usize::try_from(value).map_err(|_| FfiError::InvalidArgument("number too large"))
+}
+
+fn db_type_arg(db_type: u32) -> FfiResult<DbType> {
+    Ok(match db_type {
+        SAVE3DS_DB_TICKET => DbType::Ticket,
+        SAVE3DS_DB_NAND_TITLE => DbType::NandTitle,
+        SAVE3DS_DB_NAND_IMPORT => DbType::NandImport,
+        SAVE3DS_DB_TMP_TITLE => DbType::TmpTitle,
+        SAVE3DS_DB_TMP_IMPORT => DbType::TmpImport,
+        SAVE3DS_DB_SD_TITLE => DbType::SdTitle,
+        SAVE3DS_DB_SD_IMPORT => DbType::SdImport,
+        _ => return Err(FfiError::InvalidArgument("unknown database type")),
+    })
+}
+
+unsafe fn entries_release<T>(entries: *mut T, count: usize) {
+    if !entries.is_null() {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+            entries, count,
+        )));
+    }
+}
+
+fn list_entries(
+    archive: &dyn DynFileSystem,
+    ino: u32,
+    entries: &mut *mut Save3dsEntry,
+    count: &mut usize,
+) -> FfiResult<()> {
+    let mut list = vec![];
+    for (is_dir, sub) in [
+        (true, archive.list_sub_dir_names(ino)?),
+        (false, archive.list_sub_file_names(ino)?),
+    ] {
+        for (name, ino) in sub {
+            let name = match name {
+                ArchiveName::Bytes(name) => name,
+                ArchiveName::Id(_) => return Err(Error::Unsupported.into()),
+            };
+            list.push(Save3dsEntry { name, ino, is_dir });
+        }
+    }
+    *count = list.len();
+    *entries = Box::into_raw(list.into_boxed_slice()) as *mut Save3dsEntry;
+    Ok(())
+}
+
+fn list_db_entries(
+    archive: &dyn DynFileSystem,
+    entries: &mut *mut Save3dsDbEntry,
+    count: &mut usize,
+) -> FfiResult<()> {
+    let mut list = vec![];
+    for (name, ino) in archive.list_sub_file_names(SAVE3DS_ROOT_INO)? {
+        let id = match name {
+            ArchiveName::Id(id) => id,
+            ArchiveName::Bytes(_) => return Err(Error::Unsupported.into()),
+        };
+        list.push(Save3dsDbEntry { id, ino });
+    }
+    *count = list.len();
+    *entries = Box::into_raw(list.into_boxed_slice()) as *mut Save3dsDbEntry;
+    Ok(())
+}
+
+fn lookup(
+    archive: &dyn DynFileSystem,
+    parent: u32,
+    name: &ArchiveName,
+    is_dir: bool,
+) -> FfiResult<u32> {
+    Ok(if is_dir {
+        archive.lookup_dir(parent, name)?
+    } else {
+        archive.lookup_file(parent, name)?
+    })
+}
+
+/// Returns the message of the last error on this thread, or null if there was none.
+/// The string stays valid until the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn save3ds_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_resource_create(
+    boot9_path: *const c_char,
+    movable_path: *const c_char,
+    sd_path: *const c_char,
+    nand_path: *const c_char,
+    otp_path: *const c_char,
+    priv_path: *const c_char,
+    game_path: *const c_char,
+    x2f_key_y: *const u8,
+    x19_key_x: *const u8,
+    x1a_key_x: *const u8,
+    resource: *mut *mut Save3dsResource,
+) -> i32 {
+    ffi_call(|| {
+        let resource = out_ptr(resource)?;
+        *resource = to_raw(Save3dsResource(Resource::new(
+            opt_str(boot9_path)?,
+            opt_str(movable_path)?,
+            opt_str(sd_path)?,
+            opt_str(nand_path)?,
+            opt_str(otp_path)?,
+            opt_str(priv_path)?,
+            opt_str(game_path)?,
+            opt_key(x2f_key_y),
+            opt_key(x19_key_x),
+            opt_key(x1a_key_x),
+        )?));
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_resource_release(resource: *mut Save3dsResource) {
+    release(resource)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_open_bare_save(
+    resource: *mut Save3dsResource,
+    path: *const c_char,
+    write: bool,
+    save: *mut *mut Save3dsSaveData,
+) -> i32 {
+    ffi_call(|| {
+        let save = out_ptr(save)?;
+        let resource = &from_raw(resource)?.0;
+        *save = to_raw(Save3dsSaveData(
+            resource.open_bare_save(&str_arg(path)?, write)?,
+        ));
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_open_sd_save(
+    resource: *mut Save3dsResource,
+    id: u64,
+    write: bool,
+    save: *mut *mut Save3dsSaveData,
+) -> i32 {
+    ffi_call(|| {
+        let save = out_ptr(save)?;
+        let resource = &from_raw(resource)?.0;
+        *save = to_raw(Save3dsSaveData(resource.open_sd_save(id, write)?));
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_open_nand_save(
+    resource: *mut Save3dsResource,
+    id: u32,
+    write: bool,
+    save: *mut *mut Save3dsSaveData,
+) -> i32 {
+    ffi_call(|| {
+        let save = out_ptr(save)?;
+        let resource = &from_raw(resource)?.0;
+        *save = to_raw(Save3dsSaveData(resource.open_nand_save(id, write)?));
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_save_release(save: *mut Save3dsSaveData) {
+    release(save)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_save_commit(save: *mut Save3dsSaveData) -> i32 {
+    ffi_call(|| Ok(from_raw(save)?.0.commit()?))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_save_list(
+    save: *mut Save3dsSaveData,
+    dir_ino: u32,
+    entries: *mut *mut Save3dsEntry,
+    count: *mut usize,
+) -> i32 {
+    ffi_call(|| {
+        let (entries, count) = (out_ptr(entries)?, out_ptr(count)?);
+        list_entries(&from_raw(save)?.0, dir_ino, entries, count)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_entries_release(entries: *mut Save3dsEntry, count: usize) {
+    entries_release(entries, count)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_save_lookup(
+    save: *mut Save3dsSaveData,
+    parent_ino: u32,
+    name: *const u8,
+    is_dir: bool,
+    ino: *mut u32,
+) -> i32 {
+    ffi_call(|| {
+        let ino = out_ptr(ino)?;
+        *ino = lookup(&from_raw(save)?.0, parent_ino, &name_arg(name)?, is_dir)?;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_save_create_dir(
+    save: *mut Save3dsSaveData,
+    parent_ino: u32,
+    name: *const u8,
+    ino: *mut u32,
+) -> i32 {
+    ffi_call(|| {
+        let ino = out_ptr(ino)?;
+        *ino = from_raw(save)?.0.create_dir(parent_ino, &name_arg(name)?)?;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_save_create_file(
+    save: *mut Save3dsSaveData,
+    parent_ino: u32,
+    name: *const u8,
+    len: u64,
+    ino: *mut u32,
+) -> i32 {
+    ffi_call(|| {
+        let ino = out_ptr(ino)?;
+        *ino = from_raw(save)?
+            .0
+            .create_file(parent_ino, &name_arg(name)?, usize_arg(len)?)?;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_save_remove_dir(save: *mut Save3dsSaveData, ino: u32) -> i32 {
+    ffi_call(|| Ok(from_raw(save)?.0.remove_dir(ino)?))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_save_remove_file(save: *mut Save3dsSaveData, ino: u32) -> i32 {
+    ffi_call(|| Ok(from_raw(save)?.0.remove_file(ino)?))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_save_file_len(
+    save: *mut Save3dsSaveData,
+    ino: u32,
+    len: *mut u64,
+) -> i32 {
+    ffi_call(|| {
+        let len = out_ptr(len)?;
+        *len = from_raw(save)?.0.file_len(ino)? as u64;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_save_read_file(
+    save: *mut Save3dsSaveData,
+    ino: u32,
+    pos: u64,
+    buf: *mut u8,
+    len: usize,
+) -> i32 {
+    ffi_call(|| {
+        let buf = buf_arg_mut(buf, len)?;
+        Ok(from_raw(save)?.0.read_file(ino, usize_arg(pos)?, buf)?)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_save_write_file(
+    save: *mut Save3dsSaveData,
+    ino: u32,
+    pos: u64,
+    buf: *const u8,
+    len: usize,
+) -> i32 {
+    ffi_call(|| {
+        let buf = buf_arg(buf, len)?;
+        Ok(from_raw(save)?.0.write_file(ino, usize_arg(pos)?, buf)?)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_save_resize_file(
+    save: *mut Save3dsSaveData,
+    ino: u32,
+    len: u64,
+) -> i32 {
+    ffi_call(|| Ok(from_raw(save)?.0.resize_file(ino, usize_arg(len)?)?))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_open_sd_ext(
+    resource: *mut Save3dsResource,
+    id: u64,
+    write: bool,
+    ext: *mut *mut Save3dsExtData,
+) -> i32 {
+    ffi_call(|| {
+        let ext = out_ptr(ext)?;
+        let resource = &from_raw(resource)?.0;
+        *ext = to_raw(Save3dsExtData(resource.open_sd_ext(id, write)?));
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_open_nand_ext(
+    resource: *mut Save3dsResource,
+    id: u64,
+    write: bool,
+    ext: *mut *mut Save3dsExtData,
+) -> i32 {
+    ffi_call(|| {
+        let ext = out_ptr(ext)?;
+        let resource = &from_raw(resource)?.0;
+        *ext = to_raw(Save3dsExtData(resource.open_nand_ext(id, write)?));
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_ext_release(ext: *mut Save3dsExtData) {
+    release(ext)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_ext_commit(ext: *mut Save3dsExtData) -> i32 {
+    ffi_call(|| Ok(from_raw(ext)?.0.commit()?))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_ext_list(
+    ext: *mut Save3dsExtData,
+    dir_ino: u32,
+    entries: *mut *mut Save3dsEntry,
+    count: *mut usize,
+) -> i32 {
+    ffi_call(|| {
+        let (entries, count) = (out_ptr(entries)?, out_ptr(count)?);
+        list_entries(&from_raw(ext)?.0, dir_ino, entries, count)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_ext_lookup(
+    ext: *mut Save3dsExtData,
+    parent_ino: u32,
+    name: *const u8,
+    is_dir: bool,
+    ino: *mut u32,
+) -> i32 {
+    ffi_call(|| {
+        let ino = out_ptr(ino)?;
+        *ino = lookup(&from_raw(ext)?.0, parent_ino, &name_arg(name)?, is_dir)?;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_ext_create_dir(
+    ext: *mut Save3dsExtData,
+    parent_ino: u32,
+    name: *const u8,
+    ino: *mut u32,
+) -> i32 {
+    ffi_call(|| {
+        let ino = out_ptr(ino)?;
+        *ino = from_raw(ext)?.0.create_dir(parent_ino, &name_arg(name)?)?;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_ext_create_file(
+    ext: *mut Save3dsExtData,
+    parent_ino: u32,
+    name: *const u8,
+    len: u64,
+    ino: *mut u32,
+) -> i32 {
+    ffi_call(|| {
+        let ino = out_ptr(ino)?;
+        *ino = from_raw(ext)?
+            .0
+            .create_file(parent_ino, &name_arg(name)?, usize_arg(len)?)?;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_ext_remove_dir(ext: *mut Save3dsExtData, ino: u32) -> i32 {
+    ffi_call(|| Ok(from_raw(ext)?.0.remove_dir(ino)?))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_ext_remove_file(ext: *mut Save3dsExtData, ino: u32) -> i32 {
+    ffi_call(|| Ok(from_raw(ext)?.0.remove_file(ino)?))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_ext_file_len(
+    ext: *mut Save3dsExtData,
+    ino: u32,
+    len: *mut u64,
+) -> i32 {
+    ffi_call(|| {
+        let len = out_ptr(len)?;
+        *len = from_raw(ext)?.0.file_len(ino)? as u64;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_ext_read_file(
+    ext: *mut Save3dsExtData,
+    ino: u32,
+    pos: u64,
+    buf: *mut u8,
+    len: usize,
+) -> i32 {
+    ffi_call(|| {
+        let buf = buf_arg_mut(buf, len)?;
+        Ok(from_raw(ext)?.0.read_file(ino, usize_arg(pos)?, buf)?)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_ext_write_file(
+    ext: *mut Save3dsExtData,
+    ino: u32,
+    pos: u64,
+    buf: *const u8,
+    len: usize,
+) -> i32 {
+    ffi_call(|| {
+        let buf = buf_arg(buf, len)?;
+        Ok(from_raw(ext)?.0.write_file(ino, usize_arg(pos)?, buf)?)
+    })
+}
+
+/// Resizing rewrites the whole file. An extdata that is read-only, either by `write` or by a
+/// missing `Quota.dat`, can't resize files, and returns `SAVE3DS_ERROR_UNSUPPORTED` rather
+/// than `SAVE3DS_ERROR_READ_ONLY` like other modifications of a read-only archive.
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_ext_resize_file(
+    ext: *mut Save3dsExtData,
+    ino: u32,
+    len: u64,
+) -> i32 {
+    ffi_call(|| Ok(from_raw(ext)?.0.resize_file(ino, usize_arg(len)?)?))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_open_db(
+    resource: *mut Save3dsResource,
+    db_type: u32,
+    write: bool,
+    db: *mut *mut Save3dsDb,
+) -> i32 {
+    ffi_call(|| {
+        let db = out_ptr(db)?;
+        let resource = &from_raw(resource)?.0;
+        *db = to_raw(Save3dsDb(resource.open_db(db_type_arg(db_type)?, write)?));
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_db_release(db: *mut Save3dsDb) {
+    release(db)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_db_commit(db: *mut Save3dsDb) -> i32 {
+    ffi_call(|| Ok(from_raw(db)?.0.commit()?))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_db_list(
+    db: *mut Save3dsDb,
+    entries: *mut *mut Save3dsDbEntry,
+    count: *mut usize,
+) -> i32 {
+    ffi_call(|| {
+        let (entries, count) = (out_ptr(entries)?, out_ptr(count)?);
+        list_db_entries(&from_raw(db)?.0, entries, count)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_db_entries_release(entries: *mut Save3dsDbEntry, count: usize) {
+    entries_release(entries, count)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_db_lookup(db: *mut Save3dsDb, id: u64, ino: *mut u32) -> i32 {
+    ffi_call(|| {
+        let ino = out_ptr(ino)?;
+        *ino = lookup(
+            &from_raw(db)?.0,
+            SAVE3DS_ROOT_INO,
+            &ArchiveName::Id(id),
+            false,
+        )?;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_db_create_file(
+    db: *mut Save3dsDb,
+    id: u64,
+    len: u64,
+    ino: *mut u32,
+) -> i32 {
+    ffi_call(|| {
+        let ino = out_ptr(ino)?;
+        *ino =
+            from_raw(db)?
+                .0
+                .create_file(SAVE3DS_ROOT_INO, &ArchiveName::Id(id), usize_arg(len)?)?;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_db_remove_file(db: *mut Save3dsDb, ino: u32) -> i32 {
+    ffi_call(|| Ok(from_raw(db)?.0.remove_file(ino)?))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_db_file_len(db: *mut Save3dsDb, ino: u32, len: *mut u64) -> i32 {
+    ffi_call(|| {
+        let len = out_ptr(len)?;
+        *len = from_raw(db)?.0.file_len(ino)? as u64;
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_db_read_file(
+    db: *mut Save3dsDb,
+    ino: u32,
+    pos: u64,
+    buf: *mut u8,
+    len: usize,
+) -> i32 {
+    ffi_call(|| {
+        let buf = buf_arg_mut(buf, len)?;
+        Ok(from_raw(db)?.0.read_file(ino, usize_arg(pos)?, buf)?)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_db_write_file(
+    db: *mut Save3dsDb,
+    ino: u32,
+    pos: u64,
+    buf: *const u8,
+    len: usize,
+) -> i32 {
+    ffi_call(|| {
+        let buf = buf_arg(buf, len)?;
+        Ok(from_raw(db)?.0.write_file(ino, usize_arg(pos)?, buf)?)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn save3ds_db_resize_file(db: *mut Save3dsDb, ino: u32, len: u64) -> i32 {
+    ffi_call(|| Ok(from_raw(db)?.0.resize_file(ino, usize_arg(len)?)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use libsave3ds::ext_data::ExtDataFormatParam;
+    use libsave3ds::test_util::*;
+    use std::ptr::{null, null_mut};
+
+    fn name(s: &str) -> [u8; 16] {
+        <[u8; 16]>::name_str_to_3ds(s).unwrap()
+    }
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(save3ds_last_error()) }
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn bare_save() {
+        let root = TempPath::new("save3ds_c_bare");
+        std::fs::create_dir_all(&root).unwrap();
+        let path = root.join("save.bin");
+        let resource = resource();
+        let param = format_param();
+        resource
+            .format_bare_save(path.to_str().unwrap(), &param, 0x20000)
+            .unwrap();
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+
+        unsafe {
+            let mut resource = null_mut();
+            assert_eq!(
+                save3ds_resource_create(
+                    null(),
+                    null(),
+                    null(),
+                    null(),
+                    null(),
+                    null(),
+                    null(),
+                    null(),
+                    null(),
+                    null(),
+                    &mut resource
+                ),
+                SAVE3DS_OK
+            );
+
+            let mut save = null_mut();
+            assert_eq!(
+                save3ds_open_bare_save(resource, path.as_ptr(), true, &mut save),
+                SAVE3DS_OK
+            );
+
+            let (mut dir, mut file) = (0, 0);
+            assert_eq!(
+                save3ds_save_create_dir(save, SAVE3DS_ROOT_INO, name("d").as_ptr(), &mut dir),
+                SAVE3DS_OK
+            );
+            assert_eq!(
+                save3ds_save_create_file(save, SAVE3DS_ROOT_INO, name("f").as_ptr(), 3, &mut file),
+                SAVE3DS_OK
+            );
+            assert_eq!(
+                save3ds_save_write_file(save, file, 0, [1, 2, 3].as_ptr(), 3),
+                SAVE3DS_OK
+            );
+            assert_eq!(save3ds_save_commit(save), SAVE3DS_OK);
+            save3ds_save_release(save);
+
+            let mut save = null_mut();
+            assert_eq!(
+                save3ds_open_bare_save(resource, path.as_ptr(), false, &mut save),
+                SAVE3DS_OK
+            );
+            let (mut entries, mut count) = (null_mut(), 0);
+            assert_eq!(
+                save3ds_save_list(save, SAVE3DS_ROOT_INO, &mut entries, &mut count),
+                SAVE3DS_OK
+            );
+            let list: Vec<_> = std::slice::from_raw_parts(entries, count)
+                .iter()
+                .map(|entry| (entry.name, entry.ino, entry.is_dir))
+                .collect();
+            assert_eq!(list, vec![(name("d"), dir, true), (name("f"), file, false)]);
+            save3ds_entries_release(entries, count);
+
+            let mut ino = 0;
+            assert_eq!(
+                save3ds_save_lookup(save, SAVE3DS_ROOT_INO, name("f").as_ptr(), false, &mut ino),
+                SAVE3DS_OK
+            );
+            assert_eq!(ino, file);
+            let mut len = 0;
+            assert_eq!(save3ds_save_file_len(save, file, &mut len), SAVE3DS_OK);
+            assert_eq!(len, 3);
+            let mut buf = [0; 3];
+            assert_eq!(
+                save3ds_save_read_file(save, file, 0, buf.as_mut_ptr(), 3),
+                SAVE3DS_OK
+            );
+            assert_eq!(buf, [1, 2, 3]);
+
+            // Failures are told apart by their codes
+            assert_eq!(
+                save3ds_save_lookup(save, SAVE3DS_ROOT_INO, name("g").as_ptr(), false, &mut ino),
+                SAVE3DS_ERROR_NOT_FOUND
+            );
+            assert_eq!(
+                save3ds_save_write_file(save, file, 0, [1].as_ptr(), 1),
+                SAVE3DS_ERROR_READ_ONLY
+            );
+            assert_eq!(
+                save3ds_save_list(save, SAVE3DS_ROOT_INO, null_mut(), &mut count),
+                SAVE3DS_ERROR_INVALID_ARGUMENT
+            );
+            assert!(last_error().contains("out-pointer"));
+            save3ds_save_release(save);
+
+            let missing = CString::new(root.join("missing").to_str().unwrap()).unwrap();
+            let mut save = null_mut();
+            assert_eq!(
+                save3ds_open_bare_save(resource, missing.as_ptr(), false, &mut save),
+                SAVE3DS_ERROR_IO
+            );
+            assert!(save.is_null());
+            assert_eq!(
+                save3ds_open_nand_save(resource, 0, false, &mut save),
+                SAVE3DS_ERROR_MISSING_NAND
+            );
+            assert_eq!(last_error(), Error::MissingNand.to_string());
+
+            save3ds_resource_release(resource);
+        }
+    }
+
+    #[test]
+    fn ext_and_db() {
+        let root = TempPath::new("save3ds_c_ext");
+        let boot9 = root.join("boot9.bin");
+        let nand = root.join("nand");
+        std::fs::create_dir_all(nand.join("private")).unwrap();
+        std::fs::write(&boot9, vec![0; 0x10000]).unwrap();
+        std::fs::write(nand.join("private").join("movable.sed"), vec![0; 0x140]).unwrap();
+        let resource = Resource::new(
+            Some(boot9.to_str().unwrap().to_owned()),
+            None,
+            None,
+            Some(nand.to_str().unwrap().to_owned()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            mount_path: [[0; 0x10]; 0x10],
+        };
+        let id = 0x0004_8000_F000_000B;
+        resource.format_nand_ext(id, &param).unwrap();
+        let resource = to_raw(Save3dsResource(resource));
+
+        unsafe {
+            let mut ext = null_mut();
+            assert_eq!(
+                save3ds_open_nand_ext(resource, id, true, &mut ext),
+                SAVE3DS_OK
+            );
+            let (mut dir, mut file) = (0, 0);
+            assert_eq!(
+                save3ds_ext_create_dir(ext, SAVE3DS_ROOT_INO, name("d").as_ptr(), &mut dir),
+                SAVE3DS_OK
+            );
+            assert_eq!(
+                save3ds_ext_create_file(ext, dir, name("f").as_ptr(), 2, &mut file),
+                SAVE3DS_OK
+            );
+            assert_eq!(save3ds_ext_resize_file(ext, file, 3), SAVE3DS_OK);
+            assert_eq!(
+                save3ds_ext_write_file(ext, file, 0, [1, 2, 3].as_ptr(), 3),
+                SAVE3DS_OK
+            );
+            assert_eq!(save3ds_ext_commit(ext), SAVE3DS_OK);
+            save3ds_ext_release(ext);
+
+            let mut ext = null_mut();
+            assert_eq!(
+                save3ds_open_nand_ext(resource, id, false, &mut ext),
+                SAVE3DS_OK
+            );
+            let (mut entries, mut count) = (null_mut(), 0);
+            assert_eq!(
+                save3ds_ext_list(ext, dir, &mut entries, &mut count),
+                SAVE3DS_OK
+            );
+            let list: Vec<_> = std::slice::from_raw_parts(entries, count)
+                .iter()
+                .map(|entry| (entry.name, entry.ino, entry.is_dir))
+                .collect();
+            assert_eq!(list, vec![(name("f"), file, false)]);
+            save3ds_entries_release(entries, count);
+            let mut ino = 0;
+            assert_eq!(
+                save3ds_ext_lookup(ext, SAVE3DS_ROOT_INO, name("d").as_ptr(), true, &mut ino),
+                SAVE3DS_OK
+            );
+            assert_eq!(ino, dir);
+            let mut len = 0;
+            assert_eq!(save3ds_ext_file_len(ext, file, &mut len), SAVE3DS_OK);
+            assert_eq!(len, 3);
+            let mut buf = [0; 3];
+            assert_eq!(
+                save3ds_ext_read_file(ext, file, 0, buf.as_mut_ptr(), 3),
+                SAVE3DS_OK
+            );
+            assert_eq!(buf, [1, 2, 3]);
+            assert_eq!(
+                save3ds_ext_write_file(ext, file, 0, [1].as_ptr(), 1),
+                SAVE3DS_ERROR_READ_ONLY
+            );
+            // Files of a read-only extdata can't be resized, which is told apart from the
+            // other read-only failures
+            assert_eq!(
+                save3ds_ext_resize_file(ext, file, 4),
+                SAVE3DS_ERROR_UNSUPPORTED
+            );
+            save3ds_ext_release(ext);
+
+            let mut db = null_mut();
+            assert_eq!(
+                save3ds_open_db(resource, SAVE3DS_DB_NAND_TITLE, false, &mut db),
+                SAVE3DS_ERROR_MISSING_OTP
+            );
+            assert_eq!(
+                save3ds_open_db(resource, 7, false, &mut db),
+                SAVE3DS_ERROR_INVALID_ARGUMENT
+            );
+            assert!(db.is_null());
+            assert_eq!(save3ds_db_commit(db), SAVE3DS_ERROR_INVALID_ARGUMENT);
+
+            save3ds_resource_release(resource);
+        }
+    }
+}
@@ -1,109 +1,80 @@
 use getopts::Options;
+use libsave3ds::cart_save_data::CartSaveData;
 use libsave3ds::db::*;
 use libsave3ds::error::*;
 use libsave3ds::ext_data::*;
 use libsave3ds::file_system::*;
+use libsave3ds::header_dump::*;
+use libsave3ds::overlay::Overlay;
 use libsave3ds::save_data::*;
-use libsave3ds::Resource;
+use libsave3ds::{BareSignTarget, Capabilities, CartInfo, Resource, SdMap};
 use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::io::Read;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+mod raw_names;
+mod tar;
 
 #[cfg(all(unix, feature = "unixfuse"))]
 use {
     fuser::*,
     libc::{
-        getegid, geteuid, EBADF, EEXIST, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOSPC, ENOSYS,
-        ENOTDIR, ENOTEMPTY, EROFS,
+        getegid, geteuid, EBADF, EBUSY, EEXIST, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOSPC,
+        ENOSYS, ENOTDIR, ENOTEMPTY, EROFS, EXDEV,
     },
-    std::time::{Duration, SystemTime},
+    std::collections::BTreeSet,
+    std::sync::{Arc, Mutex},
+    std::time::SystemTime,
 };
 
 enum FileSystemOperation {
-    Mount(bool),
-    Extract,
-    Import,
+    Mount {
+        read_only: bool,
+        readahead: bool,
+        manual_commit: Option<Rc<Overlay>>,
+        snapshot_path: Option<String>,
+        autocommit: Option<Duration>,
+        subdir: Option<String>,
+    },
+    Extract {
+        since: Option<String>,
+        name_encoding: NameEncoding,
+    },
+    ExtractRaw,
+    Import(NameEncoding, Option<ImportOrder>),
+    ImportRaw,
+    ImportTar(String, NameEncoding),
+    Cat {
+        path: String,
+        offset: usize,
+        length: Option<usize>,
+    },
+    Count {
+        json: bool,
+    },
+    DumpHeaders {
+        json: bool,
+    },
+    List {
+        json: bool,
+    },
     Touch,
 }
 
-fn is_legal_char(c: u8) -> bool {
-    (32..127).contains(&c) && c != 47 && c != 92
-}
-
-trait NameConvert {
-    fn name_3ds_to_str(name: &Self) -> String;
-    fn name_str_to_3ds(name: &str) -> Option<Self>
-    where
-        Self: Sized;
-}
-
-impl NameConvert for u64 {
-    fn name_3ds_to_str(name: &u64) -> String {
-        format!("{:016x}", name)
-    }
-
-    fn name_str_to_3ds(name: &str) -> Option<u64> {
-        u64::from_str_radix(name, 16).ok()
-    }
-}
-
-impl NameConvert for [u8; 16] {
-    fn name_3ds_to_str(name: &[u8; 16]) -> String {
-        let mut last_char = 15;
-        loop {
-            if name[last_char] != 0 || last_char == 0 {
-                break;
-            }
-            last_char -= 1;
-        }
-
-        name[0..=last_char]
-            .iter()
-            .map(|x| {
-                if is_legal_char(*x) {
-                    String::from_utf8(vec![*x]).unwrap()
-                } else {
-                    format!("\\x{:02x}", *x)
-                }
-            })
-            .fold("".to_owned(), |mut x, y| {
-                x.push_str(&y);
-                x
-            })
-    }
-
-    fn name_str_to_3ds(name: &str) -> Option<[u8; 16]> {
-        let mut name_converted = [0; 16];
-        let bytes = name.as_bytes();
-        let mut out_i = 0;
-        let mut in_i = 0;
-        loop {
-            if in_i == bytes.len() {
-                break;
-            }
-            if out_i == name_converted.len() {
-                return None;
-            }
-
-            if bytes[in_i] != b'\\' {
-                name_converted[out_i] = bytes[in_i];
-                out_i += 1;
-                in_i += 1;
-            } else {
-                in_i += 1;
-                if *bytes.get(in_i)? != b'x' {
-                    return None;
-                }
-                in_i += 1;
-                name_converted[out_i] =
-                    u8::from_str_radix(std::str::from_utf8(bytes.get(in_i..in_i + 2)?).ok()?, 16)
-                        .ok()?;
-                out_i += 1;
-                in_i += 2;
-            }
-        }
-        Some(name_converted)
-    }
+/// The order in which `--import` lists the entries of each directory in the archive.
+///
+/// Directories and files are each kept in a linked list, and a new entry is put at its head,
+/// so without an order, entries are listed in the reverse of the host order. The console
+/// enumerates a directory by walking the same lists, so the order is visible to games that
+/// list a directory, but not to games that open files by name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ImportOrder {
+    /// The order the host lists the directory in.
+    Host,
+    /// Sorted by the host names.
+    Sorted,
 }
 
 fn extract_impl<T: FileSystem>(
@@ -111,6 +82,7 @@ fn extract_impl<T: FileSystem>(
     dir: T::DirType,
     path: &std::path::Path,
     indent: u32,
+    encoding: NameEncoding,
 ) -> Result<(), Error>
 where
     T::NameType: NameConvert + Clone,
@@ -120,44 +92,262 @@ where
     }
 
     for (name, ino) in dir.list_sub_dir()? {
-        let name = T::NameType::name_3ds_to_str(&name);
+        let name = T::NameType::name_3ds_to_str_as(&name, encoding);
         for _ in 0..indent {
             print!(" ");
         }
         println!("+{}", &name);
         let dir = save.open_dir(ino)?;
-        extract_impl(save, dir, &path.join(name), indent + 1)?;
+        extract_impl(save, dir, &path.join(name), indent + 1, encoding)?;
     }
 
     for (name, ino) in dir.list_sub_file()? {
-        let name = T::NameType::name_3ds_to_str(&name);
+        let name = T::NameType::name_3ds_to_str_as(&name, encoding);
         for _ in 0..indent {
             print!(" ");
         }
         println!("-{}", &name);
         let file = save.open_file(ino)?;
-        let mut buffer = vec![0; file.len()];
-        match file.read(0, &mut buffer) {
-            Ok(()) | Err(Error::HashMismatch) => (),
-            e => return e,
-        }
-        std::fs::write(&path.join(name), &buffer)?;
+        std::fs::write(path.join(name), file.read_all()?)?;
     }
 
     Ok(())
 }
 
-fn extract<T: FileSystem>(save: T, mountpoint: &std::path::Path) -> Result<(), Error>
+fn extract<T: FileSystem>(
+    save: T,
+    mountpoint: &std::path::Path,
+    encoding: NameEncoding,
+) -> Result<(), Error>
 where
     T::NameType: NameConvert + Clone,
 {
     println!("Extracting...");
     let root = save.open_root()?;
-    extract_impl(&save, root, mountpoint, 0)?;
+    extract_impl(&save, root, mountpoint, 0, encoding)?;
+    println!("Finished");
+    Ok(())
+}
+
+/// Extracts `dir` for `--raw-names`. Entries are written under their index in the directory,
+/// and `raw_names::SIDECAR` records their archive names.
+fn extract_raw_impl<T: FileSystem>(
+    save: &T,
+    dir: T::DirType,
+    path: &std::path::Path,
+) -> Result<(), Error>
+where
+    T::NameType: NameConvert,
+{
+    if !path.exists() {
+        std::fs::create_dir(path)?;
+    }
+
+    let mut entries = vec![];
+    for (name, ino) in dir.list_sub_dir()? {
+        let index = raw_names::host_name(entries.len());
+        println!("{:?}", path.join(&index));
+        extract_raw_impl(save, save.open_dir(ino)?, &path.join(&index))?;
+        entries.push(raw_names::RawEntry {
+            index,
+            name: T::NameType::name_3ds_to_str_as(&name, NameEncoding::Hex),
+            ino,
+            is_dir: true,
+        });
+    }
+    for (name, ino) in dir.list_sub_file()? {
+        let index = raw_names::host_name(entries.len());
+        println!("{:?}", path.join(&index));
+        std::fs::write(path.join(&index), save.open_file(ino)?.read_all()?)?;
+        entries.push(raw_names::RawEntry {
+            index,
+            name: T::NameType::name_3ds_to_str_as(&name, NameEncoding::Hex),
+            ino,
+            is_dir: false,
+        });
+    }
+    std::fs::write(path.join(raw_names::SIDECAR), raw_names::to_json(&entries))?;
+    Ok(())
+}
+
+fn extract_raw<T: FileSystem>(save: T, mountpoint: &std::path::Path) -> Result<(), Error>
+where
+    T::NameType: NameConvert,
+{
+    println!("Extracting with raw names...");
+    extract_raw_impl(&save, save.open_root()?, mountpoint)?;
     println!("Finished");
     Ok(())
 }
 
+/// Extracts every save data and extdata on NAND to `sysdata/<ID>` and `extdata/<ID>` under
+/// `out` for `--dump-all-nand`, or only lists them if `dry_run` is set. Archives that fail to
+/// open or extract are reported and skipped. Returns the number of such archives.
+fn dump_all_nand_impl(
+    resource: &Resource,
+    out: &std::path::Path,
+    encoding: NameEncoding,
+    dry_run: bool,
+) -> Result<usize, Error> {
+    let saves = resource.list_nand_saves()?;
+    let exts = resource.list_nand_exts()?;
+    println!("Found {} save data and {} extdata", saves.len(), exts.len());
+    let mut failed = 0;
+    for id in saves {
+        let path = out.join("sysdata").join(format!("{:08x}", id));
+        println!("Save data {:08x} -> {}", id, path.display());
+        if dry_run {
+            continue;
+        }
+        if let Err(e) = std::fs::create_dir_all(&path)
+            .map_err(Error::from)
+            .and_then(|()| resource.open_nand_save(id, false))
+            .and_then(|save| extract(save, &path, encoding))
+        {
+            println!("Failed to extract save data {:08x}: {}", id, e);
+            failed += 1;
+        }
+    }
+    for id in exts {
+        let path = out.join("extdata").join(format!("{:016x}", id));
+        println!("Extdata {:016x} -> {}", id, path.display());
+        if dry_run {
+            continue;
+        }
+        if let Err(e) = std::fs::create_dir_all(&path)
+            .map_err(Error::from)
+            .and_then(|()| resource.open_nand_ext(id, false))
+            .and_then(|ext| extract(ext, &path, encoding))
+        {
+            println!("Failed to extract extdata {:016x}: {}", id, e);
+            failed += 1;
+        }
+    }
+    Ok(failed)
+}
+
+const MANIFEST_ENCODING_PREFIX: &str = "# name-encoding=";
+
+/// Parses an extract manifest, in which each line is a SHA-256 in hex, a space, and the path of
+/// a file relative to the archive root. An optional first line `# name-encoding=SCHEME` records
+/// the encoding of the names in the paths. Manifests without it use `NameEncoding::Escape`.
+fn parse_manifest(content: &str) -> Result<(NameEncoding, HashMap<String, [u8; 32]>), Error> {
+    let mut encoding = NameEncoding::Escape;
+    let mut manifest = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(scheme) = line.strip_prefix(MANIFEST_ENCODING_PREFIX) {
+            if i != 0 {
+                eprintln!("Name encoding must be on the first line of the manifest");
+                return Err(Error::InvalidValue);
+            }
+            encoding = scheme.parse().inspect_err(|_| {
+                eprintln!("Unknown name encoding in the manifest: {}", scheme);
+            })?;
+            continue;
+        }
+        let malformed = || {
+            eprintln!("Malformed manifest line: {}", line);
+            Error::InvalidValue
+        };
+        let (hash_hex, path) = line.split_once(' ').ok_or_else(malformed)?;
+        if hash_hex.len() != 64 || !hash_hex.is_ascii() {
+            return Err(malformed());
+        }
+        let mut hash = [0; 32];
+        for (i, b) in hash.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&hash_hex[i * 2..i * 2 + 2], 16).map_err(|_| malformed())?;
+        }
+        manifest.insert(path.to_owned(), hash);
+    }
+    Ok((encoding, manifest))
+}
+
+/// Reads the name encoding recorded in the manifest at `manifest_path`.
+fn manifest_encoding(manifest_path: &std::path::Path) -> Result<NameEncoding, Error> {
+    Ok(parse_manifest(&std::fs::read_to_string(manifest_path)?)?.0)
+}
+
+/// Incrementally extracts the archive to `path`, using the manifest at `manifest_path` written
+/// by the previous run. Only files whose content hash differs from the manifest, or that are
+/// missing on the host, are written. Files recorded in the manifest that no longer exist in the
+/// archive are removed from the host. The manifest is then rewritten to describe the new state.
+/// Without an existing manifest, all files are extracted.
+///
+/// Names are encoded with `encoding`, which is recorded in the manifest. It must match the
+/// encoding of the existing manifest, so that the host paths in it are still valid.
+///
+/// Returns the paths of the written files, relative to the archive root.
+fn extract_since<T: FileSystem>(
+    save: &T,
+    path: &std::path::Path,
+    manifest_path: &std::path::Path,
+    encoding: NameEncoding,
+) -> Result<Vec<String>, Error>
+where
+    T::NameType: NameConvert,
+{
+    let old_manifest = match std::fs::read_to_string(manifest_path) {
+        Ok(content) => {
+            let (old_encoding, old_manifest) = parse_manifest(&content)?;
+            if old_encoding != encoding {
+                eprintln!(
+                    "The manifest was written with --name-encoding {}, but {} is used now",
+                    old_encoding, encoding
+                );
+                return Err(Error::InvalidValue);
+            }
+            old_manifest
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    std::fs::create_dir_all(path)?;
+    let mut manifest = format!("{}{}\n", MANIFEST_ENCODING_PREFIX, encoding);
+    let mut present = std::collections::HashSet::new();
+    let mut written = vec![];
+    for entry in content_entries_as(save, encoding)? {
+        let archive_path = &entry.path[1..];
+        let host_path = path.join(archive_path);
+        let hash = match entry.file {
+            None => {
+                std::fs::create_dir_all(&host_path)?;
+                continue;
+            }
+            Some((_, hash)) => hash,
+        };
+        manifest += &format!("{} {}\n", hex(&hash), archive_path);
+        present.insert(archive_path.to_owned());
+        if old_manifest.get(archive_path) == Some(&hash) && host_path.is_file() {
+            continue;
+        }
+
+        let file = open_file_path_as(save, archive_path, encoding)?;
+        std::fs::write(&host_path, file.read_all()?)?;
+        println!("Updated {}", archive_path);
+        written.push(archive_path.to_owned());
+    }
+
+    let mut removed: Vec<_> = old_manifest
+        .keys()
+        .filter(|p| !present.contains(*p))
+        .collect();
+    removed.sort();
+    for archive_path in removed {
+        match std::fs::remove_file(path.join(archive_path)) {
+            Ok(()) => println!("Removed {}", archive_path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    std::fs::write(manifest_path, manifest)?;
+    Ok(written)
+}
+
 fn clear_impl<T: FileSystem>(save: &T, dir: &T::DirType) -> Result<(), Error>
 where
     T::NameType: NameConvert + Clone,
@@ -180,1421 +370,4964 @@ fn import_impl<T: FileSystem>(
     _save: &T,
     dir: &T::DirType,
     path: &std::path::Path,
+    encoding: NameEncoding,
+    order: Option<ImportOrder>,
 ) -> Result<(), Error>
 where
     T::NameType: NameConvert + Clone,
 {
-    for entry in std::fs::read_dir(path)? {
-        let entry = entry?;
+    let mut host_entries = std::fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+    match order {
+        None => (),
+        // Entries are listed in the reverse of the creation order
+        Some(ImportOrder::Host) => host_entries.reverse(),
+        Some(ImportOrder::Sorted) => {
+            host_entries.sort_by_key(|entry| std::cmp::Reverse(entry.file_name()))
+        }
+    }
+
+    // Files are created together after scanning the directory, which checks the capacity for
+    // all of them at once
+    let mut files = vec![];
+    for entry in host_entries {
         println!("{:?}", entry.path());
-        let name = if let Some(name) = entry
+        let name = match entry
             .path()
             .file_name()
             .and_then(OsStr::to_str)
-            .and_then(T::NameType::name_str_to_3ds)
+            .ok_or(Error::InvalidName)
+            .and_then(|name| T::NameType::validate_name(name, encoding))
         {
-            name
-        } else {
-            println!("Name not valid: {:?}", entry.path());
-            continue;
+            Ok(name) => name,
+            Err(e) => {
+                println!("Name not valid ({}): {:?}", e, entry.path());
+                continue;
+            }
         };
 
         let file_type = entry.file_type()?;
         if file_type.is_dir() {
             let dir = dir.new_sub_dir(name)?;
-            import_impl(_save, &dir, &entry.path())?
+            import_impl(_save, &dir, &entry.path(), encoding, order)?
         } else if file_type.is_file() {
-            let mut host_file = std::fs::File::open(&entry.path())?;
-            let len = host_file.metadata()?.len() as usize;
-            let file = dir.new_sub_file(name, len)?;
-            let mut buffer = vec![0; len];
-            host_file.read_exact(&mut buffer)?;
-            file.write(0, &buffer)?;
-            file.commit()?;
+            let len = entry.metadata()?.len() as usize;
+            files.push((name, len, entry.path()));
         } else {
             println!("Unrecognized file type: {:?}", entry.path());
         }
     }
 
-    Ok(())
-}
-
-fn import<T: FileSystem>(save: T, mountpoint: &std::path::Path) -> Result<(), Error>
-where
-    T::NameType: NameConvert + Clone,
-{
-    println!("Clearing the original contents...");
-    let root = save.open_root()?;
-    clear_impl(&save, &root)?;
-    println!("Importing new contents...");
-    import_impl(&save, &root, mountpoint)?;
-    save.commit()?;
-    println!("Finished");
-    Ok(())
-}
-
-#[allow(unreachable_code, unused_variables)]
-fn do_mount<T: FileSystem>(
-    save: T,
-    read_only: bool,
-    mountpoint: &std::path::Path,
-) -> Result<(), Error>
-where
-    T::NameType: NameConvert + Clone,
-{
-    #[cfg(all(unix, feature = "unixfuse"))]
-    {
-        mount2(FileSystemFrontend::new(save, read_only), &mountpoint, &[])?;
-        return Ok(());
-    }
-    println!("fuse not implemented. Please specify --extract or --import flag");
-    Ok(())
+    import_files::<T>(dir, files)
 }
 
-fn start<T: FileSystem>(
-    save: T,
-    operation: FileSystemOperation,
-    mountpoint: &std::path::Path,
+/// Creates `files` in `dir` with the content of the host files, given as tuples of names,
+/// lengths and host paths.
+fn import_files<T: FileSystem>(
+    dir: &T::DirType,
+    files: Vec<(T::NameType, usize, std::path::PathBuf)>,
 ) -> Result<(), Error>
 where
-    T::NameType: NameConvert + Clone,
+    T::NameType: Clone,
 {
-    match operation {
-        FileSystemOperation::Mount(read_only) => do_mount(save, read_only, mountpoint)?,
-        FileSystemOperation::Extract => extract(save, mountpoint)?,
-        FileSystemOperation::Import => import(save, mountpoint)?,
-        FileSystemOperation::Touch => println!("Touched"),
+    let entries: Vec<_> = files
+        .iter()
+        .map(|(name, len, _)| (name.clone(), *len))
+        .collect();
+    for (file, (_, len, host_path)) in dir.new_sub_files(&entries)?.into_iter().zip(files) {
+        let mut buffer = vec![0; len];
+        std::fs::File::open(host_path)?.read_exact(&mut buffer)?;
+        file.write(0, &buffer)?;
+        file.commit()?;
     }
 
     Ok(())
 }
 
-#[cfg(all(unix, feature = "unixfuse"))]
-struct DirEntry {
-    ino: u64,
-    file_type: FileType,
-    name: String,
-}
-
-#[cfg(all(unix, feature = "unixfuse"))]
-struct FileSystemFrontend<T: FileSystem> {
-    save: T,
-    read_only: bool,
-    file_fh_map: HashMap<u64, T::FileType>,
-    dir_fh_map: HashMap<u64, Vec<DirEntry>>,
-    next_fh: u64,
-    uid: u32,
-    gid: u32,
+/// Reads the `raw_names::SIDECAR` of the host directory at `path`, and decodes the names.
+fn read_raw_names<N: NameConvert>(
+    path: &std::path::Path,
+) -> Result<Vec<(raw_names::RawEntry, N)>, Error> {
+    let sidecar = path.join(raw_names::SIDECAR);
+    let entries = match raw_names::parse(&std::fs::read_to_string(&sidecar)?) {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("Invalid {:?}: {}", sidecar, e);
+            return Err(Error::InvalidValue);
+        }
+    };
+    entries
+        .into_iter()
+        .map(
+            |entry| match N::validate_name(&entry.name, NameEncoding::Hex) {
+                Ok(name) => Ok((entry, name)),
+                Err(e) => {
+                    println!("Name not valid ({}) in {:?}: {}", e, sidecar, entry.name);
+                    Err(e)
+                }
+            },
+        )
+        .collect()
 }
 
-#[cfg(all(unix, feature = "unixfuse"))]
-impl<T: FileSystem> FileSystemFrontend<T>
+/// Imports the host directory at `path`, extracted with `--raw-names`, into `dir`.
+fn import_raw_impl<T: FileSystem>(dir: &T::DirType, path: &std::path::Path) -> Result<(), Error>
 where
     T::NameType: NameConvert + Clone,
 {
-    fn new(save: T, read_only: bool) -> FileSystemFrontend<T> {
-        FileSystemFrontend::<T> {
-            save,
-            file_fh_map: HashMap::new(),
-            dir_fh_map: HashMap::new(),
-            next_fh: 1,
-            read_only,
-            uid: 0,
-            gid: 0,
+    // Entries are created in reverse, so that they are listed in the order of the sidecar,
+    // as they were when extracted
+    let mut files = vec![];
+    for (entry, name) in read_raw_names::<T::NameType>(path)?.into_iter().rev() {
+        let host_path = path.join(&entry.index);
+        println!("{:?}", host_path);
+        if entry.is_dir {
+            import_raw_impl::<T>(&dir.new_sub_dir(name)?, &host_path)?;
+        } else {
+            let len = std::fs::metadata(&host_path)?.len() as usize;
+            files.push((name, len, host_path));
         }
     }
+    import_files::<T>(dir, files)
 }
 
-#[cfg(all(unix, feature = "unixfuse"))]
-fn make_dir_attr(read_only: bool, uid: u32, gid: u32, ino: u64, sub_file_count: usize) -> FileAttr {
-    FileAttr {
-        ino,
-        size: 0,
-        blocks: 0,
-        atime: SystemTime::UNIX_EPOCH,
-        mtime: SystemTime::UNIX_EPOCH,
-        ctime: SystemTime::UNIX_EPOCH,
-        crtime: SystemTime::UNIX_EPOCH,
-        kind: FileType::Directory,
-        perm: if read_only { 0o555 } else { 0o755 },
-        nlink: 2 + sub_file_count as u32,
-        uid,
-        gid,
-        rdev: 0,
-        blksize: 0,
-        flags: 0,
+/// Describes imported files with identical content, which take separate blocks because
+/// archives can't share blocks between files. Returns `None` if there are no such files.
+fn duplicate_text(entries: &[ContentEntry], duplicate_data: bool) -> Option<String> {
+    let mut groups: HashMap<(usize, [u8; 32]), Vec<&str>> = HashMap::new();
+    for entry in entries {
+        if let Some((len, hash)) = entry.file {
+            if len != 0 {
+                groups.entry((len, hash)).or_default().push(&entry.path);
+            }
+        }
     }
-}
+    let mut groups: Vec<_> = groups
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|((len, _), mut paths)| {
+            paths.sort_unstable();
+            (len, paths)
+        })
+        .collect();
+    if groups.is_empty() {
+        return None;
+    }
+    groups.sort_by(|a, b| a.1.cmp(&b.1));
 
-#[cfg(all(unix, feature = "unixfuse"))]
-fn make_file_attr(read_only: bool, uid: u32, gid: u32, ino: u64, file_size: usize) -> FileAttr {
-    FileAttr {
-        ino,
-        size: file_size as u64,
-        blocks: 1,
-        atime: SystemTime::UNIX_EPOCH,
-        mtime: SystemTime::UNIX_EPOCH,
-        ctime: SystemTime::UNIX_EPOCH,
-        crtime: SystemTime::UNIX_EPOCH,
-        kind: FileType::RegularFile,
-        perm: if read_only { 0o444 } else { 0o644 },
-        nlink: 1,
-        uid,
-        gid,
-        rdev: 0,
-        blksize: 0,
-        flags: 0,
+    let bytes: usize = groups
+        .iter()
+        .map(|(len, paths)| len * (paths.len() - 1))
+        .sum();
+    let mut text = format!(
+        "Warning: files with identical content take {} extra bytes\n",
+        bytes
+    );
+    for (len, paths) in &groups {
+        text += &format!("  {} bytes: {}\n", len, paths.join(", "));
+    }
+    text += "The archive format can't share blocks between files, so every copy is stored\n";
+    if duplicate_data {
+        text += "This save data is formatted with duplicate_data: true, which stores file data\n";
+        text += "twice. Formatting it with duplicate_data: false halves the space of file data,\n";
+        text += "but file changes are then not journaled\n";
     }
+    Some(text)
 }
 
-#[allow(unused)]
-fn name_os_to_3ds<T: NameConvert>(name: &OsStr) -> Option<(T, &str)> {
-    let s = name.to_str()?;
-    let argument_pos = s.find("\\+");
-    let (l, r) = if let Some(pos) = argument_pos {
-        let (l, r) = s.split_at(pos);
-        (l, &r[2..])
-    } else {
-        (s, "")
-    };
-    Some((T::name_str_to_3ds(l)?, r))
+/// Prints the `duplicate_text` of the archive after importing.
+fn warn_duplicates<T: FileSystem>(save: &T, duplicate_data: bool) -> Result<(), Error>
+where
+    T::NameType: NameConvert,
+{
+    if let Some(text) = duplicate_text(&content_entries(save)?, duplicate_data) {
+        print!("{}", text);
+    }
+    Ok(())
 }
 
-#[cfg(all(unix, feature = "unixfuse"))]
-enum Ino {
-    Dir(u32),
-    File(u32),
+/// Directory slots, file slots and blocks taken by a tree of directories and files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Usage {
+    dirs: usize,
+    files: usize,
+    blocks: usize,
 }
 
-#[cfg(all(unix, feature = "unixfuse"))]
-impl Ino {
-    fn to_os(&self) -> u64 {
-        match *self {
-            Ino::Dir(ino) => u64::from(ino),
-            Ino::File(ino) => u64::from(ino) + 0x1_0000_0000,
-        }
-    }
+fn blocks_for(len: usize, block_len: usize) -> usize {
+    len.div_ceil(block_len)
+}
 
-    fn from_os(ino: u64) -> Ino {
-        if ino >= 0x1_0000_0000 {
-            Ino::File((ino - 0x1_0000_0000) as u32)
-        } else {
-            Ino::Dir(ino as u32)
+/// Computes what importing the host directory at `path` takes, skipping the same entries
+/// `import_impl` skips. `file_blocks` gives the blocks a file of the given length takes.
+fn host_usage<N: NameConvert>(
+    path: &std::path::Path,
+    encoding: NameEncoding,
+    file_blocks: impl Fn(usize) -> usize,
+) -> Result<Usage, Error> {
+    let mut usage = Usage::default();
+    let mut pending = vec![path.to_owned()];
+    while let Some(path) = pending.pop() {
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            let valid = entry
+                .path()
+                .file_name()
+                .and_then(OsStr::to_str)
+                .is_some_and(|name| N::validate_name(name, encoding).is_ok());
+            if !valid {
+                continue;
+            }
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                usage.dirs += 1;
+                pending.push(entry.path());
+            } else if file_type.is_file() {
+                usage.files += 1;
+                usage.blocks += file_blocks(entry.metadata()?.len() as usize);
+            }
         }
     }
+    Ok(usage)
 }
 
-#[cfg(all(unix, feature = "unixfuse"))]
-impl<T: FileSystem> Drop for FileSystemFrontend<T> {
-    fn drop(&mut self) {
-        if !self.read_only {
-            self.save.commit().unwrap();
-            println!("Saved");
+/// Same as `host_usage`, for a host directory extracted with `--raw-names`. This also checks
+/// that all sidecars and the files they list are valid.
+fn raw_usage<N: NameConvert>(
+    path: &std::path::Path,
+    file_blocks: impl Fn(usize) -> usize,
+) -> Result<Usage, Error> {
+    let mut usage = Usage::default();
+    let mut pending = vec![path.to_owned()];
+    while let Some(path) = pending.pop() {
+        for (entry, _) in read_raw_names::<N>(&path)? {
+            let host_path = path.join(&entry.index);
+            if entry.is_dir {
+                usage.dirs += 1;
+                pending.push(host_path);
+            } else {
+                usage.files += 1;
+                usage.blocks += file_blocks(std::fs::metadata(host_path)?.len() as usize);
+            }
         }
     }
+    Ok(usage)
 }
 
-#[cfg(all(unix, feature = "unixfuse"))]
-impl<T: FileSystem> Filesystem for FileSystemFrontend<T>
+/// Computes the free space of the archive after clearing it.
+fn usage_after_clear<T: FileSystem + SaveLayout>(save: &T) -> Result<Usage, Error>
 where
-    T::NameType: NameConvert + Clone,
+    T::NameType: Clone,
 {
-    fn init(&mut self, _req: &Request, _kc: &mut KernelConfig) -> Result<(), i32> {
-        let (uid, gid) = unsafe { (geteuid(), getegid()) };
-        self.uid = uid;
-        self.gid = gid;
-        println!("Initialized");
+    let stat = save.stat()?;
+    let mut available = Usage {
+        dirs: stat.free_dirs,
+        files: stat.free_files,
+        blocks: stat.free_blocks,
+    };
+    walk(save, |_, kind, ino| {
+        match kind {
+            InodeKind::Dir => available.dirs += 1,
+            InodeKind::File => {
+                available.files += 1;
+                available.blocks += save.file_blocks(save.open_file(ino)?.len(), &stat);
+            }
+        }
         Ok(())
+    })?;
+    Ok(available)
+}
+
+/// Describes what doesn't fit when importing `required` into `available`.
+/// Returns `None` if everything fits.
+fn shortage_text(available: &Usage, required: &Usage) -> Option<String> {
+    let mut text = String::new();
+    for (what, available, required) in [
+        ("directory slots", available.dirs, required.dirs),
+        ("file slots", available.files, required.files),
+        ("blocks", available.blocks, required.blocks),
+    ] {
+        if required > available {
+            text += &format!(
+                "Not enough {}: {} needed, {} available\n",
+                what, required, available
+            );
+        }
+    }
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
     }
+}
 
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let name_converted: T::NameType = if let Some((n, _)) = name_os_to_3ds(name) {
-            n
-        } else {
-            reply.error(ENAMETOOLONG);
-            return;
+fn import<T: FileSystem + SaveLayout>(
+    save: T,
+    mountpoint: &std::path::Path,
+    encoding: NameEncoding,
+    order: Option<ImportOrder>,
+    duplicate_data: bool,
+) -> Result<(), Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    let stat = save.stat()?;
+    let required =
+        host_usage::<T::NameType>(mountpoint, encoding, |len| save.file_blocks(len, &stat))?;
+    replace_contents(save, required, duplicate_data, |save, root| {
+        import_impl(save, root, mountpoint, encoding, order)
+    })
+}
+
+fn import_raw<T: FileSystem + SaveLayout>(
+    save: T,
+    mountpoint: &std::path::Path,
+    duplicate_data: bool,
+) -> Result<(), Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    let stat = save.stat()?;
+    let required = raw_usage::<T::NameType>(mountpoint, |len| save.file_blocks(len, &stat))?;
+    replace_contents(save, required, duplicate_data, |_, root| {
+        import_raw_impl::<T>(root, mountpoint)
+    })
+}
+
+/// Clears the archive and fills the root with `import`, which takes `required`.
+fn replace_contents<T: FileSystem + SaveLayout>(
+    save: T,
+    required: Usage,
+    duplicate_data: bool,
+    import: impl FnOnce(&T, &T::DirType) -> Result<(), Error>,
+) -> Result<(), Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    // Checked before clearing, so that the original contents are kept if the import can't fit
+    if let Some(text) = shortage_text(&usage_after_clear(&save)?, &required) {
+        print!("{}", text);
+        println!("The original contents are kept");
+        return Err(Error::NoSpace);
+    }
+
+    println!("Clearing the original contents...");
+    let root = save.open_root()?;
+    clear_impl(&save, &root)?;
+    println!("Importing new contents...");
+    import(&save, &root)?;
+    save.commit()?;
+    warn_duplicates(&save, duplicate_data)?;
+    println!("Finished");
+    Ok(())
+}
+
+fn open_or_create_dir<T: FileSystem>(save: &T, names: &[T::NameType]) -> Result<T::DirType, Error>
+where
+    T::NameType: Clone,
+{
+    let mut dir = save.open_root()?;
+    for name in names {
+        dir = match dir.open_sub_dir(name.clone()) {
+            Ok(sub_dir) => sub_dir,
+            Err(Error::NotFound) => dir.new_sub_dir(name.clone())?,
+            Err(e) => return Err(e),
         };
+    }
+    Ok(dir)
+}
 
-        match Ino::from_os(parent) {
-            Ino::File(_) => {
-                reply.error(ENOTDIR);
+/// An entry read from a tar archive: the path, and the content if it is a file.
+type TarImportEntry<N> = (Vec<N>, Option<Vec<u8>>);
+
+/// Reads the whole tar archive, skipping the same entries `import_impl` skips. The archive is
+/// read completely before the import starts, so that a broken or truncated archive fails before
+/// anything is cleared.
+fn read_tar<N: NameConvert>(
+    reader: impl Read,
+    encoding: NameEncoding,
+) -> Result<Vec<TarImportEntry<N>>, Error> {
+    let mut reader = tar::TarReader::new(reader);
+    let mut entries = vec![];
+    while let Some(entry) = reader.next_entry()? {
+        println!("{}", entry.path);
+        let names: Vec<N> = match validate_path(&entry.path, encoding) {
+            Ok(names) => names,
+            Err(e) => {
+                println!("Name not valid ({}): {}", e, entry.path);
+                continue;
             }
-            Ino::Dir(ino) => {
-                let parent_dir = if let Ok(parent_dir) = self.save.open_dir(ino) {
-                    parent_dir
-                } else {
-                    reply.error(EIO);
-                    return;
-                };
+        };
 
-                if let Ok(child) = parent_dir.open_sub_dir(name_converted.clone()) {
-                    let children_len = if let Ok(chidren) = child.list_sub_dir() {
-                        chidren.len()
-                    } else {
-                        reply.error(EIO);
-                        return;
-                    };
+        match entry.kind {
+            tar::TarEntryKind::Dir => entries.push((names, None)),
+            tar::TarEntryKind::File(_) if names.is_empty() => {
+                println!("Name not valid: {}", entry.path);
+            }
+            tar::TarEntryKind::File(data) => entries.push((names, Some(data))),
+            tar::TarEntryKind::Other(_) => {
+                println!("Unrecognized file type: {}", entry.path);
+            }
+        }
+    }
+    Ok(entries)
+}
 
-                    reply.entry(
-                        &Duration::new(1, 0),
-                        &make_dir_attr(
-                            self.read_only,
-                            self.uid,
-                            self.gid,
-                            Ino::Dir(child.get_ino()).to_os(),
-                            children_len,
-                        ),
-                        0,
-                    );
-                    return;
-                }
-                if let Ok(child) = parent_dir.open_sub_file(name_converted) {
-                    reply.entry(
-                        &Duration::new(1, 0),
-                        &make_file_attr(
-                            self.read_only,
-                            self.uid,
-                            self.gid,
-                            Ino::File(child.get_ino()).to_os(),
-                            child.len(),
-                        ),
-                        0,
-                    );
-                    return;
-                }
-                reply.error(ENOENT);
+/// Same as `host_usage`, for entries read by `read_tar`. Parent directories missing from the
+/// archive are counted, as `import_tar_impl` creates them.
+fn tar_usage<N: NameConvert>(
+    entries: &[TarImportEntry<N>],
+    file_blocks: impl Fn(usize) -> usize,
+) -> Usage {
+    let mut usage = Usage::default();
+    let mut dirs = std::collections::BTreeSet::new();
+    for (names, data) in entries {
+        let dir_len = match data {
+            None => names.len(),
+            Some(data) => {
+                usage.files += 1;
+                usage.blocks += file_blocks(data.len());
+                names.len() - 1
             }
+        };
+        for i in 1..=dir_len {
+            dirs.insert(
+                names[0..i]
+                    .iter()
+                    .map(N::name_3ds_to_str)
+                    .collect::<Vec<_>>(),
+            );
         }
     }
+    usage.dirs = dirs.len();
+    usage
+}
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        match Ino::from_os(ino) {
-            Ino::File(ino) => {
-                if let Ok(file) = self.save.open_file(ino) {
-                    reply.attr(
-                        &Duration::new(1, 0),
-                        &make_file_attr(
-                            self.read_only,
-                            self.uid,
-                            self.gid,
-                            Ino::File(file.get_ino()).to_os(),
-                            file.len(),
-                        ),
-                    );
-                } else {
-                    reply.error(ENOENT);
-                }
+fn import_tar_impl<T: FileSystem>(
+    save: &T,
+    entries: Vec<TarImportEntry<T::NameType>>,
+) -> Result<(), Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    for (mut names, data) in entries {
+        match data {
+            None => {
+                open_or_create_dir(save, &names)?;
             }
-            Ino::Dir(ino) => {
-                if let Ok(dir) = self.save.open_dir(ino) {
-                    let children_len = if let Ok(chidren) = dir.list_sub_dir() {
-                        chidren.len()
-                    } else {
-                        reply.error(EIO);
-                        return;
-                    };
-                    reply.attr(
-                        &Duration::new(1, 0),
-                        &make_dir_attr(
-                            self.read_only,
-                            self.uid,
-                            self.gid,
-                            Ino::Dir(dir.get_ino()).to_os(),
-                            children_len,
-                        ),
-                    );
-                } else {
-                    reply.error(ENOENT);
-                }
+            Some(data) => {
+                let name = names.pop().unwrap();
+                let dir = open_or_create_dir(save, &names)?;
+                let file = dir.new_sub_file(name, data.len())?;
+                file.write(0, &data)?;
+                file.commit()?;
             }
         }
     }
 
-    fn setattr(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        size: Option<u64>,
-        _atime: Option<TimeOrNow>,
-        _mtime: Option<TimeOrNow>,
-        _ctime: Option<SystemTime>,
-        fh: Option<u64>,
-        _crtime: Option<SystemTime>,
-        _chgtime: Option<SystemTime>,
-        _bkuptime: Option<SystemTime>,
-        _flags: Option<u32>,
-        reply: ReplyAttr,
-    ) {
-        match Ino::from_os(ino) {
-            Ino::File(ino) => {
-                let mut file_holder: Option<T::FileType>;
-                let file = if let Some(fh) = fh {
-                    if let Some(file) = self.file_fh_map.get_mut(&fh) {
-                        file
-                    } else {
-                        reply.error(ENOENT);
-                        return;
-                    }
-                } else if let Some(file) = self
-                    .file_fh_map
-                    .iter_mut()
-                    .filter(|(_, b)| b.get_ino() == ino)
-                    .map(|(_, b)| b)
-                    .next()
-                {
-                    // bash stdout redirection would do this when the dest file exists
-                    // TODO: revisit this when implementing safe multi fh
-                    println!("Warning: resize when another fh is opened.");
-                    file
-                } else if let Ok(file) = self.save.open_file(ino) {
-                    file_holder = Some(file);
-                    file_holder.as_mut().unwrap()
-                } else {
-                    reply.error(ENOENT);
-                    return;
-                };
+    Ok(())
+}
 
-                if let Some(size) = size {
-                    if file.resize(size as usize).is_err() {
-                        reply.error(EIO);
-                        return;
-                    }
-                }
+fn import_tar<T: FileSystem + SaveLayout>(
+    save: T,
+    tar_path: &str,
+    encoding: NameEncoding,
+    duplicate_data: bool,
+) -> Result<(), Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    let reader: Box<dyn Read> = if tar_path == "-" {
+        Box::new(std::io::stdin())
+    } else {
+        Box::new(std::fs::File::open(tar_path)?)
+    };
+    let entries = read_tar(std::io::BufReader::new(reader), encoding)?;
+    let stat = save.stat()?;
+    let required = tar_usage(&entries, |len| save.file_blocks(len, &stat));
+    replace_contents(save, required, duplicate_data, |save, _| {
+        import_tar_impl(save, entries)
+    })
+}
 
-                reply.attr(
-                    &Duration::new(1, 0),
-                    &make_file_attr(
-                        self.read_only,
-                        self.uid,
-                        self.gid,
-                        Ino::File(file.get_ino()).to_os(),
-                        file.len(),
-                    ),
-                );
-            }
-            Ino::Dir(_) => reply.error(ENOSYS),
+/// Writes `length` bytes (or up to the end if `None`) starting at `offset` of the file at `path`
+/// to `out`. The range is clamped to the file size.
+///
+/// Uninitialized data is written as the fill byte (`0xDD` by default), with a warning on stderr.
+fn cat<T: FileSystem>(
+    save: &T,
+    path: &str,
+    offset: usize,
+    length: Option<usize>,
+    out: &mut impl std::io::Write,
+) -> Result<(), Error>
+where
+    T::NameType: NameConvert,
+{
+    let file = open_file_path(save, path)?;
+    let len = file.len();
+    if offset > len {
+        eprintln!("Offset 0x{:X} is beyond the file size 0x{:X}", offset, len);
+        return Err(Error::OutOfBound);
+    }
+    let end = length.map_or(len, |length| {
+        std::cmp::min(offset.saturating_add(length), len)
+    });
+
+    let mut buf = vec![0; 0x10000];
+    let mut pos = offset;
+    while pos < end {
+        let chunk_len = std::cmp::min(buf.len(), end - pos);
+        let chunk = &mut buf[0..chunk_len];
+        match file.read(pos, chunk) {
+            Ok(()) => (),
+            Err(Error::HashMismatch) => eprintln!(
+                "Warning: uninitialized data in 0x{:X}..0x{:X}",
+                pos,
+                pos + chunk_len
+            ),
+            Err(e) => return Err(e),
         }
+        out.write_all(chunk)?;
+        pos += chunk_len;
     }
+    out.flush()?;
+    Ok(())
+}
 
-    fn mkdir(
-        &mut self,
-        _req: &Request<'_>,
-        parent: u64,
-        name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        reply: ReplyEntry,
-    ) {
-        if self.read_only {
-            reply.error(EROFS);
-            return;
+#[allow(unreachable_code, unused_variables, clippy::too_many_arguments)]
+fn do_mount<T: FileSystem>(
+    save: T,
+    reopen: &dyn Fn(bool) -> Result<T, Error>,
+    read_only: bool,
+    readahead: bool,
+    manual_commit: Option<Rc<Overlay>>,
+    snapshot_path: Option<&str>,
+    autocommit: Option<Duration>,
+    subdir: Option<&str>,
+    mountpoint: &std::path::Path,
+) -> Result<(), Error>
+where
+    T::NameType: NameConvert + Clone + 'static,
+{
+    let root_ino = match subdir {
+        Some(subdir) => open_dir_path(&save, subdir)?.get_ino(),
+        None => 1,
+    };
+    #[cfg(all(unix, feature = "unixfuse"))]
+    {
+        let manual = manual_commit.map(|overlay| ManualCommit {
+            overlay,
+            reopen,
+            snapshot_path: snapshot_path.map(std::path::PathBuf::from),
+        });
+        let mut frontend = FileSystemFrontend::new(save, read_only, readahead, manual, root_ino);
+        frontend.refresh_snapshot()?;
+        if let Some(idle) = autocommit {
+            let pending = Arc::new(Mutex::new(PendingChanges::default()));
+            spawn_autocommit(pending.clone(), idle, mountpoint.to_owned());
+            frontend.pending = Some(pending);
         }
-        let name_converted: T::NameType = if let Some((n, _)) = name_os_to_3ds(name) {
-            n
-        } else {
-            reply.error(ENAMETOOLONG);
-            return;
-        };
-        match Ino::from_os(parent) {
-            Ino::File(_) => {
-                reply.error(ENOTDIR);
-            }
-            Ino::Dir(ino) => {
-                let parent_dir = if let Ok(parent_dir) = self.save.open_dir(ino) {
-                    parent_dir
-                } else {
-                    reply.error(EIO);
-                    return;
-                };
-                match parent_dir.new_sub_dir(name_converted) {
-                    Ok(child) => reply.entry(
-                        &Duration::new(1, 0),
-                        &make_dir_attr(
-                            self.read_only,
-                            self.uid,
-                            self.gid,
-                            Ino::Dir(child.get_ino()).to_os(),
-                            0,
-                        ),
-                        0,
-                    ),
-                    Err(Error::AlreadyExist) => reply.error(EEXIST),
-                    Err(Error::NoSpace) => reply.error(ENOSPC),
-                    Err(_) => reply.error(EIO),
+        mount2(frontend, mountpoint, &[])?;
+        return Ok(());
+    }
+    println!("fuse not implemented. Please specify --extract or --import flag");
+    Ok(())
+}
+
+/// Archives whose header structures can be printed by `--dump-headers`.
+trait DumpHeaders {
+    fn dump_headers(&self) -> Result<Vec<HeaderDump>, Error>;
+}
+
+impl DumpHeaders for SaveData {
+    fn dump_headers(&self) -> Result<Vec<HeaderDump>, Error> {
+        SaveData::dump_headers(self)
+    }
+}
+
+impl DumpHeaders for CartSaveData {
+    fn dump_headers(&self) -> Result<Vec<HeaderDump>, Error> {
+        CartSaveData::dump_headers(self)
+    }
+}
+
+impl DumpHeaders for ExtData {
+    fn dump_headers(&self) -> Result<Vec<HeaderDump>, Error> {
+        ExtData::dump_headers(self)
+    }
+}
+
+impl DumpHeaders for Db {
+    fn dump_headers(&self) -> Result<Vec<HeaderDump>, Error> {
+        Err(Error::Unsupported)
+    }
+}
+
+/// Layout details only save data has, for the duplicate content warning of import modes
+/// and for `--count`, and how files take blocks, for the capacity check of import modes.
+trait SaveLayout {
+    /// Returns the number of blocks in `stat` that a file with `len` bytes of data takes.
+    fn file_blocks(&self, len: usize, stat: &Stat) -> usize {
+        blocks_for(len, stat.block_len)
+    }
+
+    fn duplicate_data(&self) -> bool {
+        false
+    }
+
+    fn table_storage(&self) -> Option<TableStorage> {
+        None
+    }
+
+    fn metadata_hash(&self) -> Result<Option<[u8; 32]>, Error> {
+        Ok(None)
+    }
+
+    /// Returns the size file `ino` takes on the host file system, for archives that store
+    /// each file in its own host file.
+    fn physical_len(&self, _ino: u32) -> Result<Option<usize>, Error> {
+        Ok(None)
+    }
+}
+
+impl SaveLayout for SaveData {
+    fn duplicate_data(&self) -> bool {
+        SaveData::duplicate_data(self)
+    }
+
+    fn table_storage(&self) -> Option<TableStorage> {
+        Some(SaveData::table_storage(self))
+    }
+
+    fn metadata_hash(&self) -> Result<Option<[u8; 32]>, Error> {
+        SaveData::metadata_hash(self).map(Some)
+    }
+}
+
+impl SaveLayout for CartSaveData {
+    fn duplicate_data(&self) -> bool {
+        CartSaveData::duplicate_data(self)
+    }
+
+    fn table_storage(&self) -> Option<TableStorage> {
+        Some(CartSaveData::table_storage(self))
+    }
+
+    fn metadata_hash(&self) -> Result<Option<[u8; 32]>, Error> {
+        CartSaveData::metadata_hash(self).map(Some)
+    }
+}
+
+impl SaveLayout for ExtData {
+    fn file_blocks(&self, len: usize, _stat: &Stat) -> usize {
+        // Without a quota, files don't take blocks from anywhere
+        if self.has_quota() {
+            ExtData::quota_blocks(len)
+        } else {
+            0
+        }
+    }
+
+    fn physical_len(&self, ino: u32) -> Result<Option<usize>, Error> {
+        Ok(Some(self.open_file(ino)?.physical_len()))
+    }
+}
+
+impl SaveLayout for Db {}
+
+/// Runs `operation` on `save`. `reopen` opens the same archive again, in the given write mode,
+/// for `--manual-commit`.
+fn start<T: FileSystem + DumpHeaders + SaveLayout>(
+    save: T,
+    reopen: impl Fn(bool) -> Result<T, Error>,
+    operation: FileSystemOperation,
+    mountpoint: &std::path::Path,
+) -> Result<(), Error>
+where
+    T::NameType: NameConvert + Clone + 'static,
+{
+    match operation {
+        FileSystemOperation::Mount {
+            read_only,
+            readahead,
+            manual_commit,
+            snapshot_path,
+            autocommit,
+            subdir,
+        } => do_mount(
+            save,
+            &reopen,
+            read_only,
+            readahead,
+            manual_commit,
+            snapshot_path.as_deref(),
+            autocommit,
+            subdir.as_deref(),
+            mountpoint,
+        )?,
+        FileSystemOperation::Extract {
+            since: None,
+            name_encoding,
+        } => extract(save, mountpoint, name_encoding)?,
+        FileSystemOperation::Extract {
+            since: Some(manifest),
+            name_encoding,
+        } => {
+            println!("Extracting changed files...");
+            extract_since(
+                &save,
+                mountpoint,
+                std::path::Path::new(&manifest),
+                name_encoding,
+            )?;
+            println!("Finished");
+        }
+        FileSystemOperation::ExtractRaw => extract_raw(save, mountpoint)?,
+        FileSystemOperation::Import(name_encoding, order) => {
+            let duplicate_data = save.duplicate_data();
+            import(save, mountpoint, name_encoding, order, duplicate_data)?
+        }
+        FileSystemOperation::ImportRaw => {
+            let duplicate_data = save.duplicate_data();
+            import_raw(save, mountpoint, duplicate_data)?
+        }
+        FileSystemOperation::ImportTar(tar_path, name_encoding) => {
+            let duplicate_data = save.duplicate_data();
+            import_tar(save, &tar_path, name_encoding, duplicate_data)?
+        }
+        FileSystemOperation::Cat {
+            path,
+            offset,
+            length,
+        } => cat(&save, &path, offset, length, &mut std::io::stdout().lock())?,
+        FileSystemOperation::Count { json } => print!(
+            "{}",
+            summary_text(
+                &summarize(&save)?,
+                &content_fingerprint(&save)?,
+                save.table_storage(),
+                save.metadata_hash()?,
+                json
+            )
+        ),
+        FileSystemOperation::DumpHeaders { json } => {
+            print!("{}", headers_text(&save.dump_headers()?, json))
+        }
+        FileSystemOperation::List { json: true } => {
+            let map = save.inode_map()?;
+            let mut file_lens = HashMap::new();
+            for &ino in map.files.keys() {
+                let len = save.open_file(ino)?.len();
+                file_lens.insert(ino, (len, save.physical_len(ino)?));
+            }
+            print!("{}", inode_map_json(&map, &file_lens))
+        }
+        FileSystemOperation::List { json: false } => {
+            let mut tree = String::new();
+            format_tree(&save, &mut tree)?;
+            print!("{}", tree);
+        }
+        FileSystemOperation::Touch => println!("Touched"),
+    }
+
+    Ok(())
+}
+
+/// Per-handle read-ahead buffer for sequential reads.
+///
+/// When a read starts where the previous one ended, a larger window is read from the file,
+/// and following reads within the window are served from the buffer.
+/// Any non-sequential read drops the buffer.
+#[allow(unused)]
+struct ReadAhead {
+    window: usize,
+    next_pos: usize,
+    buf_pos: usize,
+    buf: Vec<u8>,
+}
+
+#[allow(unused)]
+impl ReadAhead {
+    /// Number of additional bytes to prefetch on sequential reads.
+    const WINDOW: usize = 0x10_0000;
+
+    fn new(window: usize) -> ReadAhead {
+        ReadAhead {
+            window,
+            next_pos: 0,
+            buf_pos: 0,
+            buf: vec![],
+        }
+    }
+
+    /// Drops the buffered data. This must be called when the file is modified.
+    fn invalidate(&mut self) {
+        self.buf.clear();
+    }
+
+    /// Reads the range `[pos, end)` from `file`, where `end` must not exceed `file.len()`.
+    /// Uninitialized data is returned as-is, as a read ignoring `Error::HashMismatch` would do.
+    fn read<F: FileSystemFile>(
+        &mut self,
+        file: &F,
+        pos: usize,
+        end: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let sequential = pos == self.next_pos;
+        self.next_pos = end;
+
+        if pos >= self.buf_pos && end <= self.buf_pos + self.buf.len() {
+            return Ok(self.buf[pos - self.buf_pos..end - self.buf_pos].to_vec());
+        }
+
+        let fetch_end = if sequential {
+            std::cmp::min(end + self.window, file.len())
+        } else {
+            end
+        };
+        let mut buf = vec![0; fetch_end - pos];
+        match file.read(pos, &mut buf) {
+            Ok(()) | Err(Error::HashMismatch) => (),
+            Err(e) => return Err(e),
+        }
+
+        let result = buf[0..end - pos].to_vec();
+        if sequential {
+            self.buf_pos = pos;
+            self.buf = buf;
+        } else {
+            self.invalidate();
+        }
+        Ok(result)
+    }
+}
+
+/// Changes not committed yet in mount mode, shared with the thread of `--autocommit`.
+#[allow(unused)]
+#[derive(Default)]
+struct PendingChanges {
+    /// Time of the last change, or `None` if nothing changed since the last commit.
+    last_change: Option<Instant>,
+    open_files: usize,
+}
+
+#[allow(unused)]
+impl PendingChanges {
+    /// Returns whether an autocommit is due at `now`. That is, something changed, nothing
+    /// changed for `idle`, and no file is open, so that a file being written in several
+    /// steps is never committed half way.
+    fn due(&self, idle: Duration, now: Instant) -> bool {
+        self.open_files == 0
+            && self
+                .last_change
+                .is_some_and(|t| now.saturating_duration_since(t) >= idle)
+    }
+}
+
+/// Starts the thread of `--autocommit`. The archive can't be shared with other threads, so
+/// the thread syncs the mount root instead, which commits the archive in `fsyncdir`.
+#[cfg(all(unix, feature = "unixfuse"))]
+fn spawn_autocommit(
+    pending: Arc<Mutex<PendingChanges>>,
+    idle: Duration,
+    mountpoint: std::path::PathBuf,
+) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(std::cmp::min(idle, Duration::from_secs(1)));
+        if !pending.lock().unwrap().due(idle, Instant::now()) {
+            continue;
+        }
+        println!("Autocommitting after {:?} without changes", idle);
+        if let Err(e) = std::fs::File::open(&mountpoint).and_then(|root| root.sync_all()) {
+            eprintln!("Autocommit failed: {}", e);
+            // Retry after another idle period instead of every tick
+            pending.lock().unwrap().last_change = Some(Instant::now());
+        }
+    });
+}
+
+#[cfg(all(unix, feature = "unixfuse"))]
+struct DirEntry {
+    ino: u64,
+    file_type: FileType,
+    name: String,
+}
+
+/// An opened directory. Entries are read from the archive as `readdir` asks for them.
+#[cfg(all(unix, feature = "unixfuse"))]
+struct DirStream {
+    ino: u32,
+    entries: Box<dyn Iterator<Item = Result<DirEntry, Error>>>,
+    /// The offset of the next entry.
+    offset: i64,
+    /// The entry at `offset` if it has been taken from `entries` but didn't fit in the last reply.
+    pending: Option<DirEntry>,
+}
+
+#[cfg(all(unix, feature = "unixfuse"))]
+impl DirStream {
+    /// Passes entries to `add` until it returns true for a full buffer, or the directory ends.
+    ///
+    /// Each entry is passed with the offset of the entry after it, which is where the kernel
+    /// continues from. The entry that didn't fit is kept for the next call.
+    fn fill(&mut self, mut add: impl FnMut(u64, i64, FileType, &str) -> bool) -> Result<(), Error> {
+        loop {
+            let entry = match self.pending.take() {
+                Some(entry) => entry,
+                None => match self.entries.next() {
+                    None => return Ok(()),
+                    Some(entry) => entry?,
+                },
+            };
+            if add(entry.ino, self.offset + 1, entry.file_type, &entry.name) {
+                self.pending = Some(entry);
+                return Ok(());
+            }
+            self.offset += 1;
+        }
+    }
+}
+
+/// Name of the control file of `--manual-commit` in the mount root.
+#[allow(unused)]
+const CONTROL_NAME: &str = ".save3ds_control";
+
+/// Host inode of the control file, outside the range `InodeMap` encodes archive inodes in.
+#[allow(unused)]
+const CONTROL_INO: u64 = 1 << 48;
+
+/// Name of the manifest `--snapshot-path` keeps in the snapshot directory, as for
+/// `--extract --since`.
+#[allow(unused)]
+const SNAPSHOT_MANIFEST: &str = ".save3ds_manifest";
+
+/// State of `--manual-commit`, where the archive is opened over an overlay that keeps all
+/// changes in memory until a command written to the control file commits or discards them.
+#[cfg(all(unix, feature = "unixfuse"))]
+struct ManualCommit<'a, T> {
+    overlay: Rc<Overlay>,
+    reopen: &'a dyn Fn(bool) -> Result<T, Error>,
+    snapshot_path: Option<std::path::PathBuf>,
+}
+
+#[cfg(all(unix, feature = "unixfuse"))]
+struct FileSystemFrontend<'a, T: FileSystem> {
+    save: T,
+    read_only: bool,
+    file_fh_map: HashMap<u64, T::FileType>,
+    readahead_map: Option<HashMap<u64, ReadAhead>>,
+    manual: Option<ManualCommit<'a, T>>,
+    dir_fh_map: HashMap<u64, DirStream>,
+    next_fh: u64,
+    uid: u32,
+    gid: u32,
+    root_ino: u32,
+    written_files: BTreeSet<u32>,
+    pending: Option<Arc<Mutex<PendingChanges>>>,
+}
+
+#[cfg(all(unix, feature = "unixfuse"))]
+impl<'a, T: FileSystem> FileSystemFrontend<'a, T>
+where
+    T::NameType: NameConvert + Clone + 'static,
+{
+    fn new(
+        save: T,
+        read_only: bool,
+        readahead: bool,
+        manual: Option<ManualCommit<'a, T>>,
+        root_ino: u32,
+    ) -> FileSystemFrontend<'a, T> {
+        FileSystemFrontend {
+            save,
+            file_fh_map: HashMap::new(),
+            readahead_map: if readahead {
+                Some(HashMap::new())
+            } else {
+                None
+            },
+            manual,
+            dir_fh_map: HashMap::new(),
+            next_fh: 1,
+            read_only,
+            uid: 0,
+            gid: 0,
+            root_ino,
+            written_files: BTreeSet::new(),
+            pending: None,
+        }
+    }
+
+    /// Records a change for `--autocommit`.
+    fn note_change(&self) {
+        if let Some(pending) = self.pending.as_ref() {
+            pending.lock().unwrap().last_change = Some(Instant::now());
+        }
+    }
+
+    /// Records the number of open files for `--autocommit`.
+    fn note_open_files(&self) {
+        if let Some(pending) = self.pending.as_ref() {
+            pending.lock().unwrap().open_files = self.file_fh_map.len();
+        }
+    }
+
+    fn decode_ino(&self, ino: u64) -> Option<Ino> {
+        Some(Ino::from_os(ino)?.swap_root(self.root_ino))
+    }
+
+    fn encode_dir(&self, ino: u32) -> u64 {
+        Ino::Dir(ino).swap_root(self.root_ino).to_os()
+    }
+
+    /// Starts listing the directory `dir` with inode `ino`, including "." and "..".
+    fn dir_stream(&self, dir: &T::DirType, ino: u32) -> Result<DirStream, Error> {
+        // The root directory is its own parent
+        let parent_ino = if ino == self.root_ino {
+            ino
+        } else {
+            dir.get_parent_ino()?
+        };
+        let dots = vec![
+            DirEntry {
+                ino: self.encode_dir(ino),
+                file_type: FileType::Directory,
+                name: ".".to_owned(),
+            },
+            DirEntry {
+                ino: self.encode_dir(parent_ino),
+                file_type: FileType::Directory,
+                name: "..".to_owned(),
+            },
+        ];
+        let root_ino = self.root_ino;
+        let sub_dirs = dir.iter_sub_dir()?.map(move |entry| {
+            entry.map(|(name, i)| DirEntry {
+                ino: Ino::Dir(i).swap_root(root_ino).to_os(),
+                file_type: FileType::Directory,
+                name: T::NameType::name_3ds_to_str(&name),
+            })
+        });
+        let sub_files = dir.iter_sub_file()?.map(|entry| {
+            entry.map(|(name, i)| DirEntry {
+                ino: Ino::File(i).to_os(),
+                file_type: FileType::RegularFile,
+                name: T::NameType::name_3ds_to_str(&name),
+            })
+        });
+        Ok(DirStream {
+            ino,
+            entries: Box::new(dots.into_iter().map(Ok).chain(sub_dirs).chain(sub_files)),
+            offset: 0,
+            pending: None,
+        })
+    }
+
+    /// Moves `stream` to `offset`, which is the offset of the next entry that the kernel wants.
+    /// `stream` is left as it was on failure.
+    fn seek_dir(&self, stream: &mut DirStream, offset: i64) -> Result<(), Error> {
+        if offset == stream.offset {
+            return Ok(());
+        }
+        // Only happens after seekdir/rewinddir. Start over and skip to the offset
+        let ino = stream.ino;
+        let mut new_stream = self.dir_stream(&self.save.open_dir(ino)?, ino)?;
+        while new_stream.offset < offset {
+            match new_stream.entries.next() {
+                None => break,
+                Some(entry) => {
+                    entry?;
+                    new_stream.offset += 1;
                 }
             }
         }
+        *stream = new_stream;
+        Ok(())
+    }
+}
+
+#[cfg(all(unix, feature = "unixfuse"))]
+fn make_dir_attr(read_only: bool, uid: u32, gid: u32, ino: u64, sub_file_count: usize) -> FileAttr {
+    FileAttr {
+        ino,
+        size: 0,
+        blocks: 0,
+        atime: SystemTime::UNIX_EPOCH,
+        mtime: SystemTime::UNIX_EPOCH,
+        ctime: SystemTime::UNIX_EPOCH,
+        crtime: SystemTime::UNIX_EPOCH,
+        kind: FileType::Directory,
+        perm: if read_only { 0o555 } else { 0o755 },
+        nlink: 2 + sub_file_count as u32,
+        uid,
+        gid,
+        rdev: 0,
+        blksize: 0,
+        flags: 0,
+    }
+}
+
+#[cfg(all(unix, feature = "unixfuse"))]
+fn make_file_attr(read_only: bool, uid: u32, gid: u32, ino: u64, file_size: usize) -> FileAttr {
+    FileAttr {
+        ino,
+        size: file_size as u64,
+        blocks: 1,
+        atime: SystemTime::UNIX_EPOCH,
+        mtime: SystemTime::UNIX_EPOCH,
+        ctime: SystemTime::UNIX_EPOCH,
+        crtime: SystemTime::UNIX_EPOCH,
+        kind: FileType::RegularFile,
+        perm: if read_only { 0o444 } else { 0o644 },
+        nlink: 1,
+        uid,
+        gid,
+        rdev: 0,
+        blksize: 0,
+        flags: 0,
+    }
+}
+
+/// Converts a host file name to an archive name and the argument after `\\+`, if any.
+#[allow(unused)]
+fn name_os_to_3ds<T: NameConvert>(name: &OsStr) -> Result<(T, &str), Error> {
+    let s = name.to_str().ok_or(Error::InvalidName)?;
+    let argument_pos = s.find("\\+");
+    let (l, r) = if let Some(pos) = argument_pos {
+        let (l, r) = s.split_at(pos);
+        (l, &r[2..])
+    } else {
+        (s, "")
+    };
+    Ok((T::validate_name(l, NameEncoding::Escape)?, r))
+}
+
+/// Returns the errno for a name rejected by `name_os_to_3ds`.
+#[cfg(all(unix, feature = "unixfuse"))]
+fn name_errno(e: &Error) -> i32 {
+    match e {
+        Error::NameTooLong => ENAMETOOLONG,
+        _ => EINVAL,
+    }
+}
+
+/// An inode in an archive, tagged with whether it refers to a directory or a file.
+/// Directories and files are indexed separately in archives, so the same number can refer to both.
+#[cfg(all(unix, feature = "unixfuse"))]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Ino {
+    Dir(u32),
+    File(u32),
+}
+
+/// Encodes archive inodes into a single host inode space.
+///
+/// A host inode is laid out as follows, from the least significant bit:
+///  - bit 0-31: the inode in the archive.
+///  - bit 32: set if the inode refers to a file, clear if it refers to a directory.
+///  - bit 33-47: the index of the file system the inode belongs to,
+///    reserved for exposing multiple archives in one mount.
+///  - bit 48-63: must be zero. `CONTROL_INO` sets them, so that it never meets an archive inode.
+///
+/// This keeps host inodes of the first file system identical to the archive directory inodes,
+/// so that the archive root directory (1) maps to the FUSE root inode (1).
+#[cfg(all(unix, feature = "unixfuse"))]
+struct InodeMap;
+
+#[cfg(all(unix, feature = "unixfuse"))]
+impl InodeMap {
+    const FILE_BIT: u64 = 1 << 32;
+    const FS_INDEX_SHIFT: u32 = 33;
+    const MAX_FS_INDEX: u32 = (1 << 15) - 1;
+
+    /// Encodes an archive inode of the file system `fs_index`.
+    /// Returns `None` if `fs_index` exceeds the supported range.
+    fn encode(fs_index: u32, ino: Ino) -> Option<u64> {
+        if fs_index > InodeMap::MAX_FS_INDEX {
+            return None;
+        }
+        let fs_bits = u64::from(fs_index) << InodeMap::FS_INDEX_SHIFT;
+        Some(match ino {
+            Ino::Dir(ino) => fs_bits | u64::from(ino),
+            Ino::File(ino) => fs_bits | InodeMap::FILE_BIT | u64::from(ino),
+        })
+    }
+
+    /// Decodes a host inode into the file system index and the archive inode.
+    /// Returns `None` if the host inode is not produced by `encode`.
+    fn decode(os_ino: u64) -> Option<(u32, Ino)> {
+        let fs_index = os_ino >> InodeMap::FS_INDEX_SHIFT;
+        if fs_index > u64::from(InodeMap::MAX_FS_INDEX) {
+            return None;
+        }
+        let ino = (os_ino & 0xFFFF_FFFF) as u32;
+        let ino = if os_ino & InodeMap::FILE_BIT != 0 {
+            Ino::File(ino)
+        } else {
+            Ino::Dir(ino)
+        };
+        Some((fs_index as u32, ino))
+    }
+
+    /// Checks whether two host inodes belong to the same file system, which is required for
+    /// moving entries between them. Inodes not produced by `encode` pass, and are left for the
+    /// caller to reject.
+    fn same_fs(os_ino_a: u64, os_ino_b: u64) -> bool {
+        !matches!(
+            (InodeMap::decode(os_ino_a), InodeMap::decode(os_ino_b)),
+            (Some((a, _)), Some((b, _))) if a != b
+        )
+    }
+}
+
+#[cfg(all(unix, feature = "unixfuse"))]
+impl Ino {
+    fn to_os(self) -> u64 {
+        InodeMap::encode(0, self).unwrap()
+    }
+
+    fn from_os(ino: u64) -> Option<Ino> {
+        match InodeMap::decode(ino)? {
+            (0, ino) => Some(ino),
+            _ => None,
+        }
+    }
+
+    /// Swaps the archive root directory with the directory `root_ino`, so that the latter is
+    /// exposed as the root of the mount. This is its own inverse, and an identity if `root_ino`
+    /// is 1.
+    fn swap_root(self, root_ino: u32) -> Ino {
+        match self {
+            Ino::Dir(1) => Ino::Dir(root_ino),
+            Ino::Dir(ino) if ino == root_ino => Ino::Dir(1),
+            ino => ino,
+        }
+    }
+}
+
+/// Opens the directory at `path`, relative to the archive root.
+/// Path components are separated by `/`, and empty components are ignored.
+fn open_dir_path<T: FileSystem>(save: &T, path: &str) -> Result<T::DirType, Error>
+where
+    T::NameType: NameConvert,
+{
+    open_dir_path_as(save, path, NameEncoding::Escape)
+}
+
+/// Same as `open_dir_path`, with names in `path` encoded using `encoding`.
+fn open_dir_path_as<T: FileSystem>(
+    save: &T,
+    path: &str,
+    encoding: NameEncoding,
+) -> Result<T::DirType, Error>
+where
+    T::NameType: NameConvert,
+{
+    let mut dir = save.open_root()?;
+    for name in path.split('/').filter(|c| !c.is_empty()) {
+        let name = T::NameType::name_str_to_3ds_as(name, encoding).ok_or(Error::NotFound)?;
+        dir = dir.open_sub_dir(name)?;
+    }
+    Ok(dir)
+}
+
+/// Opens the file at `path`, relative to the archive root. See `open_dir_path`.
+fn open_file_path<T: FileSystem>(save: &T, path: &str) -> Result<T::FileType, Error>
+where
+    T::NameType: NameConvert,
+{
+    open_file_path_as(save, path, NameEncoding::Escape)
+}
+
+/// Same as `open_file_path`, with names in `path` encoded using `encoding`.
+fn open_file_path_as<T: FileSystem>(
+    save: &T,
+    path: &str,
+    encoding: NameEncoding,
+) -> Result<T::FileType, Error>
+where
+    T::NameType: NameConvert,
+{
+    let (dir_path, name) = match path.rfind('/') {
+        Some(i) => (&path[0..i], &path[i + 1..]),
+        None => ("", path),
+    };
+    if name.is_empty() {
+        return Err(Error::NotFound);
+    }
+    let name = T::NameType::name_str_to_3ds_as(name, encoding).ok_or(Error::NotFound)?;
+    open_dir_path_as(save, dir_path, encoding)?.open_sub_file(name)
+}
+
+#[cfg(all(unix, feature = "unixfuse"))]
+impl<T: FileSystem> FileSystemFrontend<'_, T> {
+    /// Commits the archive, reporting the files whose changes are lost on failure. With
+    /// `--manual-commit`, this also writes the changes kept in the overlay to the disk.
+    fn commit_archive(&mut self) -> Result<(), Error> {
+        let result = self.save.commit().and_then(|()| match &self.manual {
+            Some(manual) => manual.overlay.persist(),
+            None => Ok(()),
+        });
+        match result {
+            Ok(()) => {
+                println!("Saved");
+                self.written_files.clear();
+                if let Some(pending) = self.pending.as_ref() {
+                    pending.lock().unwrap().last_change = None;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!("!!! Failed to save the archive: {}", e);
+                if self.manual.is_some() {
+                    eprintln!("!!! Changes are kept in memory. Commit again or discard them");
+                } else {
+                    eprintln!(
+                        "!!! Changes are lost, including data written to files {:?}",
+                        self.written_files
+                    );
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "unixfuse"))]
+impl<T: FileSystem> FileSystemFrontend<'_, T>
+where
+    T::NameType: NameConvert,
+{
+    /// Runs a command written to the control file of `--manual-commit`: `commit` saves the
+    /// archive to the disk, and `discard` drops the changes since the last commit.
+    fn control(&mut self, command: &[u8]) -> Result<(), Error> {
+        match std::str::from_utf8(command).map(str::trim) {
+            Ok("commit") => {
+                self.commit_archive()?;
+                self.refresh_snapshot()
+            }
+            Ok("discard") => self.discard(),
+            _ => {
+                eprintln!(
+                    "Unknown command {:?}, expected commit or discard",
+                    String::from_utf8_lossy(command).trim()
+                );
+                Err(Error::InvalidValue)
+            }
+        }
+    }
+
+    /// Drops the changes kept in the overlay, and opens the archive again from the disk.
+    /// Open files and directories would refer to the dropped archive, so this is refused with
+    /// `Error::Busy` while any is open.
+    fn discard(&mut self) -> Result<(), Error> {
+        if !self.file_fh_map.is_empty() || !self.dir_fh_map.is_empty() {
+            eprintln!("Close all files and directories in the mount before discarding");
+            return Err(Error::Busy);
+        }
+        let manual = self.manual.as_ref().unwrap();
+        manual.overlay.discard();
+        match (manual.reopen)(true) {
+            Ok(save) => {
+                self.save = save;
+                self.written_files.clear();
+                println!("Discarded changes since the last commit");
+                Ok(())
+            }
+            Err(e) => {
+                // The archive in use doesn't match the disk any more, so it must not be saved
+                eprintln!("!!! Failed to open the archive again: {}", e);
+                eprintln!("!!! The mount is read-only now. Unmount and mount it again");
+                self.read_only = true;
+                Err(e)
+            }
+        }
+    }
+
+    /// Extracts the state last committed to the disk to the directory of `--snapshot-path`,
+    /// if given. Only files that changed since the last extraction are written.
+    fn refresh_snapshot(&self) -> Result<(), Error> {
+        if let Some(manual) = self.manual.as_ref() {
+            if let Some(path) = manual.snapshot_path.as_ref() {
+                extract_since(
+                    &(manual.reopen)(false)?,
+                    path,
+                    &path.join(SNAPSHOT_MANIFEST),
+                    NameEncoding::Escape,
+                )?;
+                println!("Updated the snapshot at {}", path.display());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the attributes of the control file of `--manual-commit`.
+    fn control_attr(&self) -> FileAttr {
+        make_file_attr(false, self.uid, self.gid, CONTROL_INO, 0)
+    }
+}
+
+#[cfg(all(unix, feature = "unixfuse"))]
+impl<T: FileSystem> Drop for FileSystemFrontend<'_, T> {
+    fn drop(&mut self) {
+        if self.read_only {
+            return;
+        }
+        if self.manual.is_some() {
+            println!("Discarded changes since the last commit");
+        } else {
+            let _ = self.commit_archive();
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "unixfuse"))]
+impl<T: FileSystem> Filesystem for FileSystemFrontend<'_, T>
+where
+    T::NameType: NameConvert + Clone + 'static,
+{
+    fn init(&mut self, _req: &Request, _kc: &mut KernelConfig) -> Result<(), i32> {
+        let (uid, gid) = unsafe { (geteuid(), getegid()) };
+        self.uid = uid;
+        self.gid = gid;
+        println!("Initialized");
+        Ok(())
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if self.manual.is_some() && parent == FUSE_ROOT_ID && name == CONTROL_NAME {
+            reply.entry(&Duration::new(1, 0), &self.control_attr(), 0);
+            return;
+        }
+        let name_converted: T::NameType = match name_os_to_3ds(name) {
+            Ok((n, _)) => n,
+            Err(e) => {
+                reply.error(name_errno(&e));
+                return;
+            }
+        };
+
+        match self.decode_ino(parent) {
+            None => reply.error(ENOENT),
+            Some(Ino::File(_)) => {
+                reply.error(ENOTDIR);
+            }
+            Some(Ino::Dir(ino)) => {
+                let parent_dir = if let Ok(parent_dir) = self.save.open_dir(ino) {
+                    parent_dir
+                } else {
+                    reply.error(EIO);
+                    return;
+                };
+
+                match parent_dir.open_sub_dir(name_converted.clone()) {
+                    Ok(child) => {
+                        let children_len = if let Ok(chidren) = child.list_sub_dir() {
+                            chidren.len()
+                        } else {
+                            reply.error(EIO);
+                            return;
+                        };
+
+                        reply.entry(
+                            &Duration::new(1, 0),
+                            &make_dir_attr(
+                                self.read_only,
+                                self.uid,
+                                self.gid,
+                                self.encode_dir(child.get_ino()),
+                                children_len,
+                            ),
+                            0,
+                        );
+                        return;
+                    }
+                    Err(Error::NotFound) => (),
+                    Err(_) => {
+                        reply.error(EIO);
+                        return;
+                    }
+                }
+                match parent_dir.open_sub_file(name_converted) {
+                    Ok(child) => reply.entry(
+                        &Duration::new(1, 0),
+                        &make_file_attr(
+                            self.read_only,
+                            self.uid,
+                            self.gid,
+                            Ino::File(child.get_ino()).to_os(),
+                            child.len(),
+                        ),
+                        0,
+                    ),
+                    Err(Error::NotFound) => reply.error(ENOENT),
+                    Err(_) => reply.error(EIO),
+                }
+            }
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        if self.manual.is_some() && ino == CONTROL_INO {
+            reply.attr(&Duration::new(1, 0), &self.control_attr());
+            return;
+        }
+        match self.decode_ino(ino) {
+            None => reply.error(ENOENT),
+            Some(Ino::File(ino)) => {
+                if let Ok(file) = self.save.open_file(ino) {
+                    reply.attr(
+                        &Duration::new(1, 0),
+                        &make_file_attr(
+                            self.read_only,
+                            self.uid,
+                            self.gid,
+                            Ino::File(file.get_ino()).to_os(),
+                            file.len(),
+                        ),
+                    );
+                } else {
+                    reply.error(ENOENT);
+                }
+            }
+            Some(Ino::Dir(ino)) => {
+                if let Ok(dir) = self.save.open_dir(ino) {
+                    let children_len = if let Ok(chidren) = dir.list_sub_dir() {
+                        chidren.len()
+                    } else {
+                        reply.error(EIO);
+                        return;
+                    };
+                    reply.attr(
+                        &Duration::new(1, 0),
+                        &make_dir_attr(
+                            self.read_only,
+                            self.uid,
+                            self.gid,
+                            self.encode_dir(dir.get_ino()),
+                            children_len,
+                        ),
+                    );
+                } else {
+                    reply.error(ENOENT);
+                }
+            }
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        // Truncating the control file, as opening it with O_TRUNC does, is ignored
+        if self.manual.is_some() && ino == CONTROL_INO {
+            reply.attr(&Duration::new(1, 0), &self.control_attr());
+            return;
+        }
+        self.note_change();
+        match self.decode_ino(ino) {
+            None => reply.error(ENOENT),
+            Some(Ino::File(ino)) => {
+                let mut file_holder: Option<T::FileType>;
+                let file = if let Some(fh) = fh {
+                    if let Some(file) = self.file_fh_map.get_mut(&fh) {
+                        file
+                    } else {
+                        reply.error(ENOENT);
+                        return;
+                    }
+                } else if let Some(file) = self
+                    .file_fh_map
+                    .iter_mut()
+                    .filter(|(_, b)| b.get_ino() == ino)
+                    .map(|(_, b)| b)
+                    .next()
+                {
+                    // bash stdout redirection would do this when the dest file exists
+                    // TODO: revisit this when implementing safe multi fh
+                    println!("Warning: resize when another fh is opened.");
+                    file
+                } else if let Ok(file) = self.save.open_file(ino) {
+                    file_holder = Some(file);
+                    file_holder.as_mut().unwrap()
+                } else {
+                    reply.error(ENOENT);
+                    return;
+                };
+
+                if let Some(readahead_map) = self.readahead_map.as_mut() {
+                    readahead_map.values_mut().for_each(ReadAhead::invalidate);
+                }
+
+                if let Some(size) = size {
+                    if file.resize(size as usize).is_err() {
+                        reply.error(EIO);
+                        return;
+                    }
+                    self.written_files.insert(ino);
+                }
+
+                reply.attr(
+                    &Duration::new(1, 0),
+                    &make_file_attr(
+                        self.read_only,
+                        self.uid,
+                        self.gid,
+                        Ino::File(file.get_ino()).to_os(),
+                        file.len(),
+                    ),
+                );
+            }
+            Some(Ino::Dir(_)) => reply.error(ENOSYS),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        self.note_change();
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+        let name_converted: T::NameType = match name_os_to_3ds(name) {
+            Ok((n, _)) => n,
+            Err(e) => {
+                reply.error(name_errno(&e));
+                return;
+            }
+        };
+        match self.decode_ino(parent) {
+            None => reply.error(ENOENT),
+            Some(Ino::File(_)) => {
+                reply.error(ENOTDIR);
+            }
+            Some(Ino::Dir(ino)) => {
+                let parent_dir = if let Ok(parent_dir) = self.save.open_dir(ino) {
+                    parent_dir
+                } else {
+                    reply.error(EIO);
+                    return;
+                };
+                match parent_dir.new_sub_dir(name_converted) {
+                    Ok(child) => reply.entry(
+                        &Duration::new(1, 0),
+                        &make_dir_attr(
+                            self.read_only,
+                            self.uid,
+                            self.gid,
+                            self.encode_dir(child.get_ino()),
+                            0,
+                        ),
+                        0,
+                    ),
+                    Err(Error::AlreadyExist) => reply.error(EEXIST),
+                    Err(Error::NoSpace) => reply.error(ENOSPC),
+                    Err(_) => reply.error(EIO),
+                }
+            }
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        self.note_change();
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+        let (name_converted, size): (T::NameType, usize) = match name_os_to_3ds(name) {
+            Ok((n, s)) => (n, str::parse::<usize>(s).unwrap_or(0)),
+            Err(e) => {
+                reply.error(name_errno(&e));
+                return;
+            }
+        };
+        match self.decode_ino(parent) {
+            None => reply.error(ENOENT),
+            Some(Ino::File(_)) => {
+                reply.error(ENOTDIR);
+            }
+            Some(Ino::Dir(ino)) => {
+                let parent_dir = if let Ok(parent_dir) = self.save.open_dir(ino) {
+                    parent_dir
+                } else {
+                    reply.error(EIO);
+                    return;
+                };
+
+                match parent_dir.new_sub_file(name_converted, size) {
+                    Ok(child) => reply.entry(
+                        &Duration::new(1, 0),
+                        &make_file_attr(
+                            self.read_only,
+                            self.uid,
+                            self.gid,
+                            Ino::File(child.get_ino()).to_os(),
+                            0,
+                        ),
+                        0,
+                    ),
+                    Err(Error::AlreadyExist) => reply.error(EEXIST),
+                    Err(Error::NoSpace) => reply.error(ENOSPC),
+                    Err(_) => reply.error(EIO),
+                }
+            }
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.note_change();
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+        let name_converted: T::NameType = match name_os_to_3ds(name) {
+            Ok((n, _)) => n,
+            Err(e) => {
+                reply.error(name_errno(&e));
+                return;
+            }
+        };
+
+        match self.decode_ino(parent) {
+            None => reply.error(ENOENT),
+            Some(Ino::File(_)) => {
+                reply.error(ENOTDIR);
+            }
+            Some(Ino::Dir(ino)) => {
+                let parent_dir = if let Ok(parent_dir) = self.save.open_dir(ino) {
+                    parent_dir
+                } else {
+                    reply.error(EIO);
+                    return;
+                };
+
+                match parent_dir.open_sub_dir(name_converted) {
+                    Ok(child) => match child.delete() {
+                        Ok(()) => reply.ok(),
+                        Err(Error::NotEmpty) => reply.error(ENOTEMPTY),
+                        Err(_) => reply.error(EIO),
+                    },
+                    Err(Error::NotFound) => reply.error(ENOENT),
+                    Err(_) => reply.error(EIO),
+                }
+            }
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.note_change();
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+        let name_converted: T::NameType = match name_os_to_3ds(name) {
+            Ok((n, _)) => n,
+            Err(e) => {
+                reply.error(name_errno(&e));
+                return;
+            }
+        };
+
+        match self.decode_ino(parent) {
+            None => reply.error(ENOENT),
+            Some(Ino::File(_)) => {
+                reply.error(ENOTDIR);
+            }
+            Some(Ino::Dir(ino)) => {
+                let parent_dir = if let Ok(parent_dir) = self.save.open_dir(ino) {
+                    parent_dir
+                } else {
+                    reply.error(EIO);
+                    return;
+                };
+
+                match parent_dir.open_sub_file(name_converted) {
+                    Ok(child) => match child.delete() {
+                        Ok(()) => reply.ok(),
+                        Err(_) => reply.error(EIO),
+                    },
+                    Err(Error::NotFound) => reply.error(ENOENT),
+                    Err(_) => reply.error(EIO),
+                }
+            }
+        }
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
+        // The control file has no handle of its own. Handles of files start from 1
+        if self.manual.is_some() && ino == CONTROL_INO {
+            reply.opened(0, 0);
+            return;
+        }
+        match self.decode_ino(ino) {
+            None => reply.error(ENOENT),
+            Some(Ino::File(ino)) => match self.save.open_file(ino) {
+                Ok(file) => {
+                    self.file_fh_map.insert(self.next_fh, file);
+                    if let Some(readahead_map) = self.readahead_map.as_mut() {
+                        readahead_map.insert(self.next_fh, ReadAhead::new(ReadAhead::WINDOW));
+                    }
+                    reply.opened(self.next_fh, 0);
+                    self.next_fh += 1;
+                    self.note_open_files();
+                }
+                Err(Error::Busy) => reply.error(EBUSY),
+                Err(_) => reply.error(ENOENT),
+            },
+            Some(Ino::Dir(_)) => {
+                reply.error(EISDIR);
+            }
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        if let Some(readahead_map) = self.readahead_map.as_mut() {
+            readahead_map.remove(&fh);
+        }
+        if let Some(file) = self.file_fh_map.remove(&fh) {
+            if !self.read_only {
+                if let Err(e) = file.commit() {
+                    println!("Failed to save file: {}", e);
+                }
+            }
+        }
+        self.note_open_files();
+        reply.ok();
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        if self.manual.is_some() && ino == CONTROL_INO {
+            reply.data(&[]);
+            return;
+        }
+        let offset = offset as usize;
+        let size = size as usize;
+        if let Some(file) = self.file_fh_map.get(&fh) {
+            if size == 0 {
+                reply.data(&[]);
+                return;
+            }
+            let end = std::cmp::min(offset + size, file.len());
+            if end <= offset {
+                reply.data(&[]);
+                return;
+            }
+            if let Some(readahead) = self
+                .readahead_map
+                .as_mut()
+                .and_then(|readahead_map| readahead_map.get_mut(&fh))
+            {
+                match readahead.read(file, offset, end) {
+                    Ok(buf) => reply.data(&buf),
+                    _ => reply.error(EIO),
+                }
+                return;
+            }
+            let mut buf = vec![0; end - offset];
+            match file.read(offset, &mut buf) {
+                Ok(()) | Err(Error::HashMismatch) => reply.data(&buf),
+                _ => reply.error(EIO),
+            }
+        } else {
+            reply.error(EBADF);
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        if self.manual.is_some() && ino == CONTROL_INO {
+            match self.control(data) {
+                Ok(()) => reply.written(data.len() as u32),
+                Err(Error::Busy) => reply.error(EBUSY),
+                Err(Error::InvalidValue) => reply.error(EINVAL),
+                Err(_) => reply.error(EIO),
+            }
+            return;
+        }
+        self.note_change();
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        let offset = offset as usize;
+        if let Some(readahead_map) = self.readahead_map.as_mut() {
+            readahead_map.values_mut().for_each(ReadAhead::invalidate);
+        }
+        if let Some(file) = self.file_fh_map.get_mut(&fh) {
+            if data.is_empty() {
+                reply.written(0);
+                return;
+            }
+            self.written_files.insert(file.get_ino());
+            match file.write_all_resize(offset, data) {
+                Ok(()) => reply.written(data.len() as u32),
+                Err(Error::NoSpace) => reply.error(ENOSPC),
+                Err(_) => reply.error(EIO),
+            }
+        } else {
+            reply.error(EBADF);
+        }
+    }
+
+    fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match self.decode_ino(ino) {
+            None => reply.error(ENOENT),
+            Some(Ino::File(_)) => reply.error(ENOTDIR),
+            Some(Ino::Dir(ino)) => {
+                if let Ok(dir) = self.save.open_dir(ino) {
+                    let stream = if let Ok(stream) = self.dir_stream(&dir, ino) {
+                        stream
+                    } else {
+                        reply.error(EIO);
+                        return;
+                    };
+                    self.dir_fh_map.insert(self.next_fh, stream);
+                    reply.opened(self.next_fh, 0);
+                    self.next_fh += 1;
+                } else {
+                    reply.error(ENOENT);
+                }
+            }
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let mut stream = if let Some(stream) = self.dir_fh_map.remove(&fh) {
+            stream
+        } else {
+            reply.error(EBADF);
+            return;
+        };
+
+        let result = self.seek_dir(&mut stream, offset).and_then(|()| {
+            stream.fill(|ino, offset, file_type, name| reply.add(ino, offset, file_type, name))
+        });
+        // The handle stays valid after a failure, until releasedir
+        self.dir_fh_map.insert(fh, stream);
+        match result {
+            Ok(()) => reply.ok(),
+            Err(_) => reply.error(EIO),
+        }
+    }
+
+    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        self.dir_fh_map.remove(&fh);
+        reply.ok();
+    }
+
+    /// With `--autocommit`, syncing the mount root commits the whole archive, which is how the
+    /// autocommit thread reaches the archive. Otherwise syncing does nothing, as in other mounts
+    /// the archive is only saved on unmount or by the control file of `--manual-commit`.
+    fn fsyncdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        if self.read_only || ino != FUSE_ROOT_ID || self.pending.is_none() {
+            reply.ok();
+            return;
+        }
+        match self.commit_archive() {
+            Ok(()) => reply.ok(),
+            Err(_) => reply.error(EIO),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.note_change();
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+
+        if !InodeMap::same_fs(parent, newparent) {
+            reply.error(EXDEV);
+            return;
+        }
+
+        let name_converted: T::NameType = match name_os_to_3ds(name) {
+            Ok((n, _)) => n,
+            Err(e) => {
+                reply.error(name_errno(&e));
+                return;
+            }
+        };
+        let newname_converted: T::NameType = match name_os_to_3ds(newname) {
+            Ok((n, _)) => n,
+            Err(e) => {
+                reply.error(name_errno(&e));
+                return;
+            }
+        };
+
+        let dir = match self.decode_ino(parent) {
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+            Some(Ino::File(_)) => {
+                reply.error(ENOTDIR);
+                return;
+            }
+            Some(Ino::Dir(ino)) => match self.save.open_dir(ino) {
+                Ok(dir) => dir,
+                Err(_) => {
+                    reply.error(EIO);
+                    return;
+                }
+            },
+        };
+
+        let newdir = match self.decode_ino(newparent) {
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+            Some(Ino::File(_)) => {
+                reply.error(ENOTDIR);
+                return;
+            }
+            Some(Ino::Dir(ino)) => match self.save.open_dir(ino) {
+                Ok(dir) => dir,
+                Err(_) => {
+                    reply.error(EIO);
+                    return;
+                }
+            },
+        };
+
+        let kind = match dir.sub_metadata(name_converted.clone()) {
+            Ok(Some(kind)) => kind,
+            Ok(None) => {
+                reply.error(ENOENT);
+                return;
+            }
+            Err(_) => {
+                reply.error(EIO);
+                return;
+            }
+        };
+
+        if kind == InodeKind::File {
+            let mut file = match dir.open_sub_file(name_converted) {
+                Ok(file) => file,
+                Err(_) => {
+                    reply.error(EIO);
+                    return;
+                }
+            };
+            match newdir.open_sub_file(newname_converted.clone()) {
+                Ok(old_file) => {
+                    if old_file.delete().is_err() {
+                        reply.error(EIO);
+                        return;
+                    }
+                }
+                Err(Error::NotFound) => (),
+                Err(_) => {
+                    reply.error(EIO);
+                    return;
+                }
+            }
+
+            match file.rename(&newdir, newname_converted) {
+                Ok(()) => reply.ok(),
+                Err(Error::AlreadyExist) => reply.error(EEXIST),
+                Err(_) => reply.error(EIO),
+            }
+        } else {
+            let mut dir = match dir.open_sub_dir(name_converted) {
+                Ok(dir) => dir,
+                Err(_) => {
+                    reply.error(EIO);
+                    return;
+                }
+            };
+            match newdir.open_sub_dir(newname_converted.clone()) {
+                Ok(old_dir) => match old_dir.delete() {
+                    Ok(()) => (),
+                    Err(Error::NotEmpty) => {
+                        reply.error(ENOTEMPTY);
+                        return;
+                    }
+                    Err(_) => {
+                        reply.error(EIO);
+                        return;
+                    }
+                },
+                Err(Error::NotFound) => (),
+                Err(_) => {
+                    reply.error(EIO);
+                    return;
+                }
+            }
+
+            match dir.rename(&newdir, newname_converted) {
+                Ok(()) => reply.ok(),
+                Err(Error::AlreadyExist) => reply.error(EEXIST),
+                Err(Error::InvalidValue) => reply.error(EINVAL),
+                Err(_) => reply.error(EIO),
+            }
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        match self.save.stat() {
+            Err(_) => reply.error(EIO),
+            Ok(stat) => reply.statfs(
+                stat.total_blocks as u64,
+                stat.free_blocks as u64,
+                stat.free_blocks as u64,
+                stat.total_files as u64,
+                stat.free_files as u64,
+                stat.block_len as u32,
+                16,
+                0,
+            ),
+        }
+    }
+}
+
+fn print_usage(program: &str, opts: Options) {
+    let brief = format!(
+        "Usage: {} [OPTIONS] [MOUNT_PATH]
+
+MOUNT_PATH is the FUSE mount point when mounting the archive.
+With --extract or --import, it serves as the destination or source directory
+unless --output or --input is specified.",
+        program
+    );
+    print!("{}", opts.usage(&brief));
+}
+
+fn check_format_param_keys(
+    raw: &HashMap<String, String>,
+    valid_keys: &[&str],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut unknown_keys: Vec<&str> = raw
+        .keys()
+        .map(|k| k.as_str())
+        .filter(|k| !valid_keys.contains(k))
+        .collect();
+    if !unknown_keys.is_empty() {
+        unknown_keys.sort_unstable();
+        println!(
+            "Unknown format parameter(s): {}. Valid parameters are: {}",
+            unknown_keys.join(", "),
+            valid_keys.join(", ")
+        );
+        return Err(Box::from(Error::InvalidValue));
+    }
+    Ok(())
+}
+
+const EXT_DATA_FORMAT_KEYS: &[&str] = &["max_dir", "dir_buckets", "max_file", "file_buckets"];
+
+const SAVE_DATA_FORMAT_KEYS: &[&str] = &[
+    "block_len",
+    "max_dir",
+    "dir_buckets",
+    "max_file",
+    "file_buckets",
+    "duplicate_data",
+    "len",
+];
+
+fn to_ext_data_format_param(
+    raw: HashMap<String, String>,
+) -> Result<ExtDataFormatParam, Box<dyn std::error::Error>> {
+    check_format_param_keys(&raw, EXT_DATA_FORMAT_KEYS)?;
+
+    let max_dir = raw
+        .get("max_dir")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or(100);
+
+    let dir_buckets = raw
+        .get("dir_buckets")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or_else(|| default_buckets(max_dir));
+
+    let max_file = raw
+        .get("max_file")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or(100);
+
+    let file_buckets = raw
+        .get("file_buckets")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or_else(|| default_buckets(max_file));
+
+    Ok(ExtDataFormatParam {
+        max_dir,
+        dir_buckets,
+        max_file,
+        file_buckets,
+        mount_path: [[0; 0x10]; 0x10],
+    })
+}
+
+/// Parses the argument of `--sign`, which is one of `sd:<title ID>`, `nand:<save ID>` or `cart`.
+fn parse_sign_target(s: &str) -> Option<BareSignTarget> {
+    match s.split_once(':') {
+        Some(("sd", id)) => u64::from_str_radix(id, 16).ok().map(BareSignTarget::Sd),
+        Some(("nand", id)) => u32::from_str_radix(id, 16).ok().map(BareSignTarget::Nand),
+        None if s == "cart" => Some(BareSignTarget::Cart),
+        _ => None,
+    }
+}
+
+fn to_save_data_format_param(
+    raw: HashMap<String, String>,
+    default_block_len: usize,
+) -> Result<(SaveDataFormatParam, usize), Box<dyn std::error::Error>> {
+    check_format_param_keys(&raw, SAVE_DATA_FORMAT_KEYS)?;
+
+    let block_len = raw
+        .get("block_len")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or(default_block_len);
+
+    let block_type = match block_len {
+        512 => SaveDataBlockType::Small,
+        4096 => SaveDataBlockType::Large,
+        _ => {
+            println!("Unsupported block_len value");
+            return Err(Box::from(Error::InvalidValue));
+        }
+    };
+
+    let max_dir = raw
+        .get("max_dir")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or(100);
+
+    let dir_buckets = raw
+        .get("dir_buckets")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or_else(|| default_buckets(max_dir));
+
+    let max_file = raw
+        .get("max_file")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or(100);
+
+    let file_buckets = raw
+        .get("file_buckets")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or_else(|| default_buckets(max_file));
+
+    let duplicate_data = raw
+        .get("duplicate_data")
+        .map(|s| s.parse::<bool>())
+        .transpose()?
+        .unwrap_or(true);
+
+    let len = raw
+        .get("len")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or(512 * 1024);
+
+    Ok((
+        SaveDataFormatParam {
+            block_type,
+            max_dir,
+            dir_buckets,
+            max_file,
+            file_buckets,
+            duplicate_data,
+        },
+        len,
+    ))
+}
+
+/// Parses a title list in which each line is in the form of `ID,NAME`.
+/// Empty lines and lines starting with `#` are ignored.
+fn parse_title_list(content: &str) -> Result<Vec<(u64, String)>, Box<dyn std::error::Error>> {
+    let mut titles = vec![];
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (id, name) = if let Some(mid) = line.find(',') {
+            let (l, r) = line.split_at(mid);
+            (l.trim(), r[1..].trim())
+        } else {
+            println!("Malformed title list line: {}", line);
+            return Err(Box::from(Error::InvalidValue));
+        };
+        let id = u64::from_str_radix(id.trim_start_matches("0x"), 16)?;
+        titles.push((id, name.trim_matches('"').to_owned()));
+    }
+    Ok(titles)
+}
+
+/// Finds titles whose name contains `name`, ignoring case.
+fn find_title_id<'a>(titles: &'a [(u64, String)], name: &str) -> Vec<&'a (u64, String)> {
+    let name = name.to_lowercase();
+    titles
+        .iter()
+        .filter(|(_, n)| n.to_lowercase().contains(&name))
+        .collect()
+}
+
+/// Renders a `FormatReport` as `key=value` lines, for scripts to parse.
+fn format_report_text(report: &FormatReport) -> String {
+    let mut text = format!("fits={}\nblock_count={}\n", report.fits, report.block_count);
+    if let Some(stat) = report.stat {
+        text += &format!(
+            "block_len={}\ntotal_blocks={}\nfree_blocks={}\n\
+             total_files={}\nfree_files={}\ntotal_dirs={}\nfree_dirs={}\n",
+            stat.block_len,
+            stat.total_blocks,
+            stat.free_blocks,
+            stat.total_files,
+            stat.free_files,
+            stat.total_dirs,
+            stat.free_dirs
+        );
+    }
+    text
+}
+
+/// Renders a `Summary` for `--count`, either for humans or as a JSON object.
+/// `table_storage` and `metadata_hash` are `None` for archives other than save data.
+fn summary_text(
+    summary: &Summary,
+    fingerprint: &[u8; 32],
+    table_storage: Option<TableStorage>,
+    metadata_hash: Option<[u8; 32]>,
+    json: bool,
+) -> String {
+    let stat = &summary.stat;
+    let fingerprint = hex(fingerprint);
+    if json {
+        let table_storage = table_storage
+            .map(|t| format!("\"{}\"", t))
+            .unwrap_or_else(|| "null".to_owned());
+        let metadata_hash = metadata_hash
+            .map(|h| format!("\"{}\"", hex(&h)))
+            .unwrap_or_else(|| "null".to_owned());
+        format!(
+            "{{\"dirs\":{},\"files\":{},\"bytes\":{},\"block_len\":{},\
+             \"total_blocks\":{},\"free_blocks\":{},\"total_files\":{},\"free_files\":{},\
+             \"total_dirs\":{},\"free_dirs\":{},\"table_storage\":{},\"fingerprint\":\"{}\",\
+             \"metadata_hash\":{}}}\n",
+            summary.dirs,
+            summary.files,
+            summary.bytes,
+            stat.block_len,
+            stat.total_blocks,
+            stat.free_blocks,
+            stat.total_files,
+            stat.free_files,
+            stat.total_dirs,
+            stat.free_dirs,
+            table_storage,
+            fingerprint,
+            metadata_hash
+        )
+    } else {
+        let table_storage = table_storage
+            .map(|t| format!("Table storage: {}\n", t))
+            .unwrap_or_default();
+        let metadata_hash = metadata_hash
+            .map(|h| format!("Metadata hash: {}\n", hex(&h)))
+            .unwrap_or_default();
+        format!(
+            "Directories: {}\nFiles: {}\nTotal file size: {} bytes\n\
+             Allocated blocks: {} of {} ({} bytes each)\n\
+             Free: {} blocks ({} bytes), {} file slots, {} directory slots\n\
+             {}Content fingerprint: {}\n{}",
+            summary.dirs,
+            summary.files,
+            summary.bytes,
+            stat.total_blocks - stat.free_blocks,
+            stat.total_blocks,
+            stat.block_len,
+            stat.free_blocks,
+            stat.free_blocks * stat.block_len,
+            stat.free_files,
+            stat.free_dirs,
+            table_storage,
+            fingerprint,
+            metadata_hash
+        )
+    }
+}
+
+/// Renders the result of `inode_map` for `--list --json`, as a JSON object with the paths of
+/// directories and files keyed by inode in ascending order. Each file also has the logical
+/// length and the physical length from `file_lens`, the latter `null` if there is none.
+fn inode_map_json<N: NameConvert>(
+    map: &libsave3ds::file_system::InodeMap<N>,
+    file_lens: &HashMap<u32, (usize, Option<usize>)>,
+) -> String {
+    let entries = |inodes: &HashMap<u32, Vec<N>>, entry: &dyn Fn(u32, String) -> String| {
+        let mut inodes: Vec<_> = inodes.iter().collect();
+        inodes.sort_by_key(|(&ino, _)| ino);
+        inodes
+            .into_iter()
+            .map(|(&ino, path)| {
+                let path: String = path
+                    .iter()
+                    .map(|name| format!("/{}", N::name_3ds_to_str(name)))
+                    .collect();
+                format!("\"{}\":{}", ino, entry(ino, json_string(&path)))
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let file = |ino, path| {
+        let (len, physical_len) = file_lens[&ino];
+        let physical_len = physical_len
+            .map(|len| len.to_string())
+            .unwrap_or_else(|| "null".to_owned());
+        format!(
+            "{{\"path\":{},\"len\":{},\"physical_len\":{}}}",
+            path, len, physical_len
+        )
+    };
+    format!(
+        "{{\"dirs\":{{{}}},\"files\":{{{}}}}}\n",
+        entries(&map.dirs, &|_, path| path),
+        entries(&map.files, &file)
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Zeroes the action field of `ext` for `--reset-action`.
+fn reset_action_impl(ext: &ExtData) -> Result<(), Error> {
+    let action = ext.action()?;
+    if action == 0 {
+        println!("The action field is already 0");
+        return Ok(());
+    }
+    ext.reset_action()?;
+    println!("Reset the action field from {} to 0", action);
+    Ok(())
+}
+
+/// Renders the result of `dump_headers` for `--dump-headers`, either for humans or as a
+/// JSON array. Byte arrays are printed in hexadecimal, with the text for humans if it is
+/// printable ASCII, such as a magic.
+fn headers_text(dumps: &[HeaderDump], json: bool) -> String {
+    let mut text = String::new();
+    if json {
+        let headers: Vec<String> = dumps
+            .iter()
+            .map(|dump| {
+                let fields: Vec<String> = dump
+                    .fields
+                    .iter()
+                    .map(|(name, value)| match value {
+                        FieldValue::Int(v) => format!("\"{}\":{}", name, v),
+                        FieldValue::Bytes(v) => format!("\"{}\":\"{}\"", name, hex(v)),
+                    })
+                    .collect();
+                format!(
+                    "{{\"name\":\"{}\",\"fields\":{{{}}}}}",
+                    dump.name,
+                    fields.join(",")
+                )
+            })
+            .collect();
+        text += &format!("[{}]\n", headers.join(","));
+    } else {
+        for dump in dumps {
+            text += &format!("[{}]\n", dump.name);
+            for (name, value) in &dump.fields {
+                match value {
+                    FieldValue::Int(v) => text += &format!("{} = {} (0x{:x})\n", name, v, v),
+                    FieldValue::Bytes(v) if v.iter().all(|c| (32..127).contains(c)) => {
+                        text +=
+                            &format!("{} = {} \"{}\"\n", name, hex(v), String::from_utf8_lossy(v))
+                    }
+                    FieldValue::Bytes(v) => text += &format!("{} = {}\n", name, hex(v)),
+                }
+            }
+        }
+    }
+    text
+}
+
+/// Renders a `CartInfo` as `key=value` lines, for scripts to parse.
+fn cart_info_text(info: &CartInfo) -> String {
+    format!(
+        "program_id={:016x}\nproduct_code={}\nmaker_code={}\n\
+         ncch_version={}\ncrypto_version={}\nwear_leveling={}\n",
+        info.program_id,
+        info.product_code,
+        info.maker_code,
+        info.ncch_version,
+        info.crypto_version,
+        info.wear_leveling
+    )
+}
+
+/// Formats title entries as CSV, with a header line.
+fn titles_csv(titles: &[TitleEntry]) -> String {
+    let mut csv = "title_id,size,type,version,tmd_content_id,product_code\n".to_owned();
+    for title in titles {
+        csv += &format!(
+            "{:016x},{},{:x},{},{:08x},{}\n",
+            title.title_id,
+            title.size,
+            title.title_type,
+            title.version,
+            title.tmd_content_id,
+            title.product_code
+        );
+    }
+    csv
+}
+
+/// Renders the result of `SaveData::dpfs_state` as `key=value` lines for `--debug-dpfs`.
+/// Selectors are printed as hexadecimal 32-bit chunks, and the active copy of each block
+/// as a string of `0` and `1`.
+fn dpfs_state_text(states: &[DpfsState]) -> String {
+    let mut text = String::new();
+    for state in states {
+        text += &format!(
+            "partition={}
+level1_selector={}
+",
+            state.partition, state.level1_selector
+        );
+        for (i, level) in [&state.level2, &state.level3].iter().enumerate() {
+            let selector: Vec<String> = level
+                .selector
+                .iter()
+                .map(|c| format!("{:08x}", c))
+                .collect();
+            let active: String = level
+                .active
+                .iter()
+                .map(|&a| if a == 0 { '0' } else { '1' })
+                .collect();
+            text += &format!(
+                "level{0}_block_len={1}
+level{0}_selector={2}
+level{0}_active={3}
+",
+                i + 2,
+                level.block_len,
+                selector.join(" "),
+                active
+            );
+        }
+    }
+    text
+}
+
+fn capabilities_text(capabilities: &Capabilities) -> String {
+    format!(
+        "sd_save={}\nsd_ext={}\nsd_db={}\nnand_save={}\nnand_ext={}\nnand_db={}\ncart_save={}\n",
+        capabilities.sd_save,
+        capabilities.sd_ext,
+        capabilities.sd_db,
+        capabilities.nand_save,
+        capabilities.nand_ext,
+        capabilities.nand_db,
+        capabilities.cart_save
+    )
+}
+
+/// Prints the SD tree, followed by the IDs of save data and extdata found in it. Each ID is
+/// marked `ok` if its main file decrypts to the expected magic, `unrecognized` otherwise, or
+/// `missing` if an extdata directory has no VSXE file.
+fn sd_map_text(map: &SdMap) -> String {
+    let mut text = format!("id0={}\nid1={}\n", map.id0, map.id1);
+    for entry in &map.entries {
+        if entry.is_dir {
+            text += &format!("{}/\n", entry.path);
+        } else {
+            text += &format!(
+                "{} len={} magic={}\n",
+                entry.path,
+                entry.len,
+                entry.magic.as_deref().unwrap_or("none")
+            );
+        }
+    }
+
+    let status = |path: &str, magic: &str| match map.entries.iter().find(|e| e.path == path) {
+        Some(entry) if entry.magic.as_deref() == Some(magic) => "ok",
+        Some(_) => "unrecognized",
+        None => "missing",
+    };
+    for entry in &map.entries {
+        let parts: Vec<&str> = entry.path.split('/').collect();
+        match parts[..] {
+            ["title", high, low, "data", "00000001.sav"] => {
+                text += &format!("sd_save={}{} {}\n", high, low, status(&entry.path, "DISA"))
+            }
+            ["extdata", high, low] if entry.is_dir => {
+                let vsxe = format!("{}/00000000/00000001", entry.path);
+                text += &format!("sd_ext={}{} {}\n", high, low, status(&vsxe, "DIFF"))
+            }
+            _ => (),
+        }
+    }
+    text
+}
+
+fn read_key(s: String) -> std::io::Result<[u8; 16]> {
+    let mut key = [0; 16];
+    if s.len() == 32 {
+        let mut success = true;
+        for i in 0..16 {
+            if let Ok(v) = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16) {
+                key[i] = v;
+            } else {
+                success = false;
+                break;
+            }
+        }
+        if success {
+            return Ok(key);
+        }
+    }
+    let mut file = std::fs::File::open(s)?;
+    file.read_exact(&mut key)?;
+    Ok(key)
+}
+
+fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optflag(
+        "",
+        "allow-inconsistent",
+        "open a file from --bare even if its FAT has more entries than data blocks",
+    );
+    opts.optopt(
+        "",
+        "autocommit",
+        "in mount mode, also save after SECONDS without changes while no file is open",
+        "SECONDS",
+    );
+    opts.optopt("", "bare", "mount a bare DISA file", "FILE");
+    opts.optopt("b", "boot9", "boot9.bin file path", "FILE");
+    opts.optopt("c", "cart", "(experimental) mount a cartridge save", "FILE");
+    opts.optopt(
+        "",
+        "cat",
+        "write the content of the file at PATH in the archive to stdout",
+        "PATH",
+    );
+    opts.optflag(
+        "",
+        "cart-info",
+        "print the title information of the game from --game",
+    );
+    opts.optflag(
+        "",
+        "count",
+        "print the numbers of directories and files, total file size and free space",
+    );
+    opts.optopt(
+        "",
+        "db",
+        "mount a database. DB_TYPE is one of the following:
+    nandtitle, nandimport, tmptitle, tmpimport, sdtitle, sdimport, ticket",
+        "DB_TYPE",
+    );
+    opts.optflag(
+        "",
+        "debug-dpfs",
+        "print which DPFS copy is active for each block of the image from --bare",
+    );
+    opts.optopt(
+        "",
+        "decrypt",
+        "decrypt the file at PATH in the SD tree to MOUNT_PATH, without opening it as an archive",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "encrypt",
+        "encrypt MOUNT_PATH to the file at PATH in the SD tree, replacing the file",
+        "PATH",
+    );
+    opts.optflag(
+        "",
+        "dry-run",
+        "with --dump-all-nand, only list the archives that would be extracted",
+    );
+    opts.optopt(
+        "",
+        "dump-all-nand",
+        "extract every save data and extdata on the NAND from --nand into OUTDIR",
+        "OUTDIR",
+    );
+    opts.optflag(
+        "",
+        "dump-headers",
+        "print every header structure of the archive, for bug reports",
+    );
+    opts.optflag("x", "extract", "extract the content instead of mounting");
+    opts.optopt(
+        "",
+        "export-titles",
+        "write the title entries of the database from --db to FILE as CSV",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "fill-byte",
+        "fill data failing hash verification, such as uninitialized data, with the hex byte BYTE instead of DD",
+        "BYTE",
+    );
+    opts.optopt(
+        "",
+        "force-block-type",
+        "recovery: read a file from --bare with BLOCK_LEN (512 or 4096) instead of the recorded block length",
+        "BLOCK_LEN",
+    );
+    opts.optopt(
+        "f",
+        "format",
+        "format the specified archive",
+        "[\"\"|param1:value1[,...]]",
+    );
+    opts.optflag(
+        "",
+        "format-preflight",
+        "print the capacity that --format would produce, without formatting",
+    );
+    opts.optopt("g", "game", "cartridge ROM in CCI/NCSD format", "FILE");
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("i", "import", "import the content instead of mounting");
+    opts.optopt(
+        "",
+        "import-order",
+        "list the entries of each imported directory in the host order or sorted by name",
+        "host|sorted",
+    );
+    opts.optopt(
+        "",
+        "id-from-name",
+        "print the IDs of titles whose name contains NAME, using the list from --title-list",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "import-tar",
+        "import the content from a tar archive instead of mounting. Use - for stdin",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "input",
+        "source directory for --import, overriding MOUNT_PATH",
+        "DIR",
+    );
+    opts.optflag(
+        "",
+        "json",
+        "print the result of --count, --dump-headers or --list as JSON",
+    );
+    opts.optopt(
+        "k",
+        "key",
+        "AES slot 0x2F key Y for decrypting v6.0 cartridge save",
+        "HEX|FILE",
+    );
+    opts.optopt(
+        "",
+        "key19x",
+        "AES slot 0x19 key X for decrypting New3DS exclusive cartridge save",
+        "HEX|FILE",
+    );
+    opts.optopt(
+        "",
+        "key1ax",
+        "AES slot 0x1A key X for decrypting New3DS exclusive cartridge save",
+        "HEX|FILE",
+    );
+    opts.optopt(
+        "",
+        "length",
+        "number of bytes to write for --cat. Default to the rest of the file",
+        "N",
+    );
+    opts.optflag("l", "list", "print the directory tree of the archive");
+    opts.optopt(
+        "",
+        "manifest",
+        "with --import or --import-tar, decode names with the encoding recorded in the --since \
+         manifest FILE",
+        "FILE",
+    );
+    opts.optflag(
+        "",
+        "manual-commit",
+        "in mount mode, keep changes in memory until \"commit\" or \"discard\" is written to \
+         .save3ds_control in the mount root, and discard them on unmount",
+    );
+    opts.optopt("m", "movable", "movable.sed file path", "FILE");
+    opts.optopt(
+        "",
+        "name-encoding",
+        "how names are encoded as host file names for --dump-all-nand, --extract, --import and \
+         --import-tar: escape (default), hex, base64 or quoted",
+        "SCHEME",
+    );
+    opts.optopt("", "nand", "NAND root path", "DIR");
+    opts.optopt("", "nandext", "mount the NAND Extdata with the ID", "ID");
+    opts.optopt("", "nandsave", "mount the NAND save with the ID", "ID");
+    opts.optopt("o", "otp", "OTP file path", "FILE");
+    opts.optopt("", "offset", "starting byte offset for --cat", "N");
+    opts.optopt(
+        "",
+        "output",
+        "destination directory for --extract, overriding MOUNT_PATH",
+        "DIR",
+    );
+    opts.optflag(
+        "",
+        "no-wear-leveling",
+        "treat the cartridge save as a flat image without wear leveling",
+    );
+    opts.optopt("p", "priv", "cartridge private header path", "FILE");
+    opts.optflag(
+        "",
+        "readahead",
+        "prefetch file data on sequential reads in mount mode",
+    );
+    opts.optflag(
+        "",
+        "raw-names",
+        "with --extract or --import, name host files by their index in the directory, and keep \
+         the archive names in names.json of each directory",
+    );
+    opts.optflag("r", "readonly", "mount as read-only file system");
+    opts.optflag(
+        "",
+        "reset-action",
+        "zero the action field that an interrupted operation left in the header of --sdext or \
+         --nandext, after checking that the extdata is readable",
+    );
+    opts.optopt(
+        "",
+        "since",
+        "with --extract, only write files changed since the manifest FILE, and update it",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "snapshot-path",
+        "with --manual-commit, keep a copy of the last committed content in DIR",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "subdir",
+        "mount the specified sub directory of the archive as the root",
+        "PATH",
+    );
+    opts.optopt("", "sd", "SD root path", "DIR");
+    opts.optflag(
+        "",
+        "sd-map",
+        "print the decrypted directory tree of the SD, and whether each file is recognized",
+    );
+    opts.optopt("", "sdext", "mount the SD Extdata with the ID", "ID");
+    opts.optopt("", "sdsave", "mount the SD save with the ID", "ID");
+    opts.optopt(
+        "",
+        "sign",
+        "with --bare, re-sign the save afterwards for where it goes to on the console",
+        "sd:ID|nand:ID|cart",
+    );
+    opts.optflag(
+        "t",
+        "touch",
+        "just try opening and closing the archive, after reporting what the given resources can open",
+    );
+    opts.optopt(
+        "",
+        "title-list",
+        "title list file for --id-from-name. Each line is in the form of ID,NAME",
+        "FILE",
+    );
+    opts.optflagmulti("v", "verbose", "more v for more verbose logging");
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            println!("Failed to parse the arguments: {}", f);
+            print_usage(&program, opts);
+            return Ok(());
+        }
+    };
+
+    let verbose = matches.opt_count("verbose");
+    stderrlog::new()
+        .module(module_path!())
+        .module("libsave3ds")
+        .verbosity(verbose)
+        .init()?;
+
+    if matches.opt_present("h") {
+        print_usage(&program, opts);
+        return Ok(());
+    }
+
+    if let Some(name) = matches.opt_str("id-from-name") {
+        let title_list = if let Some(title_list) = matches.opt_str("title-list") {
+            title_list
+        } else {
+            println!("--id-from-name requires a title list specified by --title-list");
+            return Ok(());
+        };
+        let titles = parse_title_list(&std::fs::read_to_string(title_list)?)?;
+        let candidates = find_title_id(&titles, &name);
+        if candidates.is_empty() {
+            println!("No title found for {}", name);
+        }
+        for (id, name) in candidates {
+            println!("{:016x} {}", id, name);
+        }
+        return Ok(());
+    }
+
+    if matches.opt_present("cart-info") {
+        let game_path = if let Some(game_path) = matches.opt_str("game") {
+            game_path
+        } else {
+            println!("--cart-info requires a game specified by --game");
+            return Ok(());
+        };
+        print!("{}", cart_info_text(&Resource::cart_info(&game_path)?));
+        return Ok(());
+    }
+
+    let touch = matches.opt_present("touch");
+    let import = matches.opt_present("import");
+    let extract = matches.opt_present("extract");
+    let list = matches.opt_present("list");
+    let import_tar = matches.opt_str("import-tar");
+    let cat_path = matches.opt_str("cat");
+    let count = matches.opt_present("count");
+    let export_titles = matches.opt_str("export-titles");
+    let debug_dpfs = matches.opt_present("debug-dpfs");
+    let dump_headers = matches.opt_present("dump-headers");
+    let dump_all_nand = matches.opt_str("dump-all-nand");
+    let reset_action = matches.opt_present("reset-action");
+    let sd_map = matches.opt_present("sd-map");
+
+    if touch as i32
+        + count as i32
+        + dump_all_nand.is_some() as i32
+        + debug_dpfs as i32
+        + dump_headers as i32
+        + export_titles.is_some() as i32
+        + import as i32
+        + extract as i32
+        + list as i32
+        + import_tar.is_some() as i32
+        + cat_path.is_some() as i32
+        + reset_action as i32
+        + sd_map as i32
+        > 1
+    {
+        println!(
+            "At most one of the following can be specified:
+    --cat, --count, --debug-dpfs, --dump-all-nand, --dump-headers, --export-titles, --extract,
+    --import, --import-tar, --list, --reset-action, --sd-map, --touch "
+        );
+        return Ok(());
+    }
+
+    let cat_offset = matches.opt_str("offset").map(|s| s.parse()).transpose()?;
+    let cat_length = matches.opt_str("length").map(|s| s.parse()).transpose()?;
+    if (cat_offset.is_some() || cat_length.is_some()) && cat_path.is_none() {
+        println!("--offset and --length can only be used with --cat");
+        return Ok(());
+    }
+
+    let dry_run = matches.opt_present("dry-run");
+    if dry_run && dump_all_nand.is_none() {
+        println!("--dry-run can only be used with --dump-all-nand");
+        return Ok(());
+    }
+
+    let since = matches.opt_str("since");
+    if since.is_some() && !extract {
+        println!("--since can only be used with --extract");
+        return Ok(());
+    }
+
+    let name_encoding = match matches
+        .opt_str("name-encoding")
+        .map(|s| s.parse::<NameEncoding>())
+        .transpose()
+    {
+        Ok(name_encoding) => name_encoding,
+        Err(_) => {
+            println!("--name-encoding must be one of escape, hex, base64 or quoted");
+            return Ok(());
+        }
+    };
+    if name_encoding.is_some()
+        && !extract
+        && !import
+        && import_tar.is_none()
+        && dump_all_nand.is_none()
+    {
+        println!(
+            "--name-encoding can only be used with --dump-all-nand, --extract, --import or --import-tar"
+        );
+        return Ok(());
+    }
+
+    let raw_names = matches.opt_present("raw-names");
+    if raw_names && !extract && !import {
+        println!("--raw-names can only be used with --extract or --import");
+        return Ok(());
+    }
+    if raw_names && (name_encoding.is_some() || since.is_some() || matches.opt_present("manifest"))
+    {
+        println!("--raw-names can't be used with --manifest, --name-encoding or --since");
+        return Ok(());
+    }
+
+    let import_order = match matches.opt_str("import-order").as_deref() {
+        None => None,
+        Some("host") => Some(ImportOrder::Host),
+        Some("sorted") => Some(ImportOrder::Sorted),
+        Some(_) => {
+            println!("--import-order must be one of host or sorted");
+            return Ok(());
+        }
+    };
+    if import_order.is_some() && (!import || raw_names) {
+        println!("--import-order can only be used with --import, without --raw-names");
+        return Ok(());
+    }
+
+    let manifest = matches.opt_str("manifest");
+    if manifest.is_some() && !import && import_tar.is_none() {
+        println!("--manifest can only be used with --import or --import-tar");
+        return Ok(());
+    }
+    let name_encoding = if let Some(manifest) = manifest {
+        let manifest_encoding = manifest_encoding(std::path::Path::new(&manifest))?;
+        if name_encoding.is_some_and(|e| e != manifest_encoding) {
+            println!(
+                "--name-encoding conflicts with the encoding {} recorded in the manifest",
+                manifest_encoding
+            );
+            return Ok(());
+        }
+        manifest_encoding
+    } else {
+        name_encoding.unwrap_or(NameEncoding::Escape)
+    };
+
+    let json = matches.opt_present("json");
+    if json && !count && !dump_headers && !list {
+        println!("--json can only be used with --count, --dump-headers or --list");
+        return Ok(());
+    }
+
+    let format_preflight = matches.opt_present("format-preflight");
+    let no_mountpoint = touch
+        || count
+        || dump_all_nand.is_some()
+        || list
+        || import_tar.is_some()
+        || cat_path.is_some()
+        || export_titles.is_some()
+        || debug_dpfs
+        || dump_headers
+        || format_preflight
+        || reset_action
+        || sd_map;
+    let force_block_type = matches
+        .opt_str("force-block-type")
+        .map(|s| match s.as_str() {
+            "512" => Ok(SaveDataBlockType::Small),
+            "4096" => Ok(SaveDataBlockType::Large),
+            _ => {
+                println!("Unsupported --force-block-type value");
+                Err(Error::InvalidValue)
+            }
+        })
+        .transpose()?;
+
+    let read_only = matches.opt_present("r")
+        || force_block_type.is_some()
+        || extract
+        || touch
+        || count
+        || list
+        || cat_path.is_some()
+        || export_titles.is_some()
+        || debug_dpfs
+        || dump_headers
+        || sd_map;
+
+    let manual_commit = matches.opt_present("manual-commit");
+    if manual_commit && (no_mountpoint || extract || import || read_only) {
+        println!("--manual-commit can only be used in read-write mount mode");
+        return Ok(());
+    }
+    let snapshot_path = matches.opt_str("snapshot-path");
+    if snapshot_path.is_some() && !manual_commit {
+        println!("--snapshot-path can only be used with --manual-commit");
+        return Ok(());
+    }
+    let overlay = manual_commit.then(|| Rc::new(Overlay::new()));
+
+    let autocommit = matches
+        .opt_str("autocommit")
+        .map(|s| s.parse().map(Duration::from_secs))
+        .transpose()?;
+    if autocommit.is_some() && (no_mountpoint || extract || import || read_only || manual_commit) {
+        println!("--autocommit can only be used in read-write mount mode, without --manual-commit");
+        return Ok(());
+    }
+
+    let operation = if extract && raw_names {
+        FileSystemOperation::ExtractRaw
+    } else if import && raw_names {
+        FileSystemOperation::ImportRaw
+    } else if extract {
+        FileSystemOperation::Extract {
+            since,
+            name_encoding,
+        }
+    } else if import {
+        FileSystemOperation::Import(name_encoding, import_order)
+    } else if let Some(tar_path) = import_tar {
+        FileSystemOperation::ImportTar(tar_path, name_encoding)
+    } else if let Some(path) = cat_path {
+        FileSystemOperation::Cat {
+            path,
+            offset: cat_offset.unwrap_or(0),
+            length: cat_length,
+        }
+    } else if count {
+        FileSystemOperation::Count { json }
+    } else if dump_headers {
+        FileSystemOperation::DumpHeaders { json }
+    } else if list {
+        FileSystemOperation::List { json }
+    } else if touch {
+        FileSystemOperation::Touch
+    } else {
+        FileSystemOperation::Mount {
+            read_only,
+            readahead: matches.opt_present("readahead"),
+            manual_commit: overlay.clone(),
+            snapshot_path,
+            autocommit,
+            subdir: matches.opt_str("subdir"),
+        }
+    };
+
+    let input_path = matches.opt_str("input");
+    let output_path = matches.opt_str("output");
+
+    if input_path.is_some() && !import {
+        println!("--input can only be used with --import");
+        return Ok(());
+    }
+
+    if output_path.is_some() && !extract {
+        println!("--output can only be used with --extract");
+        return Ok(());
+    }
+
+    let explicit_path = input_path.or(output_path);
+
+    if matches.free.len() > 1
+        || (matches.free.is_empty() && !no_mountpoint && explicit_path.is_none())
+    {
+        println!("Please specify one mount path");
+        return Ok(());
+    }
+
+    let mountpoint = if let Some(path) = explicit_path.as_ref() {
+        std::path::Path::new(path)
+    } else if no_mountpoint {
+        std::path::Path::new("dummy")
+    } else {
+        std::path::Path::new(&matches.free[0])
+    };
+
+    let boot9_path = matches.opt_str("boot9");
+    let movable_path = matches.opt_str("movable");
+    let otp_path = matches.opt_str("otp");
+    let bare_path = matches.opt_str("bare");
+    let sign_target = match matches.opt_str("sign") {
+        None => None,
+        Some(s) => match parse_sign_target(&s) {
+            Some(target) => Some(target),
+            None => {
+                println!("--sign must be one of sd:<title ID>, nand:<save ID> or cart");
+                return Ok(());
+            }
+        },
+    };
+    if sign_target.is_some() && (bare_path.is_none() || (read_only && !touch)) {
+        println!("--sign can only be used with --bare, without read-only modes other than --touch");
+        return Ok(());
+    }
+    let cart_path = matches.opt_str("cart");
+    let sd_path = matches.opt_str("sd");
+    let sd_save_id = matches.opt_str("sdsave");
+    let sd_ext_id = matches.opt_str("sdext");
+    let nand_path = matches.opt_str("nand");
+    let nand_ext_id = matches.opt_str("nandext");
+    let nand_save_id = matches.opt_str("nandsave");
+    let db_type = matches.opt_str("db");
+    let format_param = matches.opt_str("format");
+    let priv_path = matches.opt_str("priv");
+    let game_path = matches.opt_str("game");
+    let x2f_key_y = matches.opt_str("key");
+    let x19_key_x = matches.opt_str("key19x");
+    let x1a_key_x = matches.opt_str("key1ax");
+
+    let x2f_key_y = x2f_key_y.map(read_key).transpose()?;
+    let x19_key_x = x19_key_x.map(read_key).transpose()?;
+    let x1a_key_x = x1a_key_x.map(read_key).transpose()?;
+    let fill_byte = matches
+        .opt_str("fill-byte")
+        .map(|s| u8::from_str_radix(s.trim_start_matches("0x"), 16))
+        .transpose()?;
+
+    let format_param: Option<HashMap<String, String>> = format_param.map(|s| {
+        s.split(',')
+            .filter_map(|p| {
+                if let Some(mid) = p.find(':') {
+                    let (l, r) = p.split_at(mid);
+                    Some((l.to_owned(), r[1..].to_owned()))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    });
+
+    let decrypt = matches.opt_str("decrypt");
+    let encrypt = matches.opt_str("encrypt");
+    let sd_crypto = decrypt.is_some() || encrypt.is_some();
+
+    let archive_count = [
+        &sd_save_id,
+        &sd_ext_id,
+        &nand_save_id,
+        &nand_ext_id,
+        &bare_path,
+        &db_type,
+        &cart_path,
+    ]
+    .iter()
+    .map(|x| if x.is_none() { 0 } else { 1 })
+    .sum::<i32>();
+
+    if sd_crypto {
+        if (decrypt.is_some() && encrypt.is_some())
+            || archive_count != 0
+            || format_param.is_some()
+            || !matches!(operation, FileSystemOperation::Mount { .. })
+        {
+            println!(
+                "--decrypt and --encrypt can't be used together, or with an archive or other modes"
+            );
+            return Ok(());
+        }
+    } else if dump_all_nand.is_some() {
+        if archive_count != 0 || format_param.is_some() {
+            println!("--dump-all-nand can't be used with an archive or --format");
+            return Ok(());
+        }
+    } else if sd_map {
+        if archive_count != 0 || format_param.is_some() {
+            println!("--sd-map can't be used with an archive or --format");
+            return Ok(());
+        }
+    } else if archive_count != 1 {
+        println!(
+            "One and only one of the following arguments must be supplied:
+    --sdext, --sdsave, --nandsave, --nandext, --bare, --db, --cart"
+        );
+        return Ok(());
+    }
+
+    if manual_commit && (sd_ext_id.is_some() || nand_ext_id.is_some()) {
+        println!("--manual-commit is not supported for extdata, whose files are saved on close");
+        return Ok(());
+    }
+
+    if manual_commit && format_param.is_some() {
+        println!("--manual-commit can't be used with --format");
+        return Ok(());
+    }
+
+    if reset_action && ((sd_ext_id.is_none() && nand_ext_id.is_none()) || read_only) {
+        println!("--reset-action can only be used with --sdext or --nandext, without -r");
+        return Ok(());
+    }
+
+    if dump_headers && db_type.is_some() {
+        println!("--dump-headers doesn't support --db");
+        return Ok(());
+    }
+
+    if export_titles.is_some() && db_type.is_none() {
+        println!("--export-titles can only be used with --db");
+        return Ok(());
+    }
+
+    let allow_inconsistent = matches.opt_present("allow-inconsistent");
+    if allow_inconsistent && bare_path.is_none() {
+        println!("--allow-inconsistent can only be used with --bare");
+        return Ok(());
+    }
+
+    if force_block_type.is_some()
+        && (bare_path.is_none() || format_param.is_some() || import || allow_inconsistent)
+    {
+        println!(
+            "--force-block-type can only be used with --bare, without --format, --import or --allow-inconsistent"
+        );
+        return Ok(());
     }
 
-    fn mknod(
-        &mut self,
-        _req: &Request<'_>,
-        parent: u64,
-        name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        _rdev: u32,
-        reply: ReplyEntry,
-    ) {
-        if self.read_only {
-            reply.error(EROFS);
-            return;
-        }
-        let (name_converted, size): (T::NameType, usize) =
-            if let Some((n, s)) = name_os_to_3ds(name) {
-                (n, str::parse::<usize>(s).unwrap_or(0))
-            } else {
-                reply.error(ENAMETOOLONG);
-                return;
-            };
-        match Ino::from_os(parent) {
-            Ino::File(_) => {
-                reply.error(ENOTDIR);
-            }
-            Ino::Dir(ino) => {
-                let parent_dir = if let Ok(parent_dir) = self.save.open_dir(ino) {
-                    parent_dir
-                } else {
-                    reply.error(EIO);
-                    return;
-                };
+    if debug_dpfs && (bare_path.is_none() || format_param.is_some()) {
+        println!("--debug-dpfs can only be used with --bare, without --format");
+        return Ok(());
+    }
 
-                match parent_dir.new_sub_file(name_converted, size) {
-                    Ok(child) => reply.entry(
-                        &Duration::new(1, 0),
-                        &make_file_attr(
-                            self.read_only,
-                            self.uid,
-                            self.gid,
-                            Ino::File(child.get_ino()).to_os(),
-                            0,
-                        ),
-                        0,
-                    ),
-                    Err(Error::AlreadyExist) => reply.error(EEXIST),
-                    Err(Error::NoSpace) => reply.error(ENOSPC),
-                    Err(_) => reply.error(EIO),
-                }
-            }
-        }
+    let mut resource = Resource::new(
+        boot9_path,
+        movable_path,
+        sd_path,
+        nand_path,
+        otp_path,
+        priv_path,
+        game_path,
+        x2f_key_y,
+        x19_key_x,
+        x1a_key_x,
+    )?;
+    if let Some(fill_byte) = fill_byte {
+        resource.set_fill_byte(fill_byte);
     }
+    resource.set_overlay(overlay);
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        if self.read_only {
-            reply.error(EROFS);
-            return;
+    if format_preflight {
+        if sd_ext_id.is_some() || nand_ext_id.is_some() || db_type.is_some() {
+            println!("--format-preflight only supports save data archives");
+            return Ok(());
         }
-        let name_converted: T::NameType = if let Some((n, _)) = name_os_to_3ds(name) {
-            n
-        } else {
-            reply.error(ENAMETOOLONG);
-            return;
-        };
+        let default_block_len = if nand_save_id.is_some() { 4096 } else { 512 };
+        let (param, len) =
+            to_save_data_format_param(format_param.unwrap_or_default(), default_block_len)?;
+        print!(
+            "{}",
+            format_report_text(&resource.format_preflight(&param, len)?)
+        );
+        return Ok(());
+    }
 
-        match Ino::from_os(parent) {
-            Ino::File(_) => {
-                reply.error(ENOTDIR);
-            }
-            Ino::Dir(ino) => {
-                let parent_dir = if let Ok(parent_dir) = self.save.open_dir(ino) {
-                    parent_dir
-                } else {
-                    reply.error(EIO);
-                    return;
-                };
+    if let Some(path) = decrypt {
+        std::fs::write(mountpoint, resource.decrypt_sd_file(&path)?)?;
+        return Ok(());
+    }
 
-                if let Ok(child) = parent_dir.open_sub_dir(name_converted) {
-                    match child.delete() {
-                        Ok(()) => reply.ok(),
-                        Err(Error::NotEmpty) => reply.error(ENOTEMPTY),
-                        Err(_) => reply.error(EIO),
-                    }
-                    return;
-                }
-                reply.error(ENOENT);
-            }
-        }
+    if let Some(path) = encrypt {
+        resource.encrypt_sd_file(&path, &std::fs::read(mountpoint)?)?;
+        return Ok(());
     }
 
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        if self.read_only {
-            reply.error(EROFS);
-            return;
+    if let Some(out) = dump_all_nand {
+        let failed = dump_all_nand_impl(
+            &resource,
+            std::path::Path::new(&out),
+            name_encoding,
+            dry_run,
+        )?;
+        if failed != 0 {
+            println!("Failed to extract {} archives", failed);
         }
-        let name_converted: T::NameType = if let Some((n, _)) = name_os_to_3ds(name) {
-            n
+        return Ok(());
+    }
+
+    if sd_map {
+        print!("{}", sd_map_text(&resource.sd_map()?));
+        return Ok(());
+    }
+
+    if touch {
+        print!("{}", capabilities_text(&resource.capabilities()));
+    }
+
+    if debug_dpfs {
+        let bare = bare_path.as_ref().unwrap();
+        let save = if let Some(block_type) = force_block_type {
+            resource.open_bare_save_with_block_type(bare, block_type)?
+        } else if allow_inconsistent {
+            resource.open_bare_save_allow_inconsistent(bare, false)?
         } else {
-            reply.error(ENAMETOOLONG);
-            return;
+            resource.open_bare_save(bare, false)?
         };
+        print!("{}", dpfs_state_text(&save.dpfs_state()?));
+        return Ok(());
+    }
 
-        match Ino::from_os(parent) {
-            Ino::File(_) => {
-                reply.error(ENOTDIR);
-            }
-            Ino::Dir(ino) => {
-                let parent_dir = if let Ok(parent_dir) = self.save.open_dir(ino) {
-                    parent_dir
-                } else {
-                    reply.error(EIO);
-                    return;
-                };
+    if let Some(bare) = bare_path {
+        if let Some(format_param) = format_param {
+            println!("Formatting...");
+            let (param, len) = to_save_data_format_param(format_param, 512)?;
+            resource.format_bare_save(&bare, &param, len)?;
+            println!("Formatting done");
+        }
 
-                if let Ok(child) = parent_dir.open_sub_file(name_converted) {
-                    match child.delete() {
-                        Ok(()) => reply.ok(),
-                        Err(_) => reply.error(EIO),
-                    }
-                    return;
-                }
-                reply.error(ENOENT);
-            }
+        if !read_only && sign_target.is_none() {
+            println!(
+                "WARNING: After modification, you need to sign the CMAC header with --sign or other tools."
+            );
         }
-    }
 
-    fn open(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
-        match Ino::from_os(ino) {
-            Ino::File(ino) => {
-                if let Ok(file) = self.save.open_file(ino) {
-                    self.file_fh_map.insert(self.next_fh, file);
-                    reply.opened(self.next_fh, 0);
-                    self.next_fh += 1;
-                } else {
-                    reply.error(ENOENT);
-                }
-            }
-            Ino::Dir(_) => {
-                reply.error(EISDIR);
+        let open = |write| {
+            if let Some(block_type) = force_block_type {
+                resource.open_bare_save_with_block_type(&bare, block_type)
+            } else if allow_inconsistent {
+                resource.open_bare_save_allow_inconsistent(&bare, write)
+            } else {
+                resource.open_bare_save(&bare, write)
             }
+        };
+        start(open(!read_only)?, open, operation, mountpoint)?;
+
+        if let Some(target) = sign_target {
+            // Releases the save held open by the overlay of --manual-commit
+            resource.set_overlay(None);
+            println!("Signing...");
+            resource.sign_bare_save(&bare, target)?;
+            println!("Signing done");
+        }
+    } else if let Some(id) = nand_save_id {
+        let id = u32::from_str_radix(&id, 16)?;
+        if let Some(format_param) = format_param {
+            println!("Formatting...");
+            let (param, len) = to_save_data_format_param(format_param, 4096)?;
+            resource.format_nand_save(id, &param, len)?;
+            println!("Formatting done");
         }
-    }
 
-    fn release(
-        &mut self,
-        _req: &Request,
-        _ino: u64,
-        fh: u64,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        _flush: bool,
-        reply: ReplyEmpty,
-    ) {
-        if let Some(file) = self.file_fh_map.remove(&fh) {
-            if !self.read_only {
-                if let Err(e) = file.commit() {
-                    println!("Failed to save file: {}", e);
-                }
-            }
+        let open = |write| resource.open_nand_save(id, write);
+        start(open(!read_only)?, open, operation, mountpoint)?
+    } else if let Some(id) = sd_save_id {
+        let id = u64::from_str_radix(&id, 16)?;
+        if let Some(format_param) = format_param {
+            println!("Formatting...");
+            let (param, len) = to_save_data_format_param(format_param, 512)?;
+            resource.format_sd_save(id, &param, len)?;
+            println!("Formatting done");
         }
-        reply.ok();
-    }
 
-    fn read(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyData,
-    ) {
-        let offset = offset as usize;
-        let size = size as usize;
-        if let Some(file) = self.file_fh_map.get(&fh) {
-            if size == 0 {
-                reply.data(&[]);
-                return;
-            }
-            let end = std::cmp::min(offset + size, file.len());
-            if end <= offset {
-                reply.data(&[]);
-                return;
-            }
-            let mut buf = vec![0; end - offset];
-            match file.read(offset, &mut buf) {
-                Ok(()) | Err(Error::HashMismatch) => reply.data(&buf),
-                _ => reply.error(EIO),
-            }
-        } else {
-            reply.error(EBADF);
+        let open = |write| resource.open_sd_save(id, write);
+        start(open(!read_only)?, open, operation, mountpoint)?
+    } else if let Some(id) = sd_ext_id {
+        let id = u64::from_str_radix(&id, 16)?;
+        if let Some(format_param) = format_param {
+            println!("Formatting...");
+            let param = to_ext_data_format_param(format_param)?;
+            resource.format_sd_ext(id, &param)?;
+            println!("Formatting done");
         }
-    }
 
-    fn write(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyWrite,
-    ) {
-        if self.read_only {
-            reply.error(EROFS);
-            return;
+        let open = |write| resource.open_sd_ext(id, write);
+        let ext = open(!read_only)?;
+        if reset_action {
+            reset_action_impl(&ext)?;
+        } else {
+            start(ext, open, operation, mountpoint)?
+        }
+    } else if let Some(id) = nand_ext_id {
+        let id = u64::from_str_radix(&id, 16)?;
+        if let Some(format_param) = format_param {
+            println!("Formatting...");
+            let param = to_ext_data_format_param(format_param)?;
+            resource.format_nand_ext(id, &param)?;
+            println!("Formatting done");
         }
 
-        let offset = offset as usize;
-        let end = offset + data.len();
-        if let Some(file) = self.file_fh_map.get_mut(&fh) {
-            if data.is_empty() {
-                reply.written(0);
-                return;
-            }
-            if end > file.len() {
-                match file.resize(end) {
-                    Ok(()) => (),
-                    Err(Error::NoSpace) => {
-                        reply.error(ENOSPC);
-                        return;
-                    }
-                    Err(_) => {
-                        reply.error(EIO);
-                        return;
-                    }
-                }
+        let open = |write| resource.open_nand_ext(id, write);
+        let ext = open(!read_only)?;
+        if reset_action {
+            reset_action_impl(&ext)?;
+        } else {
+            start(ext, open, operation, mountpoint)?
+        }
+    } else if let Some(db_type) = db_type {
+        if format_param.is_some() {
+            println!("Warning: formatting not supported");
+        }
+        let db_type = match db_type.as_ref() {
+            "nandtitle" => DbType::NandTitle,
+            "nandimport" => DbType::NandImport,
+            "tmptitle" => DbType::TmpTitle,
+            "tmpimport" => DbType::TmpImport,
+            "sdtitle" => DbType::SdTitle,
+            "sdimport" => DbType::SdImport,
+            "ticket" => DbType::Ticket,
+            _ => {
+                println!("Unknown database type {}", db_type);
+                return Ok(());
             }
+        };
 
-            match file.write(offset, &data) {
-                Ok(()) => reply.written(data.len() as u32),
-                _ => reply.error(EIO),
-            }
+        let open = |write| resource.open_db(db_type, write);
+        let db = open(!read_only)?;
+        if let Some(export_path) = export_titles {
+            std::fs::write(export_path, titles_csv(&db.list_titles()?))?;
         } else {
-            reply.error(EBADF);
+            start(db, open, operation, mountpoint)?
+        }
+    } else if let Some(cart) = cart_path {
+        if let Some(format_param) = format_param {
+            println!("Formatting...");
+            let (param, len) = to_save_data_format_param(format_param, 512)?;
+            resource.format_cart_save(&cart, &param, len)?;
+            println!("Formatting done");
         }
+        let no_wear_leveling = matches.opt_present("no-wear-leveling");
+        let open = |write| {
+            if no_wear_leveling {
+                resource.open_cart_save_flat(&cart, write)
+            } else {
+                resource.open_cart_save(&cart, write)
+            }
+        };
+        start(open(!read_only)?, open, operation, mountpoint)?
+    } else {
+        panic!()
+    };
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let result = main_inner();
+    if let Err(e) = &result {
+        println!("{}", e);
     }
+    // Printed to stderr so that it doesn't mix with --cat output
+    #[cfg(feature = "metrics")]
+    eprintln!("{}", libsave3ds::metrics::snapshot());
+    result
+}
 
-    fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
-        match Ino::from_os(ino) {
-            Ino::File(_) => reply.error(ENOTDIR),
-            Ino::Dir(ino) => {
-                if let Ok(dir) = self.save.open_dir(ino) {
-                    let parent_ino = if ino == 1 {
-                        1
-                    } else if let Ok(parent_ino) = dir.get_parent_ino() {
-                        parent_ino
-                    } else {
-                        reply.error(EIO);
-                        return;
-                    };
-                    let mut entries = vec![
-                        DirEntry {
-                            ino: Ino::Dir(ino).to_os(),
-                            file_type: FileType::Directory,
-                            name: ".".to_owned(),
-                        },
-                        DirEntry {
-                            ino: Ino::Dir(parent_ino).to_os(),
-                            file_type: FileType::Directory,
-                            name: "..".to_owned(),
-                        },
-                    ];
-
-                    let sub_dirs = if let Ok(r) = dir.list_sub_dir() {
-                        r
-                    } else {
-                        reply.error(EIO);
-                        return;
-                    };
-                    for (name, i) in sub_dirs {
-                        entries.push(DirEntry {
-                            ino: Ino::Dir(i).to_os(),
-                            file_type: FileType::Directory,
-                            name: T::NameType::name_3ds_to_str(&name),
-                        });
-                    }
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use libsave3ds::test_util::*;
 
-                    let sub_files = if let Ok(r) = dir.list_sub_file() {
-                        r
-                    } else {
-                        reply.error(EIO);
-                        return;
-                    };
-                    for (name, i) in sub_files {
-                        entries.push(DirEntry {
-                            ino: Ino::File(i).to_os(),
-                            file_type: FileType::RegularFile,
-                            name: T::NameType::name_3ds_to_str(&name),
-                        });
-                    }
+    #[test]
+    fn test_string_conversion() {
+        assert_eq!(
+            <[u8; 16]>::name_3ds_to_str(&[b'a', b'b', b'c', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            "abc"
+        );
 
-                    self.dir_fh_map.insert(self.next_fh, entries);
-                    reply.opened(self.next_fh, 0);
-                    self.next_fh += 1;
-                } else {
-                    reply.error(ENOENT);
-                }
-            }
-        }
+        assert_eq!(
+            <[u8; 16]>::name_3ds_to_str(&[
+                b'a', b'b', b'c', 0, 0, 0, b'd', 0, 0, 0, 0, 0, 0, 0, 0, 0
+            ]),
+            "abc\\x00\\x00\\x00d"
+        );
+
+        assert_eq!(
+            <[u8; 16]>::name_3ds_to_str(&[
+                b'a', b'/', b'\n', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+            ]),
+            "a\\x2f\\x0a"
+        );
+
+        assert_eq!(
+            <[u8; 16]>::name_3ds_to_str(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            "\\x00"
+        );
+
+        assert_eq!(
+            name_os_to_3ds::<[u8; 16]>(OsStr::new("abc")).ok(),
+            Some((
+                [b'a', b'b', b'c', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                ""
+            ))
+        );
+        assert_eq!(
+            name_os_to_3ds::<[u8; 16]>(OsStr::new("a\\x12c")).ok(),
+            Some((
+                [b'a', 0x12, b'c', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                ""
+            ))
+        );
+        assert_eq!(
+            name_os_to_3ds::<[u8; 16]>(OsStr::new("a\\x12\x34c")).ok(),
+            Some((
+                [b'a', 0x12, 0x34, b'c', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                ""
+            ))
+        );
+        assert_eq!(
+            name_os_to_3ds::<[u8; 16]>(OsStr::new("a\\x12\x34c\\+x\\yz")).ok(),
+            Some((
+                [b'a', 0x12, 0x34, b'c', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                "x\\yz"
+            ))
+        );
+        assert_eq!(
+            name_os_to_3ds::<[u8; 16]>(OsStr::new("a\\x12\x34c\\+")).ok(),
+            Some((
+                [b'a', 0x12, 0x34, b'c', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                ""
+            ))
+        );
+        assert_eq!(name_os_to_3ds::<[u8; 16]>(OsStr::new("a\\2c")).ok(), None);
+        assert_eq!(name_os_to_3ds::<[u8; 16]>(OsStr::new("a\\x1")).ok(), None);
+        assert_eq!(name_os_to_3ds::<[u8; 16]>(OsStr::new("a\\x")).ok(), None);
+        assert_eq!(name_os_to_3ds::<[u8; 16]>(OsStr::new("a\\")).ok(), None);
+        assert!(name_os_to_3ds::<[u8; 16]>(OsStr::new("aaaaaaaaaaaaaaaa")).is_ok());
+        assert!(matches!(
+            name_os_to_3ds::<[u8; 16]>(OsStr::new("aaaaaaaaaaaaaaaaa")),
+            Err(Error::NameTooLong)
+        ));
+        assert!(matches!(
+            name_os_to_3ds::<[u8; 16]>(OsStr::new("a\\x")),
+            Err(Error::InvalidName)
+        ));
     }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        _ino: u64,
-        fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        if let Some(entries) = self.dir_fh_map.get(&fh) {
-            for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
-                if reply.add(entry.ino, (i + 1) as i64, entry.file_type, &entry.name) {
-                    break;
-                }
-            }
-            reply.ok();
-        } else {
-            reply.error(EBADF);
-        }
+    fn make_format_param(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
     }
 
-    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
-        self.dir_fh_map.remove(&fh);
-        reply.ok();
+    #[test]
+    fn test_format_report_text() {
+        let (param, _) = to_save_data_format_param(make_format_param(&[]), 512).unwrap();
+        let resource = resource();
+        let text = format_report_text(&resource.format_preflight(&param, 1000).unwrap());
+        assert_eq!(text, "fits=false\nblock_count=0\n");
+
+        let report = resource.format_preflight(&param, 0x80000).unwrap();
+        let text = format_report_text(&report);
+        assert!(text.starts_with(&format!("fits=true\nblock_count={}\n", report.block_count)));
+        assert!(text.contains("block_len=512\n"));
+        assert!(text.contains("total_files=100\n"));
     }
 
-    fn rename(
-        &mut self,
-        _req: &Request<'_>,
-        parent: u64,
-        name: &OsStr,
-        newparent: u64,
-        newname: &OsStr,
-        _flags: u32,
-        reply: ReplyEmpty,
-    ) {
-        if self.read_only {
-            reply.error(EROFS);
-            return;
-        }
+    #[test]
+    fn test_format_param_keys() {
+        assert!(to_save_data_format_param(make_format_param(&[("blocklen", "512")]), 512).is_err());
+        assert!(to_ext_data_format_param(make_format_param(&[("len", "4096")])).is_err());
 
-        let name_converted: T::NameType = if let Some((n, _)) = name_os_to_3ds(name) {
-            n
-        } else {
-            reply.error(ENAMETOOLONG);
-            return;
-        };
-        let newname_converted: T::NameType = if let Some((n, _)) = name_os_to_3ds(newname) {
-            n
-        } else {
-            reply.error(ENAMETOOLONG);
-            return;
-        };
+        let (param, len) = to_save_data_format_param(
+            make_format_param(&[
+                ("block_len", "4096"),
+                ("max_dir", "10"),
+                ("dir_buckets", "11"),
+                ("max_file", "20"),
+                ("file_buckets", "23"),
+                ("duplicate_data", "false"),
+                ("len", "65536"),
+            ]),
+            512,
+        )
+        .unwrap();
+        assert_eq!(param.block_type, SaveDataBlockType::Large);
+        assert_eq!(param.max_dir, 10);
+        assert_eq!(param.dir_buckets, 11);
+        assert_eq!(param.max_file, 20);
+        assert_eq!(param.file_buckets, 23);
+        assert!(!param.duplicate_data);
+        assert_eq!(len, 65536);
 
-        let dir = match Ino::from_os(parent) {
-            Ino::File(_) => {
-                reply.error(ENOTDIR);
-                return;
-            }
-            Ino::Dir(ino) => match self.save.open_dir(ino) {
-                Ok(dir) => dir,
-                Err(_) => {
-                    reply.error(EIO);
-                    return;
-                }
-            },
-        };
+        let (param, _) = to_save_data_format_param(make_format_param(&[]), 512).unwrap();
+        assert_eq!(param.block_type, SaveDataBlockType::Small);
 
-        let newdir = match Ino::from_os(newparent) {
-            Ino::File(_) => {
-                reply.error(ENOTDIR);
-                return;
-            }
-            Ino::Dir(ino) => match self.save.open_dir(ino) {
-                Ok(dir) => dir,
-                Err(_) => {
-                    reply.error(EIO);
-                    return;
-                }
-            },
-        };
+        let param =
+            to_ext_data_format_param(make_format_param(&[("max_dir", "5"), ("max_file", "7")]))
+                .unwrap();
+        assert_eq!(param.max_dir, 5);
+        assert_eq!(param.max_file, 7);
+    }
 
-        if let Ok(mut file) = dir.open_sub_file(name_converted.clone()) {
-            if let Ok(old_file) = newdir.open_sub_file(newname_converted.clone()) {
-                match old_file.delete() {
-                    Ok(()) => (),
-                    Err(_) => {
-                        reply.error(EIO);
-                        return;
-                    }
-                }
-            }
+    #[test]
+    fn test_title_list() {
+        let titles = parse_title_list(
+            "# comment
+0004000000055D00,Pokemon X
 
-            match file.rename(&newdir, newname_converted) {
-                Ok(()) => reply.ok(),
-                Err(Error::AlreadyExist) => reply.error(EEXIST),
-                Err(_) => reply.error(EIO),
-            }
-        } else if let Ok(mut dir) = dir.open_sub_dir(name_converted) {
-            if let Ok(old_dir) = newdir.open_sub_dir(newname_converted.clone()) {
-                match old_dir.delete() {
-                    Ok(()) => (),
-                    Err(Error::NotEmpty) => {
-                        reply.error(ENOTEMPTY);
-                        return;
-                    }
-                    Err(_) => {
-                        reply.error(EIO);
-                        return;
-                    }
-                }
-            }
+0x0004000000055e00, \"Pokemon Y\"
+00040000000EDF00,Super Smash Bros. for Nintendo 3DS
+",
+        )
+        .unwrap();
+        assert_eq!(titles.len(), 3);
+        assert_eq!(titles[1], (0x0004_0000_0005_5e00, "Pokemon Y".to_owned()));
 
-            match dir.rename(&newdir, newname_converted) {
-                Ok(()) => reply.ok(),
-                Err(Error::AlreadyExist) => reply.error(EEXIST),
-                Err(_) => reply.error(EIO),
-            }
-        } else {
-            reply.error(ENOENT);
-        }
-    }
+        let found = find_title_id(&titles, "pokemon");
+        assert_eq!(
+            found.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![0x0004_0000_0005_5d00, 0x0004_0000_0005_5e00]
+        );
+        assert!(find_title_id(&titles, "zelda").is_empty());
 
-    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
-        match self.save.stat() {
-            Err(_) => reply.error(EIO),
-            Ok(stat) => reply.statfs(
-                stat.total_blocks as u64,
-                stat.free_blocks as u64,
-                stat.free_blocks as u64,
-                stat.total_files as u64,
-                stat.free_files as u64,
-                stat.block_len as u32,
-                16,
-                0,
-            ),
-        }
+        assert!(parse_title_list("0004000000055D00").is_err());
+        assert!(parse_title_list("xyz,Pokemon X").is_err());
     }
-}
-
-fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} [OPTIONS] MOUNT_PATH", program);
-    print!("{}", opts.usage(&brief));
-}
 
-fn get_default_bucket(n: usize) -> usize {
-    if n < 3 {
-        3
-    } else if n < 19 {
-        n | 1
-    } else {
-        let mut count = n;
-        while count % 2 == 0
-            || count % 3 == 0
-            || count % 5 == 0
-            || count % 7 == 0
-            || count % 11 == 0
-            || count % 13 == 0
-            || count % 17 == 0
-        {
-            count += 1;
+    #[test]
+    fn test_sign_target() {
+        assert_eq!(
+            parse_sign_target("sd:0004000000055D00"),
+            Some(BareSignTarget::Sd(0x0004_0000_0005_5d00))
+        );
+        assert_eq!(
+            parse_sign_target("nand:00010011"),
+            Some(BareSignTarget::Nand(0x0001_0011))
+        );
+        assert_eq!(parse_sign_target("cart"), Some(BareSignTarget::Cart));
+        for s in ["", "sd", "nand:", "nand:100000000", "cart:0", "ext:0"] {
+            assert_eq!(parse_sign_target(s), None, "{}", s);
         }
-        count
     }
-}
 
-fn to_ext_data_format_param(
-    raw: HashMap<String, String>,
-) -> Result<ExtDataFormatParam, Box<dyn std::error::Error>> {
-    let max_dir = raw
-        .get("max_dir")
-        .map(|s| s.parse::<usize>())
-        .transpose()?
-        .unwrap_or(100);
+    #[test]
+    fn test_import_tar() {
+        let path = TempPath::new("save3ds_import_tar");
+        let path = path.to_str().unwrap().to_owned();
+        let resource = resource();
+        let param = format_param();
+        resource.format_bare_save(&path, &param, 0x20000).unwrap();
 
-    let dir_buckets = raw
-        .get("dir_buckets")
-        .map(|s| s.parse::<usize>())
-        .transpose()?
-        .unwrap_or_else(|| get_default_bucket(max_dir));
+        let mut tar = vec![];
+        crate::tar::test::append(&mut tar, "./a/b/", b'5', &[]);
+        crate::tar::test::append(&mut tar, "./a/c/file", b'0', &[5; 700]);
+        crate::tar::test::append(&mut tar, "empty", b'0', &[]);
+        crate::tar::test::append(&mut tar, "link", b'2', &[]);
+        crate::tar::test::append(&mut tar, "this_name_is_too_long", b'0', &[1]);
+        tar.extend_from_slice(&[0; 1024]);
 
-    let max_file = raw
-        .get("max_file")
-        .map(|s| s.parse::<usize>())
-        .transpose()?
-        .unwrap_or(100);
+        {
+            let save = resource.open_bare_save(&path, true).unwrap();
+            let entries = read_tar(&tar[..], NameEncoding::Escape).unwrap();
+            // "a" is created for "a/c/file" even though the archive has no entry for it
+            assert_eq!(
+                tar_usage(&entries, |len| blocks_for(len, 0x200)),
+                Usage {
+                    dirs: 3,
+                    files: 2,
+                    blocks: 2
+                }
+            );
+            import_tar_impl(&save, entries).unwrap();
+            save.commit().unwrap();
+        }
 
-    let file_buckets = raw
-        .get("file_buckets")
-        .map(|s| s.parse::<usize>())
-        .transpose()?
-        .unwrap_or_else(|| get_default_bucket(max_file));
+        let save = resource.open_bare_save(&path, false).unwrap();
+        let mut tree = String::new();
+        format_tree(&save, &mut tree).unwrap();
+        assert_eq!(tree, "+a\n +c\n  -file\n +b\n-empty\n");
 
-    Ok(ExtDataFormatParam {
-        max_dir,
-        dir_buckets,
-        max_file,
-        file_buckets,
-    })
-}
+        let file = save
+            .open_root()
+            .unwrap()
+            .open_sub_dir(<[u8; 16]>::name_str_to_3ds("a").unwrap())
+            .unwrap()
+            .open_sub_dir(<[u8; 16]>::name_str_to_3ds("c").unwrap())
+            .unwrap()
+            .open_sub_file(<[u8; 16]>::name_str_to_3ds("file").unwrap())
+            .unwrap();
+        let mut buf = vec![0; 700];
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf, vec![5; 700]);
+    }
 
-fn to_save_data_format_param(
-    raw: HashMap<String, String>,
-    default_block_len: usize,
-) -> Result<(SaveDataFormatParam, usize), Box<dyn std::error::Error>> {
-    let block_len = raw
-        .get("block_len")
-        .map(|s| s.parse::<usize>())
-        .transpose()?
-        .unwrap_or(default_block_len);
+    #[test]
+    fn test_dump_all_nand() {
+        let root = TempPath::new("save3ds_dump_all_nand");
+        let boot9 = root.join("boot9.bin");
+        let nand = root.join("nand");
+        let out = root.join("out");
+        std::fs::create_dir_all(nand.join("private")).unwrap();
+        std::fs::write(&boot9, vec![0; 0x10000]).unwrap();
+        std::fs::write(nand.join("private").join("movable.sed"), vec![0; 0x140]).unwrap();
+        let resource = Resource::new(
+            Some(boot9.to_str().unwrap().to_owned()),
+            None,
+            None,
+            Some(nand.to_str().unwrap().to_owned()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
 
-    let block_type = match block_len {
-        512 => SaveDataBlockType::Small,
-        4096 => SaveDataBlockType::Large,
-        _ => {
-            println!("Unsupported block_len value");
-            return Err(Box::from(Error::InvalidValue));
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Large,
+            ..format_param()
+        };
+        resource
+            .format_nand_save(0x0001_0017, &param, 0x80000)
+            .unwrap();
+        resource
+            .format_nand_save(0x0001_0011, &param, 0x80000)
+            .unwrap();
+        resource
+            .format_shared_ext(
+                0xF000_000B,
+                &ExtDataFormatParam {
+                    max_dir: 10,
+                    dir_buckets: 10,
+                    max_file: 10,
+                    file_buckets: 10,
+                    mount_path: [[0; 0x10]; 0x10],
+                },
+            )
+            .unwrap();
+        {
+            let save = resource.open_nand_save(0x0001_0017, true).unwrap();
+            let name = <[u8; 16]>::name_str_to_3ds("file").unwrap();
+            let file = save.open_root().unwrap().new_sub_file(name, 3).unwrap();
+            file.write(0, &[1, 2, 3]).unwrap();
+            save.commit().unwrap();
         }
-    };
+        // Corrupts one of the saves
+        let nand_data = nand.join("data").join(
+            std::fs::read_dir(nand.join("data"))
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap()
+                .file_name(),
+        );
+        std::fs::write(
+            nand_data.join("sysdata").join("00010011").join("00000000"),
+            vec![0; 0x80000],
+        )
+        .unwrap();
 
-    let max_dir = raw
-        .get("max_dir")
-        .map(|s| s.parse::<usize>())
-        .transpose()?
-        .unwrap_or(100);
+        assert_eq!(
+            dump_all_nand_impl(&resource, &out, NameEncoding::Escape, true).unwrap(),
+            0
+        );
+        assert!(!out.exists());
 
-    let dir_buckets = raw
-        .get("dir_buckets")
-        .map(|s| s.parse::<usize>())
-        .transpose()?
-        .unwrap_or_else(|| get_default_bucket(max_dir));
+        assert_eq!(
+            dump_all_nand_impl(&resource, &out, NameEncoding::Escape, false).unwrap(),
+            1
+        );
+        assert_eq!(
+            std::fs::read(out.join("sysdata").join("00010017").join("file")).unwrap(),
+            [1, 2, 3]
+        );
+        assert!(out.join("extdata").join("00048000f000000b").is_dir());
+    }
 
-    let max_file = raw
-        .get("max_file")
-        .map(|s| s.parse::<usize>())
-        .transpose()?
-        .unwrap_or(100);
+    #[test]
+    fn test_raw_names() {
+        let root = TempPath::new("save3ds_raw_names");
+        let host = root.join("host");
+        std::fs::create_dir_all(&root).unwrap();
+        let resource = resource();
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 20,
+            dir_buckets: 23,
+            max_file: 20,
+            file_buckets: 23,
+            duplicate_data: false,
+        };
+        let paths: Vec<String> = ["a.bin", "b.bin"]
+            .iter()
+            .map(|name| root.join(name).to_str().unwrap().to_owned())
+            .collect();
+        for path in &paths {
+            resource.format_bare_save(path, &param, 0x20000).unwrap();
+        }
 
-    let file_buckets = raw
-        .get("file_buckets")
-        .map(|s| s.parse::<usize>())
-        .transpose()?
-        .unwrap_or_else(|| get_default_bucket(max_file));
+        // Together, the names contain every byte value
+        let names: Vec<[u8; 16]> = (0..16)
+            .map(|i| {
+                let mut name = [0; 16];
+                for (j, c) in name.iter_mut().enumerate() {
+                    *c = (i * 16 + j) as u8;
+                }
+                name
+            })
+            .collect();
+        let save = resource.open_bare_save(&paths[0], true).unwrap();
+        let dirs: Vec<_> = names[0..8]
+            .iter()
+            .map(|name| save.open_root().unwrap().new_sub_dir(*name).unwrap())
+            .collect();
+        for (i, name) in names[8..16].iter().enumerate() {
+            let file = dirs[0].new_sub_file(*name, i * 100).unwrap();
+            file.write(0, &vec![i as u8; i * 100]).unwrap();
+        }
+        save.commit().unwrap();
+        let fingerprint = content_fingerprint(&save).unwrap();
+        let listed = |save: &SaveData| -> Vec<[u8; 16]> {
+            let root = save.open_root().unwrap();
+            let mut names: Vec<_> = root.list_sub_dir().unwrap();
+            names.extend(
+                root.open_sub_dir(names[0].0)
+                    .unwrap()
+                    .list_sub_file()
+                    .unwrap(),
+            );
+            names.into_iter().map(|(name, _)| name).collect()
+        };
+        let order = listed(&save);
 
-    let duplicate_data = raw
-        .get("duplicate_data")
-        .map(|s| s.parse::<bool>())
-        .transpose()?
-        .unwrap_or(true);
+        extract_raw(save, &host).unwrap();
+        let sidecar =
+            raw_names::parse(&std::fs::read_to_string(host.join(raw_names::SIDECAR)).unwrap())
+                .unwrap();
+        assert_eq!(sidecar.len(), 8);
+        assert!(sidecar.iter().all(|entry| entry.is_dir));
+        let sub_dir = &sidecar
+            .iter()
+            .find(|entry| entry.name == "000102030405060708090a0b0c0d0e0f")
+            .unwrap()
+            .index;
+        assert!(host.join(sub_dir).join("007").is_file());
+        assert!(!host.join(sub_dir).join("008").exists());
 
-    let len = raw
-        .get("len")
-        .map(|s| s.parse::<usize>())
-        .transpose()?
-        .unwrap_or(512 * 1024);
+        let save = resource.open_bare_save(&paths[1], true).unwrap();
+        import_raw(save, &host, false).unwrap();
+        let save = resource.open_bare_save(&paths[1], false).unwrap();
+        assert_eq!(content_fingerprint(&save).unwrap(), fingerprint);
+        assert_eq!(listed(&save), order);
+        drop(save);
 
-    Ok((
-        SaveDataFormatParam {
-            block_type,
-            max_dir,
-            dir_buckets,
-            max_file,
-            file_buckets,
-            duplicate_data,
-        },
-        len,
-    ))
-}
+        std::fs::write(host.join(raw_names::SIDECAR), "{\"000\": {}}").unwrap();
+        let save = resource.open_bare_save(&paths[1], true).unwrap();
+        assert!(import_raw(save, &host, false).is_err());
+        let save = resource.open_bare_save(&paths[1], false).unwrap();
+        assert_eq!(content_fingerprint(&save).unwrap(), fingerprint);
+    }
 
-fn read_key(s: String) -> std::io::Result<[u8; 16]> {
-    let mut key = [0; 16];
-    if s.len() == 32 {
-        let mut success = true;
-        for i in 0..16 {
-            if let Ok(v) = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16) {
-                key[i] = v;
-            } else {
-                success = false;
-                break;
-            }
+    #[test]
+    fn test_import_order() {
+        let root = TempPath::new("save3ds_import_order");
+        let save_path = root.join("save.bin");
+        let host = root.join("host");
+        for name in ["m", "z", "a"] {
+            std::fs::create_dir_all(host.join(format!("dir_{}", name))).unwrap();
+            std::fs::write(host.join(name), [1; 10]).unwrap();
         }
-        if success {
-            return Ok(key);
+        let save_path = save_path.to_str().unwrap();
+        let resource = resource();
+        let param = SaveDataFormatParam {
+            duplicate_data: false,
+            ..format_param()
+        };
+        resource
+            .format_bare_save(save_path, &param, 0x20000)
+            .unwrap();
+
+        let host_order: Vec<String> = std::fs::read_dir(&host)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        let split = |names: Vec<String>| -> (Vec<String>, Vec<String>) {
+            names.into_iter().partition(|name| name.starts_with("dir_"))
+        };
+        let (host_dirs, host_files) = split(host_order);
+        let mut sorted_dirs = host_dirs.clone();
+        sorted_dirs.sort();
+        let mut sorted_files = host_files.clone();
+        sorted_files.sort();
+        let reversed = |names: &[String]| names.iter().rev().cloned().collect::<Vec<_>>();
+
+        for (order, dirs, files) in [
+            (None, reversed(&host_dirs), reversed(&host_files)),
+            (
+                Some(ImportOrder::Host),
+                host_dirs.clone(),
+                host_files.clone(),
+            ),
+            (Some(ImportOrder::Sorted), sorted_dirs, sorted_files),
+        ] {
+            let save = resource.open_bare_save(save_path, true).unwrap();
+            import(save, &host, NameEncoding::Escape, order, false).unwrap();
+            let save = resource.open_bare_save(save_path, false).unwrap();
+            let names = |list: Vec<([u8; 16], u32)>| -> Vec<String> {
+                list.iter()
+                    .map(|(name, _)| <[u8; 16]>::name_3ds_to_str(name))
+                    .collect()
+            };
+            let root_dir = save.open_root().unwrap();
+            assert_eq!(names(root_dir.list_sub_dir().unwrap()), dirs, "{:?}", order);
+            assert_eq!(
+                names(root_dir.list_sub_file().unwrap()),
+                files,
+                "{:?}",
+                order
+            );
         }
     }
-    let mut file = std::fs::File::open(s)?;
-    file.read_exact(&mut key)?;
-    Ok(key)
-}
 
-fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = std::env::args().collect();
-    let program = args[0].clone();
+    #[test]
+    fn test_import_preflight() {
+        let root = TempPath::new("save3ds_preflight");
+        let save_path = root.join("save.bin");
+        let host = root.join("host");
+        std::fs::create_dir_all(host.join("d")).unwrap();
+        std::fs::write(host.join("d/file"), [1; 1000]).unwrap();
+        std::fs::write(host.join("this_name_is_too_long"), [1; 0x100000]).unwrap();
+        let save_path = save_path.to_str().unwrap();
 
-    let mut opts = Options::new();
-    opts.optopt("", "bare", "mount a bare DISA file", "FILE");
-    opts.optopt("b", "boot9", "boot9.bin file path", "FILE");
-    opts.optopt("c", "cart", "(experimental) mount a cartridge save", "FILE");
-    opts.optopt(
-        "",
-        "db",
-        "mount a database. DB_TYPE is one of the following:
-    nandtitle, nandimport, tmptitle, tmpimport, sdtitle, sdimport, ticket",
-        "DB_TYPE",
-    );
-    opts.optflag("x", "extract", "extract the content instead of mounting");
-    opts.optopt(
-        "f",
-        "format",
-        "format the specified archive",
-        "[\"\"|param1:value1[,...]]",
-    );
-    opts.optopt("g", "game", "cartridge ROM in CCI/NCSD format", "FILE");
-    opts.optflag("h", "help", "print this help menu");
-    opts.optflag("i", "import", "import the content instead of mounting");
-    opts.optopt(
-        "k",
-        "key",
-        "AES slot 0x2F key Y for decrypting v6.0 cartridge save",
-        "HEX|FILE",
-    );
-    opts.optopt(
-        "",
-        "key19x",
-        "AES slot 0x19 key X for decrypting New3DS exclusive cartridge save",
-        "HEX|FILE",
-    );
-    opts.optopt(
-        "",
-        "key1ax",
-        "AES slot 0x19 key X for decrypting New3DS exclusive cartridge save",
-        "HEX|FILE",
-    );
-    opts.optopt("m", "movable", "movable.sed file path", "FILE");
-    opts.optopt("", "nand", "NAND root path", "DIR");
-    opts.optopt("", "nandext", "mount the NAND Extdata with the ID", "ID");
-    opts.optopt("", "nandsave", "mount the NAND save with the ID", "ID");
-    opts.optopt("o", "otp", "OTP file path", "FILE");
-    opts.optopt("p", "priv", "cartridge private header path", "FILE");
-    opts.optflag("r", "readonly", "mount as read-only file system");
-    opts.optopt("", "sd", "SD root path", "DIR");
-    opts.optopt("", "sdext", "mount the SD Extdata with the ID", "ID");
-    opts.optopt("", "sdsave", "mount the SD save with the ID", "ID");
-    opts.optflag("t", "touch", "just try opening and closing the archive");
-    opts.optflagmulti("v", "verbose", "more v for more verbose logging");
+        let resource = resource();
+        let param = SaveDataFormatParam {
+            duplicate_data: false,
+            ..format_param()
+        };
+        resource
+            .format_bare_save(save_path, &param, 0x20000)
+            .unwrap();
+        let save = resource.open_bare_save(save_path, true).unwrap();
+        let free = usage_after_clear(&save).unwrap();
+        let required =
+            host_usage::<[u8; 16]>(&host, NameEncoding::Escape, |len| blocks_for(len, 512))
+                .unwrap();
+        assert_eq!(
+            required,
+            Usage {
+                dirs: 1,
+                files: 1,
+                blocks: 2
+            }
+        );
+        import(save, &host, NameEncoding::Escape, None, false).unwrap();
+        let save = resource.open_bare_save(save_path, true).unwrap();
+        assert_eq!(usage_after_clear(&save).unwrap(), free);
+        let fingerprint = content_fingerprint(&save).unwrap();
 
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => m,
-        Err(f) => {
-            println!("Failed to parse the arguments: {}", f);
-            print_usage(&program, opts);
-            return Ok(());
+        for i in 0..free.files {
+            std::fs::write(host.join(format!("{}", i)), []).unwrap();
         }
-    };
+        std::fs::write(host.join("big"), vec![2; free.blocks * 512]).unwrap();
+        assert!(matches!(
+            import(save, &host, NameEncoding::Escape, None, false),
+            Err(Error::NoSpace)
+        ));
+        let save = resource.open_bare_save(save_path, false).unwrap();
+        assert_eq!(content_fingerprint(&save).unwrap(), fingerprint);
 
-    let verbose = matches.opt_count("verbose");
-    stderrlog::new()
-        .module(module_path!())
-        .module("libsave3ds")
-        .verbosity(verbose)
-        .init()?;
-
-    if matches.opt_present("h") {
-        print_usage(&program, opts);
-        return Ok(());
+        let available = Usage {
+            dirs: 3,
+            files: 4,
+            blocks: 5,
+        };
+        assert_eq!(shortage_text(&available, &available), None);
+        assert_eq!(
+            shortage_text(
+                &available,
+                &Usage {
+                    dirs: 4,
+                    files: 4,
+                    blocks: 6
+                }
+            )
+            .unwrap(),
+            "Not enough directory slots: 4 needed, 3 available\n\
+             Not enough blocks: 6 needed, 5 available\n"
+        );
     }
 
-    let touch = matches.opt_present("touch");
-    let import = matches.opt_present("import");
-    let extract = matches.opt_present("extract");
+    #[cfg(all(unix, feature = "unixfuse"))]
+    #[test]
+    fn test_inode_map() {
+        assert_eq!(Ino::Dir(1).to_os(), 1);
+        assert_eq!(Ino::File(0).to_os(), 0x1_0000_0000);
 
-    if touch as i32 + import as i32 + extract as i32 > 1 {
-        println!(
-            "At most one of the following can be specified:
-    --extract, --import, --touch "
+        for &fs_index in &[0, 1, 0x1234, InodeMap::MAX_FS_INDEX] {
+            for &ino in &[
+                Ino::Dir(0),
+                Ino::Dir(1),
+                Ino::Dir(0xFFFF_FFFF),
+                Ino::File(0),
+                Ino::File(7),
+                Ino::File(0xFFFF_FFFF),
+            ] {
+                let os_ino = InodeMap::encode(fs_index, ino).unwrap();
+                assert_eq!(InodeMap::decode(os_ino), Some((fs_index, ino)));
+            }
+        }
+
+        assert_eq!(
+            InodeMap::encode(InodeMap::MAX_FS_INDEX + 1, Ino::Dir(1)),
+            None
         );
-        return Ok(());
+        assert_eq!(InodeMap::decode(1 << 48), None);
+        assert_eq!(InodeMap::decode(u64::MAX), None);
+        assert_eq!(Ino::from_os(1 << 33), None);
+        assert_eq!(Ino::from_os(0x1_0000_0005), Some(Ino::File(5)));
+
+        assert_eq!(Ino::Dir(1).swap_root(1), Ino::Dir(1));
+        assert_eq!(Ino::Dir(5).swap_root(1), Ino::Dir(5));
+        assert_eq!(Ino::Dir(1).swap_root(5), Ino::Dir(5));
+        assert_eq!(Ino::Dir(5).swap_root(5), Ino::Dir(1));
+        assert_eq!(Ino::Dir(3).swap_root(5), Ino::Dir(3));
+        assert_eq!(Ino::File(5).swap_root(5), Ino::File(5));
+        assert_eq!(Ino::File(1).swap_root(5), Ino::File(1));
     }
 
-    let read_only = matches.opt_present("r") || extract || touch;
+    #[cfg(all(unix, feature = "unixfuse"))]
+    #[test]
+    fn test_same_fs() {
+        let a = InodeMap::encode(0, Ino::Dir(1)).unwrap();
+        let b = InodeMap::encode(0, Ino::Dir(5)).unwrap();
+        let c = InodeMap::encode(1, Ino::Dir(1)).unwrap();
+        assert!(InodeMap::same_fs(a, b));
+        assert!(InodeMap::same_fs(c, c));
+        assert!(!InodeMap::same_fs(a, c));
+        assert!(!InodeMap::same_fs(c, b));
+        // invalid inodes are rejected later as ENOENT
+        assert!(InodeMap::same_fs(a, u64::MAX));
+    }
 
-    let operation = if extract {
-        FileSystemOperation::Extract
-    } else if import {
-        FileSystemOperation::Import
-    } else if touch {
-        FileSystemOperation::Touch
-    } else {
-        FileSystemOperation::Mount(read_only)
-    };
+    #[test]
+    fn test_pending_changes() {
+        let idle = Duration::from_secs(5);
+        let t = Instant::now();
+        let mut pending = PendingChanges::default();
+        assert!(!pending.due(idle, t + idle));
 
-    if matches.free.len() != 1 && !touch {
-        println!("Please specify one mount path");
-        return Ok(());
+        pending.last_change = Some(t);
+        assert!(!pending.due(idle, t));
+        assert!(!pending.due(idle, t + Duration::from_secs(4)));
+        assert!(pending.due(idle, t + idle));
+
+        pending.open_files = 1;
+        assert!(!pending.due(idle, t + idle * 2));
+        pending.open_files = 0;
+        assert!(pending.due(idle, t + idle * 2));
+
+        pending.last_change = None;
+        assert!(!pending.due(idle, t + idle * 2));
     }
 
-    let mountpoint = if touch {
-        std::path::Path::new("dummy")
-    } else {
-        std::path::Path::new(&matches.free[0])
-    };
+    #[test]
+    fn test_readahead() {
+        let path = TempPath::new("save3ds_readahead");
+        let path = path.to_str().unwrap().to_owned();
+        let resource = resource();
+        let param = format_param();
+        resource.format_bare_save(&path, &param, 0x20000).unwrap();
+        let save = resource.open_bare_save(&path, true).unwrap();
 
-    let boot9_path = matches.opt_str("boot9");
-    let movable_path = matches.opt_str("movable");
-    let otp_path = matches.opt_str("otp");
-    let bare_path = matches.opt_str("bare");
-    let cart_path = matches.opt_str("cart");
-    let sd_path = matches.opt_str("sd");
-    let sd_save_id = matches.opt_str("sdsave");
-    let sd_ext_id = matches.opt_str("sdext");
-    let nand_path = matches.opt_str("nand");
-    let nand_ext_id = matches.opt_str("nandext");
-    let nand_save_id = matches.opt_str("nandsave");
-    let db_type = matches.opt_str("db");
-    let format_param = matches.opt_str("format");
-    let priv_path = matches.opt_str("priv");
-    let game_path = matches.opt_str("game");
-    let x2f_key_y = matches.opt_str("key");
-    let x19_key_x = matches.opt_str("key19x");
-    let x1a_key_x = matches.opt_str("key1ax");
+        let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let file = save
+            .open_root()
+            .unwrap()
+            .new_sub_file(<[u8; 16]>::name_str_to_3ds("a").unwrap(), data.len())
+            .unwrap();
+        file.write(0, &data).unwrap();
+
+        let mut readahead = ReadAhead::new(1000);
+        assert_eq!(readahead.read(&file, 0, 100).unwrap(), data[0..100]);
+        assert_eq!(readahead.buf_pos, 0);
+        assert_eq!(readahead.buf.len(), 1100);
 
-    let x2f_key_y = x2f_key_y.map(read_key).transpose()?;
-    let x19_key_x = x19_key_x.map(read_key).transpose()?;
-    let x1a_key_x = x1a_key_x.map(read_key).transpose()?;
+        // served from the buffer
+        assert_eq!(readahead.read(&file, 100, 1100).unwrap(), data[100..1100]);
+        assert_eq!(readahead.buf.len(), 1100);
 
-    let format_param: Option<HashMap<String, String>> = format_param.map(|s| {
-        s.split(',')
-            .filter_map(|p| {
-                if let Some(mid) = p.find(':') {
-                    let (l, r) = p.split_at(mid);
-                    Some((l.to_owned(), r[1..].to_owned()))
-                } else {
-                    None
-                }
-            })
-            .collect()
-    });
+        // sequential but beyond the buffer, refilled up to the end of the file
+        assert_eq!(readahead.read(&file, 1100, 4500).unwrap(), data[1100..4500]);
+        assert_eq!(readahead.buf_pos, 1100);
+        assert_eq!(readahead.buf.len(), 3900);
 
-    if [
-        &sd_save_id,
-        &sd_ext_id,
-        &nand_save_id,
-        &nand_ext_id,
-        &bare_path,
-        &db_type,
-        &cart_path,
-    ]
-    .iter()
-    .map(|x| if x.is_none() { 0 } else { 1 })
-    .sum::<i32>()
-        != 1
-    {
-        println!(
-            "One and only one of the following arguments must be supplied:
-    --sdext, --sdsave, --nandsave, --nandext, --bare, --db, --cart"
-        );
-        return Ok(());
+        // non-sequential access drops the buffer
+        assert_eq!(readahead.read(&file, 10, 20).unwrap(), data[10..20]);
+        assert!(readahead.buf.is_empty());
+
+        // modified data is visible after invalidation
+        assert_eq!(readahead.read(&file, 20, 30).unwrap(), data[20..30]);
+        file.write(40, &[0xEE; 10]).unwrap();
+        readahead.invalidate();
+        assert_eq!(readahead.read(&file, 40, 50).unwrap(), vec![0xEE; 10]);
     }
 
-    let resource = Resource::new(
-        boot9_path,
-        movable_path,
-        sd_path,
-        nand_path,
-        otp_path,
-        priv_path,
-        game_path,
-        x2f_key_y,
-        x19_key_x,
-        x1a_key_x,
-    )?;
+    #[test]
+    fn test_subdir_root() {
+        let path = TempPath::new("save3ds_subdir");
+        let path = path.to_str().unwrap().to_owned();
+        let resource = resource();
+        let param = format_param();
+        resource.format_bare_save(&path, &param, 0x20000).unwrap();
+        let save = resource.open_bare_save(&path, true).unwrap();
 
-    if let Some(bare) = bare_path {
-        if let Some(format_param) = format_param {
-            println!("Formatting...");
-            let (param, len) = to_save_data_format_param(format_param, 512)?;
-            resource.format_bare_save(&bare, &param, len)?;
-            println!("Formatting done");
-        }
+        let root = save.open_root().unwrap();
+        assert_eq!(root.get_parent_ino().unwrap(), 1);
+        let a = root
+            .new_sub_dir(<[u8; 16]>::name_str_to_3ds("a").unwrap())
+            .unwrap();
+        let b = a
+            .new_sub_dir(<[u8; 16]>::name_str_to_3ds("b").unwrap())
+            .unwrap();
+        a.new_sub_file(<[u8; 16]>::name_str_to_3ds("f").unwrap(), 0)
+            .unwrap();
 
-        println!(
-            "WARNING: After modification, you need to sign the CMAC header using other tools."
+        assert_eq!(open_dir_path(&save, "").unwrap().get_ino(), 1);
+        assert_eq!(open_dir_path(&save, "/").unwrap().get_ino(), 1);
+        assert_eq!(open_dir_path(&save, "a").unwrap().get_ino(), a.get_ino());
+        assert_eq!(
+            open_dir_path(&save, "/a/b/").unwrap().get_ino(),
+            b.get_ino()
         );
+        assert!(matches!(open_dir_path(&save, "a/c"), Err(Error::NotFound)));
+        assert!(matches!(open_dir_path(&save, "a/f"), Err(Error::NotFound)));
+        assert!(matches!(
+            open_dir_path(&save, "a/0123456789abcdefg"),
+            Err(Error::NotFound)
+        ));
+    }
 
-        start(
-            resource.open_bare_save(&bare, !read_only)?,
-            operation,
-            mountpoint,
-        )?
-    } else if let Some(id) = nand_save_id {
-        let id = u32::from_str_radix(&id, 16)?;
-        if let Some(format_param) = format_param {
-            println!("Formatting...");
-            let (param, len) = to_save_data_format_param(format_param, 4096)?;
-            resource.format_nand_save(id, &param, len)?;
-            println!("Formatting done");
+    #[test]
+    fn test_extract_since() {
+        let root = TempPath::new("save3ds_since");
+        std::fs::create_dir_all(&root).unwrap();
+        let save_path = root.join("save.bin");
+        let save_path = save_path.to_str().unwrap();
+        let out = root.join("out");
+        let manifest = root.join("manifest.txt");
+        let resource = resource();
+        let param = SaveDataFormatParam {
+            duplicate_data: false,
+            ..format_param()
+        };
+        resource
+            .format_bare_save(save_path, &param, 0x40000)
+            .unwrap();
+        let save = resource.open_bare_save(save_path, true).unwrap();
+        let name = |s| <[u8; 16]>::name_str_to_3ds(s).unwrap();
+        let dir = save.open_root().unwrap().new_sub_dir(name("a")).unwrap();
+        for (n, content) in &[("f", b"ffff"), ("g", b"gggg"), ("h", b"hhhh")] {
+            dir.new_sub_file(name(n), 4)
+                .unwrap()
+                .write(0, &content[..])
+                .unwrap();
         }
 
-        start(
-            resource.open_nand_save(id, !read_only)?,
-            operation,
-            mountpoint,
-        )?
-    } else if let Some(id) = sd_save_id {
-        let id = u64::from_str_radix(&id, 16)?;
-        if let Some(format_param) = format_param {
-            println!("Formatting...");
-            let (param, len) = to_save_data_format_param(format_param, 512)?;
-            resource.format_sd_save(id, &param, len)?;
-            println!("Formatting done");
-        }
+        let mut written = extract_since(&save, &out, &manifest, NameEncoding::Escape).unwrap();
+        written.sort();
+        assert_eq!(written, vec!["a/f", "a/g", "a/h"]);
+        assert_eq!(std::fs::read(out.join("a").join("g")).unwrap(), b"gggg");
 
-        start(
-            resource.open_sd_save(id, !read_only)?,
-            operation,
-            mountpoint,
-        )?
-    } else if let Some(id) = sd_ext_id {
-        let id = u64::from_str_radix(&id, 16)?;
-        if let Some(format_param) = format_param {
-            println!("Formatting...");
-            let param = to_ext_data_format_param(format_param)?;
-            resource.format_sd_ext(id, &param)?;
-            println!("Formatting done");
-        }
+        // Nothing changed
+        assert!(extract_since(&save, &out, &manifest, NameEncoding::Escape)
+            .unwrap()
+            .is_empty());
 
-        start(resource.open_sd_ext(id, !read_only)?, operation, mountpoint)?
-    } else if let Some(id) = nand_ext_id {
-        let id = u64::from_str_radix(&id, 16)?;
-        if let Some(format_param) = format_param {
-            println!("Formatting...");
-            let param = to_ext_data_format_param(format_param)?;
-            resource.format_nand_ext(id, &param)?;
-            println!("Formatting done");
-        }
+        // Mark unchanged host files, which must be left alone
+        std::fs::write(out.join("a").join("f"), b"host").unwrap();
+        dir.open_sub_file(name("g"))
+            .unwrap()
+            .write(0, b"GG")
+            .unwrap();
+        dir.open_sub_file(name("h")).unwrap().delete().unwrap();
+        assert_eq!(
+            extract_since(&save, &out, &manifest, NameEncoding::Escape).unwrap(),
+            vec!["a/g"]
+        );
+        assert_eq!(std::fs::read(out.join("a").join("f")).unwrap(), b"host");
+        assert_eq!(std::fs::read(out.join("a").join("g")).unwrap(), b"GGgg");
+        assert!(!out.join("a").join("h").exists());
 
-        start(
-            resource.open_nand_ext(id, !read_only)?,
-            operation,
-            mountpoint,
-        )?
-    } else if let Some(db_type) = db_type {
-        if format_param.is_some() {
-            println!("Warning: formatting not supported");
-        }
-        let db_type = match db_type.as_ref() {
-            "nandtitle" => DbType::NandTitle,
-            "nandimport" => DbType::NandImport,
-            "tmptitle" => DbType::TmpTitle,
-            "tmpimport" => DbType::TmpImport,
-            "sdtitle" => DbType::SdTitle,
-            "sdimport" => DbType::SdImport,
-            "ticket" => DbType::Ticket,
-            _ => {
-                println!("Unknown database type {}", db_type);
-                return Ok(());
-            }
+        std::fs::write(&manifest, "not a manifest\n").unwrap();
+        assert!(matches!(
+            extract_since(&save, &out, &manifest, NameEncoding::Escape),
+            Err(Error::InvalidValue)
+        ));
+
+        drop(dir);
+        drop(save);
+    }
+
+    #[test]
+    fn test_name_encoding_round_trip() {
+        let root = TempPath::new("save3ds_encoding");
+        std::fs::create_dir_all(&root).unwrap();
+        let resource = resource();
+        let param = SaveDataFormatParam {
+            duplicate_data: false,
+            ..format_param()
+        };
+        let format = |name: &str| {
+            let path = root.join(name);
+            let path = path.to_str().unwrap().to_owned();
+            resource.format_bare_save(&path, &param, 0x40000).unwrap();
+            resource.open_bare_save(&path, true).unwrap()
         };
 
-        start(
-            resource.open_db(db_type, !read_only)?,
-            operation,
-            mountpoint,
-        )?
-    } else if let Some(cart) = cart_path {
-        if let Some(format_param) = format_param {
-            println!("Formatting...");
-            let (param, len) = to_save_data_format_param(format_param, 512)?;
-            resource.format_cart_save(&cart, &param, len)?;
-            println!("Formatting done");
+        let save = format("source.bin");
+        let mut odd_name = *b"A/b%\\x41\0\0\0\0\0\0\0\0";
+        odd_name[8] = 0xE9;
+        let dir = save.open_root().unwrap().new_sub_dir(odd_name).unwrap();
+        dir.new_sub_file(*b"file\0\0\0\0\0\0\0\0\0\0\0\0", 4)
+            .unwrap()
+            .write(0, b"data")
+            .unwrap();
+        let expected = content_entries(&save).unwrap();
+
+        for &encoding in &[
+            NameEncoding::Escape,
+            NameEncoding::Hex,
+            NameEncoding::Base64,
+            NameEncoding::Quoted,
+        ] {
+            let out = root.join(format!("out_{}", encoding));
+            let manifest = root.join(format!("manifest_{}.txt", encoding));
+            extract_since(&save, &out, &manifest, encoding).unwrap();
+            assert_eq!(manifest_encoding(&manifest).unwrap(), encoding);
+            let other = if encoding == NameEncoding::Hex {
+                NameEncoding::Base64
+            } else {
+                NameEncoding::Hex
+            };
+            assert!(matches!(
+                extract_since(&save, &out, &manifest, other),
+                Err(Error::InvalidValue)
+            ));
+
+            let copy = format(&format!("copy_{}.bin", encoding));
+            let copy_root = copy.open_root().unwrap();
+            import_impl(
+                &copy,
+                &copy_root,
+                &out,
+                manifest_encoding(&manifest).unwrap(),
+                None,
+            )
+            .unwrap();
+            assert_eq!(content_entries(&copy).unwrap(), expected);
         }
-        start(
-            resource.open_cart_save(&cart, !read_only)?,
-            operation,
-            mountpoint,
-        )?
-    } else {
-        panic!()
-    };
-    Ok(())
-}
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let result = main_inner();
-    if let Err(e) = &result {
-        println!("{}", e);
+        drop(dir);
+        drop(save);
     }
-    result
-}
-
-#[cfg(test)]
-mod test {
-    use crate::*;
 
     #[test]
-    fn test_string_conversion() {
+    fn test_cat() {
+        let path = TempPath::new("save3ds_cat");
+        let path = path.to_str().unwrap().to_owned();
+        let resource = resource();
+        let param = SaveDataFormatParam {
+            duplicate_data: false,
+            ..format_param()
+        };
+        resource.format_bare_save(&path, &param, 0x40000).unwrap();
+        let save = resource.open_bare_save(&path, true).unwrap();
+
+        let data: Vec<u8> = (0..0x12345).map(|i| (i % 251) as u8).collect();
+        let dir = save
+            .open_root()
+            .unwrap()
+            .new_sub_dir(<[u8; 16]>::name_str_to_3ds("a").unwrap())
+            .unwrap();
+        let file = dir
+            .new_sub_file(<[u8; 16]>::name_str_to_3ds("f").unwrap(), data.len())
+            .unwrap();
+        file.write(0, &data).unwrap();
+        dir.new_sub_file(<[u8; 16]>::name_str_to_3ds("g").unwrap(), 4)
+            .unwrap();
+
+        let cat_to_vec = |path: &str, offset: usize, length: Option<usize>| {
+            let mut out = vec![];
+            cat(&save, path, offset, length, &mut out).map(|()| out)
+        };
+
+        assert_eq!(cat_to_vec("a/f", 0, None).unwrap(), data);
         assert_eq!(
-            <[u8; 16]>::name_3ds_to_str(&[b'a', b'b', b'c', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
-            "abc"
+            cat_to_vec("/a/f", 0x100, Some(3)).unwrap(),
+            data[0x100..0x103]
+        );
+        assert_eq!(
+            cat_to_vec("a/f", 0x12340, Some(0x100)).unwrap(),
+            data[0x12340..]
         );
+        assert!(cat_to_vec("a/f", data.len(), None).unwrap().is_empty());
+        assert!(matches!(
+            cat_to_vec("a/f", data.len() + 1, None),
+            Err(Error::OutOfBound)
+        ));
+        assert!(matches!(cat_to_vec("a", 0, None), Err(Error::NotFound)));
+        assert!(matches!(cat_to_vec("a/", 0, None), Err(Error::NotFound)));
+
+        // never written data comes out as 0xDD
+        assert_eq!(cat_to_vec("a/g", 0, None).unwrap(), [0xDD; 4]);
+    }
 
+    #[test]
+    fn test_titles_csv() {
+        let titles = vec![
+            TitleEntry {
+                title_id: 0x0004_0000_0012_3400,
+                size: 0x10_0000,
+                title_type: 0x40,
+                version: 1024,
+                tmd_content_id: 1,
+                product_code: "CTR-P-ABCE".to_owned(),
+            },
+            TitleEntry {
+                title_id: 0x0004_0010_0002_0000,
+                size: 0,
+                title_type: 0x40,
+                version: 0,
+                tmd_content_id: 0x10,
+                product_code: "".to_owned(),
+            },
+        ];
         assert_eq!(
-            <[u8; 16]>::name_3ds_to_str(&[
-                b'a', b'b', b'c', 0, 0, 0, b'd', 0, 0, 0, 0, 0, 0, 0, 0, 0
-            ]),
-            "abc\\x00\\x00\\x00d"
+            titles_csv(&titles),
+            "title_id,size,type,version,tmd_content_id,product_code\n\
+             0004000000123400,1048576,40,1024,00000001,CTR-P-ABCE\n\
+             0004001000020000,0,40,0,00000010,\n"
         );
+        assert_eq!(
+            titles_csv(&[]),
+            "title_id,size,type,version,tmd_content_id,product_code\n"
+        );
+    }
+
+    #[test]
+    fn test_duplicate_text() {
+        let file = |path: &str, len, hash| ContentEntry {
+            path: path.to_owned(),
+            file: Some((len, [hash; 32])),
+        };
+        let mut entries = vec![
+            ContentEntry {
+                path: "/d".to_owned(),
+                file: None,
+            },
+            file("/d/a", 10, 1),
+            file("/c", 10, 2),
+            file("/e", 0, 0),
+            file("/f", 0, 0),
+        ];
+        assert_eq!(duplicate_text(&entries, false), None);
+        entries.push(file("/b", 10, 1));
+        entries.push(file("/d/c", 10, 2));
+        entries.push(file("/g", 10, 2));
+        let text = "Warning: files with identical content take 30 extra bytes\n  \
+             10 bytes: /b, /d/a\n  \
+             10 bytes: /c, /d/c, /g\n\
+             The archive format can't share blocks between files, so every copy is stored\n";
+        assert_eq!(duplicate_text(&entries, false).unwrap(), text);
+        let text = duplicate_text(&entries, true).unwrap();
+        assert!(text.contains("duplicate_data: false"));
+    }
 
+    #[test]
+    fn test_headers_text() {
+        let dumps = vec![
+            HeaderDump {
+                name: "A".to_owned(),
+                fields: vec![
+                    ("magic".to_owned(), FieldValue::Bytes(b"DISA".to_vec())),
+                    ("size".to_owned(), FieldValue::Int(0x20)),
+                ],
+            },
+            HeaderDump {
+                name: "B".to_owned(),
+                fields: vec![("sha".to_owned(), FieldValue::Bytes(vec![0, 0xFF]))],
+            },
+        ];
         assert_eq!(
-            <[u8; 16]>::name_3ds_to_str(&[
-                b'a', b'/', b'\n', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
-            ]),
-            "a\\x2f\\x0a"
+            headers_text(&dumps, false),
+            "[A]\nmagic = 44495341 \"DISA\"\nsize = 32 (0x20)\n[B]\nsha = 00ff\n"
+        );
+        assert_eq!(
+            headers_text(&dumps, true),
+            "[{\"name\":\"A\",\"fields\":{\"magic\":\"44495341\",\"size\":32}},\
+             {\"name\":\"B\",\"fields\":{\"sha\":\"00ff\"}}]\n"
         );
+    }
 
+    #[test]
+    fn test_dpfs_state_text() {
+        let states = vec![DpfsState {
+            partition: 0,
+            level1_selector: 1,
+            level1_modified: false,
+            level2: DpfsLevelState {
+                block_len: 128,
+                selector: vec![0x8000_0000],
+                active: vec![1, 0],
+                modified: vec![false; 2],
+            },
+            level3: DpfsLevelState {
+                block_len: 4096,
+                selector: vec![0x4000_0000, 0],
+                active: vec![0, 1, 0],
+                modified: vec![false; 3],
+            },
+        }];
         assert_eq!(
-            <[u8; 16]>::name_3ds_to_str(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
-            "\\x00"
+            dpfs_state_text(&states),
+            "partition=0\nlevel1_selector=1\n\
+             level2_block_len=128\nlevel2_selector=80000000\nlevel2_active=10\n\
+             level3_block_len=4096\nlevel3_selector=40000000 00000000\nlevel3_active=010\n"
         );
+    }
 
+    #[test]
+    fn test_capabilities_text() {
+        let capabilities = Capabilities {
+            sd_save: true,
+            sd_ext: true,
+            sd_db: true,
+            nand_save: false,
+            nand_ext: false,
+            nand_db: false,
+            cart_save: true,
+        };
         assert_eq!(
-            name_os_to_3ds::<[u8; 16]>(OsStr::new("abc")),
-            Some((
-                [b'a', b'b', b'c', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-                ""
-            ))
+            capabilities_text(&capabilities),
+            "sd_save=true\nsd_ext=true\nsd_db=true\nnand_save=false\n\
+             nand_ext=false\nnand_db=false\ncart_save=true\n"
         );
+    }
+
+    #[test]
+    fn test_sd_map_text() {
+        use libsave3ds::SdMapEntry;
+        let entry = |path: &str, is_dir: bool, magic: Option<&str>| SdMapEntry {
+            path: path.to_owned(),
+            is_dir,
+            len: if is_dir { 0 } else { 0x200 },
+            magic: magic.map(str::to_owned),
+        };
+        let map = SdMap {
+            id0: "aaaa".to_owned(),
+            id1: "bbbb".to_owned(),
+            entries: vec![
+                entry("extdata", true, None),
+                entry("extdata/00000000", true, None),
+                entry("extdata/00000000/00001234", true, None),
+                entry("extdata/00000000/00001234/00000000", true, None),
+                entry(
+                    "extdata/00000000/00001234/00000000/00000001",
+                    false,
+                    Some("DIFF"),
+                ),
+                entry("extdata/00000000/00005678", true, None),
+                entry("title", true, None),
+                entry("title/00040000", true, None),
+                entry("title/00040000/00123400", true, None),
+                entry("title/00040000/00123400/data", true, None),
+                entry("title/00040000/00123400/data/00000001.sav", false, None),
+            ],
+        };
         assert_eq!(
-            name_os_to_3ds::<[u8; 16]>(OsStr::new("a\\x12c")),
-            Some((
-                [b'a', 0x12, b'c', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-                ""
-            ))
+            sd_map_text(&map),
+            "id0=aaaa\nid1=bbbb\n\
+             extdata/\nextdata/00000000/\nextdata/00000000/00001234/\n\
+             extdata/00000000/00001234/00000000/\n\
+             extdata/00000000/00001234/00000000/00000001 len=512 magic=DIFF\n\
+             extdata/00000000/00005678/\n\
+             title/\ntitle/00040000/\ntitle/00040000/00123400/\ntitle/00040000/00123400/data/\n\
+             title/00040000/00123400/data/00000001.sav len=512 magic=none\n\
+             sd_ext=0000000000001234 ok\nsd_ext=0000000000005678 missing\n\
+             sd_save=0004000000123400 unrecognized\n"
         );
+    }
+
+    #[test]
+    fn test_summary_text() {
+        let summary = Summary {
+            dirs: 2,
+            files: 3,
+            bytes: 1001,
+            stat: Stat {
+                block_len: 512,
+                total_blocks: 10,
+                free_blocks: 7,
+                total_files: 20,
+                free_files: 17,
+                total_dirs: 11,
+                free_dirs: 9,
+            },
+        };
+        let mut fingerprint = [0; 32];
+        fingerprint[0] = 0xAB;
+        fingerprint[31] = 0x01;
+        let hex = "ab00000000000000000000000000000000000000000000000000000000000001";
         assert_eq!(
-            name_os_to_3ds::<[u8; 16]>(OsStr::new("a\\x12\x34c")),
-            Some((
-                [b'a', 0x12, 0x34, b'c', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-                ""
-            ))
+            summary_text(&summary, &fingerprint, None, None, false),
+            format!(
+                "Directories: 2\nFiles: 3\nTotal file size: 1001 bytes\n\
+                 Allocated blocks: 3 of 10 (512 bytes each)\n\
+                 Free: 7 blocks (3584 bytes), 17 file slots, 9 directory slots\n\
+                 Content fingerprint: {}\n",
+                hex
+            )
         );
         assert_eq!(
-            name_os_to_3ds::<[u8; 16]>(OsStr::new("a\\x12\x34c\\+x\\yz")),
-            Some((
-                [b'a', 0x12, 0x34, b'c', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-                "x\\yz"
-            ))
+            summary_text(&summary, &fingerprint, None, None, true),
+            format!(
+                "{{\"dirs\":2,\"files\":3,\"bytes\":1001,\"block_len\":512,\"total_blocks\":10,\
+                 \"free_blocks\":7,\"total_files\":20,\"free_files\":17,\"total_dirs\":11,\
+                 \"free_dirs\":9,\"table_storage\":null,\"fingerprint\":\"{}\",\
+                 \"metadata_hash\":null}}\n",
+                hex
+            )
         );
+        assert!(summary_text(
+            &summary,
+            &fingerprint,
+            Some(TableStorage::Inline),
+            None,
+            false
+        )
+        .contains("directory slots\nTable storage: inline\nContent fingerprint"));
+        assert!(
+            summary_text(&summary, &fingerprint, Some(TableStorage::Fat), None, true)
+                .contains("\"table_storage\":\"fat\",")
+        );
+        assert!(
+            summary_text(&summary, &fingerprint, None, Some(fingerprint), false).ends_with(
+                &format!("Content fingerprint: {}\nMetadata hash: {}\n", hex, hex)
+            )
+        );
+        assert!(
+            summary_text(&summary, &fingerprint, None, Some(fingerprint), true)
+                .ends_with(&format!("\"metadata_hash\":\"{}\"}}\n", hex))
+        );
+    }
+
+    #[test]
+    fn test_inode_map_json() {
+        let mut map = libsave3ds::file_system::InodeMap {
+            dirs: HashMap::new(),
+            files: HashMap::new(),
+        };
+        let name = |s: &[u8]| {
+            let mut name = [0; 16];
+            name[0..s.len()].copy_from_slice(s);
+            name
+        };
+        map.dirs.insert(1, vec![]);
+        map.dirs.insert(3, vec![name(b"d")]);
+        map.dirs.insert(2, vec![name(b"d"), name(b"s")]);
+        map.files.insert(2, vec![name(b"d"), name(b"a\"")]);
+        map.files.insert(1, vec![name(b"b\x01")]);
+        let mut file_lens = HashMap::new();
+        file_lens.insert(1, (10, None));
+        file_lens.insert(2, (0, Some(0x2000)));
         assert_eq!(
-            name_os_to_3ds::<[u8; 16]>(OsStr::new("a\\x12\x34c\\+")),
-            Some((
-                [b'a', 0x12, 0x34, b'c', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-                ""
-            ))
+            inode_map_json(&map, &file_lens),
+            "{\"dirs\":{\"1\":\"\",\"2\":\"/d/s\",\"3\":\"/d\"},\
+             \"files\":{\"1\":{\"path\":\"/b\\\\x01\",\"len\":10,\"physical_len\":null},\
+             \"2\":{\"path\":\"/d/a\\\"\",\"len\":0,\"physical_len\":8192}}}\n"
         );
-        assert_eq!(name_os_to_3ds::<[u8; 16]>(OsStr::new("a\\2c")), None);
-        assert_eq!(name_os_to_3ds::<[u8; 16]>(OsStr::new("a\\x1")), None);
-        assert_eq!(name_os_to_3ds::<[u8; 16]>(OsStr::new("a\\x")), None);
-        assert_eq!(name_os_to_3ds::<[u8; 16]>(OsStr::new("a\\")), None);
-        assert!(name_os_to_3ds::<[u8; 16]>(OsStr::new("aaaaaaaaaaaaaaaa")).is_some());
-        assert!(name_os_to_3ds::<[u8; 16]>(OsStr::new("aaaaaaaaaaaaaaaaa")).is_none());
+    }
+
+    #[cfg(all(unix, feature = "unixfuse"))]
+    #[test]
+    fn test_readdir_offsets() {
+        let path = TempPath::new("save3ds_readdir");
+        let path = path.to_str().unwrap().to_owned();
+        let resource = resource();
+        let param = SaveDataFormatParam {
+            max_file: 3000,
+            file_buckets: 1009,
+            ..format_param()
+        };
+        resource.format_bare_save(&path, &param, 0x80000).unwrap();
+        let save = resource.open_bare_save(&path, true).unwrap();
+
+        let root = save.open_root().unwrap();
+        root.new_sub_dir(<[u8; 16]>::name_str_to_3ds("d").unwrap())
+            .unwrap();
+        for i in 0..3000 {
+            root.new_sub_file(<[u8; 16]>::name_str_to_3ds(&i.to_string()).unwrap(), 0)
+                .unwrap();
+        }
+        let frontend = FileSystemFrontend::new(save, false, false, None, 1);
+        let new_stream = || frontend.dir_stream(&frontend.save.open_root().unwrap(), 1);
+
+        // A full listing in one go, for comparison
+        let mut all = vec![];
+        new_stream()
+            .unwrap()
+            .fill(|ino, offset, _, name| {
+                all.push((ino, offset, name.to_owned()));
+                false
+            })
+            .unwrap();
+        assert_eq!(all.len(), 3003);
+        assert_eq!((all[0].2.as_str(), all[1].2.as_str()), (".", ".."));
+        let mut names: Vec<&str> = all[2..].iter().map(|(_, _, name)| name.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), 3001);
+        for (i, (_, offset, _)) in all.iter().enumerate() {
+            assert_eq!(*offset, i as i64 + 1);
+        }
+
+        // Small buffers that take at most `capacity` entries per call, with the kernel passing
+        // back the offset of the last entry it got
+        for capacity in [1, 2, 7, 64] {
+            let mut stream = new_stream().unwrap();
+            let mut got = vec![];
+            let mut offset = 0;
+            for _ in 0..all.len() + 1 {
+                frontend.seek_dir(&mut stream, offset).unwrap();
+                let mut taken = 0;
+                stream
+                    .fill(|ino, next, _, name| {
+                        if taken == capacity {
+                            return true;
+                        }
+                        taken += 1;
+                        got.push((ino, next, name.to_owned()));
+                        offset = next;
+                        false
+                    })
+                    .unwrap();
+                if taken == 0 {
+                    break;
+                }
+            }
+            assert_eq!(got, all, "capacity {}", capacity);
+        }
+
+        // Seeking, whether backward, forward, or to the end, restarts at the requested entry
+        let mut stream = new_stream().unwrap();
+        for offset in [2000, 5, 0, 3002, 3003, 5000, 1] {
+            frontend.seek_dir(&mut stream, offset).unwrap();
+            let mut first = None;
+            stream
+                .fill(|ino, next, _, name| {
+                    first = Some((ino, next, name.to_owned()));
+                    true
+                })
+                .unwrap();
+            assert_eq!(
+                first.as_ref(),
+                all.get(offset as usize),
+                "offset {}",
+                offset
+            );
+        }
+
+        // A failed seek, here for a directory that can't be opened again, leaves the stream
+        // as it was
+        let mut stream = new_stream().unwrap();
+        stream.ino = u32::MAX;
+        assert!(frontend.seek_dir(&mut stream, 1).is_err());
+        assert_eq!(stream.offset, 0);
+        let mut names = vec![];
+        stream
+            .fill(|_, _, _, name| {
+                names.push(name.to_owned());
+                false
+            })
+            .unwrap();
+        assert_eq!(names.len(), 3003);
+    }
+
+    #[cfg(all(unix, feature = "unixfuse"))]
+    #[test]
+    fn test_manual_commit() {
+        let path = TempPath::new("save3ds_manual_commit");
+        let path = path.to_str().unwrap().to_owned();
+        let snapshot = TempPath::new("save3ds_manual_commit_snapshot");
+        let mut resource = resource();
+        resource
+            .format_bare_save(&path, &format_param(), 0x20000)
+            .unwrap();
+        let image = std::fs::read(&path).unwrap();
+        let overlay = Rc::new(Overlay::new());
+        resource.set_overlay(Some(overlay.clone()));
+
+        let reopen = |write| resource.open_bare_save(&path, write);
+        let manual = ManualCommit {
+            overlay: overlay.clone(),
+            reopen: &reopen,
+            snapshot_path: Some(snapshot.to_path_buf()),
+        };
+        let mut frontend =
+            FileSystemFrontend::new(reopen(true).unwrap(), false, false, Some(manual), 1);
+        frontend.refresh_snapshot().unwrap();
+        assert!(snapshot.join(SNAPSHOT_MANIFEST).exists());
+
+        let name = |name| <[u8; 16]>::name_str_to_3ds(name).unwrap();
+        let add_file = |save: &SaveData, file| {
+            save.open_root()
+                .unwrap()
+                .new_sub_file(name(file), 4)
+                .unwrap()
+                .write(0, b"data")
+                .unwrap();
+        };
+        let has_file =
+            |save: &SaveData, file| save.open_root().unwrap().open_sub_file(name(file)).is_ok();
+
+        // Discarding drops the changes, including committed ones, and reopens the archive
+        add_file(&frontend.save, "a");
+        frontend.save.commit().unwrap();
+        frontend.control(b"discard\n").unwrap();
+        assert!(!has_file(&frontend.save, "a"));
+        assert!(!overlay.is_dirty());
+        assert_eq!(std::fs::read(&path).unwrap(), image);
+
+        // Committing writes the changes to the disk, and updates the snapshot
+        add_file(&frontend.save, "b");
+        assert!(!snapshot.join("b").exists());
+        frontend.control(b"commit\n").unwrap();
+        assert!(!overlay.is_dirty());
+        assert_ne!(std::fs::read(&path).unwrap(), image);
+        assert_eq!(std::fs::read(snapshot.join("b")).unwrap(), b"data");
+        assert!(has_file(&reopen(false).unwrap(), "b"));
+
+        // Discarding is refused while a file is open, and other commands are rejected
+        add_file(&frontend.save, "c");
+        let file = frontend.save.open_root().unwrap().open_sub_file(name("c"));
+        frontend.file_fh_map.insert(1, file.unwrap());
+        assert!(matches!(frontend.control(b"discard"), Err(Error::Busy)));
+        assert!(matches!(
+            frontend.control(b"save"),
+            Err(Error::InvalidValue)
+        ));
+        frontend.file_fh_map.clear();
+
+        // The rest is discarded on unmount
+        drop(frontend);
+        resource.set_overlay(None);
+        drop(overlay);
+        let save = resource.open_bare_save(&path, false).unwrap();
+        assert!(has_file(&save, "b"));
+        assert!(!has_file(&save, "c"));
     }
 }
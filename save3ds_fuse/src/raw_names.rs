@@ -0,0 +1,277 @@
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result};
+
+/// The file in each extracted directory that maps host file names to archive names.
+pub const SIDECAR: &str = "names.json";
+
+/// An entry of a directory extracted with `--raw-names`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RawEntry {
+    /// The host file name, which is the position of the entry in the directory.
+    pub index: String,
+    /// The archive name in `NameEncoding::Hex`.
+    pub name: String,
+    /// The inode in the original archive. This is informational: importing assigns new inodes.
+    pub ino: u32,
+    pub is_dir: bool,
+}
+
+/// Returns the host file name of the `index`-th entry of a directory.
+pub fn host_name(index: usize) -> String {
+    format!("{:03}", index)
+}
+
+/// Writes the sidecar as a JSON object keyed by the host file names, in the form of
+/// `{"000": {"name": "6100", "ino": 2, "type": "dir"}, ...}`.
+pub fn to_json(entries: &[RawEntry]) -> String {
+    let lines: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "  \"{}\": {{\"name\": \"{}\", \"ino\": {}, \"type\": \"{}\"}}",
+                entry.index,
+                entry.name,
+                entry.ino,
+                if entry.is_dir { "dir" } else { "file" }
+            )
+        })
+        .collect();
+    if lines.is_empty() {
+        "{}\n".to_owned()
+    } else {
+        format!("{{\n{}\n}}\n", lines.join(",\n"))
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_owned())
+}
+
+/// The subset of JSON values that sidecars use.
+enum Value {
+    String(String),
+    Number(u64),
+    Object(Vec<(String, Value)>),
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.s.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.s.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<()> {
+        if self.peek() != Some(c) {
+            return Err(invalid(&format!("expected '{}'", c as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut s = String::new();
+        loop {
+            let c = *self
+                .s
+                .get(self.pos)
+                .ok_or_else(|| invalid("unterminated string"))?;
+            self.pos += 1;
+            match c {
+                b'"' => return Ok(s),
+                b'\\' => {
+                    let c = *self.s.get(self.pos).ok_or_else(|| invalid("bad escape"))?;
+                    self.pos += 1;
+                    s.push(match c {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'n' => '\n',
+                        b't' => '\t',
+                        b'u' => {
+                            let code = self
+                                .s
+                                .get(self.pos..self.pos + 4)
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| invalid("bad escape"))?;
+                            self.pos += 4;
+                            code
+                        }
+                        _ => return Err(invalid("bad escape")),
+                    });
+                }
+                _ => {
+                    // Copies a whole UTF-8 sequence
+                    let start = self.pos - 1;
+                    while self.s.get(self.pos).is_some_and(|c| c & 0xC0 == 0x80) {
+                        self.pos += 1;
+                    }
+                    s += std::str::from_utf8(&self.s[start..self.pos])
+                        .map_err(|_| invalid("non-UTF-8 string"))?;
+                }
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.peek() {
+            Some(b'"') => Ok(Value::String(self.string()?)),
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = vec![];
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                }
+                loop {
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    members.push((key, self.value()?));
+                    if self.peek() == Some(b',') {
+                        self.pos += 1;
+                    } else {
+                        self.expect(b'}')?;
+                        return Ok(Value::Object(members));
+                    }
+                }
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let start = self.pos;
+                while self.s.get(self.pos).is_some_and(u8::is_ascii_digit) {
+                    self.pos += 1;
+                }
+                std::str::from_utf8(&self.s[start..self.pos])
+                    .unwrap()
+                    .parse()
+                    .map(Value::Number)
+                    .map_err(|_| invalid("number out of range"))
+            }
+            _ => Err(invalid("unsupported value")),
+        }
+    }
+}
+
+/// Parses a sidecar written by `to_json`. Entries are returned in the order of the file.
+/// Unknown fields are ignored.
+pub fn parse(content: &str) -> Result<Vec<RawEntry>> {
+    let mut parser = Parser {
+        s: content.as_bytes(),
+        pos: 0,
+    };
+    let members = match parser.value()? {
+        Value::Object(members) => members,
+        _ => return Err(invalid("expected an object")),
+    };
+    if parser.peek().is_some() {
+        return Err(invalid("trailing characters"));
+    }
+
+    let mut entries: Vec<RawEntry> = vec![];
+    for (index, value) in members {
+        // The index is used as a host path, so it can't be anything else than digits
+        if index.is_empty() || !index.bytes().all(|c| c.is_ascii_digit()) {
+            return Err(invalid(&format!("bad index {:?}", index)));
+        }
+        if entries.iter().any(|entry| entry.index == index) {
+            return Err(invalid(&format!("duplicate index {}", index)));
+        }
+        let fields = match value {
+            Value::Object(fields) => fields,
+            _ => return Err(invalid(&format!("entry {} is not an object", index))),
+        };
+        let (mut name, mut ino, mut is_dir) = (None, None, None);
+        for (key, value) in fields {
+            match (key.as_str(), value) {
+                ("name", Value::String(s)) => name = Some(s),
+                ("ino", Value::Number(n)) => ino = u32::try_from(n).ok(),
+                ("type", Value::String(s)) if s == "dir" => is_dir = Some(true),
+                ("type", Value::String(s)) if s == "file" => is_dir = Some(false),
+                ("name", _) | ("ino", _) | ("type", _) => {
+                    return Err(invalid(&format!("bad {} in entry {}", key, index)))
+                }
+                _ => (),
+            }
+        }
+        match (name, ino, is_dir) {
+            (Some(name), Some(ino), Some(is_dir)) => entries.push(RawEntry {
+                index,
+                name,
+                ino,
+                is_dir,
+            }),
+            _ => return Err(invalid(&format!("missing fields in entry {}", index))),
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let entries = vec![
+            RawEntry {
+                index: host_name(0),
+                name: "00ff2f5c".to_owned(),
+                ino: 2,
+                is_dir: true,
+            },
+            RawEntry {
+                index: host_name(1),
+                name: "61".to_owned(),
+                ino: 7,
+                is_dir: false,
+            },
+        ];
+        let json = to_json(&entries);
+        assert_eq!(
+            json,
+            "{\n  \"000\": {\"name\": \"00ff2f5c\", \"ino\": 2, \"type\": \"dir\"},\n  \
+             \"001\": {\"name\": \"61\", \"ino\": 7, \"type\": \"file\"}\n}\n"
+        );
+        assert_eq!(parse(&json).unwrap(), entries);
+        assert_eq!(parse(&to_json(&[])).unwrap(), vec![]);
+
+        // Hand-edited sidecars can be formatted differently and have extra fields
+        assert_eq!(
+            parse(
+                "{\"001\":{\"type\":\"file\",\"ino\":7,\"note\":{\"a\":\"\\u0062\"},\"name\":\"61\"}}"
+            )
+            .unwrap(),
+            entries[1..]
+        );
+    }
+
+    #[test]
+    fn reject() {
+        for json in [
+            "",
+            "[]",
+            "{\"000\": {\"name\": \"61\", \"ino\": 2}}",
+            "{\"000\": {\"name\": \"61\", \"ino\": 2, \"type\": \"link\"}}",
+            "{\"000\": {\"name\": 61, \"ino\": 2, \"type\": \"dir\"}}",
+            "{\"000\": {\"name\": \"61\", \"ino\": 4294967296, \"type\": \"dir\"}}",
+            "{\"../a\": {\"name\": \"61\", \"ino\": 2, \"type\": \"dir\"}}",
+            "{\"000\": {\"name\": \"61\", \"ino\": 2, \"type\": \"dir\"}, \
+             \"000\": {\"name\": \"62\", \"ino\": 3, \"type\": \"dir\"}}",
+            "{\"000\": {\"name\": \"61\", \"ino\": 2, \"type\": \"dir\"}} x",
+        ] {
+            assert!(parse(json).is_err(), "{}", json);
+        }
+    }
+}
@@ -0,0 +1,399 @@
+use std::io::{Error, ErrorKind, Read, Result};
+
+/// The kind of an entry in a tar archive.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TarEntryKind {
+    Dir,
+    File(Vec<u8>),
+    /// Any other entry type (symlink, device, etc.), with the raw type flag.
+    Other(u8),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TarEntry {
+    pub path: String,
+    pub kind: TarEntryKind,
+}
+
+/// A minimal streaming reader for ustar / GNU tar archives.
+pub struct TarReader<R: Read> {
+    reader: R,
+    finished: bool,
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_owned())
+}
+
+fn parse_octal(field: &[u8]) -> Result<u64> {
+    let s = std::str::from_utf8(field).map_err(|_| invalid("non-ASCII numeric field"))?;
+    let s = s.trim_matches(|c| c == '\0' || c == ' ');
+    if s.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(s, 8).map_err(|_| invalid("malformed numeric field"))
+}
+
+fn parse_str(field: &[u8]) -> Result<String> {
+    let end = field.iter().position(|&c| c == 0).unwrap_or(field.len());
+    String::from_utf8(field[0..end].to_vec()).map_err(|_| invalid("non-UTF-8 path"))
+}
+
+impl<R: Read> TarReader<R> {
+    pub fn new(reader: R) -> TarReader<R> {
+        TarReader {
+            reader,
+            finished: false,
+        }
+    }
+
+    /// Fills `buf` from the stream, failing if the stream ends before that.
+    fn read_block(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.reader.read_exact(buf).map_err(|e| {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                Error::new(ErrorKind::UnexpectedEof, "the archive ends unexpectedly")
+            } else {
+                e
+            }
+        })
+    }
+
+    fn read_data(&mut self, size: u64) -> Result<Vec<u8>> {
+        // Read in chunks, so that a corrupted size fails at the end of the stream instead of
+        // allocating all the memory it claims upfront
+        let mut data = vec![];
+        let mut chunk = vec![0; 0x10000];
+        let mut left = size;
+        while left != 0 {
+            let len = std::cmp::min(left, chunk.len() as u64) as usize;
+            self.read_block(&mut chunk[0..len])?;
+            data.extend_from_slice(&chunk[0..len]);
+            left -= len as u64;
+        }
+        let padding = (512 - size % 512) % 512;
+        self.read_block(&mut chunk[0..padding as usize])?;
+        Ok(data)
+    }
+
+    /// Reads the next entry. Returns `None` at the end of the archive, which is marked by two
+    /// zero blocks. A stream ending before them is an error, because it is likely cut short.
+    pub fn next_entry(&mut self) -> Result<Option<TarEntry>> {
+        let mut long_name = None;
+        let mut pax_path = None;
+        let mut pax_size = None;
+        loop {
+            if self.finished {
+                return Ok(None);
+            }
+
+            let mut header = [0; 512];
+            self.read_block(&mut header)?;
+
+            if header.iter().all(|&c| c == 0) {
+                self.read_block(&mut header)?;
+                if header.iter().any(|&c| c != 0) {
+                    return Err(invalid("a single zero block in the middle of the archive"));
+                }
+                if long_name.is_some() || pax_path.is_some() || pax_size.is_some() {
+                    return Err(invalid("an extended header without an entry"));
+                }
+                self.finished = true;
+                return Ok(None);
+            }
+
+            let checksum = parse_octal(&header[148..156])?;
+            let actual: u64 = header
+                .iter()
+                .enumerate()
+                .map(|(i, &c)| {
+                    if (148..156).contains(&i) {
+                        32
+                    } else {
+                        c as u64
+                    }
+                })
+                .sum();
+            if checksum != actual {
+                return Err(invalid("header checksum mismatch"));
+            }
+
+            let type_flag = header[156];
+            let size = match type_flag {
+                b'L' | b'x' | b'g' => parse_octal(&header[124..136])?,
+                _ => match pax_size.take() {
+                    Some(size) => size,
+                    None => parse_octal(&header[124..136])?,
+                },
+            };
+            let data = self.read_data(size)?;
+
+            let kind = match type_flag {
+                b'L' => {
+                    // GNU long name for the next entry
+                    long_name = Some(parse_str(&data)?);
+                    continue;
+                }
+                b'x' => {
+                    // pax extended header for the next entry. Only the keys affecting where
+                    // and what to import matter here.
+                    for (key, value) in parse_pax(&data)? {
+                        match key.as_str() {
+                            "path" => pax_path = Some(value),
+                            "size" => {
+                                pax_size =
+                                    Some(value.parse().map_err(|_| invalid("malformed pax size"))?)
+                            }
+                            _ => (),
+                        }
+                    }
+                    continue;
+                }
+                b'g' => {
+                    // pax global header. Paths and sizes set for all entries are not supported
+                    if parse_pax(&data)?
+                        .iter()
+                        .any(|(key, _)| key == "path" || key == "size")
+                    {
+                        return Err(invalid("unsupported pax global path or size"));
+                    }
+                    continue;
+                }
+                b'0' | 0 | b'7' => TarEntryKind::File(data),
+                b'5' => TarEntryKind::Dir,
+                c => TarEntryKind::Other(c),
+            };
+
+            let path = if let Some(path) = pax_path.take() {
+                path
+            } else if let Some(name) = long_name.take() {
+                name
+            } else {
+                let name = parse_str(&header[0..100])?;
+                let prefix = if &header[257..262] == b"ustar" {
+                    parse_str(&header[345..500])?
+                } else {
+                    String::new()
+                };
+                if prefix.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", prefix, name)
+                }
+            };
+
+            let kind = match kind {
+                TarEntryKind::File(_) if path.ends_with('/') => TarEntryKind::Dir,
+                kind => kind,
+            };
+
+            return Ok(Some(TarEntry { path, kind }));
+        }
+    }
+}
+
+/// Parses the records of a pax extended header, each in the form of `"<len> <key>=<value>\n"`
+/// where `<len>` is the length of the whole record.
+fn parse_pax(data: &[u8]) -> Result<Vec<(String, String)>> {
+    let mut records = vec![];
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = rest
+            .iter()
+            .position(|&c| c == b' ')
+            .ok_or_else(|| invalid("malformed pax record"))?;
+        let len: usize = std::str::from_utf8(&rest[0..space])
+            .ok()
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| invalid("malformed pax record length"))?;
+        if len <= space + 1 || len > rest.len() || rest[len - 1] != b'\n' {
+            return Err(invalid("malformed pax record length"));
+        }
+        let record = std::str::from_utf8(&rest[space + 1..len - 1])
+            .map_err(|_| invalid("non-UTF-8 pax record"))?;
+        let (key, value) = record
+            .split_once('=')
+            .ok_or_else(|| invalid("malformed pax record"))?;
+        records.push((key.to_owned(), value.to_owned()));
+        rest = &rest[len..];
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// Builds a ustar header with a valid checksum.
+    fn make_header(path: &str, type_flag: u8, size: usize) -> [u8; 512] {
+        let mut header = [0; 512];
+        header[0..path.len()].copy_from_slice(path.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[156] = type_flag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[148..156].copy_from_slice(b"        ");
+        let checksum: u32 = header.iter().map(|&c| c as u32).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+        header
+    }
+
+    /// Appends an entry to a tar archive being built.
+    pub(crate) fn append(tar: &mut Vec<u8>, path: &str, type_flag: u8, data: &[u8]) {
+        tar.extend_from_slice(&make_header(path, type_flag, data.len()));
+        tar.extend_from_slice(data);
+        tar.resize(tar.len() + (512 - data.len() % 512) % 512, 0);
+    }
+
+    #[test]
+    fn read_entries() {
+        let mut tar = vec![];
+        append(&mut tar, "dir/", b'5', &[]);
+        append(&mut tar, "dir/file", b'0', &[1, 2, 3]);
+        append(&mut tar, "empty", b'0', &[]);
+        append(&mut tar, "././@LongLink", b'L', b"very/long/name\0");
+        append(&mut tar, "truncated", b'0', &[4; 600]);
+        append(&mut tar, "link", b'2', &[]);
+        tar.extend_from_slice(&[0; 1024]);
+
+        let mut reader = TarReader::new(&tar[..]);
+        let mut entries = vec![];
+        while let Some(entry) = reader.next_entry().unwrap() {
+            entries.push(entry);
+        }
+
+        assert_eq!(
+            entries,
+            vec![
+                TarEntry {
+                    path: "dir/".to_owned(),
+                    kind: TarEntryKind::Dir
+                },
+                TarEntry {
+                    path: "dir/file".to_owned(),
+                    kind: TarEntryKind::File(vec![1, 2, 3])
+                },
+                TarEntry {
+                    path: "empty".to_owned(),
+                    kind: TarEntryKind::File(vec![])
+                },
+                TarEntry {
+                    path: "very/long/name".to_owned(),
+                    kind: TarEntryKind::File(vec![4; 600])
+                },
+                TarEntry {
+                    path: "link".to_owned(),
+                    kind: TarEntryKind::Other(b'2')
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let mut tar = vec![];
+        append(&mut tar, "file", b'0', &[1]);
+        tar[0] = b'g';
+        assert!(TarReader::new(&tar[..]).next_entry().is_err());
+    }
+
+    /// Builds a pax record, whose length field counts the whole record including itself.
+    fn pax_record(key: &str, value: &str) -> String {
+        let body = format!(" {}={}\n", key, value);
+        let mut len = body.len() + 1;
+        while format!("{}{}", len, body).len() != len {
+            len += 1;
+        }
+        format!("{}{}", len, body)
+    }
+
+    #[test]
+    fn pax_header() {
+        let long_path = format!("{}/file", "d".repeat(120));
+        let mut tar = vec![];
+        let records = pax_record("mtime", "1.5") + &pax_record("path", &long_path);
+        append(&mut tar, "PaxHeaders/file", b'x', records.as_bytes());
+        append(&mut tar, "file", b'0', &[1, 2]);
+        append(&mut tar, "other", b'0', &[3]);
+        tar.extend_from_slice(&[0; 1024]);
+
+        let mut reader = TarReader::new(&tar[..]);
+        assert_eq!(
+            reader.next_entry().unwrap(),
+            Some(TarEntry {
+                path: long_path,
+                kind: TarEntryKind::File(vec![1, 2])
+            })
+        );
+        // The extended header only applies to the entry right after it
+        assert_eq!(
+            reader.next_entry().unwrap(),
+            Some(TarEntry {
+                path: "other".to_owned(),
+                kind: TarEntryKind::File(vec![3])
+            })
+        );
+        assert_eq!(reader.next_entry().unwrap(), None);
+
+        let mut tar = vec![];
+        append(&mut tar, "global", b'g', pax_record("path", "a").as_bytes());
+        append(&mut tar, "file", b'0', &[1]);
+        tar.extend_from_slice(&[0; 1024]);
+        assert!(TarReader::new(&tar[..]).next_entry().is_err());
+
+        let mut tar = vec![];
+        append(&mut tar, "bad", b'x', b"100 path=a\n");
+        append(&mut tar, "file", b'0', &[1]);
+        tar.extend_from_slice(&[0; 1024]);
+        assert!(TarReader::new(&tar[..]).next_entry().is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let mut tar = vec![];
+        append(&mut tar, "file", b'0', &[1; 1000]);
+        append(&mut tar, "next", b'0', &[2]);
+        tar.extend_from_slice(&[0; 1024]);
+
+        // Cut at block boundaries, including right before or between the end blocks, or in the
+        // middle of a block
+        for len in [0, 512, 1024, 1536, 2048, 2560, 100, 1100] {
+            let mut reader = TarReader::new(&tar[0..len]);
+            let result: Result<()> = (|| {
+                while reader.next_entry()?.is_some() {}
+                Ok(())
+            })();
+            assert!(result.is_err(), "{}", len);
+        }
+
+        let mut reader = TarReader::new(&tar[..]);
+        while reader.next_entry().unwrap().is_some() {}
+    }
+
+    #[test]
+    fn corrupted_size() {
+        // A size of 8 GiB with no data fails at the end of the stream instead of allocating it
+        let mut tar = make_header("file", b'0', 0).to_vec();
+        tar[124..135].copy_from_slice(b"77777777777");
+        tar[148..156].copy_from_slice(b"        ");
+        let checksum: u32 = tar.iter().map(|&c| c as u32).sum();
+        tar[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+        tar.extend_from_slice(&[0; 1024]);
+
+        let error = TarReader::new(&tar[..]).next_entry().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn zero_block_in_the_middle() {
+        let mut tar = vec![];
+        append(&mut tar, "file", b'0', &[1]);
+        tar.extend_from_slice(&[0; 512]);
+        append(&mut tar, "next", b'0', &[2]);
+        tar.extend_from_slice(&[0; 1024]);
+
+        let mut reader = TarReader::new(&tar[..]);
+        reader.next_entry().unwrap();
+        assert!(reader.next_entry().is_err());
+    }
+}